@@ -0,0 +1,151 @@
+//! A tiny language server for testing the editor's LSP client.
+//!
+//! It keeps each open document in sync from (incremental) `didChange` notifications
+//! and reports every `TODO` in it as a warning, so the diagnostics reveal whether the
+//! client's edits were applied correctly. Completion, hover, definition, references and
+//! rename return canned answers.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok()?;
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn send(message: Value) {
+    let body = message.to_string();
+    let mut out = io::stdout().lock();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    out.flush().unwrap();
+}
+
+/// Byte offset of an LSP position (UTF-16 columns)
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap() as usize;
+    let character = position["character"].as_u64().unwrap() as usize;
+    let start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn publish_todos(uri: &str, text: &str) {
+    let mut diagnostics = Vec::new();
+    for (line, content) in text.lines().enumerate() {
+        for (byte, _) in content.match_indices("TODO") {
+            let character = content[..byte].encode_utf16().count();
+            diagnostics.push(json!({
+                "range": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line, "character": character + 4 },
+                },
+                "severity": 2,
+                "source": "stub",
+                "message": "TODO left in code",
+            }));
+        }
+    }
+    send(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+fn main() {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    while let Some(message) = read_message(&mut reader) {
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        let position = &params["position"];
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 2 },
+                    "completionProvider": { "triggerCharacters": ["."] },
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "renameProvider": true,
+                    "documentFormattingProvider": true,
+                },
+            }),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default().to_string();
+                publish_todos(&uri, &text);
+                documents.insert(uri, text);
+                continue;
+            }
+            "textDocument/didChange" => {
+                let text = documents.entry(uri.clone()).or_default();
+                for change in params["contentChanges"].as_array().into_iter().flatten() {
+                    let new_text = change["text"].as_str().unwrap_or_default();
+                    match change.get("range") {
+                        Some(range) => {
+                            let (start, end) = (offset(text, &range["start"]), offset(text, &range["end"]));
+                            text.replace_range(start..end, new_text);
+                        }
+                        None => *text = new_text.to_string(),
+                    }
+                }
+                publish_todos(&uri, text);
+                continue;
+            }
+            "textDocument/completion" => json!({
+                "isIncomplete": false,
+                "items": [{ "label": "alpha", "detail": "fn()" }, { "label": "beta" }],
+            }),
+            "textDocument/hover" => json!({
+                "contents": { "kind": "plaintext", "value": format!("hover {}:{}", position["line"], position["character"]) },
+            }),
+            "textDocument/definition" | "textDocument/references" => json!([{
+                "uri": uri,
+                "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 2 } },
+            }]),
+            "textDocument/rename" => json!({
+                "changes": { uri: [{
+                    "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 2 } },
+                    "newText": params["newName"],
+                }]},
+            }),
+            // Says which options it was given, at the start of the document
+            "textDocument/formatting" => {
+                let options = &params["options"];
+                json!([{
+                    "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } },
+                    "newText": format!("tabSize {} insertSpaces {}\n", options["tabSize"], options["insertSpaces"]),
+                }])
+            }
+            "shutdown" => Value::Null,
+            "exit" => break,
+            _ => continue,
+        };
+        if let Some(id) = message.get("id") {
+            send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+        }
+    }
+}
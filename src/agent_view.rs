@@ -1,6 +1,5 @@
 use crate::terminal::Terminal;
 use eframe::egui::{self, Color32, CornerRadius, FontId, Rect, Vec2};
-use std::sync::{Arc, Mutex};
 
 /// Message types in the chat view
 #[derive(Clone, Debug)]
@@ -20,8 +19,6 @@ pub struct AgentView {
     terminal: Terminal,
     messages: Vec<ChatMessage>,
     input_buf: String,
-    /// Raw output buffer from PTY, accumulated between parses
-    raw_buf: Arc<Mutex<Vec<u8>>>,
     /// Track what we've already processed from the terminal
     last_screen_text: String,
     /// Whether we're currently accumulating assistant output
@@ -41,7 +38,6 @@ impl AgentView {
             terminal,
             messages: Vec::new(),
             input_buf: String::new(),
-            raw_buf: Arc::new(Mutex::new(Vec::new())),
             last_screen_text: String::new(),
             accumulating_assistant: false,
             id,
@@ -50,11 +46,6 @@ impl AgentView {
         }
     }
 
-    /// Get the underlying terminal (for resize, etc)
-    pub fn terminal_mut(&mut self) -> &mut Terminal {
-        &mut self.terminal
-    }
-
     /// Poll terminal screen for new output and parse into messages
     fn poll_output(&mut self) {
         let current_text = self.terminal.screen_text();
@@ -416,7 +407,7 @@ fn render_markdown_simple(ui: &mut egui::Ui, text: &str) {
         }
 
         // Numbered list
-        if line.len() > 2 && line.chars().next().map_or(false, |c| c.is_ascii_digit()) && line.contains(". ") {
+        if line.len() > 2 && line.chars().next().is_some_and(|c| c.is_ascii_digit()) && line.contains(". ") {
             ui.label(
                 egui::RichText::new(format!("  {}", line))
                     .size(13.0)
@@ -447,32 +438,14 @@ fn render_inline_text(ui: &mut egui::Ui, line: &str) {
         // Has bold markers - render with bold
         let parts: Vec<&str> = line.split("**").collect();
         let mut job = egui::text::LayoutJob::default();
-        for (i, part) in parts.iter().enumerate() {
+        for part in &parts {
             if part.is_empty() {
                 continue;
             }
-            let format = if i % 2 == 1 {
-                egui::TextFormat {
-                    font_id: FontId::proportional(13.0),
-                    color: crate::theme::TEXT_PRIMARY,
-                    ..Default::default()
-                }
-            } else {
-                egui::TextFormat {
-                    font_id: FontId::proportional(13.0),
-                    color: crate::theme::TEXT_PRIMARY,
-                    ..Default::default()
-                }
-            };
-            // For bold parts (odd index)
-            let format = if i % 2 == 1 {
-                egui::TextFormat {
-                    font_id: FontId::new(13.0, egui::FontFamily::Proportional),
-                    color: crate::theme::TEXT_PRIMARY,
-                    ..Default::default()
-                }
-            } else {
-                format
+            let format = egui::TextFormat {
+                font_id: FontId::proportional(13.0),
+                color: crate::theme::TEXT_PRIMARY,
+                ..Default::default()
             };
             job.append(part, 0.0, format);
         }
@@ -1,25 +1,54 @@
 use crate::agent_view::AgentView;
+use crate::config::{Config, TerminalProfile};
+use crate::diff_view::{Compare, DiffView, Side};
 use crate::editor::Editor;
 use crate::file_tree::FileTree;
+use crate::file_view::{self, FileKind, FileView};
+use crate::format::{self, FormatJob, Formatter};
+use crate::lsp::{self, Location, LspEvent, LspManager, LspRequest, TextEdit};
 use crate::pane::{self, PaneNode, TabContent};
+use crate::problems::Problems;
+use crate::project_search::{FileReplacement, ProjectSearch};
 use crate::terminal::Terminal;
 use crate::theme::Theme;
+use crate::vim::Vim;
+use crate::watcher::FileWatcher;
 use eframe::egui;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use crate::buffer::TextBuffer;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often terminal output is searched for build errors while it's changing
+const BUILD_SCAN_INTERVAL: Duration = Duration::from_millis(500);
 
 pub struct AioApp {
     pane_root: PaneNode,
     terminals: HashMap<usize, Terminal>,
     editors: HashMap<usize, Editor>,
+    file_views: HashMap<usize, FileView>,
+    diff_views: HashMap<usize, DiffView>,
     agent_views: HashMap<usize, AgentView>,
     file_tree: FileTree,
+    project_search: ProjectSearch,
+    problems: Problems,
     next_terminal_id: usize,
     next_editor_id: usize,
     pending_open_folder: Option<PathBuf>,
     pending_focus: Option<TabContent>,
     /// Tab that should grab keyboard focus on next render
     focus_grab: Option<TabContent>,
+    config: Config,
+    /// Filter text of the open terminal profile picker (Cmd+Shift+T)
+    profile_picker: Option<String>,
+    lsp: LspManager,
+    /// Title and entries of the open definition/references picker
+    location_picker: Option<(String, Vec<Location>)>,
+    /// Formatter programs running for editors
+    format_jobs: Vec<FormatJob>,
+    watcher: FileWatcher,
+    /// When terminal output was last searched for build errors
+    build_scanned: Instant,
 }
 
 impl AioApp {
@@ -54,19 +83,36 @@ impl AioApp {
                 break;
             }
         }
+
+        // Register per-profile terminal fonts, falling back to the monospace chain
+        let config = Config::load();
+        let monospace = fonts.families.get(&egui::FontFamily::Monospace).cloned().unwrap_or_default();
+        for profile in &config.profiles {
+            let Some(path) = profile.font_path() else { continue };
+            match std::fs::read(&path) {
+                Ok(data) => {
+                    let key = profile.font_family_name();
+                    fonts.font_data.insert(key.clone(), egui::FontData::from_owned(data).into());
+                    let mut family = vec![key.clone()];
+                    family.extend(monospace.iter().cloned());
+                    fonts.families.insert(egui::FontFamily::Name(key.into()), family);
+                }
+                Err(e) => eprintln!("Failed to load font {}: {}", path.display(), e),
+            }
+        }
         cc.egui_ctx.set_fonts(fonts);
 
         let cwd = std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from("/"));
 
+        let profile = config.default_terminal_profile();
+        let colors = config.color_scheme_for(&profile);
         let mut terminals = HashMap::new();
-        let term0 = Terminal::new(24, 80).expect("Failed to create terminal");
-        terminals.insert(0, term0);
-        let term1 = Terminal::new(24, 80).expect("Failed to create terminal");
-        terminals.insert(1, term1);
-
-        // Create a third terminal for the agent pane
-        let term2 = Terminal::new(24, 80).expect("Failed to create terminal");
-        terminals.insert(2, term2);
+        for id in 0..3 {
+            // The third terminal is for the agent pane
+            let mut term = Terminal::new(24, 80, &profile, colors.clone()).expect("Failed to create terminal");
+            term.clipboard_read = config.clipboard_read;
+            terminals.insert(id, term);
+        }
 
         // Layout: FileTree(15%) | Editor/Terminal area(55%) | Agent pane(30%)
         let layout = PaneNode::hsplit(
@@ -87,41 +133,491 @@ impl AioApp {
             pane_root: layout,
             terminals,
             editors: HashMap::new(),
+            file_views: HashMap::new(),
+            diff_views: HashMap::new(),
             agent_views: HashMap::new(),
+            project_search: ProjectSearch::new(cwd.clone()),
+            problems: Problems::new(cwd.clone()),
             file_tree: FileTree::new(cwd),
             next_terminal_id: 3,
             next_editor_id: 0,
             pending_open_folder: None,
             pending_focus: None,
             focus_grab: None,
+            lsp: LspManager::new(config.lsp_servers.clone()),
+            config,
+            profile_picker: None,
+            location_picker: None,
+            format_jobs: Vec::new(),
+            watcher: FileWatcher::new(&cc.egui_ctx),
+            build_scanned: Instant::now(),
+        }
+    }
+
+    fn open_terminal(&mut self, profile: &TerminalProfile) {
+        let id = self.next_terminal_id;
+        self.next_terminal_id += 1;
+        match Terminal::new(24, 80, profile, self.config.color_scheme_for(profile)) {
+            Ok(mut term) => {
+                term.clipboard_read = self.config.clipboard_read;
+                self.terminals.insert(id, term);
+                let tab = TabContent::Terminal(id);
+                Self::add_tab_to_pane(&mut self.pane_root, tab.clone());
+                self.pending_focus = Some(tab);
+            }
+            Err(e) => {
+                eprintln!("Failed to start terminal profile '{}': {}", profile.name, e);
+            }
+        }
+    }
+
+    /// Draw the profile picker; returns the chosen profile, if any
+    fn show_profile_picker(&mut self, ctx: &egui::Context) -> Option<TerminalProfile> {
+        let query = self.profile_picker.as_mut()?;
+        let profiles = self.config.terminal_profiles();
+        let needle = query.to_lowercase();
+        let matching: Vec<&TerminalProfile> = profiles
+            .iter()
+            .filter(|p| p.name.to_lowercase().contains(&needle))
+            .collect();
+
+        let mut chosen = None;
+        let mut close = false;
+        egui::Window::new("New Terminal")
+            .collapsible(false)
+            .resizable(false)
+            .title_bar(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 60.0))
+            .fixed_size(egui::vec2(320.0, 0.0))
+            .show(ctx, |ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(query)
+                        .hint_text("Terminal profile...")
+                        .desired_width(f32::INFINITY),
+                );
+                response.request_focus();
+                ui.separator();
+                for profile in &matching {
+                    let label = match &profile.program {
+                        Some(program) => format!("{}  —  {}", profile.name, program),
+                        None => profile.name.clone(),
+                    };
+                    if ui.selectable_label(false, label).clicked() {
+                        chosen = Some((*profile).clone());
+                    }
+                }
+                if matching.is_empty() {
+                    ui.label(egui::RichText::new("No matching profiles").color(crate::theme::TEXT_SECONDARY));
+                }
+                if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    chosen = matching.first().map(|p| (*p).clone());
+                }
+                if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                    close = true;
+                }
+            });
+
+        if chosen.is_some() || close {
+            self.profile_picker = None;
+        }
+        chosen
+    }
+
+    /// Draw the definition/references picker; returns the chosen location, if any
+    fn show_location_picker(&mut self, ctx: &egui::Context) -> Option<Location> {
+        let (title, locations) = self.location_picker.as_ref()?;
+        let root = self.file_tree.root.clone();
+        let mut chosen = None;
+        let mut close = false;
+        egui::Window::new(title.as_str())
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 60.0))
+            .fixed_size(egui::vec2(480.0, 0.0))
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical().max_height(360.0).show(ui, |ui| {
+                    for location in locations {
+                        let path = location.path.strip_prefix(&root).unwrap_or(&location.path);
+                        let start = location.range.start;
+                        let label = format!("{}:{}:{}", path.display(), start.line + 1, start.character + 1);
+                        if ui.selectable_label(false, label).clicked() {
+                            chosen = Some(location.clone());
+                        }
+                    }
+                });
+                if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    chosen = locations.first().cloned();
+                }
+                if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                    close = true;
+                }
+            });
+
+        if chosen.is_some() || close {
+            self.location_picker = None;
+        }
+        chosen
+    }
+
+    /// Open a file at an LSP location
+    fn open_location(&mut self, location: Location) {
+        if let Some(id) = self.open_file_in_editor(location.path) {
+            if let Some(editor) = self.editors.get_mut(&id) {
+                editor.reveal(location.range.to_bytes(&editor.content));
+            }
+        }
+    }
+
+    /// Watch the open files and the expanded folders of the file tree, reloading
+    /// editors whose files were changed by other programs
+    fn watch_files(&mut self) {
+        let mut dirs: HashSet<PathBuf> = self.file_tree.expanded_dirs().cloned().collect();
+        dirs.extend(self.editors.values().filter_map(|e| e.file_path.as_deref()?.parent().map(Path::to_path_buf)));
+        self.watcher.watch_dirs(dirs);
+
+        let changed = self.watcher.take_changed();
+        if changed.is_empty() {
+            return;
+        }
+        self.file_tree.invalidate(&changed);
+        for editor in self.editors.values_mut() {
+            let Some(path) = &editor.file_path else { continue };
+            // Either side may have come through a symlink
+            if changed.contains(path) || changed.contains(&crate::watcher::canonical(path)) {
+                editor.disk_changed();
+            }
+        }
+    }
+
+    /// Show a diff in a new tab
+    fn open_diff(&mut self, view: DiffView) {
+        let id = self.next_editor_id;
+        self.next_editor_id += 1;
+        self.diff_views.insert(id, view);
+        let tab = TabContent::Diff(id);
+        Self::add_tab_to_pane(&mut self.pane_root, tab.clone());
+        self.pending_focus = Some(tab);
+    }
+
+    /// Open the comparisons editors asked for: the buffer against the file on disk
+    /// or against its last commit
+    fn open_requested_diffs(&mut self) {
+        let mut views = Vec::new();
+        for editor in self.editors.values_mut() {
+            let Some(with) = editor.diff_request.take() else { continue };
+            let text = match editor.compare_text(with) {
+                Ok(text) => text,
+                Err(e) => {
+                    editor.notice = Some(e);
+                    continue;
+                }
+            };
+            let path = editor.file_path.clone();
+            let name = path.as_ref().and_then(|p| p.file_name()).map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            let (label, title) = match with {
+                Compare::Disk => ("On disk", format!("{} (saved ↔ yours)", name)),
+                Compare::Head => ("HEAD", format!("{} (HEAD ↔ working tree)", name)),
+            };
+            let old = Side::new(label.to_string(), &text, path.as_deref());
+            let new = Side::new("Yours".to_string(), &editor.content.to_string(), path.as_deref());
+            views.push(DiffView::new(title, old, new));
+        }
+        for view in views {
+            self.open_diff(view);
+        }
+    }
+
+    /// Compare two files from the file tree
+    fn compare_files(&mut self, old: &Path, new: &Path) {
+        let read = |path: &Path| std::fs::read(path).map(|bytes| crate::encoding::decode(&bytes).0);
+        let (old_text, new_text) = match (read(old), read(new)) {
+            (Ok(old_text), Ok(new_text)) => (old_text, new_text),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Failed to compare files: {}", e);
+                return;
+            }
+        };
+        let name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let old_side = Side::new(old.display().to_string(), &old_text, Some(old));
+        let new_side = Side::new(new.display().to_string(), &new_text, Some(new));
+        self.open_diff(DiffView::new(format!("{} ↔ {}", name(old), name(new)), old_side, new_side));
+    }
+
+    /// Start the formatters editors asked for and hand finished results back
+    fn run_formatters(&mut self, ctx: &egui::Context) {
+        for (id, editor) in &mut self.editors {
+            // A formatter that never answers mustn't hold up saving: give up on it
+            // (dropping its job kills it) and save what there is
+            if editor.formatting_overdue() {
+                self.format_jobs.retain(|job| job.editor != *id);
+                let seconds = format::TIMEOUT.as_secs();
+                editor.finish_formatting(Err(format!("the formatter didn't finish within {} seconds", seconds)));
+            }
+            // Requests made while formatting wait their turn
+            if editor.is_formatting() {
+                continue;
+            }
+            let Some(save) = editor.format_request.take() else { continue };
+            editor.start_formatting(save);
+            let Some(path) = editor.file_path.clone() else {
+                editor.finish_formatting(Err("save the file first".to_string()));
+                continue;
+            };
+            match format::formatter_for(&path, &self.config.formatters) {
+                Formatter::Command(config) => {
+                    self.format_jobs.push(FormatJob::start(*id, config, &path, editor.content.to_string()));
+                }
+                Formatter::LanguageServer if self.lsp.client_for(&path).is_some() => {
+                    editor.lsp_requests.push(LspRequest::Formatting(editor.indent));
+                }
+                Formatter::LanguageServer => {
+                    editor.finish_formatting(Err("no formatter or language server for this file".to_string()));
+                }
+            }
+        }
+        let editors = &mut self.editors;
+        self.format_jobs.retain(|job| match job.poll() {
+            Some(result) => {
+                if let Some(editor) = editors.get_mut(&job.editor) {
+                    editor.finish_formatting(result);
+                }
+                false
+            }
+            None => true,
+        });
+        if !self.format_jobs.is_empty() || self.editors.values().any(Editor::is_formatting) {
+            ctx.request_repaint_after(Duration::from_millis(50));
+        }
+    }
+
+    /// Turn compiler errors printed in terminals into problems
+    fn scan_build_output(&mut self, ctx: &egui::Context) {
+        if self.build_scanned.elapsed() < BUILD_SCAN_INTERVAL {
+            return;
+        }
+        self.build_scanned = Instant::now();
+        let terminals = &self.terminals;
+        let mut changed = self.problems.retain_build(|id| terminals.contains_key(&id));
+        let mut scanned = false;
+        for (id, terminal) in &mut self.terminals {
+            if let Some((command, output)) = terminal.take_new_output() {
+                changed.extend(self.problems.scan_build(*id, &command, &output));
+                scanned = true;
+            }
+        }
+        // Look again once output stops, in case the rest arrived in between
+        if scanned {
+            ctx.request_repaint_after(BUILD_SCAN_INTERVAL);
+        }
+        self.refresh_diagnostics(&changed);
+    }
+
+    /// Show editors of these files their current diagnostics
+    fn refresh_diagnostics(&mut self, paths: &[PathBuf]) {
+        for editor in self.editors.values_mut() {
+            if let Some(path) = editor.file_path.as_ref().filter(|p| paths.contains(p)) {
+                editor.diagnostics = self.problems.get(path).to_vec();
+            }
+        }
+    }
+
+    /// Keep language servers in step with the open editors and hand their answers back
+    fn sync_language_servers(&mut self) {
+        for (id, editor) in &mut self.editors {
+            let Some(path) = editor.file_path.clone() else { continue };
+            let Some(client) = self.lsp.client_for(&path) else { continue };
+            if !client.is_open(&path) {
+                client.did_open(&path, &editor.content);
+                editor.lsp_attached = true;
+                editor.take_lsp_changes();
+            }
+            if editor.completion_triggers.is_empty() {
+                editor.completion_triggers = client.trigger_characters();
+            }
+            let changes = editor.take_lsp_changes();
+            if !changes.is_empty() {
+                client.did_change(&path, &changes, &editor.content);
+            }
+            if std::mem::take(&mut editor.lsp_saved) {
+                client.did_save(&path);
+            }
+            for request in std::mem::take(&mut editor.lsp_requests) {
+                // The outline is asked for as the text changes; servers without one are spared
+                if matches!(request, LspRequest::DocumentSymbols) && !client.has_document_symbols() {
+                    continue;
+                }
+                client.send_request(*id, &path, &request, &editor.content);
+            }
+        }
+        let open: HashSet<PathBuf> = self.editors.values().filter_map(|e| e.file_path.clone()).collect();
+        self.lsp.retain_open(&open);
+
+        for event in self.lsp.poll() {
+            match event {
+                LspEvent::Diagnostics { path, diagnostics } => {
+                    self.problems.set(path.clone(), diagnostics);
+                    self.refresh_diagnostics(&[path]);
+                }
+                LspEvent::Completion { owner, at, items } => {
+                    if let Some(editor) = self.editors.get_mut(&owner) {
+                        editor.show_completions(at, items);
+                    }
+                }
+                LspEvent::Hover { owner, at, text } => {
+                    if let Some(editor) = self.editors.get_mut(&owner) {
+                        editor.show_hover(at, text);
+                    }
+                }
+                LspEvent::Definition { locations } if locations.len() == 1 => {
+                    self.open_location(locations.into_iter().next().unwrap());
+                }
+                LspEvent::Definition { locations } if !locations.is_empty() => {
+                    self.location_picker = Some(("Definitions".to_string(), locations));
+                }
+                LspEvent::References { locations } if !locations.is_empty() => {
+                    self.location_picker = Some((format!("{} references", locations.len()), locations));
+                }
+                LspEvent::Rename { edits } => self.apply_workspace_edit(edits),
+                LspEvent::Formatting { owner, result } => {
+                    if let Some(editor) = self.editors.get_mut(&owner) {
+                        let result = result.map(|edits| {
+                            let mut text = editor.content.to_string();
+                            for (range, new_text) in lsp::resolve_edits(&editor.content, &edits).into_iter().rev() {
+                                text.replace_range(range, &new_text);
+                            }
+                            text
+                        });
+                        editor.finish_formatting(result);
+                    }
+                }
+                LspEvent::Symbols { owner, symbols } => {
+                    if let Some(editor) = self.editors.get_mut(&owner) {
+                        editor.set_lsp_outline(&symbols);
+                    }
+                }
+                LspEvent::Definition { .. } | LspEvent::References { .. } => {}
+            }
+        }
+    }
+
+    /// Apply a language server's edits across files: open editors change in the buffer
+    /// (left unsaved), other files are rewritten on disk
+    fn apply_workspace_edit(&mut self, files: Vec<(PathBuf, Vec<TextEdit>)>) {
+        for (path, edits) in files {
+            if let Some(editor) = self.editors.values_mut().find(|e| e.file_path.as_ref() == Some(&path)) {
+                let edits = lsp::resolve_edits(&editor.content, &edits);
+                editor.apply_replacements(edits);
+                continue;
+            }
+            let result = std::fs::File::open(&path).and_then(TextBuffer::from_reader).and_then(|mut text| {
+                for (range, new_text) in lsp::resolve_edits(&text, &edits).into_iter().rev() {
+                    text.replace(range, &new_text);
+                }
+                text.write_to(std::fs::File::create(&path)?)
+            });
+            if let Err(e) = result {
+                eprintln!("Failed to apply edits to {}: {}", path.display(), e);
+            }
         }
     }
 
-    fn open_file_in_editor(&mut self, path: PathBuf) {
+    /// Open a file (or focus its tab if it's already open); returns its editor id.
+    /// Binary and very large files open in a read-only view instead, returning `None`.
+    fn open_file_in_editor(&mut self, path: PathBuf) -> Option<usize> {
         // Check if already open — focus existing tab
         for (id, editor) in &self.editors {
             if editor.file_path.as_ref() == Some(&path) {
                 self.pending_focus = Some(TabContent::Editor(*id));
-                return;
+                return Some(*id);
+            }
+        }
+        for (id, view) in &self.file_views {
+            if view.path == path {
+                self.pending_focus = Some(TabContent::FileView(*id));
+                return None;
             }
         }
 
         let id = self.next_editor_id;
         self.next_editor_id += 1;
 
-        match Editor::open_file(id, path) {
-            Ok(editor) => {
+        match file_view::classify(&path) {
+            Ok(FileKind::Text) => {}
+            Ok(kind) => {
+                match FileView::open(path, kind) {
+                    Ok(view) => {
+                        self.file_views.insert(id, view);
+                        let tab = TabContent::FileView(id);
+                        Self::add_tab_to_pane(&mut self.pane_root, tab.clone());
+                        self.pending_focus = Some(tab);
+                    }
+                    Err(e) => eprintln!("Failed to open file: {}", e),
+                }
+                return None;
+            }
+            Err(e) => {
+                eprintln!("Failed to open file: {}", e);
+                return None;
+            }
+        }
+
+        match Editor::open_file(id, path.clone()) {
+            Ok(mut editor) => {
+                if self.config.persist_undo {
+                    editor.enable_undo_persistence();
+                }
+                editor.diagnostics = self.problems.get(&path).to_vec();
+                editor.format_on_save = self.config.format_on_save;
+                if self.config.vim_mode {
+                    editor.vim = Some(Vim::new());
+                }
                 self.editors.insert(id, editor);
                 let tab = TabContent::Editor(id);
                 Self::add_tab_to_pane(&mut self.pane_root, tab.clone());
                 self.pending_focus = Some(tab);
+                Some(id)
             }
             Err(e) => {
                 eprintln!("Failed to open file: {}", e);
+                None
             }
         }
     }
 
+    /// Carry out confirmed project-wide replacements. Files open in an editor are
+    /// changed in the buffer (one undo step, left unsaved); others are rewritten on disk.
+    /// Files that changed since they were searched are skipped and listed in the panel.
+    fn apply_replacements(&mut self, files: Vec<FileReplacement>) {
+        for file in files {
+            if let Some(editor) = self.editors.values_mut().find(|e| e.file_path.as_ref() == Some(&file.path)) {
+                if editor.content.to_string() == file.original {
+                    editor.apply_replacements(file.edits);
+                } else {
+                    self.project_search.report_skipped(file.path, "it has unsaved changes".to_string());
+                }
+                continue;
+            }
+            if let Err(e) = file.write() {
+                self.project_search.report_skipped(file.path, e);
+            }
+        }
+    }
+
+    /// Show the search tab, adding it next to the file tree if it isn't open
+    fn show_project_search(&mut self) {
+        if !Self::focus_tab(&mut self.pane_root, &TabContent::Search) {
+            Self::force_add_tab(&mut self.pane_root, TabContent::Search);
+        }
+        self.project_search.grab_focus = true;
+    }
+
+    /// Show the problems tab, adding it next to the file tree if it isn't open
+    fn show_problems(&mut self) {
+        if !Self::focus_tab(&mut self.pane_root, &TabContent::Problems) {
+            Self::force_add_tab(&mut self.pane_root, TabContent::Problems);
+        }
+    }
+
     fn add_tab_to_pane(node: &mut PaneNode, content: TabContent) {
         if Self::try_add_tab(node, &content) {
             return;
@@ -152,6 +648,8 @@ impl AioApp {
         node: &mut PaneNode,
         terminals: &mut HashMap<usize, Terminal>,
         editors: &mut HashMap<usize, Editor>,
+        file_views: &mut HashMap<usize, FileView>,
+        diff_views: &mut HashMap<usize, DiffView>,
         agent_views: &mut HashMap<usize, AgentView>,
     ) {
         // Find the first leaf and close its active tab
@@ -167,6 +665,8 @@ impl AioApp {
                         TabContent::Terminal(id) => { terminals.remove(&id); }
                         TabContent::ClaudeCode(id) | TabContent::Codex(id) => { agent_views.remove(&id); }
                         TabContent::Editor(id) => { editors.remove(&id); }
+                        TabContent::FileView(id) => { file_views.remove(&id); }
+                        TabContent::Diff(id) => { diff_views.remove(&id); }
                         _ => {}
                     }
                 } else if leaf.tabs.len() == 1 {
@@ -175,12 +675,54 @@ impl AioApp {
                         TabContent::Terminal(id) => { terminals.remove(id); }
                         TabContent::ClaudeCode(id) | TabContent::Codex(id) => { agent_views.remove(id); }
                         TabContent::Editor(id) => { editors.remove(id); }
+                        TabContent::FileView(id) => { file_views.remove(id); }
+                        TabContent::Diff(id) => { diff_views.remove(id); }
                         _ => {}
                     }
                 }
             }
-            PaneNode::HSplit { right, .. } => Self::close_active_tab(right, terminals, editors, agent_views),
-            PaneNode::VSplit { top, .. } => Self::close_active_tab(top, terminals, editors, agent_views),
+            PaneNode::HSplit { right, .. } => Self::close_active_tab(right, terminals, editors, file_views, diff_views, agent_views),
+            PaneNode::VSplit { top, .. } => Self::close_active_tab(top, terminals, editors, file_views, diff_views, agent_views),
+        }
+    }
+
+    /// Close the editors that asked to (Vim's `:q`). The last tab of a pane stays
+    /// open, so its editor is kept too.
+    fn close_requested_editors(&mut self) {
+        let closing: Vec<usize> = self.editors.iter().filter(|(_, e)| e.close_request).map(|(id, _)| *id).collect();
+        for id in closing {
+            if Self::remove_editor_tab(&mut self.pane_root, id) == Some(false) {
+                if let Some(editor) = self.editors.get_mut(&id) {
+                    editor.close_request = false;
+                    editor.notice = Some("This is the last tab in the pane, so it stays open".to_string());
+                }
+            } else {
+                self.editors.remove(&id);
+            }
+        }
+    }
+
+    /// Remove an editor's tab from the leaf holding it, unless it's the leaf's last.
+    /// `None` if no leaf has it, else whether it was removed.
+    fn remove_editor_tab(node: &mut PaneNode, id: usize) -> Option<bool> {
+        match node {
+            PaneNode::Leaf(leaf) => {
+                let i = leaf.tabs.iter().position(|t| matches!(t, TabContent::Editor(e) if *e == id))?;
+                if leaf.tabs.len() == 1 {
+                    return Some(false);
+                }
+                leaf.tabs.remove(i);
+                if leaf.active_tab >= leaf.tabs.len() || leaf.active_tab > i {
+                    leaf.active_tab = leaf.active_tab.saturating_sub(1);
+                }
+                Some(true)
+            }
+            PaneNode::HSplit { left, right, .. } => {
+                Self::remove_editor_tab(left, id).or_else(|| Self::remove_editor_tab(right, id))
+            }
+            PaneNode::VSplit { top, bottom, .. } => {
+                Self::remove_editor_tab(top, id).or_else(|| Self::remove_editor_tab(bottom, id))
+            }
         }
     }
 
@@ -192,7 +734,11 @@ impl AioApp {
                         let matches = match (tab, target) {
                             (TabContent::Terminal(a), TabContent::Terminal(b)) => a == b,
                             (TabContent::Editor(a), TabContent::Editor(b)) => a == b,
+                            (TabContent::FileView(a), TabContent::FileView(b)) => a == b,
+                            (TabContent::Diff(a), TabContent::Diff(b)) => a == b,
                             (TabContent::FileTree, TabContent::FileTree) => true,
+                            (TabContent::Search, TabContent::Search) => true,
+                            (TabContent::Problems, TabContent::Problems) => true,
                             (TabContent::ClaudeCode(a), TabContent::ClaudeCode(b)) => a == b,
                             (TabContent::Codex(a), TabContent::Codex(b)) => a == b,
                             _ => false,
@@ -242,9 +788,12 @@ impl eframe::App for AioApp {
         let mut open_folder_requested = false;
         let mut close_tab_requested = false;
         let mut new_terminal_requested = false;
+        let mut profile_picker_requested = false;
         let mut new_file_requested = false;
         let mut new_claude_requested = false;
         let mut new_codex_requested = false;
+        let mut search_requested = false;
+        let mut problems_requested = false;
         ctx.input(|i| {
             let cmd = i.modifiers.mac_cmd || i.modifiers.ctrl;
             // Cmd+Shift+A: Claude Code, Cmd+Shift+D: Codex (avoid C/X terminal conflicts)
@@ -252,7 +801,13 @@ impl eframe::App for AioApp {
                 new_claude_requested = true;
             } else if cmd && i.modifiers.shift && i.key_pressed(egui::Key::D) {
                 new_codex_requested = true;
-            } else if cmd && i.key_pressed(egui::Key::O) {
+            } else if cmd && i.modifiers.shift && i.key_pressed(egui::Key::T) {
+                profile_picker_requested = true;
+            } else if cmd && i.modifiers.shift && i.key_pressed(egui::Key::F) {
+                search_requested = true;
+            } else if cmd && i.modifiers.shift && i.key_pressed(egui::Key::M) {
+                problems_requested = true;
+            } else if cmd && !i.modifiers.shift && i.key_pressed(egui::Key::O) {
                 open_folder_requested = true;
             } else if cmd && i.key_pressed(egui::Key::W) {
                 close_tab_requested = true;
@@ -264,24 +819,38 @@ impl eframe::App for AioApp {
         });
 
         if close_tab_requested {
-            Self::close_active_tab(&mut self.pane_root, &mut self.terminals, &mut self.editors, &mut self.agent_views);
+            Self::close_active_tab(&mut self.pane_root, &mut self.terminals, &mut self.editors, &mut self.file_views, &mut self.diff_views, &mut self.agent_views);
         }
 
         if new_terminal_requested {
-            let id = self.next_terminal_id;
-            self.next_terminal_id += 1;
-            if let Ok(term) = Terminal::new(24, 80) {
-                self.terminals.insert(id, term);
-                let tab = TabContent::Terminal(id);
-                Self::add_tab_to_pane(&mut self.pane_root, tab.clone());
-                self.pending_focus = Some(tab);
-            }
+            let profile = self.config.default_terminal_profile();
+            self.open_terminal(&profile);
+        }
+
+        if search_requested {
+            self.show_project_search();
+        }
+
+        if problems_requested {
+            self.show_problems();
+        }
+
+        if profile_picker_requested {
+            self.profile_picker = Some(String::new());
+        }
+
+        if let Some(profile) = self.show_profile_picker(ctx) {
+            self.open_terminal(&profile);
         }
 
         if new_file_requested {
             let id = self.next_editor_id;
             self.next_editor_id += 1;
-            let editor = Editor::new_empty(id);
+            let mut editor = Editor::new_empty(id);
+            editor.format_on_save = self.config.format_on_save;
+            if self.config.vim_mode {
+                editor.vim = Some(Vim::new());
+            }
             self.editors.insert(id, editor);
             let tab = TabContent::Editor(id);
             Self::add_tab_to_pane(&mut self.pane_root, tab.clone());
@@ -320,6 +889,8 @@ impl eframe::App for AioApp {
 
         if let Some(folder) = self.pending_open_folder.take() {
             self.file_tree = FileTree::new(folder.clone());
+            self.project_search.set_root(folder.clone());
+            self.problems.set_root(folder.clone());
             let name = folder.file_name()
                 .map(|n| n.to_string_lossy().to_string())
                 .unwrap_or_else(|| folder.to_string_lossy().to_string());
@@ -334,6 +905,7 @@ impl eframe::App for AioApp {
             self.focus_grab = Some(target);
         }
 
+        self.watch_files();
         let file_to_open = self.file_tree.take_pending_open();
 
         egui::CentralPanel::default()
@@ -359,13 +931,27 @@ impl eframe::App for AioApp {
                                 editor.grab_focus = true;
                             }
                         }
+                        TabContent::FileView(id) => {
+                            if let Some(view) = self.file_views.get_mut(id) {
+                                view.grab_focus = true;
+                            }
+                        }
+                        TabContent::Diff(id) => {
+                            if let Some(view) = self.diff_views.get_mut(id) {
+                                view.grab_focus = true;
+                            }
+                        }
                         _ => {}
                     }
                 }
 
                 let terminals = &mut self.terminals;
                 let file_tree = &mut self.file_tree;
+                let project_search = &mut self.project_search;
+                let problems = &mut self.problems;
                 let editors = &mut self.editors;
+                let file_views = &mut self.file_views;
+                let diff_views = &mut self.diff_views;
                 let agent_views = &mut self.agent_views;
 
                 pane::render_pane_tree(
@@ -373,7 +959,7 @@ impl eframe::App for AioApp {
                     &mut self.pane_root,
                     rect,
                     &mut |ui, rect, leaf| {
-                        let content_rect = pane::draw_tab_bar_with_editors(ui, rect, leaf, editors);
+                        let content_rect = pane::draw_tab_bar_with_editors(ui, rect, leaf, editors, file_views, diff_views);
 
                         if let Some(tab) = leaf.active().cloned() {
                             match tab {
@@ -390,11 +976,27 @@ impl eframe::App for AioApp {
                                 TabContent::FileTree => {
                                     file_tree.render(ui, content_rect);
                                 }
+                                TabContent::Search => {
+                                    project_search.render(ui, content_rect);
+                                }
+                                TabContent::Problems => {
+                                    problems.render(ui, content_rect);
+                                }
                                 TabContent::Editor(id) => {
                                     if let Some(editor) = editors.get_mut(&id) {
                                         editor.render(ui, content_rect);
                                     }
                                 }
+                                TabContent::FileView(id) => {
+                                    if let Some(view) = file_views.get_mut(&id) {
+                                        view.render(ui, content_rect);
+                                    }
+                                }
+                                TabContent::Diff(id) => {
+                                    if let Some(view) = diff_views.get_mut(&id) {
+                                        view.render(ui, content_rect);
+                                    }
+                                }
                             }
                         }
                     },
@@ -404,5 +1006,33 @@ impl eframe::App for AioApp {
         if let Some(path) = file_to_open {
             self.open_file_in_editor(path);
         }
+
+        if let Some((path, range)) = self.project_search.take_pending_open() {
+            if let Some(id) = self.open_file_in_editor(path) {
+                if let Some(editor) = self.editors.get_mut(&id) {
+                    editor.reveal(range);
+                }
+            }
+        }
+
+        if let Some(files) = self.project_search.take_pending_apply() {
+            self.apply_replacements(files);
+        }
+
+        if let Some(location) = self.show_location_picker(ctx) {
+            self.open_location(location);
+        }
+
+        if let Some(location) = self.problems.take_pending_open() {
+            self.open_location(location);
+        }
+        if let Some((old, new)) = self.file_tree.take_pending_compare() {
+            self.compare_files(&old, &new);
+        }
+        self.open_requested_diffs();
+        self.close_requested_editors();
+        self.run_formatters(ctx);
+        self.sync_language_servers();
+        self.scan_build_output(ctx);
     }
 }
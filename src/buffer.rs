@@ -0,0 +1,296 @@
+use regex::Regex;
+use ropey::Rope;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+/// How much text a search copies out of the rope at a time
+const SEARCH_WINDOW: usize = 64 * 1024;
+/// Text past a search window that a match may look at (`$`, `\b`, repeats) to tell
+/// whether it carries on
+const SEARCH_LOOKAHEAD: usize = 1024;
+
+/// Editor text, stored as a rope so edits and line lookups stay O(log n) in large files.
+/// All positions are byte offsets; lines are separated by `\n` only.
+#[derive(Clone, Debug, Default)]
+pub struct TextBuffer {
+    rope: Rope,
+}
+
+impl From<&str> for TextBuffer {
+    fn from(text: &str) -> Self {
+        Self { rope: Rope::from_str(text) }
+    }
+}
+
+impl TextBuffer {
+    pub fn from_reader(reader: impl io::Read) -> io::Result<Self> {
+        Ok(Self { rope: Rope::from_reader(reader)? })
+    }
+
+    pub fn write_to(&self, writer: impl io::Write) -> io::Result<()> {
+        self.rope.write_to(writer)
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    /// Number of lines, counting the empty line after a trailing newline
+    pub fn line_count(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    /// Line containing a byte offset
+    pub fn line_of(&self, byte: usize) -> usize {
+        self.rope.byte_to_line(byte.min(self.len_bytes()))
+    }
+
+    pub fn line_start(&self, line: usize) -> usize {
+        if line >= self.line_count() {
+            self.len_bytes()
+        } else {
+            self.rope.line_to_byte(line)
+        }
+    }
+
+    /// End of a line, before its newline
+    pub fn line_end(&self, line: usize) -> usize {
+        if line + 1 < self.line_count() {
+            self.rope.line_to_byte(line + 1) - 1
+        } else {
+            self.len_bytes()
+        }
+    }
+
+    /// Text of a line without its newline
+    pub fn line(&self, line: usize) -> String {
+        self.slice(self.line_start(line)..self.line_end(line))
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> String {
+        self.rope.byte_slice(range).to_string()
+    }
+
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        let start = self.rope.byte_to_char(range.start);
+        let end = self.rope.byte_to_char(range.end);
+        self.rope.remove(start..end);
+        self.rope.insert(start, text);
+    }
+
+    /// Start of the character before `byte`
+    pub fn prev_char(&self, byte: usize) -> usize {
+        let ch = self.rope.byte_to_char(byte);
+        self.rope.char_to_byte(ch.saturating_sub(1))
+    }
+
+    /// Start of the character after the one at `byte`
+    pub fn next_char(&self, byte: usize) -> usize {
+        let ch = self.rope.byte_to_char(byte);
+        self.rope.char_to_byte((ch + 1).min(self.rope.len_chars()))
+    }
+
+    /// Offset in UTF-16 code units, as used by LSP positions
+    pub fn byte_to_utf16(&self, byte: usize) -> usize {
+        self.rope.char_to_utf16_cu(self.rope.byte_to_char(byte))
+    }
+
+    pub fn utf16_to_byte(&self, utf16: usize) -> usize {
+        self.rope.char_to_byte(self.rope.utf16_cu_to_char(utf16))
+    }
+
+    /// The rest of the chunk containing `byte`, for feeding a parser piecewise
+    pub fn chunk_at(&self, byte: usize) -> &str {
+        if byte >= self.len_bytes() {
+            return "";
+        }
+        let (chunk, chunk_start, _, _) = self.rope.chunk_at_byte(byte);
+        &chunk[byte - chunk_start..]
+    }
+
+    pub fn chunks(&self, range: Range<usize>) -> ropey::iter::Chunks<'_> {
+        self.rope.byte_slice(range).chunks()
+    }
+
+    /// Matches of `re` that lie within `range`, in order, empty ones included. The text
+    /// is copied out a window at a time, never all at once.
+    pub fn find_iter<'a>(&'a self, re: &'a Regex, range: Range<usize>) -> Matches<'a> {
+        let end = range.end.min(self.len_bytes());
+        Matches {
+            buffer: self,
+            re,
+            pos: range.start.min(end),
+            end,
+            window: String::new(),
+            // Nothing loaded yet
+            window_start: usize::MAX,
+            window_end: 0,
+            size: SEARCH_WINDOW,
+        }
+    }
+
+    /// `byte`, or the start of the next character if it's inside one
+    fn char_boundary(&self, byte: usize) -> usize {
+        let byte = byte.min(self.len_bytes());
+        let start = self.rope.char_to_byte(self.rope.byte_to_char(byte));
+        if start == byte { byte } else { self.next_char(start) }
+    }
+}
+
+/// Iterator returned by `TextBuffer::find_iter`
+pub struct Matches<'a> {
+    buffer: &'a TextBuffer,
+    re: &'a Regex,
+    /// Where the next search starts
+    pos: usize,
+    end: usize,
+    /// A character before the window, for `^` and `\b`, then the window and lookahead
+    window: String,
+    window_start: usize,
+    /// Matches must end by here; one that runs on is searched for again in a bigger window
+    window_end: usize,
+    size: usize,
+}
+
+impl Matches<'_> {
+    fn load(&mut self) {
+        let buffer = self.buffer;
+        self.window_start = buffer.prev_char(self.pos);
+        self.window_end = buffer.char_boundary(self.pos.saturating_add(self.size).min(self.end));
+        let lookahead = buffer.char_boundary(self.window_end + SEARCH_LOOKAHEAD);
+        self.window = buffer.slice(self.window_start..lookahead);
+    }
+}
+
+impl Iterator for Matches<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        while self.pos <= self.end {
+            if self.pos < self.window_start || (self.pos >= self.window_end && self.window_end < self.end) {
+                self.load();
+            }
+            let last_window = self.window_end >= self.end;
+            let Some(m) = self.re.find_at(&self.window, self.pos - self.window_start) else {
+                if last_window {
+                    break;
+                }
+                self.pos = self.window_end;
+                continue;
+            };
+            let range = self.window_start + m.start()..self.window_start + m.end();
+            if !last_window && range.start >= self.window_end {
+                self.pos = self.window_end;
+                continue;
+            }
+            if !last_window && range.end > self.window_end {
+                self.size *= 2;
+                self.load();
+                continue;
+            }
+            if range.end > self.end {
+                break;
+            }
+            // Step past empty matches (e.g. `^`) one character at a time
+            self.pos = match range.is_empty() {
+                true if range.end < self.end => self.buffer.next_char(range.end),
+                true => self.end + 1,
+                false => range.end,
+            };
+            return Some(range);
+        }
+        self.pos = self.end + 1;
+        None
+    }
+}
+
+impl fmt::Display for TextBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.rope.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_utf16_and_bytes() {
+        // a: 1 byte / 1 unit, é: 2 / 1, 😀: 4 / 2, 日: 3 / 1
+        let text = TextBuffer::from("aé😀日\nx");
+        let pairs = [(0, 0), (1, 1), (3, 2), (7, 4), (10, 5), (11, 6), (12, 7)];
+        for (byte, utf16) in pairs {
+            assert_eq!(text.byte_to_utf16(byte), utf16, "byte {}", byte);
+            assert_eq!(text.utf16_to_byte(utf16), byte, "utf16 {}", utf16);
+        }
+    }
+
+    #[test]
+    fn steps_over_whole_characters() {
+        let text = TextBuffer::from("aé😀");
+        assert_eq!(text.next_char(0), 1);
+        assert_eq!(text.next_char(1), 3);
+        assert_eq!(text.next_char(3), 7);
+        assert_eq!(text.next_char(7), 7);
+        assert_eq!(text.prev_char(7), 3);
+        assert_eq!(text.prev_char(3), 1);
+        assert_eq!(text.prev_char(0), 0);
+        assert_eq!(text.char_boundary(4), 7);
+        assert_eq!(text.char_boundary(3), 3);
+    }
+
+    #[test]
+    fn finds_lines_and_their_ends() {
+        let text = TextBuffer::from("ab\n\ncd\n");
+        assert_eq!(text.line_count(), 4);
+        assert_eq!((text.line_start(0), text.line_end(0)), (0, 2));
+        assert_eq!((text.line_start(1), text.line_end(1)), (3, 3));
+        assert_eq!((text.line_start(2), text.line_end(2)), (4, 6));
+        // The empty line after the trailing newline
+        assert_eq!((text.line_start(3), text.line_end(3)), (7, 7));
+        assert_eq!(text.line(2), "cd");
+        // Past the end
+        assert_eq!(text.line_start(9), 7);
+
+        assert_eq!(text.line_of(2), 0);
+        assert_eq!(text.line_of(3), 1);
+        // At EOF after the trailing newline, and beyond it
+        assert_eq!(text.line_of(7), 3);
+        assert_eq!(text.line_of(100), 3);
+        let text = TextBuffer::from("ab");
+        assert_eq!(text.line_of(2), 0);
+        assert_eq!(text.line_end(0), 2);
+    }
+
+    #[test]
+    fn finds_matches_across_windows() {
+        let re = Regex::new(r"(?m)x+|^b").unwrap();
+        let mut text = String::new();
+        for _ in 0..SEARCH_WINDOW / 3 + 1 {
+            text.push_str("ab\n");
+        }
+        // A match that runs over the first window's end
+        let long_start = text.len() - 3;
+        text.insert_str(long_start, &"x".repeat(SEARCH_LOOKAHEAD * 3));
+        text.push_str("é\nbx");
+        let expected: Vec<Range<usize>> = re.find_iter(&text).map(|m| m.range()).collect();
+        let found: Vec<Range<usize>> = TextBuffer::from(text.as_str()).find_iter(&re, 0..text.len()).collect();
+        assert_eq!(found, expected);
+
+        // Within a range, with its edges seen as the text around them
+        let text = TextBuffer::from("one two\ntwo one");
+        let re = Regex::new(r"\btwo\b").unwrap();
+        assert_eq!(text.find_iter(&re, 5..12).next(), Some(8..11));
+        assert_eq!(text.find_iter(&re, 5..12).count(), 1);
+        assert_eq!(text.find_iter(&re, 5..10).count(), 0);
+        // Empty matches don't stall
+        let re = Regex::new("(?m)^").unwrap();
+        assert_eq!(text.find_iter(&re, 0..text.len_bytes()).collect::<Vec<_>>(), [0..0, 8..8]);
+        let re = Regex::new("z*").unwrap();
+        assert_eq!(TextBuffer::from("aé").find_iter(&re, 0..3).collect::<Vec<_>>(), [0..0, 1..1, 3..3]);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::config::parse_hex_color;
+use eframe::egui::Color32;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Colours used to render a terminal
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorScheme {
+    /// 256-colour palette; the first 16 are the configurable ANSI colours
+    pub palette: [Color32; 256],
+    pub foreground: Color32,
+    pub background: Color32,
+    pub cursor: Color32,
+}
+
+/// A colour scheme as written in config.json
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ColorSchemeConfig {
+    /// Import from an iTerm2 `.itermcolors`, Alacritty `.toml` or Windows Terminal `.json` file
+    pub import: Option<String>,
+    /// Up to 16 ANSI colours as `#rrggbb`, black through bright white
+    pub ansi: Vec<String>,
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub cursor: Option<String>,
+}
+
+// VS Code's terminal palette
+const DEFAULT_ANSI: [(u8, u8, u8); 16] = [
+    (0, 0, 0),       (205, 49, 49),    (13, 188, 121),   (229, 229, 16),
+    (36, 114, 200),   (188, 63, 188),   (17, 168, 205),   (229, 229, 229),
+    (102, 102, 102),  (241, 76, 76),    (35, 209, 139),   (245, 245, 67),
+    (59, 142, 234),   (214, 112, 214),  (41, 184, 219),   (255, 255, 255),
+];
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        let mut palette = [Color32::BLACK; 256];
+        for (i, (r, g, b)) in DEFAULT_ANSI.iter().enumerate() {
+            palette[i] = Color32::from_rgb(*r, *g, *b);
+        }
+        for (i, color) in palette.iter_mut().enumerate().skip(16) {
+            *color = xterm_color(i as u8);
+        }
+        Self {
+            palette,
+            foreground: Color32::from_rgb(36, 36, 36),
+            background: crate::theme::TERMINAL_BG,
+            cursor: Color32::from_rgba_premultiplied(200, 200, 200, 128),
+        }
+    }
+}
+
+/// Colours 16-255 of the xterm palette: a 6x6x6 cube followed by a grey ramp
+fn xterm_color(idx: u8) -> Color32 {
+    if idx < 232 {
+        let idx = idx - 16;
+        let r = (idx / 36) % 6;
+        let g = (idx / 6) % 6;
+        let b = idx % 6;
+        let to_val = |v: u8| if v == 0 { 0 } else { 55 + 40 * v };
+        return Color32::from_rgb(to_val(r), to_val(g), to_val(b));
+    }
+    let v = 8 + 10 * (idx - 232);
+    Color32::from_rgb(v, v, v)
+}
+
+impl ColorScheme {
+    /// Build a scheme from config: defaults, then the imported file, then inline colours
+    pub fn from_config(config: &ColorSchemeConfig) -> Self {
+        let mut scheme = match &config.import {
+            Some(path) => {
+                let path = crate::config::expand_home(path);
+                Self::import(&path).unwrap_or_else(|e| {
+                    eprintln!("Failed to import colour scheme {}: {}", path.display(), e);
+                    Self::default()
+                })
+            }
+            None => Self::default(),
+        };
+        for (i, hex) in config.ansi.iter().take(16).enumerate() {
+            if let Some(c) = parse_hex_color(hex) {
+                scheme.palette[i] = c;
+            }
+        }
+        let set = |slot: &mut Color32, value: &Option<String>| {
+            if let Some(c) = value.as_deref().and_then(parse_hex_color) {
+                *slot = c;
+            }
+        };
+        set(&mut scheme.foreground, &config.foreground);
+        set(&mut scheme.background, &config.background);
+        set(&mut scheme.cursor, &config.cursor);
+        scheme
+    }
+
+    /// Import a scheme file, picking the format from its extension
+    pub fn import(path: &Path) -> Result<Self, String> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "itermcolors" => {
+                let value = plist::Value::from_file(path).map_err(|e| e.to_string())?;
+                Self::from_itermcolors(&value)
+            }
+            "toml" => {
+                let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+                Self::from_alacritty(&text)
+            }
+            "json" => {
+                let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+                Self::from_windows_terminal(&text)
+            }
+            _ => Err(format!("unknown colour scheme format '.{}'", ext)),
+        }
+    }
+
+    fn from_itermcolors(value: &plist::Value) -> Result<Self, String> {
+        let dict = value.as_dictionary().ok_or("not a plist dictionary")?;
+        let color = |key: &str| -> Option<Color32> {
+            let c = dict.get(key)?.as_dictionary()?;
+            let component = |name: &str| {
+                c.get(name)
+                    .and_then(|v| v.as_real())
+                    .map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+            };
+            Some(Color32::from_rgb(
+                component("Red Component")?,
+                component("Green Component")?,
+                component("Blue Component")?,
+            ))
+        };
+
+        let mut scheme = Self::default();
+        for i in 0..16 {
+            if let Some(c) = color(&format!("Ansi {} Color", i)) {
+                scheme.palette[i] = c;
+            }
+        }
+        if let Some(c) = color("Foreground Color") {
+            scheme.foreground = c;
+        }
+        if let Some(c) = color("Background Color") {
+            scheme.background = c;
+        }
+        if let Some(c) = color("Cursor Color") {
+            scheme.cursor = c;
+        }
+        Ok(scheme)
+    }
+
+    fn from_alacritty(text: &str) -> Result<Self, String> {
+        let value: toml::Value = toml::from_str(text).map_err(|e| e.to_string())?;
+        let colors = value.get("colors").ok_or("missing [colors] table")?;
+        let color = |table: &str, key: &str| -> Option<Color32> {
+            colors.get(table)?.get(key)?.as_str().and_then(parse_hex_color)
+        };
+
+        const NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+        let mut scheme = Self::default();
+        for (i, name) in NAMES.iter().enumerate() {
+            if let Some(c) = color("normal", name) {
+                scheme.palette[i] = c;
+            }
+            if let Some(c) = color("bright", name) {
+                scheme.palette[i + 8] = c;
+            }
+        }
+        if let Some(c) = color("primary", "foreground") {
+            scheme.foreground = c;
+        }
+        if let Some(c) = color("primary", "background") {
+            scheme.background = c;
+        }
+        if let Some(c) = color("cursor", "cursor") {
+            scheme.cursor = c;
+        }
+        Ok(scheme)
+    }
+
+    /// Accepts a single scheme object or a settings.json with a `schemes` array (first entry)
+    fn from_windows_terminal(text: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let obj = match value.get("schemes").and_then(|s| s.as_array()) {
+            Some(schemes) => schemes.first().ok_or("empty schemes array")?,
+            None => &value,
+        };
+        let color = |key: &str| obj.get(key).and_then(|v| v.as_str()).and_then(parse_hex_color);
+
+        const NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "purple", "cyan", "white"];
+        let mut scheme = Self::default();
+        for (i, name) in NAMES.iter().enumerate() {
+            if let Some(c) = color(name) {
+                scheme.palette[i] = c;
+            }
+            let bright = format!("bright{}{}", name[..1].to_ascii_uppercase(), &name[1..]);
+            if let Some(c) = color(&bright) {
+                scheme.palette[i + 8] = c;
+            }
+        }
+        if let Some(c) = color("foreground") {
+            scheme.foreground = c;
+        }
+        if let Some(c) = color("background") {
+            scheme.background = c;
+        }
+        if let Some(c) = color("cursorColor") {
+            scheme.cursor = c;
+        }
+        Ok(scheme)
+    }
+}
+
+/// Parse an X11 colour spec as used by OSC 4/10/11/12: `rgb:r/g/b` (1-4 hex digits
+/// per channel) or `#rgb`, `#rrggbb`, `#rrrgggbbb`, `#rrrrggggbbbb`
+pub fn parse_x_color(spec: &str) -> Option<Color32> {
+    fn scale(hex: &str) -> Option<u8> {
+        if hex.is_empty() || hex.len() > 4 {
+            return None;
+        }
+        let v = u32::from_str_radix(hex, 16).ok()?;
+        let max = (1u32 << (4 * hex.len())) - 1;
+        Some(((v * 255 + max / 2) / max) as u8)
+    }
+
+    if let Some(rest) = spec.strip_prefix("rgb:") {
+        let mut parts = rest.split('/');
+        let r = scale(parts.next()?)?;
+        let g = scale(parts.next()?)?;
+        let b = scale(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        return Some(Color32::from_rgb(r, g, b));
+    }
+
+    let hex = spec.strip_prefix('#')?;
+    if hex.is_empty() || hex.len() % 3 != 0 || hex.len() > 12 {
+        return None;
+    }
+    let n = hex.len() / 3;
+    // In the `#` form the digits are the most significant bits, not a scaled value
+    let channel = |i: usize| -> Option<u8> {
+        let v = u32::from_str_radix(&hex[i * n..(i + 1) * n], 16).ok()?;
+        Some(if n >= 2 { (v >> (4 * (n - 2))) as u8 } else { (v << 4) as u8 })
+    };
+    Some(Color32::from_rgb(channel(0)?, channel(1)?, channel(2)?))
+}
+
+/// Format a colour the way xterm reports it: `rgb:rrrr/gggg/bbbb`
+pub fn format_x_color(c: Color32) -> String {
+    format!(
+        "rgb:{:02x}{:02x}/{:02x}{:02x}/{:02x}{:02x}",
+        c.r(), c.r(), c.g(), c.g(), c.b(), c.b()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::term_responder::TermResponder;
+    use std::sync::{Arc, Mutex};
+
+    const ITERM: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Ansi 1 Color</key>
+    <dict>
+        <key>Color Space</key><string>sRGB</string>
+        <key>Red Component</key><real>1</real>
+        <key>Green Component</key><real>0.5</real>
+        <key>Blue Component</key><real>0</real>
+    </dict>
+    <key>Ansi 12 Color</key>
+    <dict>
+        <key>Red Component</key><real>0.2</real>
+        <key>Green Component</key><real>1.5</real>
+        <key>Blue Component</key><real>-1</real>
+    </dict>
+    <key>Ansi 2 Color</key>
+    <dict>
+        <key>Red Component</key><real>1</real>
+    </dict>
+    <key>Background Color</key>
+    <dict>
+        <key>Red Component</key><real>0.1</real>
+        <key>Green Component</key><real>0.1</real>
+        <key>Blue Component</key><real>0.1</real>
+    </dict>
+    <key>Cursor Color</key>
+    <dict>
+        <key>Red Component</key><real>1</real>
+        <key>Green Component</key><real>1</real>
+        <key>Blue Component</key><real>1</real>
+    </dict>
+</dict>
+</plist>
+"#;
+
+    const ALACRITTY: &str = r##"
+[colors.primary]
+background = "#1d1f21"
+foreground = "#c5c8c6"
+
+[colors.cursor]
+cursor = "#ffffff"
+
+[colors.selection]
+background = "#373b41"
+
+[colors.normal]
+red = "#cc6666"
+green = "not a colour"
+
+[colors.bright]
+blue = "#81a2be"
+"##;
+
+    const WINDOWS_TERMINAL: &str = r##"{
+    "profiles": {},
+    "schemes": [
+        {
+            "name": "Campbell",
+            "background": "#0C0C0C",
+            "foreground": "#CCCCCC",
+            "cursorColor": "#FFFFFF",
+            "selectionBackground": "#FFFFFF",
+            "red": "#C50F1F",
+            "brightPurple": "#B4009E"
+        },
+        { "name": "Second", "red": "#000001" }
+    ]
+}"##;
+
+    /// Import `text` from a temporary file called `name`
+    fn import(name: &str, text: &str) -> Result<ColorScheme, String> {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join(name);
+        std::fs::write(&path, text).unwrap();
+        ColorScheme::import(&path)
+    }
+
+    fn rgb(r: u8, g: u8, b: u8) -> Color32 {
+        Color32::from_rgb(r, g, b)
+    }
+
+    #[test]
+    fn imports_itermcolors() {
+        let scheme = import("a.itermcolors", ITERM).unwrap();
+        let default = ColorScheme::default();
+        assert_eq!(scheme.palette[1], rgb(255, 128, 0));
+        // Components are clamped to 0-1
+        assert_eq!(scheme.palette[12], rgb(51, 255, 0));
+        // A colour missing components is left as it was
+        assert_eq!(scheme.palette[2], default.palette[2]);
+        assert_eq!(scheme.background, rgb(26, 26, 26));
+        assert_eq!(scheme.cursor, rgb(255, 255, 255));
+        assert_eq!(scheme.foreground, default.foreground);
+        assert!(import("b.itermcolors", "not a plist").is_err());
+    }
+
+    #[test]
+    fn imports_alacritty() {
+        let scheme = import("a.toml", ALACRITTY).unwrap();
+        let default = ColorScheme::default();
+        assert_eq!(scheme.background, rgb(0x1d, 0x1f, 0x21));
+        assert_eq!(scheme.foreground, rgb(0xc5, 0xc8, 0xc6));
+        assert_eq!(scheme.cursor, rgb(255, 255, 255));
+        assert_eq!(scheme.palette[1], rgb(0xcc, 0x66, 0x66));
+        assert_eq!(scheme.palette[2], default.palette[2]);
+        assert_eq!(scheme.palette[12], rgb(0x81, 0xa2, 0xbe));
+        assert_eq!(import("b.toml", "[font]\nsize = 12\n").unwrap_err(), "missing [colors] table");
+    }
+
+    #[test]
+    fn imports_windows_terminal() {
+        // settings.json takes the first scheme
+        let scheme = import("settings.json", WINDOWS_TERMINAL).unwrap();
+        assert_eq!(scheme.background, rgb(0x0c, 0x0c, 0x0c));
+        assert_eq!(scheme.foreground, rgb(0xcc, 0xcc, 0xcc));
+        assert_eq!(scheme.palette[1], rgb(0xc5, 0x0f, 0x1f));
+        assert_eq!(scheme.palette[13], rgb(0xb4, 0x00, 0x9e));
+        // A bare scheme object
+        let scheme = import("one.json", r##"{ "green": "#00ff00" }"##).unwrap();
+        assert_eq!(scheme.palette[2], rgb(0, 255, 0));
+        assert_eq!(import("empty.json", r#"{ "schemes": [] }"#).unwrap_err(), "empty schemes array");
+        assert!(import("scheme.conf", "").unwrap_err().contains(".conf"));
+    }
+
+    #[test]
+    fn parses_x_colours() {
+        // 1-4 hex digits per channel, scaled to 8 bits
+        assert_eq!(parse_x_color("rgb:f/0/8"), Some(rgb(255, 0, 136)));
+        assert_eq!(parse_x_color("rgb:ff/80/00"), Some(rgb(255, 128, 0)));
+        assert_eq!(parse_x_color("rgb:fff/000/800"), Some(rgb(255, 0, 128)));
+        assert_eq!(parse_x_color("rgb:ffff/8000/0000"), Some(rgb(255, 128, 0)));
+        assert_eq!(parse_x_color("rgb:F/a/0"), Some(rgb(255, 170, 0)));
+        // Channels may differ in length
+        assert_eq!(parse_x_color("rgb:f/ff/ffff"), Some(rgb(255, 255, 255)));
+        for bad in ["rgb:", "rgb:1/2", "rgb:1/2/3/4", "rgb://", "rgb:12345/0/0", "rgb:g/0/0", "rgb:-1/0/0"] {
+            assert_eq!(parse_x_color(bad), None, "{}", bad);
+        }
+
+        // `#` forms keep the most significant bits
+        assert_eq!(parse_x_color("#f80"), Some(rgb(0xf0, 0x80, 0)));
+        assert_eq!(parse_x_color("#ff8000"), Some(rgb(255, 128, 0)));
+        assert_eq!(parse_x_color("#fff800000"), Some(rgb(255, 128, 0)));
+        assert_eq!(parse_x_color("#ffff80000000"), Some(rgb(255, 128, 0)));
+        for bad in ["#", "#ff", "#ffff", "#fffffffffffffff", "#ggg", "red", ""] {
+            assert_eq!(parse_x_color(bad), None, "{}", bad);
+        }
+        assert_eq!(format_x_color(rgb(0x12, 0xab, 0)), "rgb:1212/abab/0000");
+    }
+
+    /// Feed `input` to a responder and return its replies and the resulting colours
+    fn osc(input: &[u8]) -> (String, ColorScheme) {
+        let colors = Arc::new(Mutex::new(ColorScheme::default()));
+        let mut responder = TermResponder::new(colors.clone(), Arc::new(Mutex::new(Vec::new())));
+        let reply = responder.process(input, &mut vt100::Parser::new(24, 80, 0));
+        let scheme = colors.lock().unwrap().clone();
+        (String::from_utf8(reply).unwrap(), scheme)
+    }
+
+    #[test]
+    fn osc_palette_edge_cases() {
+        // Several pairs in one sequence, answered in order
+        let (reply, scheme) = osc(b"\x1b]4;1;#ff0000;2;?;255;rgb:1/2/3\x07");
+        assert_eq!(reply, format!("\x1b]4;2;{}\x07", format_x_color(ColorScheme::default().palette[2])));
+        assert_eq!(scheme.palette[1], rgb(255, 0, 0));
+        assert_eq!(scheme.palette[255], rgb(17, 34, 51));
+
+        // Out-of-range indices, bad specs and a dangling index change nothing
+        let (reply, scheme) = osc(b"\x1b]4;256;#ff0000;x;#ff0000;3;rgb:zz/0/0;4\x1b\\");
+        assert_eq!((reply.as_str(), scheme), ("", ColorScheme::default()));
+        // A later valid pair still applies after a bad one
+        let (_, scheme) = osc(b"\x1b]4;999;?;5;#00ff00\x07");
+        assert_eq!(scheme.palette[5], rgb(0, 255, 0));
+    }
+
+    #[test]
+    fn osc_dynamic_colour_edge_cases() {
+        // OSC 10 with more arguments moves on to 11, 12…
+        let default = ColorScheme::default();
+        let (reply, _) = osc(b"\x1b]10;?;?\x1b\\");
+        let expected = format!(
+            "\x1b]10;{}\x1b\\\x1b]11;{}\x1b\\",
+            format_x_color(default.foreground),
+            format_x_color(default.background)
+        );
+        assert_eq!(reply, expected);
+        let (reply, scheme) = osc(b"\x1b]10;#102030;rgb:f/f/f\x07");
+        assert_eq!(reply, "");
+        assert_eq!((scheme.foreground, scheme.background), (rgb(0x10, 0x20, 0x30), rgb(255, 255, 255)));
+
+        // Bad specs are ignored; a set then query in one read sees the new colour
+        let (reply, scheme) = osc(b"\x1b]11;nonsense\x07\x1b]11;#abc\x07\x1b]11;?\x07");
+        assert_eq!(scheme.background, rgb(0xa0, 0xb0, 0xc0));
+        assert_eq!(reply, "\x1b]11;rgb:a0a0/b0b0/c0c0\x07");
+        let (_, scheme) = osc(b"\x1b]10;\x07");
+        assert_eq!(scheme, default);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::color_scheme::{ColorScheme, ColorSchemeConfig};
+use eframe::egui::Color32;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// User configuration, loaded from `~/.aio-terminal/config.json`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Name of the profile used by Cmd+T (first profile if unset)
+    pub default_profile: Option<String>,
+    pub profiles: Vec<TerminalProfile>,
+    /// Terminal colour scheme used when a profile doesn't name one
+    pub color_scheme: Option<String>,
+    /// Named terminal colour schemes
+    pub color_schemes: BTreeMap<String, ColorSchemeConfig>,
+    /// Whether terminal programs may read the clipboard with OSC 52
+    pub clipboard_read: ClipboardRead,
+    /// Keep editor undo history across closing and reopening files
+    pub persist_undo: bool,
+    /// Language servers by LSP language id (`rust`, `python`, `typescript`, ...),
+    /// replacing the built-in ones
+    pub lsp_servers: BTreeMap<String, LspServerConfig>,
+    /// Format editor files when saving them
+    pub format_on_save: bool,
+    /// Formatters by file extension (`rs`, `py`, `ts`, ...), replacing the built-in
+    /// ones (rustfmt, black, prettier)
+    pub formatters: BTreeMap<String, FormatterConfig>,
+    /// Modal Vim keybindings in editors
+    pub vim_mode: bool,
+}
+
+/// How to start a language server
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LspServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// A program that formats text from stdin to stdout. `{path}` in the arguments stands
+/// for the file's path; the command `lsp` uses the file's language server instead.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FormatterConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// Policy for OSC 52 clipboard reads (writes are always allowed)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardRead {
+    Allow,
+    /// Ask each time a program requests the clipboard
+    #[default]
+    Ask,
+    Deny,
+}
+
+/// A named way of starting a terminal: which program, where, and how it looks
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TerminalProfile {
+    pub name: String,
+    /// Program to run; the user's login shell when unset
+    pub program: Option<String>,
+    pub args: Vec<String>,
+    /// Working directory; `~` expands to the home directory
+    pub cwd: Option<String>,
+    pub env: BTreeMap<String, String>,
+    /// Name of an entry in `color_schemes`
+    pub color_scheme: Option<String>,
+    /// Default foreground colour as `#rrggbb`, overriding the colour scheme
+    pub foreground: Option<String>,
+    /// Background colour as `#rrggbb`, overriding the colour scheme
+    pub background: Option<String>,
+    pub font_size: Option<f32>,
+    /// Path to a monospace font file (.ttf/.otf/.ttc)
+    pub font: Option<String>,
+}
+
+impl Default for TerminalProfile {
+    fn default() -> Self {
+        Self {
+            name: "Default".to_string(),
+            program: None,
+            args: Vec::new(),
+            cwd: None,
+            env: BTreeMap::new(),
+            color_scheme: None,
+            foreground: None,
+            background: None,
+            font_size: None,
+            font: None,
+        }
+    }
+}
+
+impl TerminalProfile {
+    pub fn cwd_path(&self) -> Option<PathBuf> {
+        self.cwd.as_deref().map(expand_home)
+    }
+
+    pub fn font_path(&self) -> Option<PathBuf> {
+        self.font.as_deref().map(expand_home)
+    }
+
+    /// egui font family name under which this profile's font is registered
+    pub fn font_family_name(&self) -> String {
+        format!("profile:{}", self.name)
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::home_dir().map(|h| h.join(".aio-terminal").join("config.json"))
+    }
+
+    /// Load the config file, falling back to defaults if it is missing or invalid
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Failed to parse {}: {}", path.display(), e);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// All profiles, with a built-in default shell profile if none are configured
+    pub fn terminal_profiles(&self) -> Vec<TerminalProfile> {
+        if self.profiles.is_empty() {
+            vec![TerminalProfile::default()]
+        } else {
+            self.profiles.clone()
+        }
+    }
+
+    pub fn default_terminal_profile(&self) -> TerminalProfile {
+        let profiles = self.terminal_profiles();
+        self.default_profile
+            .as_ref()
+            .and_then(|name| profiles.iter().find(|p| &p.name == name))
+            .unwrap_or(&profiles[0])
+            .clone()
+    }
+
+    /// Resolve the colour scheme for a profile, applying its colour overrides
+    pub fn color_scheme_for(&self, profile: &TerminalProfile) -> ColorScheme {
+        let name = profile.color_scheme.as_ref().or(self.color_scheme.as_ref());
+        let mut scheme = match name.and_then(|n| self.color_schemes.get(n)) {
+            Some(config) => ColorScheme::from_config(config),
+            None => {
+                if let Some(n) = name {
+                    eprintln!("Unknown colour scheme '{}'", n);
+                }
+                ColorScheme::default()
+            }
+        };
+        if let Some(fg) = profile.foreground.as_deref().and_then(parse_hex_color) {
+            scheme.foreground = fg;
+        }
+        if let Some(bg) = profile.background.as_deref().and_then(parse_hex_color) {
+            scheme.background = bg;
+        }
+        scheme
+    }
+}
+
+pub fn expand_home(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest);
+        }
+    } else if path == "~" {
+        if let Some(home) = dirs::home_dir() {
+            return home;
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// Parse `#rgb`, `#rrggbb` or `#rrggbbaa` (also without the `#`, or with `0x`) into a colour
+pub fn parse_hex_color(s: &str) -> Option<Color32> {
+    let s = s.trim();
+    let hex = s.strip_prefix("0x").unwrap_or(s).trim_start_matches('#');
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let v = u32::from_str_radix(hex, 16).ok()?;
+    let [a, b, c, d] = v.to_be_bytes();
+    match hex.len() {
+        // Each digit doubled: `f80` is `ff8800`
+        3 => Some(Color32::from_rgb((c & 0xf) * 17, (d >> 4) * 17, (d & 0xf) * 17)),
+        6 => Some(Color32::from_rgb(b, c, d)),
+        8 => Some(Color32::from_rgba_unmultiplied(a, b, c, d)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str) -> TerminalProfile {
+        TerminalProfile { name: name.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn picks_the_default_terminal_profile() {
+        let mut config = Config::default();
+        assert_eq!(config.default_terminal_profile().name, "Default");
+        // A name with no profiles to match falls back to the built-in one
+        config.default_profile = Some("zsh".to_string());
+        assert_eq!(config.default_terminal_profile().name, "Default");
+
+        config.profiles = vec![profile("bash"), profile("zsh")];
+        assert_eq!(config.default_terminal_profile().name, "zsh");
+        config.default_profile = Some("fish".to_string());
+        assert_eq!(config.default_terminal_profile().name, "bash");
+        config.default_profile = None;
+        assert_eq!(config.default_terminal_profile().name, "bash");
+    }
+
+    #[test]
+    fn expands_home() {
+        let Some(home) = dirs::home_dir() else { return };
+        assert_eq!(expand_home("~"), home);
+        assert_eq!(expand_home("~/x"), home.join("x"));
+        assert_eq!(expand_home("~/a/b.ttf"), home.join("a/b.ttf"));
+        // Only a leading `~` on its own or before `/`
+        for path in ["~user/x", "/tmp/~", "x~", "", "/abs"] {
+            assert_eq!(expand_home(path), PathBuf::from(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_eq!(parse_hex_color("#f80"), Some(Color32::from_rgb(0xff, 0x88, 0x00)));
+        assert_eq!(parse_hex_color("#1d1f21"), Some(Color32::from_rgb(0x1d, 0x1f, 0x21)));
+        assert_eq!(parse_hex_color(" 1D1F21 "), Some(Color32::from_rgb(0x1d, 0x1f, 0x21)));
+        assert_eq!(parse_hex_color("0x1d1f21"), Some(Color32::from_rgb(0x1d, 0x1f, 0x21)));
+        assert_eq!(parse_hex_color("#ff000080"), Some(Color32::from_rgba_unmultiplied(255, 0, 0, 0x80)));
+        assert_eq!(parse_hex_color("#123456ff"), Some(Color32::from_rgb(0x12, 0x34, 0x56)));
+        for bad in ["", "#", "#12", "#1234", "#12345", "#1234567", "#123456789", "#ggg", "#+12345", "red"] {
+            assert_eq!(parse_hex_color(bad), None, "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use crate::buffer::TextBuffer;
+use crate::editor::render_highlighted_line;
+use crate::syntax::{HighlightSpan, Syntax};
+use eframe::egui::{self, Color32, FontId, Rect};
+use similar::{ChangeTag, DiffOp, TextDiff};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long a diff may look for the smallest set of changes before settling for a coarser one
+const DIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What an editor's text is compared with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compare {
+    /// The file as last saved
+    Disk,
+    /// The file in the last commit
+    Head,
+}
+
+/// A line of one side of a diff, with the bytes of it that changed word by word
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub line: usize,
+    pub changed: bool,
+    pub words: Vec<Range<usize>>,
+}
+
+/// A row of a diff. Side by side, rows pair old lines with new ones; inline, a
+/// changed row holds one side only.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Row {
+    pub old: Option<Line>,
+    pub new: Option<Line>,
+}
+
+impl Row {
+    pub fn changed(&self) -> bool {
+        self.old.as_ref().or(self.new.as_ref()).is_some_and(|l| l.changed)
+    }
+}
+
+/// Rows pairing `old`'s lines with `new`'s: unchanged lines side by side, and each
+/// run of changes with its removed and added lines matched up in order
+pub fn side_by_side(old: &str, new: &str) -> Vec<Row> {
+    rows_until(old, new, Instant::now() + DIFF_TIMEOUT)
+}
+
+/// [`side_by_side`], with line and word diffs that stop refining at `deadline`
+fn rows_until(old: &str, new: &str, deadline: Instant) -> Vec<Row> {
+    let diff = TextDiff::configure().deadline(deadline).diff_lines(old, new);
+    let (old_lines, new_lines) = (diff.old_slices(), diff.new_slices());
+    let same = |line| Some(Line { line, changed: false, words: Vec::new() });
+    let mut rows = Vec::new();
+    let mut ops = diff.ops().iter().peekable();
+    while let Some(op) = ops.next() {
+        if let DiffOp::Equal { old_index, new_index, len } = *op {
+            rows.extend((0..len).map(|i| Row { old: same(old_index + i), new: same(new_index + i) }));
+            continue;
+        }
+        // Deletes and inserts next to each other make one block
+        let (mut old_range, mut new_range) = (op.old_range(), op.new_range());
+        while let Some(next) = ops.next_if(|op| !matches!(op, DiffOp::Equal { .. })) {
+            old_range.end = next.old_range().end;
+            new_range.end = next.new_range().end;
+        }
+        for i in 0..old_range.len().max(new_range.len()) {
+            let old = Some(old_range.start + i).filter(|l| old_range.contains(l));
+            let new = Some(new_range.start + i).filter(|l| new_range.contains(l));
+            let (old_words, new_words) = match (old, new) {
+                (Some(o), Some(n)) => word_changes(old_lines[o], new_lines[n], deadline),
+                _ => Default::default(),
+            };
+            rows.push(Row {
+                old: old.map(|line| Line { line, changed: true, words: old_words }),
+                new: new.map(|line| Line { line, changed: true, words: new_words }),
+            });
+        }
+    }
+    rows
+}
+
+/// Byte ranges of the words that differ between two versions of a line; none when
+/// the lines have nothing but whitespace in common
+fn word_changes(old: &str, new: &str, deadline: Instant) -> (Vec<Range<usize>>, Vec<Range<usize>>) {
+    let (old, new) = (old.trim_end_matches(['\n', '\r']), new.trim_end_matches(['\n', '\r']));
+    let diff = TextDiff::configure().deadline(deadline).diff_words(old, new);
+    let (mut old_at, mut new_at) = (0, 0);
+    let (mut old_words, mut new_words) = (Vec::new(), Vec::new());
+    let mut shared = false;
+    for change in diff.iter_all_changes() {
+        let len = change.value().len();
+        match change.tag() {
+            ChangeTag::Equal => {
+                shared |= !change.value().trim().is_empty();
+                old_at += len;
+                new_at += len;
+            }
+            ChangeTag::Delete => {
+                push_merged(&mut old_words, old_at..old_at + len);
+                old_at += len;
+            }
+            ChangeTag::Insert => {
+                push_merged(&mut new_words, new_at..new_at + len);
+                new_at += len;
+            }
+        }
+    }
+    if shared {
+        (old_words, new_words)
+    } else {
+        Default::default()
+    }
+}
+
+fn push_merged(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+/// The same rows one above the other: each run of changes shows its old lines,
+/// then its new ones
+pub fn inline(rows: &[Row]) -> Vec<Row> {
+    let mut out = Vec::with_capacity(rows.len());
+    let mut i = 0;
+    while i < rows.len() {
+        if !rows[i].changed() {
+            out.push(rows[i].clone());
+            i += 1;
+            continue;
+        }
+        let end = rows[i..].iter().position(|r| !r.changed()).map_or(rows.len(), |n| i + n);
+        let block = &rows[i..end];
+        out.extend(block.iter().filter_map(|r| r.old.clone()).map(|old| Row { old: Some(old), new: None }));
+        out.extend(block.iter().filter_map(|r| r.new.clone()).map(|new| Row { old: None, new: Some(new) }));
+        i = end;
+    }
+    out
+}
+
+/// Rows where each run of changes starts
+pub fn hunk_starts(rows: &[Row]) -> Vec<usize> {
+    (0..rows.len()).filter(|&i| rows[i].changed() && (i == 0 || !rows[i - 1].changed())).collect()
+}
+
+/// One version of the text being compared
+pub struct Side {
+    label: String,
+    text: TextBuffer,
+    syntax: Option<Syntax>,
+    /// Highlights for the byte range last drawn
+    highlights: Option<(Range<usize>, Vec<HighlightSpan>)>,
+}
+
+impl Side {
+    /// `path` picks the syntax highlighting
+    pub fn new(label: String, text: &str, path: Option<&Path>) -> Self {
+        let text = TextBuffer::from(text);
+        let syntax = path.and_then(Syntax::for_path);
+        Self { label, text, syntax, highlights: None }
+    }
+
+    /// Highlight spans covering `lines`
+    fn highlights(&mut self, lines: Range<usize>) -> Vec<HighlightSpan> {
+        let range = self.text.line_start(lines.start)..self.text.line_end(lines.end.saturating_sub(1));
+        let fresh = matches!(&self.highlights, Some((r, _)) if *r == range);
+        if !fresh {
+            let spans = match &mut self.syntax {
+                Some(syntax) => syntax.highlights(&self.text, range.clone()),
+                None => Vec::new(),
+            };
+            self.highlights = Some((range, spans));
+        }
+        self.highlights.as_ref().map(|(_, s)| s.clone()).unwrap_or_default()
+    }
+}
+
+/// Read-only comparison of two texts, side by side or inline, scrolled together
+pub struct DiffView {
+    title: String,
+    old: Side,
+    new: Side,
+    rows: Vec<Row>,
+    inline_rows: Vec<Row>,
+    /// First row of each change, the same number in both layouts
+    hunks: Vec<usize>,
+    inline_hunks: Vec<usize>,
+    pub inline: bool,
+    /// Change last navigated to
+    current: Option<usize>,
+    /// Change to bring into view on the next frame
+    reveal: Option<usize>,
+    scroll_y: f32,
+    scroll_x: f32,
+    pub grab_focus: bool,
+}
+
+impl DiffView {
+    pub fn new(title: String, old: Side, new: Side) -> Self {
+        let rows = side_by_side(&old.text.to_string(), &new.text.to_string());
+        let inline_rows = inline(&rows);
+        let hunks = hunk_starts(&rows);
+        let inline_hunks = hunk_starts(&inline_rows);
+        let first = (!hunks.is_empty()).then_some(0);
+        Self {
+            title,
+            old,
+            new,
+            rows,
+            inline_rows,
+            hunks,
+            inline_hunks,
+            inline: false,
+            current: first,
+            reveal: first,
+            scroll_y: 0.0,
+            scroll_x: 0.0,
+            grab_focus: false,
+        }
+    }
+
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    fn rows(&self) -> &[Row] {
+        if self.inline {
+            &self.inline_rows
+        } else {
+            &self.rows
+        }
+    }
+
+    fn set_inline(&mut self, inline: bool) {
+        self.inline = inline;
+        self.reveal = self.current;
+    }
+
+    /// Move to the next change (or the previous one)
+    fn step(&mut self, forward: bool) {
+        if self.hunks.is_empty() {
+            return;
+        }
+        let last = self.hunks.len() - 1;
+        let next = match (self.current, forward) {
+            (None, _) => 0,
+            (Some(i), true) => (i + 1).min(last),
+            (Some(i), false) => i.saturating_sub(1),
+        };
+        self.current = Some(next);
+        self.reveal = Some(next);
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        let font = FontId::monospace(13.0);
+        let char_width = 7.8_f32;
+        let line_height = 16.0_f32;
+        let toolbar_height = 24.0;
+        let header_height = 18.0;
+        let scrollbar_width = 10.0;
+
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_SURFACE);
+        let toolbar = Rect::from_min_size(rect.left_top(), egui::vec2(rect.width(), toolbar_height));
+        let header = Rect::from_min_size(toolbar.left_bottom(), egui::vec2(rect.width() - scrollbar_width, header_height));
+        // A pane shorter than the toolbar and header has an empty body, not a negative one
+        let body = Rect::from_min_max(header.left_bottom(), egui::pos2(rect.right() - scrollbar_width, rect.bottom().max(header.bottom())));
+        let track = Rect::from_min_max(egui::pos2(body.right(), header.top()), rect.right_bottom());
+
+        self.render_toolbar(ui, toolbar);
+
+        let id = ui.id().with(("diff_view", &self.title));
+        let response = ui.interact(body, id, egui::Sense::click());
+        if response.clicked() || std::mem::take(&mut self.grab_focus) {
+            response.request_focus();
+        }
+        let row_count = self.rows().len();
+        let max_y = (row_count as f32 * line_height - body.height() + line_height).max(0.0);
+        if response.hovered() {
+            let delta = ui.input(|i| i.smooth_scroll_delta);
+            self.scroll_y -= delta.y;
+            self.scroll_x -= delta.x;
+        }
+        if response.has_focus() {
+            let page = (body.height() - line_height).max(line_height);
+            ui.input(|i| {
+                if i.key_pressed(egui::Key::F7) {
+                    self.step(!i.modifiers.shift);
+                }
+                if i.key_pressed(egui::Key::ArrowDown) {
+                    self.scroll_y += line_height;
+                }
+                if i.key_pressed(egui::Key::ArrowUp) {
+                    self.scroll_y -= line_height;
+                }
+                if i.key_pressed(egui::Key::PageDown) {
+                    self.scroll_y += page;
+                }
+                if i.key_pressed(egui::Key::PageUp) {
+                    self.scroll_y -= page;
+                }
+                if i.key_pressed(egui::Key::Home) {
+                    self.scroll_y = 0.0;
+                }
+                if i.key_pressed(egui::Key::End) {
+                    self.scroll_y = max_y;
+                }
+            });
+        }
+        // Changes are brought to a third of the way down, with context above
+        if let Some(hunk) = self.reveal.take() {
+            let starts = if self.inline { &self.inline_hunks } else { &self.hunks };
+            if let Some(&row) = starts.get(hunk) {
+                self.scroll_y = row as f32 * line_height - body.height() / 3.0;
+            }
+        }
+        let track_response = ui.interact(track, id.with("scrollbar"), egui::Sense::click_and_drag());
+        if let Some(pos) = track_response.interact_pointer_pos() {
+            let fraction = ((pos.y - track.top()) / track.height()).clamp(0.0, 1.0);
+            self.scroll_y = fraction * max_y;
+        }
+        self.scroll_y = self.scroll_y.clamp(0.0, max_y);
+
+        self.render_scrollbar(ui, track, line_height, body.height() / line_height);
+
+        let last = ((self.scroll_y / line_height) as usize + (body.height() / line_height) as usize + 2).min(row_count);
+        let first = ((self.scroll_y / line_height) as usize).min(last);
+        let visible = first..last;
+        // Borrowing the fields directly leaves the sides free to highlight
+        let all_rows = if self.inline { &self.inline_rows } else { &self.rows };
+        let rows = &all_rows[visible.clone()];
+        let lines = |pick: fn(&Row) -> Option<&Line>| {
+            let mut lines = rows.iter().filter_map(pick).map(|l| l.line);
+            let start = lines.next().unwrap_or(0);
+            start..lines.next_back().unwrap_or(start) + 1
+        };
+        let old_lines = lines(|r| r.old.as_ref());
+        let new_lines = lines(|r| r.new.as_ref());
+        let old_spans = self.old.highlights(old_lines);
+        let new_spans = self.new.highlights(new_lines);
+
+        // Both sides scroll together, so the widest visible line bounds either
+        let widest = rows
+            .iter()
+            .flat_map(|r| [r.old.as_ref().map(|l| (&self.old, l)), r.new.as_ref().map(|l| (&self.new, l))])
+            .flatten()
+            .map(|(side, l)| crate::wrap::width(&side.text.line(l.line)))
+            .max()
+            .unwrap_or(0);
+        let gutter = if self.inline { 12.0 * char_width } else { 6.0 * char_width };
+        let column_width = if self.inline { body.width() } else { body.width() / 2.0 };
+        let max_x = (widest as f32 * char_width - (column_width - gutter) + 4.0 * char_width).max(0.0);
+        self.scroll_x = self.scroll_x.clamp(0.0, max_x);
+
+        let painter = ui.painter_at(body);
+        let number = |line: Option<&Line>| line.map(|l| format!("{:>5}", l.line + 1)).unwrap_or_default();
+        let draw = |side: &Side, spans: &[HighlightSpan], line: &Line, row_rect: Rect, text_rect: Rect, tint: Color32| {
+            if line.changed {
+                painter.rect_filled(row_rect, 0.0, tint.linear_multiply(0.1));
+            }
+            let text = side.text.line(line.line);
+            let text_painter = painter.with_clip_rect(text_rect.intersect(body));
+            let left = text_rect.left() + 4.0 - self.scroll_x;
+            for word in &line.words {
+                let x = |at: usize| left + crate::wrap::width(text.get(..at).unwrap_or(&text)) as f32 * char_width;
+                let word_rect = Rect::from_x_y_ranges(x(word.start)..=x(word.end), row_rect.y_range());
+                text_painter.rect_filled(word_rect, 2.0, tint.linear_multiply(0.3));
+            }
+            render_highlighted_line(&text_painter, &font, char_width, left, row_rect.top(), &text, side.text.line_start(line.line), spans);
+        };
+        for (i, row) in rows.iter().enumerate() {
+            let y = body.top() + (first + i) as f32 * line_height - self.scroll_y;
+            let row_rect = Rect::from_min_size(egui::pos2(body.left(), y), egui::vec2(body.width(), line_height));
+            if self.inline {
+                let text_rect = Rect::from_min_max(egui::pos2(row_rect.left() + gutter, y), row_rect.right_bottom());
+                let (sign, tint) = match (&row.old, &row.new) {
+                    (Some(old), None) => {
+                        draw(&self.old, &old_spans, old, row_rect, text_rect, crate::theme::ERROR);
+                        ("-", crate::theme::ERROR)
+                    }
+                    (_, Some(new)) => {
+                        draw(&self.new, &new_spans, new, row_rect, text_rect, crate::theme::SUCCESS);
+                        (if new.changed { "+" } else { " " }, crate::theme::SUCCESS)
+                    }
+                    (None, None) => (" ", crate::theme::TEXT_SECONDARY),
+                };
+                let numbers = format!("{:>5} {:>5}", number(row.old.as_ref()), number(row.new.as_ref()));
+                painter.text(egui::pos2(row_rect.left(), y), egui::Align2::LEFT_TOP, numbers, font.clone(), crate::theme::TEXT_SECONDARY);
+                painter.text(egui::pos2(text_rect.left() - char_width, y), egui::Align2::LEFT_TOP, sign, font.clone(), tint);
+            } else {
+                for (column, side, spans, line, tint) in [
+                    (0.0, &self.old, &old_spans, &row.old, crate::theme::ERROR),
+                    (1.0, &self.new, &new_spans, &row.new, crate::theme::SUCCESS),
+                ] {
+                    let left = body.left() + column * column_width;
+                    let cell = Rect::from_min_size(egui::pos2(left, y), egui::vec2(column_width, line_height));
+                    let text_rect = Rect::from_min_max(egui::pos2(left + gutter, y), cell.right_bottom());
+                    match line {
+                        Some(line) => draw(side, spans, line, cell, text_rect, tint),
+                        // The other side has lines here that this one doesn't
+                        None if row.changed() => {
+                            painter.rect_filled(cell, 0.0, crate::theme::BG_ELEVATED);
+                        }
+                        None => {}
+                    }
+                    painter.text(cell.left_top(), egui::Align2::LEFT_TOP, number(line.as_ref()), font.clone(), crate::theme::TEXT_SECONDARY);
+                }
+            }
+        }
+        if !self.inline {
+            let x = body.center().x;
+            painter.vline(x, body.y_range(), egui::Stroke::new(1.0, crate::theme::BORDER));
+        }
+        if row_count == 0 || self.hunks.is_empty() {
+            painter.text(body.center(), egui::Align2::CENTER_CENTER, "No differences", FontId::proportional(13.0), crate::theme::TEXT_SECONDARY);
+        }
+
+        // Which version is which
+        ui.painter().rect_filled(header, 0.0, crate::theme::BG_ELEVATED);
+        let label_font = FontId::proportional(12.0);
+        let labels = if self.inline {
+            vec![(header.left(), format!("{} → {}", self.old.label, self.new.label))]
+        } else {
+            vec![(header.left(), self.old.label.clone()), (header.center().x, self.new.label.clone())]
+        };
+        for (x, label) in labels {
+            let pos = egui::pos2(x + 8.0, header.center().y);
+            ui.painter().text(pos, egui::Align2::LEFT_CENTER, label, label_font.clone(), crate::theme::TEXT_SECONDARY);
+        }
+    }
+
+    /// Layout switch, change navigation and a count of the changes
+    fn render_toolbar(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_ELEVATED);
+        let mut child = ui.new_child(
+            egui::UiBuilder::new()
+                .max_rect(rect.shrink2(egui::vec2(8.0, 0.0)))
+                .layout(egui::Layout::left_to_right(egui::Align::Center)),
+        );
+        child.style_mut().override_font_id = Some(FontId::proportional(12.0));
+        if child.selectable_label(!self.inline, "Side by Side").clicked() {
+            self.set_inline(false);
+        }
+        if child.selectable_label(self.inline, "Inline").clicked() {
+            self.set_inline(true);
+        }
+        child.separator();
+        if child.small_button("↑").on_hover_text("Previous change (Shift+F7)").clicked() {
+            self.step(false);
+        }
+        if child.small_button("↓").on_hover_text("Next change (F7)").clicked() {
+            self.step(true);
+        }
+        let status = match (self.current, self.hunks.len()) {
+            (_, 0) => "No changes".to_string(),
+            (Some(i), n) => format!("Change {} of {}", i + 1, n),
+            (None, n) => format!("{} changes", n),
+        };
+        child.label(egui::RichText::new(status).color(crate::theme::TEXT_SECONDARY));
+    }
+
+    /// Scrollbar with a mark for each change, coloured by whether it removes, adds
+    /// or modifies lines
+    fn render_scrollbar(&self, ui: &egui::Ui, track: Rect, line_height: f32, visible_rows: f32) {
+        let painter = ui.painter();
+        painter.rect_filled(track, 0.0, crate::theme::BG_ELEVATED);
+        let rows = self.rows();
+        if rows.is_empty() {
+            return;
+        }
+        let scale = track.height() / rows.len() as f32;
+        let starts = if self.inline { &self.inline_hunks } else { &self.hunks };
+        for &start in starts {
+            let end = rows[start..].iter().position(|r| !r.changed()).map_or(rows.len(), |n| start + n);
+            let block = &rows[start..end];
+            let color = match (block.iter().any(|r| r.old.is_some()), block.iter().any(|r| r.new.is_some())) {
+                (true, false) => crate::theme::ERROR,
+                (false, true) => crate::theme::SUCCESS,
+                _ => crate::theme::WARNING,
+            };
+            let top = track.top() + start as f32 * scale;
+            let mark = Rect::from_min_size(egui::pos2(track.left() + 2.0, top), egui::vec2(track.width() - 4.0, (block.len() as f32 * scale).max(2.0)));
+            painter.rect_filled(mark, 0.0, color);
+        }
+        if (rows.len() as f32) > visible_rows {
+            let top = track.top() + self.scroll_y / line_height * scale;
+            let thumb = Rect::from_min_size(egui::pos2(track.left(), top), egui::vec2(track.width(), (visible_rows * scale).max(20.0)));
+            painter.rect_stroke(thumb, 3.0, egui::Stroke::new(1.0, crate::theme::TEXT_SECONDARY), egui::StrokeKind::Inside);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(rows: &[Row]) -> Vec<(Option<usize>, Option<usize>)> {
+        rows.iter().map(|r| (r.old.as_ref().map(|l| l.line), r.new.as_ref().map(|l| l.line))).collect()
+    }
+
+    #[test]
+    fn pairs_changed_lines_side_by_side() {
+        let rows = side_by_side("a\nb\nc\nd\n", "a\nB\nx\nc\n");
+        assert_eq!(lines(&rows), vec![(Some(0), Some(0)), (Some(1), Some(1)), (None, Some(2)), (Some(2), Some(3)), (Some(3), None)]);
+        assert_eq!(hunk_starts(&rows), vec![1, 4]);
+
+        let inline = inline(&rows);
+        assert_eq!(lines(&inline), vec![(Some(0), Some(0)), (Some(1), None), (None, Some(1)), (None, Some(2)), (Some(2), Some(3)), (Some(3), None)]);
+        assert_eq!(hunk_starts(&inline), vec![1, 5]);
+    }
+
+    #[test]
+    fn marks_changed_words() {
+        let rows = side_by_side("let total = a + b;\n", "let sum = a + b;\n");
+        let (old, new) = (rows[0].old.as_ref().unwrap(), rows[0].new.as_ref().unwrap());
+        assert_eq!((old.words.clone(), new.words.clone()), (vec![Range { start: 4, end: 9 }], vec![Range { start: 4, end: 7 }]));
+
+        // Lines with nothing in common are marked as a whole
+        let rows = side_by_side("alpha\n", "beta\n");
+        assert!(rows[0].old.as_ref().unwrap().words.is_empty());
+    }
+
+    #[test]
+    fn diffs_past_the_deadline_still_cover_every_line() {
+        let old: String = (0..200).map(|i| format!("{} {}\n", i, i % 7)).collect();
+        let new: String = (0..200).map(|i| format!("{} {}\n", i % 11, i)).collect();
+        let rows = rows_until(&old, &new, Instant::now());
+        let old_lines: Vec<usize> = rows.iter().filter_map(|r| r.old.as_ref()).map(|l| l.line).collect();
+        let new_lines: Vec<usize> = rows.iter().filter_map(|r| r.new.as_ref()).map(|l| l.line).collect();
+        assert_eq!(old_lines, (0..200).collect::<Vec<_>>());
+        assert_eq!(new_lines, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn renders_in_panes_of_any_height() {
+        let text: String = (0..50).map(|i| format!("line {}\n", i)).collect();
+        let mut view = DiffView::new("t".to_string(), Side::new("a".to_string(), &text, None), Side::new("b".to_string(), "x\n", None));
+        let ctx = egui::Context::default();
+        for height in [0.0, 10.0, 30.0, 45.0, 400.0] {
+            for inline in [false, true] {
+                view.inline = inline;
+                view.scroll_y = 1e6;
+                let _ = ctx.run(egui::RawInput::default(), |ctx| {
+                    egui::CentralPanel::default().show(ctx, |ui| {
+                        view.render(ui, Rect::from_min_size(egui::pos2(0.0, 0.0), egui::vec2(300.0, height)));
+                    });
+                });
+            }
+        }
+    }
+}
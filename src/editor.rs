@@ -1,36 +1,161 @@
+use crate::buffer::TextBuffer;
+use crate::diff_view::Compare;
+use crate::encoding::{FileEncoding, LineEnding};
+use crate::find::FindState;
+use crate::git::{HeadJob, Hunk, HunkKind, HunksJob};
+use crate::indent::IndentUnit;
+use crate::lsp::{CompletionItem, ContentChange, Diagnostic, LspRange, LspRequest, LspSymbol, Severity};
+use crate::outline::{self, Symbol};
+use crate::syntax::{HighlightSpan, Lang, Syntax};
+use crate::undo::{EditKind, EditOp, Selection, UndoHistory};
+use crate::vim::{self, Action, Address, Command, Ex, ExRange, InsertAt, Mode, Motion, Operator, Register, Target, Vim, VimKey};
+use crate::wrap::{self, Layout as RowLayout};
 use eframe::egui::{self, Color32, FontId, Rect};
+use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Unique editor instance ID
 pub type EditorId = usize;
 
-#[derive(Clone, Debug)]
-struct UndoEntry {
-    content: String,
-    cursor: usize,
+/// How long the pointer rests on a word before asking the language server about it
+const HOVER_DELAY: Duration = Duration::from_millis(500);
+
+/// Completion items shown at once
+const COMPLETION_ROWS: usize = 10;
+
+/// Symbols shown at once in the go to symbol picker
+const SYMBOL_ROWS: usize = 12;
+
+/// While the text changes, the language server's outline is asked for at most this often
+const OUTLINE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Language server completions for the word before the cursor
+struct CompletionPopup {
+    items: Vec<CompletionItem>,
+    selected: usize,
+    /// Start of the word being completed
+    start: usize,
 }
 
 pub struct Editor {
     pub id: EditorId,
     pub file_path: Option<PathBuf>,
-    pub content: String,
+    pub content: TextBuffer,
     pub cursor: usize,         // byte offset
     pub selection_anchor: Option<usize>, // byte offset for selection start
+    /// Additional cursors for multi-cursor editing; `cursor` is the primary one
+    pub extra_cursors: Vec<Selection>,
+    /// Line and column where an Alt-drag column selection started
+    column_drag: Option<(usize, usize)>,
+    /// Where a plain drag selection started
+    drag_anchor: Option<usize>,
     pub scroll_offset: f32,    // vertical scroll in pixels
+    /// Horizontal scroll in pixels
+    scroll_x: f32,
+    /// Cursor and revision the view last scrolled to, so it only follows real moves
+    followed: (usize, u64),
+    /// Wrap long lines at the pane width instead of scrolling sideways
+    pub soft_wrap: bool,
+    /// How lines map to visual rows, while soft wrap or folds make that not one to one
+    layout: Option<RowLayout>,
+    /// Columns that fit across the pane, for soft wrap
+    wrap_cols: usize,
+    /// Folded regions: the text hidden after each fold's first line
+    folds: Vec<Range<usize>>,
+    /// Foldable regions as (first line, last line), and the revision they're for
+    fold_ranges: Option<(u64, Vec<(usize, usize)>)>,
     pub modified: bool,
     pub line_count: usize,
 
-    // Search
-    pub search_open: bool,
-    pub search_query: String,
-    pub search_matches: Vec<(usize, usize)>, // (start, end) byte offsets
-    pub search_current: usize,
+    pub find: FindState,
     pub grab_focus: bool,
+    /// In-progress IME composition (preedit) text, drawn inline at the cursor
+    ime_preedit: String,
 
     // Undo/Redo
-    undo_stack: Vec<UndoEntry>,
-    redo_stack: Vec<UndoEntry>,
-    last_snapshot_content: String,
+    history: UndoHistory,
+    /// Store undo history on save so it survives reopening the file
+    persist_undo: bool,
+
+    // Syntax highlighting
+    syntax: Option<Syntax>,
+    /// Bumped on every change to `content`; keys the highlight cache
+    revision: u64,
+    highlight_cache: Option<(u64, Range<usize>, Vec<HighlightSpan>)>,
+
+    // Language server
+    /// Set once the buffer is open on a language server; edits are recorded from then on
+    pub lsp_attached: bool,
+    /// Edits the language server hasn't been told about yet
+    lsp_changes: Vec<ContentChange>,
+    /// Set on save until the language server has been told
+    pub lsp_saved: bool,
+    /// Requests for the language server, sent on by the app
+    pub lsp_requests: Vec<LspRequest>,
+    /// Typed characters that ask for completions
+    pub completion_triggers: Vec<String>,
+    pub diagnostics: Vec<Diagnostic>,
+    completion: Option<CompletionPopup>,
+    /// Word start under the pointer, since when, and whether hover was requested
+    hover_probe: Option<(usize, Instant, bool)>,
+    hover: Option<(usize, String)>,
+    /// Text of the rename box (F2) while it's open
+    rename: Option<String>,
+
+    // Formatting
+    /// Cmd+S formats before saving
+    pub format_on_save: bool,
+    /// Formatting asked for by a key press; `true` to save afterwards
+    pub format_request: Option<bool>,
+    /// Revision being formatted, whether to save afterwards, and when it started
+    formatting: Option<(u64, bool, Instant)>,
+    /// Error shown in a strip above the text until dismissed
+    pub notice: Option<String>,
+
+    // Changes by other programs
+    /// Hash of the file's contents as last read or written, to tell our own saves apart
+    disk_hash: Option<u64>,
+    /// What's on disk now and its hash, when it changed under unsaved edits
+    conflict: Option<(String, u64)>,
+    /// Comparison asked for from the conflict bar or context menu, opened in a tab
+    pub diff_request: Option<Compare>,
+    /// Encoding, BOM and line endings the file is written back with
+    pub encoding: FileEncoding,
+    /// What Tab inserts, guessed from the file
+    pub indent: IndentUnit,
+
+    // Vim
+    /// Modal Vim keybindings, when turned on in the config
+    pub vim: Option<Vim>,
+    /// Set by `:q`; the app closes the tab
+    pub close_request: bool,
+    /// `:wq` waiting for format-on-save: close once the save went through
+    quit_after_save: bool,
+
+    // Navigation
+    /// Text of the go to line box (Ctrl+G) while it's open
+    goto_line: Option<String>,
+    /// Query and highlighted match of the go to symbol picker (Cmd+Shift+O) while it's open
+    symbol_picker: Option<(String, usize)>,
+    /// Symbols from the syntax tree, and the revision they're for
+    syntax_outline: Option<(u64, Vec<Symbol>)>,
+    /// Symbols from the language server, used instead once it has sent some
+    lsp_outline: Option<Vec<Symbol>>,
+    /// Revision the language server's outline was last asked for, and when
+    outline_asked: Option<(u64, Instant)>,
+
+    // Version control
+    /// The file as last committed, to mark changed lines against
+    git_head: Option<Arc<str>>,
+    git_job: Option<HeadJob>,
+    /// Changes from `git_head`, and the revision they're for
+    git_hunks: Option<(u64, Vec<Hunk>)>,
+    /// Diff of a newer revision, running in the background
+    hunks_job: Option<HunksJob>,
+    /// Lines of the hunk whose committed version is shown, after a gutter click
+    hunk_popup: Option<Range<usize>>,
 }
 
 impl Editor {
@@ -38,20 +163,62 @@ impl Editor {
         Self {
             id,
             file_path: None,
-            content: String::new(),
+            content: TextBuffer::default(),
             cursor: 0,
             selection_anchor: None,
+            extra_cursors: Vec::new(),
+            column_drag: None,
+            drag_anchor: None,
             scroll_offset: 0.0,
+            scroll_x: 0.0,
+            followed: (0, 0),
+            soft_wrap: false,
+            layout: None,
+            wrap_cols: 80,
+            folds: Vec::new(),
+            fold_ranges: None,
             modified: false,
             line_count: 1,
-            search_open: false,
-            search_query: String::new(),
-            search_matches: Vec::new(),
-            search_current: 0,
+            find: FindState::default(),
             grab_focus: false,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
-            last_snapshot_content: String::new(),
+            ime_preedit: String::new(),
+            history: UndoHistory::new(),
+            persist_undo: false,
+            syntax: None,
+            revision: 0,
+            highlight_cache: None,
+            lsp_attached: false,
+            lsp_changes: Vec::new(),
+            lsp_saved: false,
+            lsp_requests: Vec::new(),
+            completion_triggers: Vec::new(),
+            diagnostics: Vec::new(),
+            completion: None,
+            hover_probe: None,
+            hover: None,
+            rename: None,
+            format_on_save: false,
+            format_request: None,
+            formatting: None,
+            notice: None,
+            disk_hash: None,
+            conflict: None,
+            diff_request: None,
+            vim: None,
+            close_request: false,
+            quit_after_save: false,
+            goto_line: None,
+            symbol_picker: None,
+            syntax_outline: None,
+            lsp_outline: None,
+            outline_asked: None,
+            encoding: FileEncoding::default(),
+            indent: IndentUnit::default(),
+            git_head: None,
+            git_job: None,
+            git_hunks: None,
+            hunks_job: None,
+            hunk_popup: None,
         }
     }
 
@@ -60,26 +227,73 @@ impl Editor {
     }
 
     pub fn open_file(id: EditorId, path: PathBuf) -> Result<Self, std::io::Error> {
-        let content = std::fs::read_to_string(&path)?;
-        let line_count = content.lines().count().max(1);
-        let snapshot = content.clone();
+        let bytes = std::fs::read(&path)?;
+        let (text, encoding) = crate::encoding::decode(&bytes);
+        let content = TextBuffer::from(text.as_str());
+        let line_count = content.line_count();
+        let syntax = Syntax::for_path(&path);
+        let git_job = Some(HeadJob::start(&path));
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        let prose = matches!(ext.as_str(), "md" | "markdown" | "txt" | "rst" | "adoc" | "org");
         Ok(Self {
             id,
             file_path: Some(path),
             content,
             cursor: 0,
             selection_anchor: None,
+            extra_cursors: Vec::new(),
+            column_drag: None,
+            drag_anchor: None,
             scroll_offset: 0.0,
+            scroll_x: 0.0,
+            followed: (0, 0),
+            soft_wrap: prose,
+            layout: None,
+            wrap_cols: 80,
+            folds: Vec::new(),
+            fold_ranges: None,
             modified: false,
             line_count,
-            search_open: false,
-            search_query: String::new(),
-            search_matches: Vec::new(),
-            search_current: 0,
+            find: FindState::default(),
             grab_focus: false,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
-            last_snapshot_content: snapshot,
+            ime_preedit: String::new(),
+            history: UndoHistory::new(),
+            persist_undo: false,
+            syntax,
+            revision: 0,
+            highlight_cache: None,
+            lsp_attached: false,
+            lsp_changes: Vec::new(),
+            lsp_saved: false,
+            lsp_requests: Vec::new(),
+            completion_triggers: Vec::new(),
+            diagnostics: Vec::new(),
+            completion: None,
+            hover_probe: None,
+            hover: None,
+            rename: None,
+            format_on_save: false,
+            format_request: None,
+            formatting: None,
+            notice: None,
+            disk_hash: Some(hash_bytes(&bytes)),
+            conflict: None,
+            diff_request: None,
+            vim: None,
+            close_request: false,
+            quit_after_save: false,
+            goto_line: None,
+            symbol_picker: None,
+            syntax_outline: None,
+            lsp_outline: None,
+            outline_asked: None,
+            encoding,
+            indent: IndentUnit::detect(&text).unwrap_or_default(),
+            git_head: None,
+            git_job,
+            git_hunks: None,
+            hunks_job: None,
+            hunk_popup: None,
         })
     }
 
@@ -90,225 +304,2095 @@ impl Editor {
             .and_then(|p| p.file_name())
             .map(|n| n.to_string_lossy().to_string())
             .unwrap_or_else(|| "Untitled".to_string());
-        if self.modified {
+        let mut title = if self.modified {
             format!("● {}", name)
         } else {
             name
+        };
+        let (errors, warnings) = self.problem_counts();
+        if errors > 0 {
+            title.push_str(&format!(" ✖{}", errors));
+        }
+        if warnings > 0 {
+            title.push_str(&format!(" ⚠{}", warnings));
         }
+        title
+    }
+
+    /// Errors and warnings reported for this file
+    pub fn problem_counts(&self) -> (usize, usize) {
+        let count = |severity| self.diagnostics.iter().filter(|d| d.severity == severity).count();
+        (count(Severity::Error), count(Severity::Warning))
     }
 
     pub fn save(&mut self) -> Result<(), std::io::Error> {
         if let Some(ref path) = self.file_path {
-            std::fs::write(path, &self.content)?;
-            self.modified = false;
+            // Don't overwrite changes made by another program without asking
+            if self.conflict.is_none() {
+                if let Ok(bytes) = std::fs::read(path) {
+                    let hash = hash_bytes(&bytes);
+                    if self.disk_hash.is_some_and(|h| h != hash) {
+                        let (text, _) = crate::encoding::decode_as(&bytes, self.encoding.encoding);
+                        self.conflict = Some((text, hash));
+                    }
+                }
+            }
+            if self.conflict.is_some() {
+                return Err(std::io::Error::other("the file changed on disk; reload it or keep your version first"));
+            }
+            let bytes = self.encoded()?;
+            std::fs::write(path, &bytes)?;
+            self.saved(&bytes);
         } else {
             // Untitled — show save dialog
             if let Some(path) = rfd::FileDialog::new()
                 .set_file_name("untitled.txt")
                 .save_file()
             {
-                std::fs::write(&path, &self.content)?;
+                let bytes = self.encoded()?;
+                std::fs::write(&path, &bytes)?;
+                self.syntax = Syntax::for_path(&path);
+                self.highlight_cache = None;
                 self.file_path = Some(path);
-                self.modified = false;
+                self.saved(&bytes);
             }
         }
         Ok(())
     }
 
-    fn snapshot_undo(&mut self) {
-        if self.content != self.last_snapshot_content {
-            self.undo_stack.push(UndoEntry {
-                content: self.last_snapshot_content.clone(),
-                cursor: self.cursor,
+    /// The text as it will be written to disk
+    fn encoded(&self) -> Result<Vec<u8>, std::io::Error> {
+        crate::encoding::encode(&self.content.to_string(), self.encoding).map_err(std::io::Error::other)
+    }
+
+    /// Save, showing any error in the notice strip
+    fn save_reporting(&mut self) {
+        if let Err(e) = self.save() {
+            self.notice = Some(format!("Failed to save: {}", e));
+        }
+    }
+
+    fn saved(&mut self, bytes: &[u8]) {
+        self.disk_hash = Some(hash_bytes(bytes));
+        self.modified = false;
+        self.lsp_saved = true;
+        self.refresh_git_head();
+        self.history.mark_saved();
+        if let (true, Some(path)) = (self.persist_undo, &self.file_path) {
+            if let Err(e) = self.history.store(path, &self.content) {
+                eprintln!("Failed to store undo history: {}", e);
+            }
+        }
+    }
+
+    /// Keep undo history across sessions, restoring any stored for this file
+    pub fn enable_undo_persistence(&mut self) {
+        self.persist_undo = true;
+        if let Some(history) = self.file_path.as_ref().and_then(|p| UndoHistory::load(p, &self.content)) {
+            self.history = history;
+        }
+    }
+
+    /// Note that a formatter is running on the current text
+    pub fn start_formatting(&mut self, save: bool) {
+        self.formatting = Some((self.revision, save, Instant::now()));
+        self.notice = None;
+    }
+
+    pub fn is_formatting(&self) -> bool {
+        self.formatting.is_some()
+    }
+
+    /// Whether the formatter has been running longer than `format::TIMEOUT`
+    pub fn formatting_overdue(&self) -> bool {
+        self.formatting.is_some_and(|(_, _, at)| at.elapsed() > crate::format::TIMEOUT)
+    }
+
+    /// A formatter finished: apply its output unless the text changed in the meantime,
+    /// then save if that's what it ran for
+    pub fn finish_formatting(&mut self, result: Result<String, String>) {
+        let Some((revision, save, _)) = self.formatting.take() else { return };
+        match result {
+            Ok(formatted) if revision == self.revision => self.replace_text(&formatted),
+            Ok(_) => self.notice = Some("Not formatted: the file changed while the formatter ran".to_string()),
+            Err(e) => self.notice = Some(format!("Formatting failed: {}", e)),
+        }
+        if save {
+            self.save_reporting();
+            if std::mem::take(&mut self.quit_after_save) {
+                self.close_request = !self.modified;
+            }
+        }
+    }
+
+    /// The file changed on disk: reload it if there are no unsaved edits, otherwise
+    /// offer the choice in the conflict bar
+    pub fn disk_changed(&mut self) {
+        let Some(path) = &self.file_path else { return };
+        // Deleted or unreadable: keep the buffer, saving writes it back
+        let Ok(bytes) = std::fs::read(path) else { return };
+        let hash = hash_bytes(&bytes);
+        if Some(hash) == self.disk_hash {
+            return;
+        }
+        let (text, _) = crate::encoding::decode_as(&bytes, self.encoding.encoding);
+        if text == self.content.to_string() {
+            self.disk_hash = Some(hash);
+            self.conflict = None;
+        } else if self.modified {
+            self.conflict = Some((text, hash));
+        } else {
+            self.reload(&text, hash);
+        }
+    }
+
+    /// What to compare the buffer with: the file on disk or its last commit; an
+    /// error message if there's nothing to compare with
+    pub fn compare_text(&self, with: Compare) -> Result<String, String> {
+        let path = self.file_path.as_ref().ok_or("The file hasn't been saved yet")?;
+        match with {
+            Compare::Disk => {
+                if let Some((text, _)) = &self.conflict {
+                    return Ok(text.clone());
+                }
+                let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                Ok(crate::encoding::decode_as(&bytes, self.encoding.encoding).0)
+            }
+            Compare::Head => self.git_head.as_deref().map(str::to_string).ok_or_else(|| format!("{} isn't committed in a git repository", path.display())),
+        }
+    }
+
+    /// Read the file again, decoding it as `encoding`
+    fn reopen_with(&mut self, encoding: &'static encoding_rs::Encoding) {
+        let Some(path) = &self.file_path else { return };
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let (text, file_encoding) = crate::encoding::decode_as(&bytes, encoding);
+                self.reload(&text, hash_bytes(&bytes));
+                self.encoding = file_encoding;
+            }
+            Err(e) => self.notice = Some(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Take the text on disk (whose bytes hash to `hash`), as an undoable edit that
+    /// keeps cursors on unchanged lines
+    fn reload(&mut self, text: &str, hash: u64) {
+        self.replace_text(text);
+        self.modified = false;
+        self.history.mark_saved();
+        self.disk_hash = Some(hash);
+        self.conflict = None;
+    }
+
+    /// Replace the whole text as one undo step, touching only the lines that changed so
+    /// cursors stay put
+    fn replace_text(&mut self, formatted: &str) {
+        let old = self.content.to_string();
+        let edits = crate::format::minimal_edits(&old, formatted);
+        if edits.is_empty() {
+            return;
+        }
+        let map = |pos| crate::format::map_offset(&old, pos, &edits);
+        let selections: Vec<Selection> = self
+            .selections()
+            .iter()
+            .map(|s| Selection { cursor: map(s.cursor), anchor: s.anchor.map(map) })
+            .collect();
+        self.apply_edits(EditKind::Other, edits, |_| selections);
+    }
+
+    /// Select a byte range and bring it into view (e.g. a search hit)
+    pub fn reveal(&mut self, range: Range<usize>) {
+        self.set_selections(vec![Selection { cursor: range.end, anchor: Some(range.start) }]);
+    }
+
+    /// Make several replacements as one undo step, leaving the buffer unsaved
+    pub fn apply_replacements(&mut self, edits: Vec<(Range<usize>, String)>) {
+        self.apply_edits(EditKind::Other, edits, |ends| {
+            vec![Selection::caret(ends.last().copied().unwrap_or_default())]
+        });
+    }
+
+    fn selection(&self) -> Selection {
+        Selection { cursor: self.cursor, anchor: self.selection_anchor }
+    }
+
+    /// All cursors, primary first
+    fn selections(&self) -> Vec<Selection> {
+        std::iter::once(self.selection()).chain(self.extra_cursors.iter().copied()).collect()
+    }
+
+    /// Replace all cursors; the first becomes the primary. Overlapping selections merge.
+    fn set_selections(&mut self, selections: Vec<Selection>) {
+        let len = self.content.len_bytes();
+        let clamp = |s: Selection| Selection { cursor: s.cursor.min(len), anchor: s.anchor.map(|a| a.min(len)) };
+        let Some(primary) = selections.first().copied().map(clamp) else {
+            return;
+        };
+        let mut sorted: Vec<Selection> = selections.into_iter().map(clamp).collect();
+        sorted.sort_by_key(|s| (s.range().start, s.range().end));
+        let mut merged: Vec<Selection> = Vec::with_capacity(sorted.len());
+        for s in sorted {
+            match merged.last_mut() {
+                Some(last) if s.range().start < last.range().end || s.range() == last.range() => {
+                    let start = last.range().start;
+                    let end = last.range().end.max(s.range().end);
+                    *last = Selection { cursor: end, anchor: Some(start).filter(|&a| a != end) };
+                }
+                _ => merged.push(s),
+            }
+        }
+        let idx = merged
+            .iter()
+            .position(|s| s.range().start <= primary.cursor && primary.cursor <= s.range().end)
+            .unwrap_or(0);
+        let primary = merged.remove(idx);
+        self.cursor = primary.cursor;
+        self.selection_anchor = primary.anchor;
+        self.extra_cursors = merged;
+    }
+
+    /// Apply one edit per cursor as a single undo step. `f` gets each cursor in document
+    /// order with its index and returns the range to replace and the replacement;
+    /// afterwards each cursor sits after its inserted text.
+    fn edit_each(
+        &mut self,
+        kind: EditKind,
+        mut f: impl FnMut(&TextBuffer, usize, Selection) -> (Range<usize>, String),
+    ) {
+        self.edit_each_selecting(kind, |content, i, s| {
+            let (range, text) = f(content, i, s);
+            let caret = Selection::caret(text.len());
+            (range, text, caret)
+        });
+    }
+
+    /// Like `edit_each`, but `f` also places each cursor, as a selection in offsets
+    /// from the start of its inserted text
+    fn edit_each_selecting(
+        &mut self,
+        kind: EditKind,
+        mut f: impl FnMut(&TextBuffer, usize, Selection) -> (Range<usize>, String, Selection),
+    ) {
+        let before = self.selections();
+        let mut ordered = before.clone();
+        ordered.sort_by_key(|s| s.range().start);
+        let (edits, placed): (Vec<_>, Vec<_>) = ordered
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let (range, text, placed) = f(&self.content, i, *s);
+                let len = text.len();
+                ((range, text), (placed, len))
+            })
+            .unzip();
+        let primary = ordered.iter().position(|s| *s == before[0]).unwrap_or(0);
+        self.apply_edits(kind, edits, |ends| {
+            let mut selections: Vec<Selection> = ends
+                .iter()
+                .zip(&placed)
+                .map(|(&end, (s, len))| {
+                    let start = end - len;
+                    Selection { cursor: start + s.cursor, anchor: s.anchor.map(|a| start + a) }
+                })
+                .collect();
+            selections.swap(0, primary);
+            selections
+        });
+    }
+
+    /// Apply non-overlapping edits, given in document order, as a single undo step.
+    /// `select` gets the end of each replacement in the new text and returns the cursors.
+    fn apply_edits(
+        &mut self,
+        kind: EditKind,
+        mut edits: Vec<(Range<usize>, String)>,
+        select: impl FnOnce(&[usize]) -> Vec<Selection>,
+    ) {
+        // Keep edits from overlapping, e.g. backspace at two adjacent cursors
+        let mut prev_end = 0;
+        for (range, _) in &mut edits {
+            let start = range.start.max(prev_end);
+            *range = start..range.end.max(start);
+            prev_end = range.end;
+        }
+        if edits.iter().all(|(range, text)| range.is_empty() && text.is_empty()) {
+            return;
+        }
+        let before = self.selections();
+
+        // Apply back to front so the earlier offsets stay valid
+        let mut ops = Vec::with_capacity(edits.len());
+        for (range, text) in edits.iter().rev() {
+            if range.is_empty() && text.is_empty() {
+                continue;
+            }
+            ops.push(EditOp {
+                start: range.start,
+                deleted: self.content.slice(range.clone()),
+                inserted: text.clone(),
             });
-            self.last_snapshot_content = self.content.clone();
-            self.redo_stack.clear();
+            self.replace_range(range.clone(), text);
+        }
+
+        let mut shift = 0isize;
+        let mut ends = Vec::with_capacity(edits.len());
+        for (range, text) in &edits {
+            ends.push(range.start.saturating_add_signed(shift) + text.len());
+            shift += text.len() as isize - range.len() as isize;
         }
+        self.set_selections(select(&ends));
+
+        self.history.record(ops, kind, before, self.selections());
+        self.modified = true;
+        self.update_line_count();
     }
 
     fn undo(&mut self) {
-        if let Some(entry) = self.undo_stack.pop() {
-            self.redo_stack.push(UndoEntry {
-                content: self.content.clone(),
-                cursor: self.cursor,
-            });
-            self.content = entry.content.clone();
-            self.cursor = entry.cursor.min(self.content.len());
-            self.last_snapshot_content = self.content.clone();
+        if let Some(group) = self.history.undo() {
+            for op in group.ops.iter().rev() {
+                self.replace_range(op.start..op.start + op.inserted.len(), &op.deleted);
+            }
+            self.set_selections(group.before);
             self.update_line_count();
-            self.modified = true;
+            self.modified = !self.history.at_saved();
         }
     }
 
     fn redo(&mut self) {
-        if let Some(entry) = self.redo_stack.pop() {
-            self.undo_stack.push(UndoEntry {
-                content: self.content.clone(),
-                cursor: self.cursor,
-            });
-            self.content = entry.content.clone();
-            self.cursor = entry.cursor.min(self.content.len());
-            self.last_snapshot_content = self.content.clone();
+        if let Some(group) = self.history.redo() {
+            for op in &group.ops {
+                self.replace_range(op.start..op.start + op.deleted.len(), &op.inserted);
+            }
+            self.set_selections(group.after);
             self.update_line_count();
-            self.modified = true;
+            self.modified = !self.history.at_saved();
         }
     }
 
-    fn update_line_count(&mut self) {
-        self.line_count = self.content.lines().count().max(1);
-        if self.content.ends_with('\n') {
-            self.line_count += 1;
+    /// Replace `range` of the buffer with `text`. All edits go through here so the
+    /// syntax tree can be updated incrementally.
+    fn replace_range(&mut self, range: Range<usize>, text: &str) {
+        if self.lsp_attached {
+            let range = LspRange::of(&self.content, range.clone());
+            self.lsp_changes.push(ContentChange { range, text: text.to_string() });
         }
+        if let Some(syntax) = &mut self.syntax {
+            syntax.edit(&crate::syntax::input_edit(&self.content, range.clone(), text));
+        }
+        // Folds move along with edits before them; editing inside one unfolds it
+        let delta = text.len() as isize - range.len() as isize;
+        self.folds.retain_mut(|fold| {
+            if range.end <= fold.start {
+                *fold = fold.start.saturating_add_signed(delta)..fold.end.saturating_add_signed(delta);
+            }
+            range.end <= fold.start || range.start >= fold.end
+        });
+        self.content.replace(range, text);
+        self.revision += 1;
+    }
+
+    /// Highlight spans for a byte range, reusing the last result while nothing changed
+    fn highlights(&mut self, range: Range<usize>) -> &[HighlightSpan] {
+        let fresh = matches!(&self.highlight_cache, Some((rev, r, _)) if *rev == self.revision && *r == range);
+        if !fresh {
+            let spans = match &mut self.syntax {
+                Some(syntax) => syntax.highlights(&self.content, range.clone()),
+                None => Vec::new(),
+            };
+            self.highlight_cache = Some((self.revision, range, spans));
+        }
+        self.highlight_cache.as_ref().map(|(_, _, s)| s.as_slice()).unwrap_or(&[])
     }
 
-    fn cursor_line_col(&self) -> (usize, usize) {
-        let before = &self.content[..self.cursor.min(self.content.len())];
-        let line = before.matches('\n').count();
-        let col = before.rfind('\n').map(|p| self.cursor - p - 1).unwrap_or(self.cursor);
-        (line, col)
+    fn update_line_count(&mut self) {
+        self.line_count = self.content.line_count();
     }
 
     fn line_start(&self, line: usize) -> usize {
-        let mut offset = 0;
-        for (i, l) in self.content.split('\n').enumerate() {
-            if i == line {
-                return offset;
-            }
-            offset += l.len() + 1;
-        }
-        self.content.len()
+        self.content.line_start(line)
     }
 
     fn line_end(&self, line: usize) -> usize {
-        let start = self.line_start(line);
-        let rest = &self.content[start..];
-        start + rest.find('\n').unwrap_or(rest.len())
+        self.content.line_end(line)
     }
 
     fn total_lines(&self) -> usize {
-        self.content.split('\n').count()
-    }
-
-    fn delete_selection(&mut self) -> bool {
-        if let Some(anchor) = self.selection_anchor.take() {
-            let start = anchor.min(self.cursor);
-            let end = anchor.max(self.cursor);
-            let start = start.min(self.content.len());
-            let end = end.min(self.content.len());
-            self.snapshot_undo();
-            self.content.replace_range(start..end, "");
-            self.cursor = start;
-            self.modified = true;
-            self.update_line_count();
-            true
+        self.content.line_count()
+    }
+
+    /// Byte offset of a character column, clamped to the end of the line
+    fn line_col_to_byte(&self, line: usize, col: usize) -> usize {
+        let text = self.content.line(line);
+        let byte = text.char_indices().nth(col).map(|(i, _)| i).unwrap_or(text.len());
+        self.line_start(line) + byte
+    }
+
+    /// Line and character column of a byte offset
+    fn char_line_col(&self, pos: usize) -> (usize, usize) {
+        let line = self.content.line_of(pos);
+        let start = self.line_start(line);
+        (line, self.content.slice(start..pos).chars().count())
+    }
+
+    /// The word (letters, digits, `_`) touching `pos`; empty if there is none
+    fn word_range_at(&self, pos: usize) -> Range<usize> {
+        let line = self.content.line_of(pos);
+        let start = self.line_start(line);
+        let text = self.content.line(line);
+        let col = pos - start;
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let word_start = text[..col]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| is_word(*c))
+            .last()
+            .map(|(i, _)| i)
+            .unwrap_or(col);
+        let word_end = text[col..]
+            .char_indices()
+            .find(|(_, c)| !is_word(*c))
+            .map(|(i, _)| col + i)
+            .unwrap_or(text.len());
+        start + word_start..start + word_end
+    }
+
+    fn has_selection(&self) -> bool {
+        self.selections().iter().any(|s| !s.range().is_empty())
+    }
+
+    /// Text of every non-empty selection in document order, one per line
+    fn selected_text(&self) -> Option<String> {
+        let mut selections = self.selections();
+        selections.sort_by_key(|s| s.range().start);
+        let parts: Vec<String> = selections
+            .iter()
+            .filter(|s| !s.range().is_empty())
+            .map(|s| self.content.slice(s.range()))
+            .collect();
+        if parts.is_empty() {
+            None
         } else {
-            false
+            Some(parts.join("\n"))
         }
     }
 
-    fn selected_text(&self) -> Option<String> {
-        self.selection_anchor.map(|anchor| {
-            let start = anchor.min(self.cursor);
-            let end = anchor.max(self.cursor);
-            self.content[start.min(self.content.len())..end.min(self.content.len())].to_string()
-        })
+    fn delete_selection(&mut self) {
+        self.edit_each(EditKind::Other, |_, _, s| (s.range(), String::new()));
     }
 
+    /// Insert at every cursor, replacing selections
     fn insert_text(&mut self, text: &str) {
-        self.delete_selection();
-        self.snapshot_undo();
-        let pos = self.cursor.min(self.content.len());
-        self.content.insert_str(pos, text);
-        self.cursor = pos + text.len();
-        self.modified = true;
-        self.update_line_count();
+        let typed = text.chars().count() == 1 && text != "\n";
+        let kind = if typed { EditKind::Insert } else { EditKind::Other };
+        self.edit_each(kind, |_, _, s| (s.range(), text.to_string()));
+    }
+
+    /// Paste, giving each cursor its own line when there are as many lines as cursors
+    fn paste(&mut self, text: &str) {
+        let text = &text.replace("\r\n", "\n");
+        let lines: Vec<&str> = text.lines().collect();
+        if !self.extra_cursors.is_empty() && lines.len() == self.extra_cursors.len() + 1 {
+            self.edit_each(EditKind::Other, |_, i, s| (s.range(), lines[i].to_string()));
+        } else {
+            self.insert_text(text);
+        }
+    }
+
+    /// Backspace, or Delete when `forward`, at every cursor. Backspace takes an empty
+    /// bracket pair in one go, and spaces of indentation back to the previous stop.
+    fn delete_char(&mut self, forward: bool) {
+        let kind = if self.has_selection() { EditKind::Other } else { EditKind::Delete };
+        let unit = self.indent;
+        self.edit_each(kind, |content, _, s| {
+            let range = if !s.range().is_empty() {
+                s.range()
+            } else if forward {
+                s.cursor..content.next_char(s.cursor)
+            } else {
+                let prev = content.prev_char(s.cursor);
+                let line_start = content.line_start(content.line_of(s.cursor));
+                let before = content.slice(line_start..s.cursor);
+                let pair = content.slice(prev..content.next_char(s.cursor));
+                if ["()", "[]", "{}", "\"\"", "''", "``"].contains(&pair.as_str()) {
+                    prev..prev + 2
+                } else if before.len() > 1 && before.bytes().all(|b| b == b' ') {
+                    line_start + crate::indent::dedent(&before, unit).len()..s.cursor
+                } else {
+                    prev..s.cursor
+                }
+            };
+            (range, String::new())
+        });
+    }
+
+    fn lang(&self) -> Option<Lang> {
+        self.syntax.as_ref().map(|s| s.lang())
+    }
+
+    /// Type a character at every cursor. Brackets and quotes come in pairs (or wrap
+    /// the selection), a closing one that's already there is typed over, and a
+    /// closing bracket on a blank line goes back a level.
+    fn type_char(&mut self, c: char) {
+        let lang = self.lang();
+        let unit = self.indent;
+        let char_at = |content: &TextBuffer, at: usize| content.slice(at..content.next_char(at)).chars().next();
+        let overtypes = |content: &TextBuffer, s: Selection| {
+            s.range().is_empty() && matches!(c, ')' | ']' | '}' | '"' | '\'' | '`') && char_at(content, s.cursor) == Some(c)
+        };
+        if self.selections().iter().all(|s| overtypes(&self.content, *s)) {
+            self.move_cursor_right(false);
+            return;
+        }
+        self.edit_each_selecting(EditKind::Insert, |content, _, s| {
+            let range = s.range();
+            if overtypes(content, s) {
+                // Nothing to insert; just step over it
+                return (range.start..range.start, String::new(), Selection::caret(c.len_utf8()));
+            }
+            if let Some(close) = crate::indent::closing(c, lang) {
+                if !range.is_empty() {
+                    let inner = content.slice(range.clone());
+                    let selected = Selection { cursor: 1 + inner.len(), anchor: Some(1) };
+                    return (range, format!("{}{}{}", c, inner, close), selected);
+                }
+                let before = char_at(content, content.prev_char(range.start)).filter(|_| range.start > 0);
+                let after = char_at(content, range.end);
+                let word = |ch: Option<char>| ch.is_some_and(|ch| ch.is_alphanumeric() || ch == '_');
+                let free_after = after.is_none_or(|ch| ch.is_whitespace() || ")]},;:".contains(ch));
+                // Not for apostrophes in words, or a quote closing another
+                let quote_ok = close != c || !(word(before) || before == Some(c));
+                if free_after && quote_ok {
+                    return (range, format!("{}{}", c, close), Selection::caret(1));
+                }
+            }
+            if matches!(c, ')' | ']' | '}') {
+                let line_start = content.line_start(content.line_of(range.start));
+                let before = content.slice(line_start..range.start);
+                if !before.is_empty() && before.trim().is_empty() {
+                    let text = format!("{}{}", crate::indent::dedent(&before, unit), c);
+                    let caret = Selection::caret(text.len());
+                    return (line_start..range.end, text, caret);
+                }
+            }
+            let text = c.to_string();
+            let caret = Selection::caret(text.len());
+            (range, text, caret)
+        });
+    }
+
+    /// Enter: keep the line's indentation, a level deeper after an opening bracket
+    /// (or `:` in Python and YAML); between a pair of brackets the closing one gets
+    /// its own line
+    fn newline(&mut self) {
+        let lang = self.lang();
+        let unit = self.indent.text();
+        self.edit_each_selecting(EditKind::Other, |content, _, s| {
+            let range = s.range();
+            let line_start = content.line_start(content.line_of(range.start));
+            let before = content.slice(line_start..range.start);
+            let indent = crate::indent::leading(&before);
+            let mut text = format!("\n{}", indent);
+            if crate::indent::opens_block(lang, &before) {
+                text.push_str(&unit);
+                let after = content.slice(range.end..content.line_end(content.line_of(range.end)));
+                let after = after.trim_start();
+                if after.starts_with([')', ']', '}']) || (lang == Some(Lang::Html) && after.starts_with("</")) {
+                    let caret = Selection::caret(text.len());
+                    return (range, format!("{}\n{}", text, indent), caret);
+                }
+            }
+            let caret = Selection::caret(text.len());
+            (range, text, caret)
+        });
+    }
+
+    /// Tab: indent the lines of a selection spanning lines, else insert indentation
+    /// up to the next stop
+    fn tab(&mut self) {
+        let multiline = self
+            .selections()
+            .iter()
+            .any(|s| self.content.line_of(s.range().start) != self.content.line_of(s.range().end));
+        if multiline {
+            self.shift_lines(true);
+            return;
+        }
+        let unit = self.indent;
+        self.edit_each(EditKind::Other, |content, _, s| {
+            let text = match unit {
+                IndentUnit::Tabs => "\t".to_string(),
+                IndentUnit::Spaces(n) => {
+                    let line_start = content.line_start(content.line_of(s.range().start));
+                    let col = wrap::width(&content.slice(line_start..s.range().start));
+                    " ".repeat(n - col % n.max(1))
+                }
+            };
+            (s.range(), text)
+        });
+    }
+
+    /// Indent, or dedent, every line with a cursor or selection on it
+    fn shift_lines(&mut self, deeper: bool) {
+        let unit = self.indent;
+        let mut lines: Vec<usize> = self
+            .selections()
+            .iter()
+            .flat_map(|s| {
+                let range = s.range();
+                let first = self.content.line_of(range.start);
+                let mut last = self.content.line_of(range.end);
+                // A selection ending at the start of a line leaves that line alone
+                if last > first && self.line_start(last) == range.end {
+                    last -= 1;
+                }
+                first..=last
+            })
+            .collect();
+        lines.sort_unstable();
+        lines.dedup();
+        let edits: Vec<(Range<usize>, String)> = lines
+            .into_iter()
+            .filter_map(|line| {
+                let start = self.line_start(line);
+                let text = self.content.line(line);
+                let lead = crate::indent::leading(&text);
+                if deeper {
+                    (!text.trim().is_empty()).then(|| (start..start, unit.text()))
+                } else {
+                    let kept = crate::indent::dedent(lead, unit).len();
+                    (kept < lead.len()).then(|| (start + kept..start + lead.len(), String::new()))
+                }
+            })
+            .collect();
+        // Edits are all at line starts, so offsets just shift by what's before them
+        let map = |pos: usize| {
+            let mut shift = 0isize;
+            for (range, text) in edits.iter().take_while(|(range, _)| range.start <= pos) {
+                shift += text.len() as isize - (range.end.min(pos) - range.start) as isize;
+            }
+            pos.saturating_add_signed(shift)
+        };
+        let selections: Vec<Selection> = self
+            .selections()
+            .iter()
+            .map(|s| Selection { cursor: map(s.cursor), anchor: s.anchor.map(map) })
+            .collect();
+        self.apply_edits(EditKind::Other, edits, |_| selections);
+    }
+
+    /// Move every cursor with `f`, extending the selections while `shift` is held
+    fn move_cursors(&mut self, shift: bool, f: impl Fn(&Self, usize) -> usize) {
+        let moved = self
+            .selections()
+            .into_iter()
+            .map(|s| Selection {
+                cursor: self.skip_folds(s.cursor, f(self, s.cursor)),
+                anchor: if shift { Some(s.anchor.unwrap_or(s.cursor)) } else { None },
+            })
+            .collect();
+        self.set_selections(moved);
     }
 
     fn move_cursor_left(&mut self, shift: bool) {
-        if !shift {
-            self.selection_anchor = None;
-        } else if self.selection_anchor.is_none() {
-            self.selection_anchor = Some(self.cursor);
+        self.move_cursors(shift, |ed, c| ed.content.prev_char(c));
+    }
+
+    fn move_cursor_right(&mut self, shift: bool) {
+        self.move_cursors(shift, |ed, c| ed.content.next_char(c));
+    }
+
+    /// Up and down move by visual row, keeping the on-screen column
+    fn move_cursor_up(&mut self, shift: bool) {
+        self.update_layout(None);
+        self.move_cursors(shift, |ed, c| match ed.row_col(c) {
+            (0, _) => c,
+            (row, col) => ed.row_col_to_byte(row - 1, col),
+        });
+    }
+
+    fn move_cursor_down(&mut self, shift: bool) {
+        self.update_layout(None);
+        self.move_cursors(shift, |ed, c| {
+            let (row, col) = ed.row_col(c);
+            if row + 1 < ed.row_count() {
+                ed.row_col_to_byte(row + 1, col)
+            } else {
+                c
+            }
+        });
+    }
+
+    /// Lay out the visual rows again if the text, the width (in columns) or the folds
+    /// changed; `None` keeps the last width
+    fn update_layout(&mut self, cols: Option<usize>) {
+        if let Some(cols) = cols {
+            self.wrap_cols = cols;
+        }
+        let hidden = self.hidden_lines();
+        if !self.soft_wrap && hidden.is_empty() {
+            self.layout = None;
+            return;
+        }
+        let cols = self.soft_wrap.then_some(self.wrap_cols);
+        let fresh = self
+            .layout
+            .as_ref()
+            .is_some_and(|l| l.cols == cols && l.revision == self.revision && l.hidden == hidden);
+        if !fresh {
+            self.layout = Some(RowLayout::new(&self.content, cols, hidden, self.revision));
+        }
+    }
+
+    /// Visual rows: one per line, more when soft wrap breaks lines up, fewer when
+    /// lines are folded away
+    fn row_count(&self) -> usize {
+        self.layout.as_ref().map(|l| l.row_count()).unwrap_or_else(|| self.total_lines())
+    }
+
+    /// Line shown on a visual row, and the part of the buffer the row shows
+    fn row_span(&self, row: usize) -> (usize, Range<usize>) {
+        match &self.layout {
+            Some(layout) => {
+                let (line, start) = layout.row(row);
+                let line_start = self.line_start(line);
+                let end = layout.row_end(row).map(|end| line_start + end).unwrap_or_else(|| self.line_end(line));
+                (line, line_start + start..end)
+            }
+            None => {
+                let line = row.min(self.total_lines().saturating_sub(1));
+                (line, self.line_start(line)..self.line_end(line))
+            }
+        }
+    }
+
+    /// Visual row and on-screen column of a byte offset
+    fn row_col(&self, pos: usize) -> (usize, usize) {
+        let line = self.content.line_of(pos);
+        let row = match &self.layout {
+            Some(layout) => layout.row_of(line, pos - self.line_start(line)),
+            None => line,
+        };
+        let (_, span) = self.row_span(row);
+        (row, wrap::width(&self.content.slice(span.start..pos)))
+    }
+
+    /// Byte offset nearest to an on-screen column of a visual row
+    fn row_col_to_byte(&self, row: usize, col: usize) -> usize {
+        let (line, span) = self.row_span(row);
+        let at = span.start + wrap::byte_at_col(&self.content.slice(span.clone()), col);
+        // The end of a wrapped row is the start of the next one; stay on this row
+        if at == span.end && at != self.line_end(line) {
+            self.content.prev_char(at)
+        } else {
+            at
+        }
+    }
+
+    /// Fetch the committed version again, as it changes with commits and checkouts
+    fn refresh_git_head(&mut self) {
+        if let Some(path) = &self.file_path {
+            self.git_job = Some(HeadJob::start(path));
+        }
+    }
+
+    /// Lines changed since the last commit, as of the latest finished diff. Diffing
+    /// runs in the background, one revision at a time, so typing never waits on it.
+    fn git_hunks(&mut self) -> &[Hunk] {
+        let Some(head) = &self.git_head else { return &[] };
+        if let Some(job) = &self.hunks_job {
+            if let Some(hunks) = job.poll() {
+                self.git_hunks = Some((job.revision, hunks));
+                self.hunks_job = None;
+            }
+        }
+        if self.hunks_job.is_none() && !self.hunks_current() {
+            self.hunks_job = Some(HunksJob::start(self.revision, head.clone(), self.content.clone()));
+        }
+        self.git_hunks.as_ref().map(|(_, hunks)| hunks.as_slice()).unwrap_or_default()
+    }
+
+    /// Whether `git_hunks` describes the text as it is now
+    fn hunks_current(&self) -> bool {
+        self.git_hunks.as_ref().is_some_and(|(revision, _)| *revision == self.revision)
+    }
+
+    /// Put a hunk's lines back the way they were committed
+    fn revert_hunk(&mut self, hunk: &Hunk) {
+        let start = self.line_start(hunk.new.start);
+        let end = if hunk.new.end < self.total_lines() { self.line_start(hunk.new.end) } else { self.content.len_bytes() };
+        self.apply_replacements(vec![(start..end, hunk.old_text.clone())]);
+    }
+
+    /// The committed lines of the hunk clicked in the gutter, with a button to revert
+    fn render_hunk_popup(&mut self, ui: &mut egui::Ui, pos: egui::Pos2) {
+        let Some(lines) = self.hunk_popup.clone() else { return };
+        let Some(hunk) = self.git_hunks().iter().find(|h| h.new == lines).cloned() else {
+            // Edited away
+            self.hunk_popup = None;
+            return;
+        };
+        let mut close = false;
+        egui::Area::new(egui::Id::new(("hunk_popup", self.id)))
+            .order(egui::Order::Foreground)
+            .fixed_pos(pos)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.set_max_width(560.0);
+                    ui.horizontal(|ui| {
+                        let title = match hunk.kind() {
+                            HunkKind::Added => "Added lines",
+                            HunkKind::Modified => "Changed lines — committed version:",
+                            HunkKind::Deleted => "Deleted lines:",
+                        };
+                        ui.label(egui::RichText::new(title).strong());
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            close |= ui.small_button("✕").clicked();
+                            // Reverting lines from an older revision would hit the wrong text
+                            let revert = ui.add_enabled(self.hunks_current(), egui::Button::new("Revert").small());
+                            if revert.clicked() {
+                                self.revert_hunk(&hunk);
+                                close = true;
+                            }
+                        });
+                    });
+                    if !hunk.old_text.is_empty() {
+                        let mono = FontId::monospace(12.0);
+                        egui::ScrollArea::vertical().max_height(240.0).show(ui, |ui| {
+                            for line in hunk.old_text.lines() {
+                                ui.label(egui::RichText::new(format!("- {}", line)).font(mono.clone()).color(crate::theme::ERROR));
+                            }
+                        });
+                    }
+                });
+            });
+        if close || ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+            self.hunk_popup = None;
         }
-        if self.cursor > 0 {
-            // Move back one char properly
-            let prev = self.content[..self.cursor]
-                .char_indices()
-                .last()
-                .map(|(i, _)| i)
-                .unwrap_or(0);
-            self.cursor = prev;
+    }
+
+    /// Lines hidden by folds, as sorted ranges that don't overlap
+    fn hidden_lines(&self) -> Vec<Range<usize>> {
+        let mut hidden: Vec<Range<usize>> = self
+            .folds
+            .iter()
+            .map(|f| self.content.line_of(f.start) + 1..self.content.line_of(f.end) + 1)
+            .collect();
+        hidden.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(hidden.len());
+        for range in hidden {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    /// Foldable regions as (first line, last line), from the syntax tree where there
+    /// is one, else from indentation
+    fn fold_ranges(&mut self) -> &[(usize, usize)] {
+        if self.fold_ranges.as_ref().is_none_or(|(revision, _)| *revision != self.revision) {
+            let nodes = self.syntax.as_mut().and_then(|s| s.multiline_nodes(&self.content));
+            let ranges = match nodes {
+                Some(nodes) => crate::fold::from_nodes(&self.content, nodes),
+                None => crate::fold::indent_ranges(&self.content),
+            };
+            self.fold_ranges = Some((self.revision, ranges));
+        }
+        self.fold_ranges.as_ref().map(|(_, ranges)| ranges.as_slice()).unwrap_or_default()
+    }
+
+    /// The fold whose first line is `line`, if it's folded
+    fn fold_at(&self, line: usize) -> Option<usize> {
+        self.folds.iter().position(|f| self.content.line_of(f.start) == line)
+    }
+
+    /// Fold the region starting on `line`, or else the innermost one around it
+    fn fold(&mut self, line: usize) {
+        let region = self
+            .fold_ranges()
+            .iter()
+            .filter(|(start, last)| (*start..=*last).contains(&line))
+            .max_by_key(|(start, _)| *start)
+            .copied();
+        if let Some((start, last)) = region {
+            if self.fold_at(start).is_none() {
+                self.folds.push(self.line_end(start)..self.line_end(last));
+                self.leave_folds();
+            }
+        }
+    }
+
+    /// Unfold the fold starting on `line`, or else the folds around it
+    fn unfold(&mut self, line: usize) {
+        match self.fold_at(line) {
+            Some(i) => {
+                self.folds.remove(i);
+            }
+            None => {
+                let at = self.line_start(line);
+                self.folds.retain(|f| !(f.start < at && at <= f.end));
+            }
+        }
+    }
+
+    fn fold_all(&mut self) {
+        let ranges = self.fold_ranges().to_vec();
+        self.folds = ranges.into_iter().map(|(start, last)| self.line_end(start)..self.line_end(last)).collect();
+        self.leave_folds();
+    }
+
+    /// Move cursors out of folded text, to the end of the fold's first line
+    fn leave_folds(&mut self) {
+        let outside = |pos: usize| {
+            self.folds.iter().filter(|f| f.start < pos && pos <= f.end).map(|f| f.start).min().unwrap_or(pos)
+        };
+        let selections = self
+            .selections()
+            .iter()
+            .map(|s| Selection { cursor: outside(s.cursor), anchor: s.anchor.map(outside) })
+            .collect();
+        self.set_selections(selections);
+    }
+
+    /// Where a cursor moving from `from` to `to` ends up: over any fold it lands in,
+    /// to the line after going forwards or the fold's first line going backwards
+    fn skip_folds(&self, from: usize, to: usize) -> usize {
+        match self.folds.iter().find(|f| f.start < to && to <= f.end) {
+            Some(fold) if to > from && fold.end < self.content.len_bytes() => {
+                self.skip_folds(from, fold.end + 1)
+            }
+            Some(fold) => self.skip_folds(from, fold.start),
+            None => to,
+        }
+    }
+
+    /// Line and character column at an on-screen position, past the end of the row
+    /// if need be (for column selection)
+    fn row_col_to_line_col(&self, row: usize, col: usize) -> (usize, usize) {
+        let (line, span) = self.row_span(row);
+        let before = self.content.slice(self.line_start(line)..span.start).chars().count();
+        let text = self.content.slice(span);
+        let cols = wrap::width(&text);
+        let chars = if col <= cols {
+            text[..wrap::byte_at_col(&text, col)].chars().count()
+        } else {
+            text.chars().count() + col - cols
+        };
+        (line, before + chars)
+    }
+
+    /// Cmd+D: select the word at the cursor, or add a cursor at the next occurrence
+    /// of the primary selection
+    fn add_next_occurrence(&mut self) {
+        let range = self.selection().range();
+        if range.is_empty() {
+            let word = self.word_range_at(self.cursor);
+            if !word.is_empty() {
+                self.set_selections(vec![Selection { cursor: word.end, anchor: Some(word.start) }]);
+            }
+            return;
+        }
+        let needle = self.content.slice(range.clone());
+        let Ok(re) = regex::Regex::new(&regex::escape(&needle)) else { return };
+        let taken: Vec<usize> = self.selections().iter().map(|s| s.range().start).collect();
+        let after = self.content.find_iter(&re, range.end..self.content.len_bytes());
+        let wrapped = self.content.find_iter(&re, 0..range.end);
+        if let Some(found) = after.chain(wrapped).find(|m| !taken.contains(&m.start)) {
+            let mut selections = vec![Selection { cursor: found.end, anchor: Some(found.start) }];
+            selections.extend(self.selections());
+            self.set_selections(selections);
+        }
+    }
+
+    /// Cmd+Shift+L: a cursor on every occurrence of the primary selection (or word)
+    fn select_all_occurrences(&mut self) {
+        let mut range = self.selection().range();
+        if range.is_empty() {
+            range = self.word_range_at(self.cursor);
+            if range.is_empty() {
+                return;
+            }
+        }
+        let needle = self.content.slice(range.clone());
+        let Ok(re) = regex::Regex::new(&regex::escape(&needle)) else { return };
+        let mut selections: Vec<Selection> = self
+            .content
+            .find_iter(&re, 0..self.content.len_bytes())
+            .map(|m| Selection { cursor: m.end, anchor: Some(m.start) })
+            .collect();
+        if let Some(primary) = selections.iter().position(|s| s.range() == range) {
+            selections.swap(0, primary);
+        }
+        self.set_selections(selections);
+    }
+
+    /// Alt-drag: one selection per line between `from` and `to`, spanning the same
+    /// character columns. The cursor on the line under the pointer is the primary.
+    fn column_select(&mut self, from: (usize, usize), to: (usize, usize)) {
+        let (top, bottom) = (from.0.min(to.0), from.0.max(to.0));
+        let mut selections: Vec<Selection> = (top..=bottom)
+            .map(|line| Selection {
+                cursor: self.line_col_to_byte(line, to.1),
+                anchor: Some(self.line_col_to_byte(line, from.1)),
+            })
+            .collect();
+        selections.swap(0, to.0 - top);
+        self.set_selections(selections);
+    }
+
+    /// Edits made since the last call, for `textDocument/didChange`
+    pub fn take_lsp_changes(&mut self) -> Vec<ContentChange> {
+        std::mem::take(&mut self.lsp_changes)
+    }
+
+    /// Start of the identifier ending at `pos`
+    fn word_start(&self, pos: usize) -> usize {
+        let mut start = pos;
+        while start > 0 {
+            let prev = self.content.prev_char(start);
+            let c = self.content.slice(prev..start);
+            if !c.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            start = prev;
+        }
+        start
+    }
+
+    /// Completions arrived for a request made at `at`
+    pub fn show_completions(&mut self, at: usize, items: Vec<CompletionItem>) {
+        let start = self.word_start(at);
+        // Ignore a late reply once the cursor has left the word
+        if items.is_empty() || self.cursor < start || self.word_start(self.cursor) != start {
+            return;
+        }
+        self.completion = Some(CompletionPopup { items, selected: 0, start });
+        self.update_completion();
+    }
+
+    /// Hover text arrived for a request made at `at`
+    pub fn show_hover(&mut self, at: usize, text: String) {
+        if self.hover_probe.is_some_and(|(probe, _, _)| probe == at) {
+            // Markdown code fences add nothing in a plain tooltip
+            let text: Vec<&str> = text.lines().filter(|l| !l.trim_start().starts_with("```")).collect();
+            self.hover = Some((at, text.join("\n").trim().to_string()));
+        }
+    }
+
+    /// Indices of the completion items matching what has been typed so far
+    fn completion_matches(&self) -> Vec<usize> {
+        let Some(popup) = &self.completion else { return Vec::new() };
+        let typed = self.content.slice(popup.start..self.cursor.max(popup.start)).to_lowercase();
+        (0..popup.items.len()).filter(|&i| popup.items[i].label.to_lowercase().starts_with(&typed)).collect()
+    }
+
+    /// Close the completion popup once the cursor leaves its word or nothing matches
+    fn update_completion(&mut self) {
+        let Some(popup) = &self.completion else { return };
+        let matches = self.completion_matches();
+        if self.cursor < popup.start || self.word_start(self.cursor) != popup.start || matches.is_empty() {
+            self.completion = None;
+        } else if let Some(popup) = &mut self.completion {
+            if !matches.contains(&popup.selected) {
+                popup.selected = matches[0];
+            }
+        }
+    }
+
+    fn accept_completion(&mut self, index: usize) {
+        let Some(popup) = self.completion.take() else { return };
+        let Some(item) = popup.items.get(index) else { return };
+        let edit = (popup.start..self.cursor.max(popup.start), item.insert_text.clone());
+        self.apply_edits(EditKind::Other, vec![edit], |ends| vec![Selection::caret(ends[0])]);
+    }
+
+    /// Up/Down/Enter/Tab/Escape while the completion popup is open
+    fn completion_key(&mut self, key: egui::Key) {
+        let matches = self.completion_matches();
+        if matches.is_empty() {
+            self.completion = None;
+            return;
+        }
+        let Some(popup) = &mut self.completion else { return };
+        let row = matches.iter().position(|&i| i == popup.selected).unwrap_or(0);
+        match key {
+            egui::Key::ArrowUp => popup.selected = matches[(row + matches.len() - 1) % matches.len()],
+            egui::Key::ArrowDown => popup.selected = matches[(row + 1) % matches.len()],
+            egui::Key::Enter | egui::Key::Tab => self.accept_completion(matches[row]),
+            _ => self.completion = None,
+        }
+    }
+
+    /// Open the find bar, seeding the query from a single-line selection
+    fn open_find(&mut self, replace: bool) {
+        let selected = self.content.slice(self.selection().range());
+        if !selected.is_empty() && !selected.contains('\n') {
+            self.find.query = selected;
+        }
+        self.find.open = true;
+        self.find.replace_open |= replace;
+        self.find.focus_query = true;
+        self.find.revision = None;
+    }
+
+    fn close_find(&mut self) {
+        self.find.open = false;
+        self.find.scope = None;
+        self.find.matches.clear();
+        self.grab_focus = true;
+    }
+
+    /// Search again if the text changed since the matches were found
+    fn refresh_find(&mut self) {
+        if self.find.open && self.find.revision != Some(self.revision) {
+            self.find.update(&self.content, self.revision);
+        }
+    }
+
+    /// Select match `index` and make it the current one
+    fn select_match(&mut self, index: usize) {
+        if let Some(m) = self.find.matches.get(index).cloned() {
+            self.find.current = index;
+            self.set_selections(vec![Selection { cursor: m.end, anchor: Some(m.start) }]);
+        }
+    }
+
+    /// Step to the next (or previous) match from the selection, wrapping around
+    fn find_next(&mut self, backwards: bool) {
+        self.refresh_find();
+        let count = self.find.matches.len();
+        if count == 0 {
+            return;
+        }
+        let sel = self.selection().range();
+        let index = match self.find.matches.iter().position(|m| *m == sel) {
+            Some(i) if backwards => (i + count - 1) % count,
+            Some(i) => (i + 1) % count,
+            None if backwards => self.find.matches.iter().rposition(|m| m.start < sel.start).unwrap_or(count - 1),
+            None => self.find.next_from(sel.start),
+        };
+        self.select_match(index);
+    }
+
+    /// Replace the current match if it's selected, then select the next one
+    fn replace_one(&mut self) {
+        self.refresh_find();
+        let sel = self.selection().range();
+        if !self.find.matches.contains(&sel) {
+            self.find_next(false);
+            return;
+        }
+        let replacement = self.find.replacements(&self.content, std::slice::from_ref(&sel));
+        let replacement = replacement.into_iter().next().unwrap_or_default();
+        self.shift_find_scope(replacement.len() as isize - sel.len() as isize);
+        self.apply_edits(EditKind::Other, vec![(sel, replacement)], |ends| vec![Selection::caret(ends[0])]);
+        self.refresh_find();
+        let next = self.find.next_from(self.cursor);
+        self.select_match(next);
+    }
+
+    /// Replace every match as a single undo step
+    fn replace_all(&mut self) {
+        self.refresh_find();
+        if self.find.matches.is_empty() {
+            return;
+        }
+        let matches = std::mem::take(&mut self.find.matches);
+        let replacements = self.find.replacements(&self.content, &matches);
+        let delta: isize = matches
+            .iter()
+            .zip(&replacements)
+            .map(|(m, r)| r.len() as isize - m.len() as isize)
+            .sum();
+        self.shift_find_scope(delta);
+        let edits = matches.into_iter().zip(replacements).collect();
+        self.apply_edits(EditKind::Other, edits, |ends| {
+            vec![Selection::caret(ends.last().copied().unwrap_or_default())]
+        });
+    }
+
+    /// Keep the in-selection scope covering the same text after replacing inside it
+    fn shift_find_scope(&mut self, delta: isize) {
+        if let Some(scope) = &mut self.find.scope {
+            scope.end = scope.end.saturating_add_signed(delta);
+        }
+    }
+
+    /// Find field, option toggles and match count, with the replace row below
+    fn render_find_bar(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_ELEVATED);
+        let row_height = rect.height() / if self.find.replace_open { 2.0 } else { 1.0 };
+        let row = |i: f32| {
+            Rect::from_min_size(rect.left_top() + egui::vec2(8.0, i * row_height), egui::vec2(rect.width() - 16.0, row_height))
+        };
+        let field_font = FontId::proportional(13.0);
+        let query_id = egui::Id::new(("editor_find_query", self.id));
+        let mut changed = false;
+        let (mut step, mut close, mut replace_one, mut replace_all) = (None, false, false, false);
+
+        let mut find_ui = ui.new_child(egui::UiBuilder::new().max_rect(row(0.0)).layout(egui::Layout::left_to_right(egui::Align::Center)));
+        let replace_toggle = find_ui
+            .selectable_label(self.find.replace_open, "Replace")
+            .on_hover_text("Toggle replace (Cmd+Alt+F)");
+        if replace_toggle.clicked() {
+            self.find.replace_open = !self.find.replace_open;
+        }
+        let query = find_ui.add(
+            egui::TextEdit::singleline(&mut self.find.query)
+                .id(query_id)
+                .hint_text("Find")
+                .font(field_font.clone())
+                .desired_width(220.0),
+        );
+        if std::mem::take(&mut self.find.focus_query) {
+            query.request_focus();
+        }
+        changed |= query.changed();
+        if query.lost_focus() {
+            let (enter, shift, escape) =
+                find_ui.input(|i| (i.key_pressed(egui::Key::Enter), i.modifiers.shift, i.key_pressed(egui::Key::Escape)));
+            if enter {
+                step = Some(shift);
+                query.request_focus();
+            }
+            close |= escape;
+        }
+        for (value, label, hint) in [
+            (&mut self.find.case_sensitive, "Aa", "Match case"),
+            (&mut self.find.whole_word, "ab", "Match whole word"),
+            (&mut self.find.regex, ".*", "Use regular expression"),
+        ] {
+            if find_ui.selectable_label(*value, label).on_hover_text(hint).clicked() {
+                *value = !*value;
+                changed = true;
+            }
+        }
+        let in_selection = self.find.scope.is_some();
+        if find_ui.selectable_label(in_selection, "Sel").on_hover_text("Find in selection").clicked() {
+            let sel = self.selection().range();
+            self.find.scope = (!in_selection && !sel.is_empty()).then_some(sel);
+            changed = true;
+        }
+        if let Some(error) = &self.find.error {
+            find_ui.colored_label(crate::theme::ERROR, error);
+        } else if !self.find.query.is_empty() {
+            let info = match self.find.matches.len() {
+                0 => "No results".to_string(),
+                n => format!("{}/{}", self.find.current + 1, n),
+            };
+            find_ui.label(egui::RichText::new(info).size(12.0).color(crate::theme::TEXT_SECONDARY));
+        }
+        find_ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            close |= ui.button("×").on_hover_text("Close (Escape)").clicked();
+            if ui.button("↓").on_hover_text("Next match (Enter)").clicked() {
+                step = Some(false);
+            }
+            if ui.button("↑").on_hover_text("Previous match (Shift+Enter)").clicked() {
+                step = Some(true);
+            }
+        });
+
+        if self.find.replace_open {
+            let mut replace_ui =
+                ui.new_child(egui::UiBuilder::new().max_rect(row(1.0)).layout(egui::Layout::left_to_right(egui::Align::Center)));
+            replace_ui.add_space(replace_toggle.rect.width() + replace_ui.spacing().item_spacing.x);
+            let replacement = replace_ui.add(
+                egui::TextEdit::singleline(&mut self.find.replacement)
+                    .id(egui::Id::new(("editor_find_replacement", self.id)))
+                    .hint_text(if self.find.regex { "Replace ($1 for groups)" } else { "Replace" })
+                    .font(field_font)
+                    .desired_width(220.0),
+            );
+            if replacement.lost_focus() {
+                let (enter, escape) =
+                    replace_ui.input(|i| (i.key_pressed(egui::Key::Enter), i.key_pressed(egui::Key::Escape)));
+                if enter {
+                    replace_one = true;
+                    replacement.request_focus();
+                }
+                close |= escape;
+            }
+            replace_one |= replace_ui.button("Replace").on_hover_text("Replace this match (Enter)").clicked();
+            replace_all |= replace_ui.button("All").on_hover_text("Replace all matches").clicked();
+        }
+
+        if changed {
+            self.find.revision = None;
+            self.refresh_find();
+            let next = self.find.next_from(self.selection().range().start);
+            self.select_match(next);
+        }
+        if let Some(backwards) = step {
+            self.find_next(backwards);
+        }
+        if replace_one {
+            self.replace_one();
+        }
+        if replace_all {
+            self.replace_all();
+        }
+        if close {
+            self.close_find();
+        }
+    }
+
+    /// Bar offered when the file changed on disk under unsaved edits
+    fn render_conflict(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_ELEVATED);
+        let mut child = ui.new_child(
+            egui::UiBuilder::new()
+                .max_rect(rect.shrink2(egui::vec2(6.0, 2.0)))
+                .layout(egui::Layout::left_to_right(egui::Align::Center)),
+        );
+        child.label(
+            egui::RichText::new("The file changed on disk.").size(12.0).color(crate::theme::WARNING),
+        );
+        if child.small_button("Reload").on_hover_text("Discard your changes and load the file").clicked() {
+            if let Some((text, hash)) = self.conflict.take() {
+                self.reload(&text, hash);
+            }
+        }
+        if child.small_button("Keep Mine").on_hover_text("Keep your changes; saving overwrites the file").clicked() {
+            if let Some((_, hash)) = self.conflict.take() {
+                self.disk_hash = Some(hash);
+            }
+        }
+        if child.small_button("Diff").on_hover_text("Compare your changes with the file on disk").clicked() {
+            self.diff_request = Some(Compare::Disk);
+        }
+    }
+
+    // Vim mode
+
+    fn vim_mode(&self) -> Option<Mode> {
+        self.vim.as_ref().map(|v| v.mode)
+    }
+
+    fn set_vim_mode(&mut self, mode: Mode) {
+        if let Some(vim) = &mut self.vim {
+            vim.mode = mode;
+        }
+    }
+
+    /// Whether keys run Vim commands rather than type: outside insert mode, or while
+    /// the command line is open
+    fn vim_takes_keys(&self) -> bool {
+        self.vim.as_ref().is_some_and(|v| v.mode != Mode::Insert || v.command_line.is_some())
+    }
+
+    fn vim_message(&mut self, message: String) {
+        if let Some(vim) = &mut self.vim {
+            vim.message = Some(message);
+        }
+    }
+
+    /// Note a key typed in insert mode, for `.`
+    fn vim_record(&mut self, key: VimKey) {
+        if let Some(vim) = self.vim.as_mut().filter(|v| v.mode == Mode::Insert) {
+            vim.record(key);
+        }
+    }
+
+    fn set_cursor(&mut self, pos: usize) {
+        self.set_selections(vec![Selection::caret(pos)]);
+    }
+
+    /// Replace `range` as one edit, leaving the cursor at `cursor`
+    fn vim_replace(&mut self, range: Range<usize>, text: &str, cursor: usize) {
+        self.apply_edits(EditKind::Other, vec![(range, text.to_string())], |_| vec![Selection::caret(cursor)]);
+    }
+
+    /// Outside insert mode the cursor sits on a character, not after the last one
+    fn vim_clamp(&mut self) {
+        if matches!(self.vim_mode(), Some(Mode::Normal | Mode::Visual | Mode::VisualLine)) {
+            let start = self.line_start(self.content.line_of(self.cursor));
+            if self.cursor > start && self.cursor == self.line_end(self.content.line_of(self.cursor)) {
+                self.cursor = self.content.prev_char(self.cursor);
+            }
+        }
+    }
+
+    /// Catch up with the mouse: a selection made with it becomes a visual one
+    fn vim_sync(&mut self) {
+        let Some(vim) = &mut self.vim else { return };
+        if vim.mode != Mode::Insert {
+            if let Some(anchor) = self.selection_anchor.take() {
+                if vim.mode == Mode::Normal {
+                    vim.mode = Mode::Visual;
+                }
+                vim.visual_anchor = anchor;
+            }
+        }
+        self.vim_clamp();
+    }
+
+    /// Handle a key: insert mode types it, the other modes build commands from it
+    fn vim_key(&mut self, key: VimKey) {
+        let Some(vim) = &mut self.vim else { return };
+        vim.message = None;
+        if vim.command_line.is_some() {
+            self.vim_command_line_key(key);
+        } else if vim.mode == Mode::Insert {
+            // Only `.` sends insert-mode keys here; typed ones go through the usual handling
+            match key {
+                VimKey::Esc => self.vim_leave_insert(),
+                VimKey::Char(c) => self.type_char(c),
+                VimKey::Enter => self.newline(),
+                VimKey::Backspace => self.delete_char(false),
+                VimKey::Delete => self.delete_char(true),
+                VimKey::Tab => self.tab(),
+                _ => {}
+            }
+        } else if let Some(command) = vim.key(key) {
+            self.vim_execute(command);
+        }
+        self.vim_clamp();
+    }
+
+    fn vim_command_line_key(&mut self, key: VimKey) {
+        let Some(vim) = &mut self.vim else { return };
+        let Some(line) = &mut vim.command_line else { return };
+        match key {
+            VimKey::Char(c) => line.push(c),
+            VimKey::Backspace => {
+                line.pop();
+                if line.is_empty() {
+                    vim.command_line = None;
+                }
+            }
+            VimKey::Esc => vim.command_line = None,
+            VimKey::Enter => {
+                let line = vim.command_line.take().unwrap_or_default();
+                let (kind, body) = line.split_at(1);
+                if kind == ":" {
+                    self.vim_ex(body);
+                    return;
+                }
+                // An empty search repeats the last pattern
+                let forward = kind == "/";
+                let pattern = match body {
+                    "" => vim.last_search.as_ref().map(|(p, _)| p.clone()),
+                    body => Some(body.to_string()),
+                };
+                match pattern {
+                    Some(pattern) => {
+                        vim.last_search = Some((pattern.clone(), forward));
+                        self.vim_search(&pattern, forward);
+                    }
+                    None => self.vim_message("No previous search".to_string()),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn vim_execute(&mut self, command: Command) {
+        let Some(mode) = self.vim_mode() else { return };
+        let visual = matches!(mode, Mode::Visual | Mode::VisualLine);
+        let count = command.count();
+        self.extra_cursors.clear();
+        self.selection_anchor = None;
+        if let Some(vim) = &mut self.vim {
+            if !matches!(command.action, Action::Move(Motion::Up | Motion::Down)) {
+                vim.want_col = None;
+            }
+        }
+        match command.action {
+            Action::Escape => {
+                self.vim_leave_visual();
+                if self.find.open {
+                    self.close_find();
+                }
+            }
+            Action::Move(motion) => self.vim_move(motion, command.count),
+            Action::Operate(op, target) => {
+                let range = match target {
+                    Target::Selection => self.vim_visual_range(),
+                    Target::Motion(motion) => self.vim_motion_range(op, motion, command.count),
+                    Target::Object(object, around) => vim::object_range(&self.content, self.cursor, object, around),
+                    Target::Lines => {
+                        let line = self.content.line_of(self.cursor);
+                        let last = (line + count - 1).min(self.total_lines() - 1);
+                        Some((self.line_start(line)..self.line_end(last), true))
+                    }
+                };
+                if visual {
+                    self.vim_leave_visual();
+                }
+                if let Some((range, linewise)) = range {
+                    self.vim_operate(op, range, linewise, command.register);
+                }
+            }
+            Action::Insert(at) => {
+                if visual {
+                    self.vim_leave_visual();
+                }
+                self.vim_insert(at);
+            }
+            Action::Put { before } => self.vim_put(before, count, command.register, visual),
+            Action::Replace(c) => self.vim_replace_chars(c, count),
+            Action::Join => {
+                let joins = match self.vim_visual_range().filter(|_| visual) {
+                    Some((range, _)) => (self.content.line_of(range.end) - self.content.line_of(range.start)).max(1),
+                    None => count.max(2) - 1,
+                };
+                if visual {
+                    self.vim_leave_visual();
+                    let start = self.vim.as_ref().map_or(self.cursor, |v| v.visual_anchor).min(self.cursor);
+                    self.set_cursor(start);
+                }
+                self.vim_join(joins);
+            }
+            Action::ToggleCase => {
+                let line_end = self.line_end(self.content.line_of(self.cursor));
+                let end = (0..count).fold(self.cursor, |p, _| if p < line_end { self.content.next_char(p) } else { p });
+                let text = vim::change_case(&self.content.slice(self.cursor..end), Operator::SwapCase);
+                self.vim_replace(self.cursor..end, &text, end);
+            }
+            Action::Undo | Action::Redo => {
+                for _ in 0..count {
+                    if command.action == Action::Undo {
+                        self.undo();
+                    } else {
+                        self.redo();
+                    }
+                }
+                self.set_cursor(self.cursor);
+            }
+            Action::Repeat => {
+                let keys = self.vim.as_ref().map(|v| v.last_change(command.count)).unwrap_or_default();
+                if let Some(vim) = &mut self.vim {
+                    vim.replaying = true;
+                }
+                for key in keys {
+                    self.vim_key(key);
+                }
+                if let Some(vim) = &mut self.vim {
+                    vim.replaying = false;
+                }
+            }
+            Action::Visual(target) => {
+                if mode == target {
+                    self.vim_leave_visual();
+                } else if let Some(vim) = &mut self.vim {
+                    if !visual {
+                        vim.visual_anchor = self.cursor;
+                    }
+                    vim.mode = target;
+                }
+            }
+            Action::SwapEnds => {
+                if let Some(vim) = &mut self.vim {
+                    let anchor = std::mem::replace(&mut vim.visual_anchor, self.cursor);
+                    self.cursor = anchor.min(self.content.len_bytes());
+                }
+            }
+            Action::Select(object, around) => {
+                if let Some((range, linewise)) = vim::object_range(&self.content, self.cursor, object, around) {
+                    if let Some(vim) = &mut self.vim {
+                        vim.visual_anchor = range.start;
+                        if linewise {
+                            vim.mode = Mode::VisualLine;
+                        }
+                    }
+                    self.cursor = self.content.prev_char(range.end).max(range.start);
+                }
+            }
+            Action::CommandLine(c) => {
+                if visual {
+                    self.vim_leave_visual();
+                }
+                if let Some(vim) = &mut self.vim {
+                    vim.command_line = Some(if c == ':' && visual { ":'<,'>".to_string() } else { c.to_string() });
+                }
+            }
+            Action::SearchNext { reverse } => match self.vim.as_ref().and_then(|v| v.last_search.clone()) {
+                Some((pattern, forward)) => self.vim_search(&pattern, forward != reverse),
+                None => self.vim_message("No previous search".to_string()),
+            },
+            Action::SearchWord { forward } => {
+                let word = self.content.slice(self.word_range_at(self.cursor));
+                if word.is_empty() {
+                    self.vim_message("No word under the cursor".to_string());
+                } else {
+                    let pattern = format!(r"\<{}\>", regex::escape(&word));
+                    if let Some(vim) = &mut self.vim {
+                        vim.last_search = Some((pattern.clone(), forward));
+                    }
+                    self.vim_search(&pattern, forward);
+                }
+            }
+        }
+        self.vim_clamp();
+        if let Some(vim) = self.vim.as_mut().filter(|v| v.mode != Mode::Insert) {
+            vim.end_change();
+        }
+    }
+
+    fn vim_move(&mut self, motion: Motion, count: Option<usize>) {
+        let line = self.content.line_of(self.cursor);
+        let col = wrap::width(&self.content.slice(self.line_start(line)..self.cursor));
+        let want = self.vim.as_ref().and_then(|v| v.want_col).unwrap_or(col);
+        let Some(to) = vim::motion_target(&self.content, self.cursor, motion, count, want) else { return };
+        if let Some(vim) = &mut self.vim {
+            vim.want_col = match motion {
+                Motion::Up | Motion::Down => Some(want),
+                Motion::LineEnd => Some(usize::MAX),
+                _ => None,
+            };
+        }
+        self.cursor = self.skip_folds(self.cursor, to);
+    }
+
+    /// What an operator with a motion covers, and whether it's whole lines
+    fn vim_motion_range(&self, op: Operator, motion: Motion, count: Option<usize>) -> Option<(Range<usize>, bool)> {
+        let from = self.cursor;
+        let on_word = self.content.slice(from..self.content.next_char(from)).starts_with(|c: char| !c.is_whitespace());
+        // `cw` on a word changes to its end, like `ce`
+        if let (Operator::Change, Motion::WordStart(big), true) = (op, motion, on_word) {
+            let first = vim::word_end(&self.content, from, big, true);
+            let end = (1..count.unwrap_or(1)).fold(first, |p, _| vim::word_end(&self.content, p, big, false));
+            return Some((from..self.content.next_char(end), false));
+        }
+        let line = self.content.line_of(from);
+        let col = wrap::width(&self.content.slice(self.line_start(line)..from));
+        let to = vim::motion_target(&self.content, from, motion, count, col)?;
+        if motion.linewise() {
+            let to_line = self.content.line_of(to);
+            return Some((self.line_start(line.min(to_line))..self.line_end(line.max(to_line)), true));
+        }
+        let (start, mut end) = (from.min(to), from.max(to));
+        if motion.inclusive() {
+            end = self.content.next_char(end);
+        }
+        // A word motion from a line's last word stops at the end of that line
+        if let Motion::WordStart(_) = motion {
+            let end_line = self.content.line_of(end);
+            if end_line > line && end <= vim::first_non_blank(&self.content, end_line) {
+                end = self.line_end(end_line - 1).max(start);
+            }
+        }
+        Some((start..end, false))
+    }
+
+    /// The visual selection, including the character under the cursor, and whether
+    /// it's whole lines
+    fn vim_visual_range(&self) -> Option<(Range<usize>, bool)> {
+        let vim = self.vim.as_ref()?;
+        let anchor = vim.visual_anchor.min(self.content.len_bytes());
+        let (a, b) = (anchor.min(self.cursor), anchor.max(self.cursor));
+        match vim.mode {
+            Mode::Visual => Some((a..self.content.next_char(b), false)),
+            Mode::VisualLine => {
+                Some((self.line_start(self.content.line_of(a))..self.line_end(self.content.line_of(b)), true))
+            }
+            _ => None,
+        }
+    }
+
+    /// Back to normal mode, remembering the selected lines for `:'<,'>`
+    fn vim_leave_visual(&mut self) {
+        let lines = self.vim_visual_range().map(|(range, _)| {
+            let last = range.end.saturating_sub(1).max(range.start);
+            (self.content.line_of(range.start), self.content.line_of(last))
+        });
+        if let Some(vim) = &mut self.vim {
+            vim.visual_lines = lines.or(vim.visual_lines);
+            vim.mode = Mode::Normal;
+        }
+    }
+
+    fn vim_operate(&mut self, op: Operator, range: Range<usize>, linewise: bool, register: Option<char>) {
+        let (first, last) = (self.content.line_of(range.start), self.content.line_of(range.end));
+        let mut text = self.content.slice(range.clone());
+        if linewise {
+            text.push('\n');
+        }
+        let taken = Register { text, linewise };
+        match op {
+            Operator::Yank => {
+                if let Some(vim) = &mut self.vim {
+                    vim.store(register, taken, true);
+                }
+                let cursor = match linewise {
+                    true if self.content.line_of(self.cursor) > first => vim::first_non_blank(&self.content, first),
+                    true => self.cursor,
+                    false => range.start,
+                };
+                self.set_cursor(cursor);
+            }
+            Operator::Delete => {
+                if let Some(vim) = &mut self.vim {
+                    vim.store(register, taken, false);
+                }
+                if linewise {
+                    let remove = vim::line_range(&self.content, first, last);
+                    self.vim_replace(remove.clone(), "", remove.start);
+                    let line = self.content.line_of(remove.start.min(self.content.len_bytes()));
+                    self.set_cursor(vim::first_non_blank(&self.content, line));
+                } else {
+                    self.vim_replace(range.clone(), "", range.start);
+                    self.set_cursor(range.start);
+                }
+            }
+            Operator::Change => {
+                if let Some(vim) = &mut self.vim {
+                    vim.store(register, taken, false);
+                }
+                self.history.begin_group();
+                // Changing lines keeps the first one's indentation
+                let keep = if linewise { crate::indent::leading(&self.content.line(first)).to_string() } else { String::new() };
+                self.vim_replace(range.clone(), &keep, range.start + keep.len());
+                self.set_cursor(range.start + keep.len());
+                self.set_vim_mode(Mode::Insert);
+            }
+            Operator::Indent | Operator::Outdent => {
+                self.set_selections(vec![Selection { cursor: self.line_end(last), anchor: Some(self.line_start(first)) }]);
+                self.shift_lines(op == Operator::Indent);
+                self.set_cursor(vim::first_non_blank(&self.content, first));
+            }
+            Operator::Lowercase | Operator::Uppercase | Operator::SwapCase => {
+                let changed = vim::change_case(&self.content.slice(range.clone()), op);
+                self.vim_replace(range.clone(), &changed, range.start);
+                self.set_cursor(range.start);
+            }
+        }
+    }
+
+    fn vim_insert(&mut self, at: InsertAt) {
+        let line = self.content.line_of(self.cursor);
+        self.history.begin_group();
+        match at {
+            InsertAt::Cursor => {}
+            InsertAt::After => {
+                if self.cursor < self.line_end(line) {
+                    self.set_cursor(self.content.next_char(self.cursor));
+                }
+            }
+            InsertAt::LineStart => self.set_cursor(vim::first_non_blank(&self.content, line)),
+            InsertAt::LineEnd => self.set_cursor(self.line_end(line)),
+            InsertAt::LineBelow => {
+                self.set_cursor(self.line_end(line));
+                self.newline();
+            }
+            InsertAt::LineAbove => {
+                let start = self.line_start(line);
+                let indent = crate::indent::leading(&self.content.line(line)).to_string();
+                self.vim_replace(start..start, &format!("{}\n", indent), start + indent.len());
+            }
+        }
+        self.set_vim_mode(Mode::Insert);
+    }
+
+    fn vim_leave_insert(&mut self) {
+        self.history.end_group();
+        self.completion = None;
+        if let Some(vim) = &mut self.vim {
+            vim.record(VimKey::Esc);
+            vim.end_change();
+            vim.mode = Mode::Normal;
+        }
+        // Back onto the last character typed
+        let start = self.line_start(self.content.line_of(self.cursor));
+        let cursor = if self.cursor > start { self.content.prev_char(self.cursor) } else { self.cursor };
+        self.set_cursor(cursor);
+    }
+
+    /// `p` / `P`: whole lines go below (above) the cursor's line, other text after
+    /// (before) the cursor; in visual mode the text replaces the selection
+    fn vim_put(&mut self, before: bool, count: usize, register: Option<char>, visual: bool) {
+        let Some(put) = self.vim.as_ref().and_then(|v| v.register(register)) else {
+            self.vim_message("Nothing in register".to_string());
+            return;
+        };
+        let text = put.text.repeat(count);
+        if visual {
+            let Some((range, linewise)) = self.vim_visual_range() else { return };
+            self.vim_leave_visual();
+            let mut replaced = self.content.slice(range.clone());
+            if linewise {
+                replaced.push('\n');
+            }
+            let text = if linewise { text.strip_suffix('\n').unwrap_or(&text) } else { &text };
+            self.vim_replace(range.clone(), text, range.start);
+            if let Some(vim) = &mut self.vim {
+                vim.store(None, Register { text: replaced, linewise }, false);
+            }
+            return;
+        }
+        let line = self.content.line_of(self.cursor);
+        if put.linewise {
+            let (at, insert, first) = if before {
+                (self.line_start(line), text, line)
+            } else if line + 1 < self.total_lines() {
+                (self.line_start(line + 1), text, line + 1)
+            } else {
+                // After the last line, which has no newline to put it after
+                (self.content.len_bytes(), format!("\n{}", text.strip_suffix('\n').unwrap_or(&text)), line + 1)
+            };
+            self.vim_replace(at..at, &insert, at);
+            self.set_cursor(vim::first_non_blank(&self.content, first));
+        } else {
+            let at = if before || self.cursor == self.line_end(line) { self.cursor } else { self.content.next_char(self.cursor) };
+            self.vim_replace(at..at, &text, at + text.len());
+            self.set_cursor(self.content.prev_char(at + text.len()).max(at));
+        }
+    }
+
+    /// `r`: replace `count` characters with `c`, if the line has that many
+    fn vim_replace_chars(&mut self, c: char, count: usize) {
+        let start = self.cursor;
+        let line_end = self.line_end(self.content.line_of(start));
+        let mut end = start;
+        for _ in 0..count {
+            if end >= line_end {
+                return;
+            }
+            end = self.content.next_char(end);
+        }
+        let text = c.to_string().repeat(count);
+        self.vim_replace(start..end, &text, start + text.len() - c.len_utf8());
+    }
+
+    /// `J`: join the cursor's line with the next `joins` lines, a space between
+    fn vim_join(&mut self, joins: usize) {
+        let line = self.content.line_of(self.cursor);
+        self.history.begin_group();
+        for _ in 0..joins {
+            if line + 1 >= self.total_lines() {
+                break;
+            }
+            let end = self.line_end(line);
+            let current = self.content.line(line);
+            let next = self.content.line(line + 1);
+            let rest = next.trim_start();
+            let space = !(rest.is_empty() || rest.starts_with(')') || current.is_empty() || current.ends_with(char::is_whitespace));
+            let remove = end..self.line_start(line + 1) + (next.len() - rest.len());
+            self.vim_replace(remove, if space { " " } else { "" }, end);
+        }
+        self.history.end_group();
+    }
+
+    fn vim_search(&mut self, pattern: &str, forward: bool) {
+        let regex = match crate::find::build_pattern(&vim::vim_pattern(pattern), true, true, false) {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.vim_message(format!("Invalid pattern: {}", e));
+                return;
+            }
+        };
+        match vim::search(&self.content, &regex, self.cursor, forward) {
+            Some(at) => self.set_cursor(at),
+            None => self.vim_message(format!("Pattern not found: {}", pattern)),
+        }
+    }
+
+    fn vim_ex(&mut self, input: &str) {
+        match vim::parse_ex(input) {
+            Err(e) => self.vim_message(e),
+            Ok(Ex::Write) => self.vim_write(),
+            Ok(Ex::Quit { force }) => {
+                if self.modified && !force {
+                    self.vim_message("No write since last change (add ! to override)".to_string());
+                } else {
+                    self.close_request = true;
+                }
+            }
+            Ok(Ex::WriteQuit) if self.modified && self.format_on_save && self.file_path.is_some() => {
+                self.quit_after_save = true;
+                self.vim_write();
+            }
+            Ok(Ex::WriteQuit) => {
+                if self.modified {
+                    self.vim_write();
+                }
+                self.close_request = !self.modified;
+            }
+            Ok(Ex::Goto(address)) => {
+                let line = self.vim_line(address);
+                self.set_cursor(vim::first_non_blank(&self.content, line));
+            }
+            Ok(Ex::Substitute { range, pattern, replacement, all, ignore_case }) => {
+                if let Err(e) = self.vim_substitute(range, &pattern, &replacement, all, ignore_case) {
+                    self.vim_message(e);
+                }
+            }
         }
     }
 
-    fn move_cursor_right(&mut self, shift: bool) {
-        if !shift {
-            self.selection_anchor = None;
-        } else if self.selection_anchor.is_none() {
-            self.selection_anchor = Some(self.cursor);
-        }
-        if self.cursor < self.content.len() {
-            let next = self.content[self.cursor..]
-                .char_indices()
-                .nth(1)
-                .map(|(i, _)| self.cursor + i)
-                .unwrap_or(self.content.len());
-            self.cursor = next;
+    /// `:w`, formatting first like Cmd+S when format-on-save is on
+    fn vim_write(&mut self) {
+        if self.format_on_save && self.file_path.is_some() {
+            self.format_request = Some(true);
+        } else {
+            self.save_reporting();
         }
     }
 
-    fn move_cursor_up(&mut self, shift: bool) {
-        if !shift {
-            self.selection_anchor = None;
-        } else if self.selection_anchor.is_none() {
-            self.selection_anchor = Some(self.cursor);
-        }
-        let (line, col) = self.cursor_line_col();
-        if line > 0 {
-            let new_start = self.line_start(line - 1);
-            let new_end = self.line_end(line - 1);
-            let line_len = new_end - new_start;
-            self.cursor = new_start + col.min(line_len);
+    fn vim_line(&self, address: Address) -> usize {
+        let last = self.total_lines() - 1;
+        match address {
+            Address::Line(n) => n.saturating_sub(1).min(last),
+            Address::Current => self.content.line_of(self.cursor),
+            Address::Last => last,
         }
     }
 
-    fn move_cursor_down(&mut self, shift: bool) {
-        if !shift {
-            self.selection_anchor = None;
-        } else if self.selection_anchor.is_none() {
-            self.selection_anchor = Some(self.cursor);
-        }
-        let (line, col) = self.cursor_line_col();
-        let total = self.total_lines();
-        if line + 1 < total {
-            let new_start = self.line_start(line + 1);
-            let new_end = self.line_end(line + 1);
-            let line_len = new_end - new_start;
-            self.cursor = new_start + col.min(line_len);
-        }
+    /// `:s/pattern/replacement/`, on the cursor's line unless given a range
+    fn vim_substitute(
+        &mut self,
+        range: Option<ExRange>,
+        pattern: &str,
+        replacement: &str,
+        all: bool,
+        ignore_case: bool,
+    ) -> Result<(), String> {
+        let (first, last) = match range {
+            None => {
+                let line = self.content.line_of(self.cursor);
+                (line, line)
+            }
+            Some(ExRange::All) => (0, self.total_lines() - 1),
+            Some(ExRange::Selection) => {
+                self.vim.as_ref().and_then(|v| v.visual_lines).ok_or("No visual selection")?
+            }
+            Some(ExRange::Lines(a, b)) => {
+                let (a, b) = (self.vim_line(a), self.vim_line(b));
+                (a.min(b), a.max(b))
+            }
+        };
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+        let block = self.line_start(first)..self.line_end(last);
+        let mut changed = None;
+        let lines: Vec<String> = self
+            .content
+            .slice(block.clone())
+            .split('\n')
+            .enumerate()
+            .map(|(i, line)| {
+                if regex.is_match(line) {
+                    changed = Some(first + i);
+                }
+                match all {
+                    true => regex.replace_all(line, replacement).into_owned(),
+                    false => regex.replace(line, replacement).into_owned(),
+                }
+            })
+            .collect();
+        let changed = changed.ok_or_else(|| format!("Pattern not found: {}", pattern))?;
+        self.vim_replace(block.clone(), &lines.join("\n"), block.start);
+        self.set_cursor(vim::first_non_blank(&self.content, changed.min(self.total_lines() - 1)));
+        Ok(())
     }
 
-    fn update_search(&mut self) {
-        self.search_matches.clear();
-        if self.search_query.is_empty() {
-            return;
-        }
-        let query = &self.search_query.clone();
-        let mut start = 0;
-        while let Some(pos) = self.content[start..].find(query.as_str()) {
-            let abs = start + pos;
-            self.search_matches.push((abs, abs + query.len()));
-            start = abs + query.len().max(1);
+    /// Cursor position, encoding and line endings (the latter two can be changed),
+    /// and the Vim mode or command line
+    fn render_status_bar(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_ELEVATED);
+        let mut child = ui.new_child(
+            egui::UiBuilder::new()
+                .max_rect(rect.shrink2(egui::vec2(8.0, 0.0)))
+                .layout(egui::Layout::right_to_left(egui::Align::Center)),
+        );
+        child.style_mut().override_font_id = Some(FontId::proportional(12.0));
+
+        child.menu_button(self.encoding.line_ending.label(), |ui| {
+            for ending in [LineEnding::Lf, LineEnding::CrLf] {
+                if ui.radio(self.encoding.line_ending == ending, ending.label()).clicked() {
+                    if self.encoding.line_ending != ending {
+                        self.encoding.line_ending = ending;
+                        self.modified = true;
+                    }
+                    ui.close_menu();
+                }
+            }
+        });
+        child.menu_button(self.encoding.label(), |ui| {
+            ui.label(egui::RichText::new("Save with encoding").strong());
+            for &encoding in crate::encoding::CHOICES {
+                let chosen = FileEncoding { encoding, ..self.encoding };
+                if ui.radio(self.encoding.encoding == encoding, chosen.label()).clicked() {
+                    if self.encoding.encoding != encoding {
+                        self.encoding.encoding = encoding;
+                        self.modified = true;
+                    }
+                    ui.close_menu();
+                }
+            }
+            let unicode = ["UTF-8", "UTF-16LE", "UTF-16BE"].contains(&self.encoding.encoding.name());
+            if ui.add_enabled(unicode, egui::Checkbox::new(&mut self.encoding.bom, "Byte order mark")).changed() {
+                self.modified = true;
+            }
+            if self.file_path.is_some() {
+                ui.separator();
+                ui.label(egui::RichText::new("Reopen with encoding").strong());
+                for &encoding in crate::encoding::CHOICES {
+                    let label = FileEncoding { encoding, bom: false, ..self.encoding }.label();
+                    let button = ui.add_enabled(!self.modified, egui::Button::new(label));
+                    if button.on_disabled_hover_text("Save or undo your changes first").clicked() {
+                        self.reopen_with(encoding);
+                        ui.close_menu();
+                    }
+                }
+            }
+        });
+        child.menu_button(self.indent.label(), |ui| {
+            ui.label(egui::RichText::new("Indent using").strong());
+            for unit in [IndentUnit::Tabs, IndentUnit::Spaces(2), IndentUnit::Spaces(4), IndentUnit::Spaces(8)] {
+                if ui.radio(self.indent == unit, unit.label()).clicked() {
+                    self.indent = unit;
+                    ui.close_menu();
+                }
+            }
+        });
+        let wrap_label = egui::RichText::new("Wrap").color(if self.soft_wrap {
+            crate::theme::TEXT_PRIMARY
+        } else {
+            crate::theme::TEXT_SECONDARY
+        });
+        if child.selectable_label(self.soft_wrap, wrap_label).on_hover_text("Soft wrap (Alt+Z)").clicked() {
+            self.soft_wrap = !self.soft_wrap;
         }
-        if self.search_current >= self.search_matches.len() {
-            self.search_current = 0;
+        let (line, col) = self.char_line_col(self.cursor);
+        child.label(egui::RichText::new(format!("Ln {}, Col {}", line + 1, col + 1)).color(crate::theme::TEXT_SECONDARY));
+
+        // Vim: the command line while it's open, else any message, else the mode
+        if let Some(vim) = &self.vim {
+            let (text, color) = match (&vim.command_line, &vim.message) {
+                (Some(line), _) => (line.clone(), crate::theme::TEXT_PRIMARY),
+                (None, Some(message)) => (message.clone(), crate::theme::ERROR),
+                (None, None) => (format!("{}  {}", vim.mode.label(), vim.pending()), crate::theme::TEXT_SECONDARY),
+            };
+            child.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                ui.label(egui::RichText::new(text).monospace().color(color));
+            });
         }
     }
 
-    fn jump_to_search_match(&mut self) {
-        if let Some(&(start, end)) = self.search_matches.get(self.search_current) {
-            self.cursor = end;
-            self.selection_anchor = Some(start);
+    fn render_notice(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        let Some(notice) = &self.notice else { return };
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_ELEVATED);
+        let mut dismiss = false;
+        let mut child = ui.new_child(
+            egui::UiBuilder::new()
+                .max_rect(rect.shrink2(egui::vec2(6.0, 2.0)))
+                .layout(egui::Layout::right_to_left(egui::Align::Center)),
+        );
+        dismiss |= child.small_button("×").on_hover_text("Dismiss").clicked();
+        // Only the first line fits; the rest is in the tooltip
+        let first = notice.lines().next().unwrap_or_default();
+        let label = egui::Label::new(egui::RichText::new(first).size(12.0).color(crate::theme::ERROR)).truncate();
+        child.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+            ui.add(label).on_hover_text(notice.as_str());
+        });
+        if dismiss {
+            self.notice = None;
         }
     }
 
@@ -316,14 +2400,14 @@ impl Editor {
         let font = FontId::monospace(14.0);
         let char_width = 8.4_f32;
         let line_height = 17.0_f32;
-        let gutter_width = 50.0_f32;
+        let gutter_width = 64.0_f32;
 
         // Background
         ui.painter().rect_filled(rect, 0.0, crate::theme::BG_SURFACE);
 
-        // Search bar at top if open
-        let (search_rect, content_rect) = if self.search_open {
-            let search_h = 28.0;
+        // Find bar at top if open
+        let (search_rect, content_rect) = if self.find.open {
+            let search_h = if self.find.replace_open { 56.0 } else { 28.0 };
             let sr = Rect::from_min_size(rect.left_top(), egui::vec2(rect.width(), search_h));
             let cr = Rect::from_min_max(
                 egui::pos2(rect.left(), rect.top() + search_h),
@@ -334,48 +2418,42 @@ impl Editor {
             (None, rect)
         };
 
-        // Draw search bar
         if let Some(sr) = search_rect {
-            ui.painter().rect_filled(sr, 0.0, crate::theme::BG_ELEVATED);
-            let search_id = ui.id().with(("editor_search", self.id));
-            let text_rect = Rect::from_min_size(
-                egui::pos2(sr.left() + 8.0, sr.top() + 4.0),
-                egui::vec2(sr.width() - 16.0, 20.0),
-            );
+            self.render_find_bar(ui, sr);
+        }
 
-            // Simple search input via text painter
-            ui.painter().text(
-                egui::pos2(sr.left() + 8.0, sr.center().y),
-                egui::Align2::LEFT_CENTER,
-                if self.search_query.is_empty() {
-                    "Search..."
-                } else {
-                    &self.search_query
-                },
-                FontId::proportional(13.0),
-                if self.search_query.is_empty() {
-                    crate::theme::TEXT_SECONDARY
-                } else {
-                    crate::theme::TEXT_PRIMARY
-                },
-            );
+        // Conflict and notice strips below the find bar
+        let content_rect = if self.conflict.is_some() {
+            let cr = Rect::from_min_size(content_rect.left_top(), egui::vec2(content_rect.width(), 28.0));
+            self.render_conflict(ui, cr);
+            Rect::from_min_max(egui::pos2(content_rect.left(), cr.bottom()), content_rect.right_bottom())
+        } else {
+            content_rect
+        };
+        let content_rect = if self.notice.is_some() {
+            let nr = Rect::from_min_size(content_rect.left_top(), egui::vec2(content_rect.width(), 24.0));
+            self.render_notice(ui, nr);
+            Rect::from_min_max(egui::pos2(content_rect.left(), nr.bottom()), content_rect.right_bottom())
+        } else {
+            content_rect
+        };
+        // Breadcrumbs for files with symbols to show
+        let content_rect = if self.syntax.is_some() || self.lsp_attached {
+            let br = Rect::from_min_size(content_rect.left_top(), egui::vec2(content_rect.width(), 20.0));
+            self.render_breadcrumbs(ui, br);
+            Rect::from_min_max(egui::pos2(content_rect.left(), br.bottom()), content_rect.right_bottom())
+        } else {
+            content_rect
+        };
 
-            // Match count
-            if !self.search_query.is_empty() {
-                let info = format!(
-                    "{}/{}",
-                    if self.search_matches.is_empty() { 0 } else { self.search_current + 1 },
-                    self.search_matches.len()
-                );
-                ui.painter().text(
-                    egui::pos2(sr.right() - 8.0, sr.center().y),
-                    egui::Align2::RIGHT_CENTER,
-                    &info,
-                    FontId::proportional(12.0),
-                    crate::theme::TEXT_SECONDARY,
-                );
-            }
-        }
+        // Status bar at the bottom
+        let status_rect = Rect::from_min_max(
+            egui::pos2(content_rect.left(), content_rect.bottom() - 20.0),
+            content_rect.right_bottom(),
+        );
+        self.render_status_bar(ui, status_rect);
+        let content_rect = Rect::from_min_max(content_rect.left_top(), egui::pos2(content_rect.right(), status_rect.top()));
+        self.refresh_find();
 
         let gutter_rect = Rect::from_min_size(
             content_rect.left_top(),
@@ -392,44 +2470,205 @@ impl Editor {
 
         // Handle focus and input
         let unique_id = ui.id().with(("editor_input", self.id));
-        let response = ui.interact(rect, unique_id, egui::Sense::click());
+        let response = ui.interact(content_rect, unique_id, egui::Sense::click_and_drag());
 
-        if response.clicked() || self.grab_focus {
+        if response.clicked() || response.drag_started() || self.grab_focus {
             ui.memory_mut(|mem| mem.request_focus(unique_id));
             self.grab_focus = false;
+        }
+        if response.gained_focus() {
+            self.refresh_git_head();
+        }
+        if let Some(job) = &self.git_job {
+            match job.poll() {
+                Some(head) => {
+                    let encoding = self.encoding.encoding;
+                    self.git_head = head.map(|bytes| crate::encoding::decode_as(&bytes, encoding).0.into());
+                    self.git_hunks = None;
+                    self.hunks_job = None;
+                    self.git_job = None;
+                }
+                None => ui.ctx().request_repaint_after(Duration::from_millis(50)),
+            }
+        }
+
+        // Unfold wherever a cursor was put inside a fold (search, go to definition, undo)
+        let cursors: Vec<usize> = self.selections().iter().map(|s| s.cursor).collect();
+        self.folds.retain(|f| !cursors.iter().any(|&c| f.start < c && c <= f.end));
+
+        // Soft wrap fills the width, leaving a column for the cursor
+        let wrap_cols = ((text_rect.width() / char_width).floor() as usize).saturating_sub(1).max(8);
+        self.update_layout(Some(wrap_cols));
+        if self.soft_wrap {
+            self.scroll_x = 0.0;
+        }
 
-            // Calculate click position to set cursor
-            if let Some(pos) = response.interact_pointer_pos() {
-                if pos.x >= text_rect.left() {
-                    let col = ((pos.x - text_rect.left()) / char_width).floor() as usize;
-                    let row = ((pos.y - text_rect.top() + self.scroll_offset) / line_height).floor() as usize;
-                    let row = row.min(self.total_lines().saturating_sub(1));
-                    let start = self.line_start(row);
-                    let end = self.line_end(row);
-                    let line_len = end - start;
-                    self.cursor = start + col.min(line_len);
-                    self.selection_anchor = None;
+        // Pointer position as (visual row, on-screen column)
+        let (scroll_offset, scroll_x) = (self.scroll_offset, self.scroll_x);
+        let last_row = self.row_count().saturating_sub(1);
+        let to_row_col = |pos: egui::Pos2| {
+            let col = ((pos.x - text_rect.left() + scroll_x) / char_width).round().max(0.0) as usize;
+            let row = ((pos.y - text_rect.top() + scroll_offset) / line_height).floor().max(0.0) as usize;
+            (row.min(last_row), col)
+        };
+        if response.hovered() && ui.input(|i| i.pointer.hover_pos()).is_some_and(|p| text_rect.contains(p)) {
+            ui.ctx().set_cursor_icon(egui::CursorIcon::Text);
+        }
+        let (alt, shift) = ui.input(|i| (i.modifiers.alt, i.modifiers.shift));
+        if response.drag_started() {
+            let origin = ui.input(|i| i.pointer.press_origin()).filter(|p| p.x >= text_rect.left());
+            if let Some(origin) = origin {
+                let (row, col) = to_row_col(origin);
+                if alt {
+                    self.column_drag = Some(self.row_col_to_line_col(row, col));
+                } else {
+                    let offset = self.row_col_to_byte(row, col);
+                    let anchor = if shift { self.selection_anchor.unwrap_or(self.cursor) } else { offset };
+                    self.drag_anchor = Some(anchor);
+                }
+            }
+        }
+        if let Some(pos) = response.interact_pointer_pos() {
+            // Past the edges the selection grows a line (or two columns) per frame,
+            // scrolling the view along
+            let margin = egui::vec2(char_width * 2.0, line_height);
+            let outside = !text_rect.contains(pos);
+            let (row, col) = to_row_col(pos.clamp(text_rect.min - margin, text_rect.max + margin));
+            let in_text = pos.x >= text_rect.left();
+            if response.triple_clicked() && in_text {
+                let (line, _) = self.row_span(row);
+                let start = self.line_start(line);
+                let end = if line + 1 < self.total_lines() { self.line_start(line + 1) } else { self.line_end(line) };
+                self.set_selections(vec![Selection { cursor: end, anchor: Some(start) }]);
+            } else if response.double_clicked() && in_text {
+                let word = self.word_range_at(self.row_col_to_byte(row, col));
+                self.set_selections(vec![Selection { cursor: word.end, anchor: Some(word.start) }]);
+            } else if response.clicked() && pos.x < gutter_rect.left() + 8.0 {
+                // Change markers show the committed lines
+                let (line, _) = self.row_span(row);
+                let hunk = self
+                    .git_hunks()
+                    .iter()
+                    .find(|h| h.new.contains(&line) || (h.new.is_empty() && h.new.start == line))
+                    .map(|h| h.new.clone());
+                self.hunk_popup = if hunk == self.hunk_popup { None } else { hunk };
+            } else if response.clicked() && !in_text {
+                // The gutter's arrows fold and unfold
+                let (line, span) = self.row_span(row);
+                if span.start == self.line_start(line) {
+                    if self.fold_at(line).is_some() {
+                        self.unfold(line);
+                    } else if self.fold_ranges().iter().any(|(start, _)| *start == line) {
+                        self.fold(line);
+                    }
+                }
+            } else if response.clicked() {
+                let offset = self.row_col_to_byte(row, col);
+                let mut selections = vec![Selection::caret(offset)];
+                if alt {
+                    // Alt+click adds a cursor
+                    selections.extend(self.selections());
+                } else if shift {
+                    // Shift+click extends the selection
+                    selections[0].anchor = Some(self.selection_anchor.unwrap_or(self.cursor));
+                }
+                self.set_selections(selections);
+            } else if response.dragged() {
+                if let Some(from) = self.column_drag {
+                    self.column_select(from, self.row_col_to_line_col(row, col));
+                } else if let Some(anchor) = self.drag_anchor {
+                    let cursor = self.row_col_to_byte(row, col);
+                    self.set_selections(vec![Selection { cursor, anchor: Some(anchor) }]);
+                }
+                if outside {
+                    ui.ctx().request_repaint();
                 }
             }
         }
+        if response.drag_stopped() {
+            self.column_drag = None;
+            self.drag_anchor = None;
+        }
+        response.context_menu(|ui| {
+            if ui.button("Fold All").clicked() {
+                self.fold_all();
+                ui.close_menu();
+            }
+            if ui.button("Unfold All").clicked() {
+                self.folds.clear();
+                ui.close_menu();
+            }
+            ui.separator();
+            if ui.button("Compare with Saved").clicked() {
+                self.diff_request = Some(Compare::Disk);
+                ui.close_menu();
+            }
+            if ui.button("Compare with HEAD").clicked() {
+                self.diff_request = Some(Compare::Head);
+                ui.close_menu();
+            }
+        });
 
         let has_focus = ui.memory(|mem| mem.has_focus(unique_id));
+        self.vim_sync();
 
-        if has_focus {
-            let mut needs_search_update = false;
+        if response.lost_focus() {
+            self.ime_preedit.clear();
+        }
 
+        if has_focus {
             ui.input(|i| {
+                // Set by Alt shortcuts: egui-winit only drops the character such a key
+                // types when Ctrl or Cmd is held, so the Text event right after is ours
+                let mut swallow_text = false;
                 for event in &i.events {
+                    let swallow = std::mem::take(&mut swallow_text);
                     match event {
+                        egui::Event::Text(_) if swallow => {}
+                        egui::Event::Ime(ime) => match ime {
+                            egui::ImeEvent::Enabled | egui::ImeEvent::Disabled => {
+                                self.ime_preedit.clear();
+                            }
+                            egui::ImeEvent::Preedit(text) => {
+                                self.ime_preedit = text.clone();
+                            }
+                            egui::ImeEvent::Commit(text) => {
+                                self.ime_preedit.clear();
+                                if !text.is_empty() {
+                                    self.insert_text(text);
+                                    self.update_completion();
+                                }
+                            }
+                        },
+                        // While composing, Enter/Backspace/arrows belong to the IME
+                        egui::Event::Key { .. } if !self.ime_preedit.is_empty() => {}
+                        egui::Event::Text(text) if self.vim_takes_keys() => {
+                            for c in text.chars() {
+                                self.vim_key(VimKey::Char(c));
+                            }
+                            self.update_completion();
+                        }
+                        egui::Event::Key { key, pressed: true, modifiers, .. }
+                            if self.vim_takes_keys() && vim_key_of(*key, *modifiers).is_some() =>
+                        {
+                            if let Some(key) = vim_key_of(*key, *modifiers) {
+                                self.vim_key(key);
+                            }
+                        }
                         egui::Event::Text(text) => {
-                            if self.search_open {
-                                // If search is open, and we're focused, type into search
-                                // Actually we handle search input separately below
+                            for c in text.chars() {
+                                self.vim_record(VimKey::Char(c));
+                            }
+                            let mut chars = text.chars();
+                            match (chars.next(), chars.next()) {
+                                (Some(c), None) => self.type_char(c),
+                                _ => self.insert_text(text),
                             }
-                            // Normal text insertion
-                            if !self.search_open {
-                                self.insert_text(text);
+                            if self.completion_triggers.contains(text) {
+                                self.lsp_requests.push(LspRequest::Completion(self.cursor));
                             }
+                            // Keys later in the frame must see only what still matches
+                            self.update_completion();
                         }
                         egui::Event::Key {
                             key,
@@ -440,12 +2679,33 @@ impl Editor {
                             let cmd = modifiers.mac_cmd || modifiers.ctrl;
 
                             if cmd && *key == egui::Key::S {
-                                let _ = self.save();
-                            } else if cmd && *key == egui::Key::F {
-                                self.search_open = !self.search_open;
-                                if !self.search_open {
-                                    self.search_matches.clear();
+                                if self.format_on_save && self.file_path.is_some() {
+                                    self.format_request = Some(true);
+                                } else {
+                                    self.save_reporting();
+                                }
+                            } else if cmd && modifiers.alt && matches!(key, egui::Key::OpenBracket | egui::Key::OpenCurlyBracket) {
+                                // Cmd+Alt+[ folds at the cursor, with Shift everything
+                                if modifiers.shift {
+                                    self.fold_all();
+                                } else {
+                                    self.fold(self.content.line_of(self.cursor));
                                 }
+                            } else if cmd && modifiers.alt && matches!(key, egui::Key::CloseBracket | egui::Key::CloseCurlyBracket) {
+                                if modifiers.shift {
+                                    self.folds.clear();
+                                } else {
+                                    self.unfold(self.content.line_of(self.cursor));
+                                }
+                            } else if modifiers.alt && !cmd && *key == egui::Key::Z {
+                                self.soft_wrap = !self.soft_wrap;
+                                swallow_text = true;
+                            } else if modifiers.shift && modifiers.alt && *key == egui::Key::F {
+                                self.format_request = Some(false);
+                                swallow_text = true;
+                            } else if cmd && !modifiers.shift && *key == egui::Key::F {
+                                // Cmd+Alt+F opens with the replace row
+                                self.open_find(modifiers.alt);
                             } else if cmd && *key == egui::Key::Z {
                                 if modifiers.shift {
                                     self.redo();
@@ -454,8 +2714,8 @@ impl Editor {
                                 }
                             } else if cmd && *key == egui::Key::A {
                                 // Select all
-                                self.selection_anchor = Some(0);
-                                self.cursor = self.content.len();
+                                let len = self.content.len_bytes();
+                                self.set_selections(vec![Selection { cursor: len, anchor: Some(0) }]);
                             } else if cmd && *key == egui::Key::C {
                                 // Copy
                                 if let Some(text) = self.selected_text() {
@@ -467,27 +2727,38 @@ impl Editor {
                                     ui.ctx().copy_text(text);
                                     self.delete_selection();
                                 }
+                            } else if cmd && !modifiers.shift && *key == egui::Key::D {
+                                self.add_next_occurrence();
+                            } else if cmd && modifiers.shift && *key == egui::Key::L {
+                                self.select_all_occurrences();
                             } else if cmd && *key == egui::Key::V {
                                 // Paste handled via Event::Paste
-                            } else if self.search_open {
-                                // Search mode key handling
-                                match key {
-                                    egui::Key::Escape => {
-                                        self.search_open = false;
-                                        self.search_matches.clear();
-                                    }
-                                    egui::Key::Enter => {
-                                        if !self.search_matches.is_empty() {
-                                            self.search_current = (self.search_current + 1) % self.search_matches.len();
-                                            self.jump_to_search_match();
-                                        }
-                                    }
-                                    egui::Key::Backspace => {
-                                        self.search_query.pop();
-                                        needs_search_update = true;
-                                    }
-                                    _ => {}
-                                }
+                            } else if modifiers.ctrl && *key == egui::Key::Space {
+                                self.lsp_requests.push(LspRequest::Completion(self.cursor));
+                            } else if *key == egui::Key::F12 {
+                                self.lsp_requests.push(if modifiers.shift {
+                                    LspRequest::References(self.cursor)
+                                } else {
+                                    LspRequest::Definition(self.cursor)
+                                });
+                            } else if modifiers.ctrl && *key == egui::Key::G {
+                                self.goto_line = Some(String::new());
+                            } else if cmd && modifiers.shift && *key == egui::Key::O {
+                                self.symbol_picker = Some((String::new(), 0));
+                            } else if *key == egui::Key::F2 && self.lsp_attached {
+                                let word = self.word_range_at(self.cursor);
+                                self.rename = Some(self.content.slice(word));
+                            } else if self.completion.is_some()
+                                && matches!(
+                                    key,
+                                    egui::Key::ArrowUp
+                                        | egui::Key::ArrowDown
+                                        | egui::Key::Enter
+                                        | egui::Key::Tab
+                                        | egui::Key::Escape
+                                )
+                            {
+                                self.completion_key(*key);
                             } else {
                                 // Normal editing mode
                                 match key {
@@ -496,169 +2767,283 @@ impl Editor {
                                     egui::Key::ArrowUp => self.move_cursor_up(modifiers.shift),
                                     egui::Key::ArrowDown => self.move_cursor_down(modifiers.shift),
                                     egui::Key::Home => {
-                                        if !modifiers.shift { self.selection_anchor = None; }
-                                        else if self.selection_anchor.is_none() { self.selection_anchor = Some(self.cursor); }
-                                        let (line, _) = self.cursor_line_col();
-                                        self.cursor = self.line_start(line);
+                                        self.move_cursors(modifiers.shift, |ed, c| ed.line_start(ed.content.line_of(c)));
                                     }
                                     egui::Key::End => {
-                                        if !modifiers.shift { self.selection_anchor = None; }
-                                        else if self.selection_anchor.is_none() { self.selection_anchor = Some(self.cursor); }
-                                        let (line, _) = self.cursor_line_col();
-                                        self.cursor = self.line_end(line);
+                                        self.move_cursors(modifiers.shift, |ed, c| ed.line_end(ed.content.line_of(c)));
                                     }
                                     egui::Key::Enter => {
-                                        self.insert_text("\n");
+                                        self.vim_record(VimKey::Enter);
+                                        self.newline();
                                     }
+                                    egui::Key::Tab if modifiers.shift => self.shift_lines(false),
                                     egui::Key::Tab => {
-                                        self.insert_text("    ");
+                                        self.vim_record(VimKey::Tab);
+                                        self.tab();
                                     }
                                     egui::Key::Backspace => {
-                                        if !self.delete_selection() && self.cursor > 0 {
-                                            self.snapshot_undo();
-                                            let prev = self.content[..self.cursor]
-                                                .char_indices()
-                                                .last()
-                                                .map(|(i, _)| i)
-                                                .unwrap_or(0);
-                                            self.content.replace_range(prev..self.cursor, "");
-                                            self.cursor = prev;
-                                            self.modified = true;
-                                            self.update_line_count();
-                                        }
+                                        self.vim_record(VimKey::Backspace);
+                                        self.delete_char(false);
                                     }
                                     egui::Key::Delete => {
-                                        if !self.delete_selection() && self.cursor < self.content.len() {
-                                            self.snapshot_undo();
-                                            let next = self.content[self.cursor..]
-                                                .char_indices()
-                                                .nth(1)
-                                                .map(|(i, _)| self.cursor + i)
-                                                .unwrap_or(self.content.len());
-                                            self.content.replace_range(self.cursor..next, "");
-                                            self.modified = true;
-                                            self.update_line_count();
-                                        }
+                                        self.vim_record(VimKey::Delete);
+                                        self.delete_char(true);
                                     }
                                     egui::Key::Escape => {
-                                        self.selection_anchor = None;
+                                        // Drop the extra cursors first, then the find bar,
+                                        // then the selection; in Vim's insert mode, leave it
+                                        if self.vim_mode() == Some(Mode::Insert) {
+                                            self.vim_leave_insert();
+                                        } else if !self.extra_cursors.is_empty() {
+                                            self.extra_cursors.clear();
+                                        } else if self.find.open {
+                                            self.close_find();
+                                        } else {
+                                            self.selection_anchor = None;
+                                        }
                                     }
                                     _ => {}
                                 }
                             }
                         }
-                        egui::Event::Paste(text) => {
-                            if self.search_open {
-                                self.search_query.push_str(text);
-                                needs_search_update = true;
-                            } else {
-                                self.insert_text(text);
-                            }
-                        }
+                        egui::Event::Paste(text) => self.paste(text),
                         _ => {}
                     }
                 }
 
-                // Handle text input to search when search is open
-                if self.search_open {
-                    for event in &i.events {
-                        if let egui::Event::Text(text) = event {
-                            self.search_query.push_str(text);
-                            needs_search_update = true;
-                        }
-                    }
-                }
             });
+            self.update_completion();
+        } else if self.rename.is_none() {
+            self.completion = None;
+        }
 
-            if needs_search_update {
-                self.update_search();
-                if !self.search_matches.is_empty() {
-                    self.jump_to_search_match();
+        // Hover: diagnostics under the pointer show at once; language server info is
+        // asked for once the pointer rests on a word
+        let hovered = response.hover_pos().filter(|p| text_rect.contains(*p)).map(|p| {
+            let (row, col) = to_row_col(p - egui::vec2(char_width / 2.0, 0.0));
+            self.row_col_to_byte(row, col)
+        });
+        match hovered.map(|at| self.word_range_at(at).start) {
+            Some(word) if self.hover_probe.is_some_and(|(probe, _, _)| probe == word) => {}
+            Some(word) => {
+                self.hover_probe = Some((word, Instant::now(), false));
+                self.hover = None;
+            }
+            None => {
+                self.hover_probe = None;
+                self.hover = None;
+            }
+        }
+        if let Some((word, since, requested)) = &mut self.hover_probe {
+            if self.lsp_attached && !*requested {
+                if since.elapsed() >= HOVER_DELAY {
+                    *requested = true;
+                    self.lsp_requests.push(LspRequest::Hover(*word));
+                } else {
+                    ui.ctx().request_repaint_after(HOVER_DELAY - since.elapsed());
                 }
             }
         }
+        if let Some(at) = hovered {
+            let messages: Vec<&Diagnostic> = self
+                .diagnostics
+                .iter()
+                .filter(|d| {
+                    let range = d.range.to_bytes(&self.content);
+                    range.contains(&at) || range.start == at
+                })
+                .collect();
+            let info = self.hover.as_ref().filter(|(word, _)| Some(*word) == self.hover_probe.map(|p| p.0));
+            if !messages.is_empty() || info.is_some() {
+                response.clone().on_hover_ui_at_pointer(|ui| {
+                    ui.set_max_width(480.0);
+                    for d in &messages {
+                        let source = d.source.as_deref().map(|s| format!("{}: ", s)).unwrap_or_default();
+                        ui.colored_label(severity_color(d.severity), format!("{}{}", source, d.message));
+                    }
+                    if let Some((_, text)) = info {
+                        if !messages.is_empty() {
+                            ui.separator();
+                        }
+                        ui.label(egui::RichText::new(text).monospace());
+                    }
+                });
+            }
+        }
 
         // Scroll handling
+        self.update_layout(Some(wrap_cols));
+        let total_rows = self.row_count();
         ui.input(|i| {
             if rect.contains(i.pointer.hover_pos().unwrap_or_default()) {
-                let scroll_delta = i.smooth_scroll_delta.y;
-                self.scroll_offset = (self.scroll_offset - scroll_delta).max(0.0);
-                let max_scroll = (self.total_lines() as f32 * line_height - content_rect.height()).max(0.0);
+                let scroll_delta = i.smooth_scroll_delta;
+                self.scroll_offset = (self.scroll_offset - scroll_delta.y).max(0.0);
+                let max_scroll = (total_rows as f32 * line_height - content_rect.height()).max(0.0);
                 self.scroll_offset = self.scroll_offset.min(max_scroll);
+                if !self.soft_wrap {
+                    self.scroll_x = (self.scroll_x - scroll_delta.x).max(0.0);
+                }
             }
         });
 
-        // Ensure cursor is visible
-        let (cursor_line, _cursor_col) = self.cursor_line_col();
-        let cursor_y = cursor_line as f32 * line_height;
-        if cursor_y < self.scroll_offset {
-            self.scroll_offset = cursor_y;
-        } else if cursor_y + line_height > self.scroll_offset + content_rect.height() {
-            self.scroll_offset = cursor_y + line_height - content_rect.height();
+        // Keep the cursor in view when it moves or the text changes
+        let (cursor_row, cursor_x_col) = self.row_col(self.cursor);
+        if self.followed != (self.cursor, self.revision) {
+            self.followed = (self.cursor, self.revision);
+            let cursor_y = cursor_row as f32 * line_height;
+            if cursor_y < self.scroll_offset {
+                self.scroll_offset = cursor_y;
+            } else if cursor_y + line_height > self.scroll_offset + content_rect.height() {
+                self.scroll_offset = cursor_y + line_height - content_rect.height();
+            }
+            let cursor_x = cursor_x_col as f32 * char_width;
+            let margin = (char_width * 4.0).min(text_rect.width() / 3.0);
+            if cursor_x < self.scroll_x + margin {
+                self.scroll_x = (cursor_x - margin).max(0.0);
+            } else if cursor_x > self.scroll_x + text_rect.width() - margin {
+                self.scroll_x = cursor_x - text_rect.width() + margin;
+            }
         }
 
-        // Render lines
+        // Render rows
         let first_visible = (self.scroll_offset / line_height).floor() as usize;
-        let visible_lines = (content_rect.height() / line_height).ceil() as usize + 1;
-
-        // Get syntax colors for the file
-        let highlights = get_highlights(&self.content, self.file_path.as_ref());
+        let visible_rows = (content_rect.height() / line_height).ceil() as usize + 1;
+        let last_visible = (first_visible + visible_rows).min(total_rows) - 1;
+
+        // Don't scroll further right than the longest visible line needs
+        let widest = (first_visible..=last_visible)
+            .map(|row| wrap::width(&self.content.slice(self.row_span(row).1)))
+            .max()
+            .unwrap_or(0);
+        let max_scroll_x = (widest as f32 * char_width + char_width * 4.0 - text_rect.width()).max(0.0);
+        self.scroll_x = self.scroll_x.min(max_scroll_x);
+        let text_left = text_rect.left() - self.scroll_x;
+
+        // Syntax colours for the visible lines only
+        let visible_start = self.row_span(first_visible).1.start;
+        let visible_end = self.row_span(last_visible).1.end;
+        let highlights = self.highlights(visible_start..visible_end).to_vec();
+
+        // Visual mode selects the character under the cursor too
+        let selections = match self.vim_visual_range() {
+            Some((range, _)) => vec![Selection { cursor: range.end, anchor: Some(range.start) }],
+            None => self.selections(),
+        };
 
-        let lines: Vec<&str> = self.content.split('\n').collect();
-        let selection_range = self.selection_anchor.map(|a| {
-            let start = a.min(self.cursor);
-            let end = a.max(self.cursor);
-            (start, end)
-        });
+        // Diagnostics on the visible lines, errors last so they're drawn on top
+        let mut squiggles: Vec<(Range<usize>, Severity)> = self
+            .diagnostics
+            .iter()
+            .map(|d| (d.range.to_bytes(&self.content), d.severity))
+            .filter(|(r, _)| r.start <= visible_end && r.end >= visible_start)
+            .collect();
+        squiggles.sort_by_key(|(_, severity)| std::cmp::Reverse(*severity));
+
+        // Fold arrows: folded lines always show one, foldable ones while the pointer
+        // is over the gutter
+        let folded: Vec<usize> = self.folds.iter().map(|f| self.content.line_of(f.start)).collect();
+        let gutter_hovered = response.hover_pos().is_some_and(|p| gutter_rect.contains(p));
+        let foldable: Vec<usize> = if gutter_hovered {
+            self.fold_ranges().iter().map(|(start, _)| *start).collect()
+        } else {
+            Vec::new()
+        };
 
-        let mut byte_offset_at_line_start = 0;
-        for i in 0..first_visible.min(lines.len()) {
-            byte_offset_at_line_start += lines[i].len() + 1;
+        // Lines changed since the last commit
+        let changes: Vec<(Range<usize>, HunkKind)> = self.git_hunks().iter().map(|h| (h.new.clone(), h.kind())).collect();
+        if self.hunks_job.is_some() {
+            ui.ctx().request_repaint_after(Duration::from_millis(50));
         }
 
-        // Use a clipped painter for content area
+        // Clipped painters: the gutter stays put, text scrolls beneath it
         let painter = ui.painter().with_clip_rect(content_rect);
+        let text_painter = ui.painter().with_clip_rect(text_rect);
 
-        for vis_idx in 0..visible_lines {
-            let line_idx = first_visible + vis_idx;
-            if line_idx >= lines.len() {
-                break;
-            }
+        for row in first_visible..=last_visible {
+            let y = content_rect.top() + row as f32 * line_height - self.scroll_offset;
 
-            let y = content_rect.top() + vis_idx as f32 * line_height;
+            // Each row shows part of a line; the gutter marks only its first row
+            let (line_idx, span) = self.row_span(row);
+            let first_row = span.start == self.line_start(line_idx);
+            let line_string = self.content.slice(span.clone());
+            let line = line_string.as_str();
+            let (line_byte_start, line_byte_end) = (span.start, span.end);
 
             // Line number
-            let line_num = format!("{:>4}", line_idx + 1);
-            painter.text(
-                egui::pos2(gutter_rect.left() + 4.0, y),
-                egui::Align2::LEFT_TOP,
-                &line_num,
-                font.clone(),
-                crate::theme::TEXT_SECONDARY,
-            );
-
-            let line = lines[line_idx];
-            let line_byte_start = byte_offset_at_line_start;
-            let line_byte_end = line_byte_start + line.len();
-
-            // Draw selection highlight
-            if let Some((sel_start, sel_end)) = selection_range {
-                if sel_start < line_byte_end && sel_end > line_byte_start {
-                    let col_start = if sel_start > line_byte_start {
-                        sel_start - line_byte_start
-                    } else {
-                        0
-                    };
-                    let col_end = if sel_end < line_byte_end {
-                        sel_end - line_byte_start
+            if first_row {
+                let line_num = format!("{:>4}", line_idx + 1);
+                painter.text(
+                    egui::pos2(gutter_rect.left() + 4.0, y),
+                    egui::Align2::LEFT_TOP,
+                    &line_num,
+                    font.clone(),
+                    crate::theme::TEXT_SECONDARY,
+                );
+                let is_folded = folded.contains(&line_idx);
+                if is_folded || foldable.binary_search(&line_idx).is_ok() {
+                    let c = egui::pos2(gutter_rect.right() - 20.0, y + line_height / 2.0);
+                    let points = if is_folded {
+                        vec![c + egui::vec2(-2.0, -4.0), c + egui::vec2(2.5, 0.0), c + egui::vec2(-2.0, 4.0)]
                     } else {
-                        line.len()
+                        vec![c + egui::vec2(-4.0, -2.0), c + egui::vec2(4.0, -2.0), c + egui::vec2(0.0, 2.5)]
                     };
+                    painter.add(egui::Shape::convex_polygon(points, crate::theme::TEXT_SECONDARY, egui::Stroke::NONE));
+                }
+            }
+
+            // Change bar beside the line number; deletions are a wedge between lines
+            let bar = Rect::from_min_size(egui::pos2(gutter_rect.left() + 1.0, y), egui::vec2(3.0, line_height));
+            let last_line = line_idx + 1 == self.total_lines();
+            for (lines, kind) in &changes {
+                match kind {
+                    HunkKind::Added if lines.contains(&line_idx) => {
+                        painter.rect_filled(bar, 0.0, crate::theme::SUCCESS);
+                    }
+                    HunkKind::Modified if lines.contains(&line_idx) => {
+                        painter.rect_filled(bar, 0.0, crate::theme::ACCENT);
+                    }
+                    HunkKind::Deleted if first_row && (lines.start == line_idx || (last_line && lines.start > line_idx)) => {
+                        let tip = egui::pos2(gutter_rect.left() + 6.0, y);
+                        let points = vec![tip + egui::vec2(-5.0, -4.0), tip, tip + egui::vec2(-5.0, 4.0)];
+                        painter.add(egui::Shape::convex_polygon(points, crate::theme::ERROR, egui::Stroke::NONE));
+                    }
+                    _ => {}
+                }
+            }
+
+            // A folded line ends in a marker for the hidden text
+            if span.end == self.line_end(line_idx) && folded.contains(&line_idx) {
+                let x = text_left + (wrap::width(line) + 1) as f32 * char_width;
+                let marker = Rect::from_min_size(egui::pos2(x, y + 2.0), egui::vec2(char_width * 3.0, line_height - 4.0));
+                text_painter.rect_filled(marker, 3.0, crate::theme::BG_ELEVATED);
+                text_painter.text(marker.center(), egui::Align2::CENTER_CENTER, "...", font.clone(), crate::theme::TEXT_SECONDARY);
+            }
+
+            // Mark lines where a diagnostic starts with its worst severity
+            let whole_line = self.line_start(line_idx)..=self.line_end(line_idx);
+            let worst = squiggles
+                .iter()
+                .filter(|(range, _)| first_row && whole_line.contains(&range.start))
+                .map(|(_, severity)| *severity)
+                .min();
+            if let Some(severity) = worst {
+                painter.circle_filled(
+                    egui::pos2(gutter_rect.right() - 6.0, y + line_height / 2.0),
+                    3.0,
+                    severity_color(severity),
+                );
+            }
+
+            // Draw selection highlights
+            for sel in selections.iter().map(|s| s.range()).filter(|r| !r.is_empty()) {
+                if sel.start < line_byte_end && sel.end > line_byte_start {
+                    let col_start = wrap::width(&line[..sel.start.saturating_sub(line_byte_start)]);
+                    let col_end = wrap::width(&line[..sel.end.min(line_byte_end) - line_byte_start]);
                     let sel_rect = Rect::from_min_size(
-                        egui::pos2(text_rect.left() + col_start as f32 * char_width, y),
+                        egui::pos2(text_left + col_start as f32 * char_width, y),
                         egui::vec2((col_end - col_start) as f32 * char_width, line_height),
                     );
-                    painter.rect_filled(
+                    text_painter.rect_filled(
                         sel_rect,
                         0.0,
                         crate::theme::ACCENT.linear_multiply(0.15),
@@ -667,52 +3052,175 @@ impl Editor {
             }
 
             // Draw search match highlights
-            for &(ms, me) in &self.search_matches {
-                if ms < line_byte_end && me > line_byte_start {
-                    let col_start = ms.saturating_sub(line_byte_start);
-                    let col_end = (me - line_byte_start).min(line.len());
+            for (i, m) in self.find.matches.iter().enumerate() {
+                if m.start < line_byte_end && m.end > line_byte_start {
+                    let col_start = wrap::width(&line[..m.start.saturating_sub(line_byte_start)]);
+                    let col_end = wrap::width(&line[..m.end.min(line_byte_end) - line_byte_start]);
+                    let alpha = if i == self.find.current { 140 } else { 60 };
                     let hl_rect = Rect::from_min_size(
-                        egui::pos2(text_rect.left() + col_start as f32 * char_width, y),
+                        egui::pos2(text_left + col_start as f32 * char_width, y),
                         egui::vec2((col_end - col_start) as f32 * char_width, line_height),
                     );
-                    painter.rect_filled(
+                    text_painter.rect_filled(
                         hl_rect,
                         2.0,
-                        Color32::from_rgba_premultiplied(255, 200, 0, 60),
+                        Color32::from_rgba_unmultiplied(255, 200, 0, alpha),
                     );
                 }
             }
 
-            // Draw text with syntax highlighting
-            render_highlighted_line(
-                &painter,
-                &font,
-                char_width,
-                text_rect.left(),
-                y,
-                line,
-                line_byte_start,
-                &highlights,
-            );
+            // Draw text with syntax highlighting, splitting around the IME composition
+            if has_focus && !self.ime_preedit.is_empty() && row == cursor_row {
+                let split = (self.cursor - line_byte_start).min(line.len());
+                let (before, after) = line.split_at(split);
+                render_highlighted_line(
+                    &text_painter,
+                    &font,
+                    char_width,
+                    text_left,
+                    y,
+                    before,
+                    line_byte_start,
+                    &highlights,
+                );
+                let preedit_x = text_left + wrap::width(before) as f32 * char_width;
+                let preedit_rect = Rect::from_min_size(
+                    egui::pos2(preedit_x, y),
+                    egui::vec2(0.0, line_height),
+                );
+                let end = crate::ime::paint_ime_preedit(
+                    &text_painter,
+                    &self.ime_preedit,
+                    preedit_rect,
+                    &font,
+                    crate::theme::BG_SURFACE,
+                );
+                render_highlighted_line(
+                    &text_painter,
+                    &font,
+                    char_width,
+                    end.left(),
+                    y,
+                    after,
+                    line_byte_start + split,
+                    &highlights,
+                );
+            } else {
+                render_highlighted_line(
+                    &text_painter,
+                    &font,
+                    char_width,
+                    text_left,
+                    y,
+                    line,
+                    line_byte_start,
+                    &highlights,
+                );
+            }
+
+            // Underline diagnostics
+            for (range, severity) in &squiggles {
+                if range.start <= line_byte_end && range.end >= line_byte_start {
+                    let col_start = wrap::width(&line[..range.start.saturating_sub(line_byte_start).min(line.len())]);
+                    let col_end = wrap::width(&line[..range.end.min(line_byte_end).saturating_sub(line_byte_start)]);
+                    // Empty ranges still get a character's width
+                    let col_end = col_end.max(col_start + 1);
+                    squiggle(
+                        &text_painter,
+                        text_left + col_start as f32 * char_width,
+                        text_left + col_end as f32 * char_width,
+                        y + line_height - 2.0,
+                        severity_color(*severity),
+                    );
+                }
+            }
+        }
 
-            byte_offset_at_line_start = line_byte_end + 1; // +1 for '\n'
+        // Outline the bracket at the cursor and its match
+        if has_focus && !self.has_selection() {
+            let brackets = crate::indent::matching_bracket(&self.content, self.cursor).into_iter().flat_map(|(a, b)| [a, b]);
+            for at in brackets.filter(|&at| !self.folds.iter().any(|f| f.start < at && at <= f.end)) {
+                let (row, col) = self.row_col(at);
+                if (first_visible..=last_visible).contains(&row) {
+                    let pos = egui::pos2(
+                        text_left + col as f32 * char_width,
+                        content_rect.top() + row as f32 * line_height - self.scroll_offset,
+                    );
+                    text_painter.rect_stroke(
+                        Rect::from_min_size(pos, egui::vec2(char_width, line_height)),
+                        2.0,
+                        egui::Stroke::new(1.0, crate::theme::TEXT_SECONDARY),
+                        egui::StrokeKind::Inside,
+                    );
+                }
+            }
         }
 
         // Draw cursor
+        let mut caret_pos = None;
+        if has_focus && (first_visible..=last_visible).contains(&cursor_row) {
+            let cx = text_left + cursor_x_col as f32 * char_width;
+            let cy = content_rect.top() + cursor_row as f32 * line_height - self.scroll_offset;
+            // Vim's normal and visual modes show a block over the character
+            let block = self.vim.as_ref().is_some_and(|v| v.mode != Mode::Insert && v.command_line.is_none());
+            let (width, color) = if block {
+                let under = self.content.slice(self.cursor..self.content.next_char(self.cursor)).chars().next();
+                let cols = under.filter(|&c| c != '\n').map_or(1, wrap::char_cols);
+                (cols as f32 * char_width, crate::theme::ACCENT.linear_multiply(0.5))
+            } else {
+                (2.0, crate::theme::ACCENT)
+            };
+            let cursor_rect = Rect::from_min_size(
+                egui::pos2(cx, cy),
+                egui::vec2(width, line_height),
+            );
+            caret_pos = Some(cursor_rect.left_bottom());
+            let ime_cursor_rect = if self.ime_preedit.is_empty() {
+                text_painter.rect_filled(cursor_rect, 0.0, color);
+                cursor_rect
+            } else {
+                // Composition text was drawn inline; the candidate window goes after it
+                let width = text_painter
+                    .layout_no_wrap(self.ime_preedit.clone(), font.clone(), crate::theme::TEXT_PRIMARY)
+                    .size()
+                    .x;
+                cursor_rect.translate(egui::vec2(width, 0.0))
+            };
+            crate::ime::set_ime_output(ui, text_rect, ime_cursor_rect);
+        }
         if has_focus {
-            let (c_line, c_col) = self.cursor_line_col();
-            if c_line >= first_visible && c_line < first_visible + visible_lines {
-                let vis = c_line - first_visible;
-                let cx = text_rect.left() + c_col as f32 * char_width;
-                let cy = content_rect.top() + vis as f32 * line_height;
-                let cursor_rect = Rect::from_min_size(
-                    egui::pos2(cx, cy),
-                    egui::vec2(2.0, line_height),
+            for extra in &self.extra_cursors {
+                let (row, col) = self.row_col(extra.cursor);
+                if !(first_visible..=last_visible).contains(&row) {
+                    continue;
+                }
+                let pos = egui::pos2(
+                    text_left + col as f32 * char_width,
+                    content_rect.top() + row as f32 * line_height - self.scroll_offset,
+                );
+                text_painter.rect_filled(
+                    Rect::from_min_size(pos, egui::vec2(2.0, line_height)),
+                    0.0,
+                    crate::theme::ACCENT,
                 );
-                painter.rect_filled(cursor_rect, 0.0, crate::theme::ACCENT);
             }
         }
 
+        if let Some(pos) = caret_pos {
+            self.render_completion(ui, pos);
+        }
+        self.render_rename(ui, text_rect, caret_pos);
+        let palette_pos = egui::pos2(text_rect.center().x - 160.0, text_rect.top() + 8.0).max(text_rect.left_top());
+        self.render_goto_line(ui, palette_pos);
+        self.render_symbol_picker(ui, palette_pos);
+        if let Some(lines) = &self.hunk_popup {
+            // Below the changed lines, or where deleted ones were
+            let last = if lines.is_empty() { lines.start.saturating_sub(1) } else { lines.end - 1 };
+            let (row, _) = self.row_col(self.line_end(last.min(self.total_lines() - 1)));
+            let y = content_rect.top() + (row + 1) as f32 * line_height - self.scroll_offset;
+            self.render_hunk_popup(ui, egui::pos2(gutter_rect.left() + 8.0, y.clamp(content_rect.top(), content_rect.bottom())));
+        }
+
         // Focus border
         if has_focus {
             ui.painter().rect_stroke(
@@ -725,145 +3233,317 @@ impl Editor {
     }
 }
 
-/// Simple syntax highlight token
-#[derive(Clone)]
-struct HighlightSpan {
-    start: usize, // byte offset in content
-    end: usize,
-    color: Color32,
-}
-
-/// Extension-based keyword highlighting
-/// TODO: Replace with tree-sitter for proper AST-based highlighting
-fn get_highlights(content: &str, path: Option<&PathBuf>) -> Vec<HighlightSpan> {
-    let ext = path
-        .and_then(|p| p.extension())
-        .and_then(|e| e.to_str())
-        .unwrap_or("");
-
-    let (keywords, types, constants) = match ext {
-        "rs" => (
-            &["fn", "let", "mut", "pub", "use", "mod", "struct", "enum", "impl", "trait",
-              "for", "while", "loop", "if", "else", "match", "return", "self", "Self",
-              "crate", "super", "where", "async", "await", "move", "ref", "type", "const",
-              "static", "unsafe", "extern", "as", "in", "break", "continue", "dyn"][..],
-            &["i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64",
-              "u128", "usize", "f32", "f64", "bool", "char", "str", "String",
-              "Vec", "Option", "Result", "Box", "Rc", "Arc", "HashMap", "HashSet"][..],
-            &["true", "false", "None", "Some", "Ok", "Err"][..],
-        ),
-        "py" => (
-            &["def", "class", "import", "from", "if", "elif", "else", "for", "while",
-              "return", "yield", "with", "as", "try", "except", "finally", "raise",
-              "pass", "break", "continue", "and", "or", "not", "in", "is", "lambda",
-              "global", "nonlocal", "assert", "del", "async", "await"][..],
-            &["int", "float", "str", "bool", "list", "dict", "tuple", "set", "None",
-              "bytes", "type", "object"][..],
-            &["True", "False", "None"][..],
-        ),
-        "js" | "ts" | "jsx" | "tsx" => (
-            &["function", "const", "let", "var", "if", "else", "for", "while", "do",
-              "return", "class", "extends", "new", "this", "super", "import", "export",
-              "default", "from", "try", "catch", "finally", "throw", "async", "await",
-              "yield", "switch", "case", "break", "continue", "typeof", "instanceof",
-              "of", "in", "delete", "void"][..],
-            &["string", "number", "boolean", "any", "void", "never", "unknown",
-              "interface", "type", "enum", "namespace"][..],
-            &["true", "false", "null", "undefined", "NaN", "Infinity"][..],
-        ),
-        "json" => (
-            &[][..],
-            &[][..],
-            &["true", "false", "null"][..],
-        ),
-        _ => return Vec::new(),
-    };
-
-    let keyword_color = Color32::from_rgb(198, 120, 221);  // purple
-    let type_color = Color32::from_rgb(229, 192, 123);     // yellow
-    let constant_color = Color32::from_rgb(209, 154, 102); // orange
-    let string_color = Color32::from_rgb(152, 195, 121);   // green
-    let comment_color = Color32::from_rgb(150, 150, 150);  // gray
-    let number_color = Color32::from_rgb(209, 154, 102);   // orange
-
-    let mut spans = Vec::new();
-    let bytes = content.as_bytes();
-    let len = bytes.len();
-    let mut i = 0;
-
-    while i < len {
-        let b = bytes[i];
-
-        // Line comments
-        if b == b'/' && i + 1 < len && bytes[i + 1] == b'/' {
-            let start = i;
-            while i < len && bytes[i] != b'\n' {
-                i += 1;
-            }
-            spans.push(HighlightSpan { start, end: i, color: comment_color });
-            continue;
+impl Editor {
+    /// Completion list under the cursor
+    fn render_completion(&mut self, ui: &mut egui::Ui, pos: egui::Pos2) {
+        let matches = self.completion_matches();
+        let Some(popup) = &self.completion else { return };
+        let row = matches.iter().position(|&i| i == popup.selected).unwrap_or(0);
+        let first = row.saturating_sub(COMPLETION_ROWS - 1);
+        let mut chosen = None;
+        egui::Area::new(ui.id().with(("editor_completion", self.id)))
+            .fixed_pos(pos)
+            .order(egui::Order::Foreground)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.set_min_width(220.0);
+                    for &i in matches.iter().skip(first).take(COMPLETION_ROWS) {
+                        let item = &popup.items[i];
+                        let mut job = egui::text::LayoutJob::default();
+                        let mono = FontId::monospace(13.0);
+                        job.append(&item.label, 0.0, egui::TextFormat::simple(mono.clone(), crate::theme::TEXT_PRIMARY));
+                        if let Some(detail) = &item.detail {
+                            job.append(&format!("  {}", detail), 0.0, egui::TextFormat::simple(mono, crate::theme::TEXT_SECONDARY));
+                        }
+                        if ui.selectable_label(i == popup.selected, job).clicked() {
+                            chosen = Some(i);
+                        }
+                    }
+                    if matches.len() > COMPLETION_ROWS {
+                        ui.label(egui::RichText::new(format!("{} of {}", row + 1, matches.len())).size(11.0).color(crate::theme::TEXT_SECONDARY));
+                    }
+                });
+            });
+        if let Some(i) = chosen {
+            self.accept_completion(i);
+            self.grab_focus = true;
         }
+    }
 
-        // Hash comments (Python)
-        if b == b'#' && (ext == "py") {
-            let start = i;
-            while i < len && bytes[i] != b'\n' {
-                i += 1;
+    /// F2 rename box at the cursor; Enter asks the language server to rename
+    fn render_rename(&mut self, ui: &mut egui::Ui, text_rect: Rect, caret: Option<egui::Pos2>) {
+        let Some(name) = &mut self.rename else { return };
+        let pos = caret.unwrap_or(text_rect.left_top());
+        let id = ui.id().with(("editor_rename", self.id));
+        let (mut submit, mut cancel) = (false, false);
+        egui::Area::new(id.with("area"))
+            .fixed_pos(pos)
+            .order(egui::Order::Foreground)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let response = ui.add(egui::TextEdit::singleline(name).id(id).desired_width(200.0));
+                    if !response.has_focus() && !response.lost_focus() {
+                        response.request_focus();
+                    }
+                    if response.lost_focus() {
+                        if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                            submit = true;
+                        } else {
+                            cancel = true;
+                        }
+                    }
+                });
+            });
+        if submit || cancel {
+            if let Some(name) = self.rename.take().filter(|n| submit && !n.is_empty()) {
+                self.lsp_requests.push(LspRequest::Rename(self.cursor, name));
             }
-            spans.push(HighlightSpan { start, end: i, color: comment_color });
-            continue;
+            self.grab_focus = true;
         }
+    }
 
-        // Strings
-        if b == b'"' || b == b'\'' {
-            let quote = b;
-            let start = i;
-            i += 1;
-            while i < len && bytes[i] != quote {
-                if bytes[i] == b'\\' {
-                    i += 1;
-                }
-                i += 1;
-            }
-            if i < len { i += 1; }
-            spans.push(HighlightSpan { start, end: i, color: string_color });
-            continue;
+    /// Symbols in the file: the language server's once it has sent some, else the
+    /// syntax tree's
+    fn outline(&mut self) -> &[Symbol] {
+        let asked = self.outline_asked.is_some_and(|(revision, at)| {
+            revision == self.revision || at.elapsed() < OUTLINE_INTERVAL
+        });
+        if self.lsp_attached && !asked {
+            self.lsp_requests.push(LspRequest::DocumentSymbols);
+            self.outline_asked = Some((self.revision, Instant::now()));
+        }
+        if self.lsp_outline.is_none() && self.syntax_outline.as_ref().is_none_or(|(r, _)| *r != self.revision) {
+            let symbols = self.syntax.as_mut().and_then(|s| s.outline(&self.content)).unwrap_or_default();
+            self.syntax_outline = Some((self.revision, symbols));
+        }
+        match &self.lsp_outline {
+            Some(symbols) => symbols,
+            None => self.syntax_outline.as_ref().map(|(_, s)| s.as_slice()).unwrap_or_default(),
         }
+    }
+
+    /// The language server's answer to `DocumentSymbols`
+    pub fn set_lsp_outline(&mut self, symbols: &[LspSymbol]) {
+        let mut symbols: Vec<Symbol> = symbols.iter().map(|s| s.to_symbol(&self.content)).collect();
+        outline::nest(&mut symbols);
+        // An empty outline usually means the server isn't ready yet
+        self.lsp_outline = (!symbols.is_empty()).then_some(symbols);
+    }
+
+    /// Put the cursor at `pos` and give the text focus back
+    fn jump_to(&mut self, pos: usize) {
+        self.set_cursor(pos);
+        self.vim_clamp();
+        self.grab_focus = true;
+    }
 
-        // Numbers
-        if b.is_ascii_digit() && (i == 0 || !bytes[i-1].is_ascii_alphanumeric()) {
-            let start = i;
-            while i < len && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.' || bytes[i] == b'_') {
-                i += 1;
+    /// File name and the symbols around the cursor, outermost first; clicking one
+    /// jumps to it
+    fn render_breadcrumbs(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        let cursor = self.cursor;
+        let chain: Vec<Symbol> = outline::enclosing(self.outline(), cursor).into_iter().cloned().collect();
+        // Edits since the language server last answered are picked up once typing pauses
+        if self.lsp_attached && self.outline_asked.is_some_and(|(revision, _)| revision != self.revision) {
+            ui.ctx().request_repaint_after(OUTLINE_INTERVAL);
+        }
+        ui.painter().hline(rect.x_range(), rect.bottom() - 0.5, egui::Stroke::new(1.0, crate::theme::BORDER));
+        let mut child = ui.new_child(
+            egui::UiBuilder::new()
+                .max_rect(rect.shrink2(egui::vec2(8.0, 0.0)))
+                .layout(egui::Layout::left_to_right(egui::Align::Center)),
+        );
+        child.spacing_mut().item_spacing.x = 2.0;
+        let file = self.file_path.as_ref().and_then(|p| p.file_name()).map(|n| n.to_string_lossy().to_string());
+        if let Some(file) = &file {
+            child.label(egui::RichText::new(file).size(12.0).color(crate::theme::TEXT_SECONDARY));
+        }
+        let mut jump = None;
+        for (i, symbol) in chain.iter().enumerate() {
+            if i > 0 || file.is_some() {
+                child.label(egui::RichText::new("›").size(12.0).color(crate::theme::TEXT_SECONDARY));
+            }
+            let name = egui::RichText::new(&symbol.name).size(12.0).color(crate::theme::TEXT_PRIMARY);
+            if child.add(egui::Button::new(name).frame(false)).on_hover_text(symbol.kind.label()).clicked() {
+                jump = Some(symbol.at);
             }
-            spans.push(HighlightSpan { start, end: i, color: number_color });
-            continue;
         }
+        if let Some(at) = jump {
+            self.jump_to(at);
+        }
+    }
 
-        // Identifiers / keywords
-        if b.is_ascii_alphabetic() || b == b'_' {
-            let start = i;
-            while i < len && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
-                i += 1;
-            }
-            let word = &content[start..i];
-            if keywords.contains(&word) {
-                spans.push(HighlightSpan { start, end: i, color: keyword_color });
-            } else if types.contains(&word) {
-                spans.push(HighlightSpan { start, end: i, color: type_color });
-            } else if constants.contains(&word) {
-                spans.push(HighlightSpan { start, end: i, color: constant_color });
+    /// Ctrl+G box at the top of the text; Enter goes to `line[:column]`
+    fn render_goto_line(&mut self, ui: &mut egui::Ui, pos: egui::Pos2) {
+        let lines = self.total_lines();
+        let Some(target) = &mut self.goto_line else { return };
+        let id = ui.id().with(("editor_goto_line", self.id));
+        let (mut submit, mut cancel) = (false, false);
+        egui::Area::new(id.with("area"))
+            .fixed_pos(pos)
+            .order(egui::Order::Foreground)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let hint = format!("Line[:column], 1 to {}", lines);
+                    let edit = egui::TextEdit::singleline(target).id(id).hint_text(hint).desired_width(320.0);
+                    let response = ui.add(edit);
+                    if !response.has_focus() && !response.lost_focus() {
+                        response.request_focus();
+                    }
+                    if response.lost_focus() {
+                        if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                            submit = true;
+                        } else {
+                            cancel = true;
+                        }
+                    }
+                });
+            });
+        if submit || cancel {
+            let target = self.goto_line.take().filter(|_| submit);
+            if let Some((line, col)) = target.as_deref().and_then(outline::parse_line_col) {
+                let line = line.min(lines - 1);
+                self.jump_to(self.line_col_to_byte(line, col.unwrap_or(0)));
+            } else {
+                self.grab_focus = true;
             }
-            continue;
         }
+    }
+
+    /// Cmd+Shift+O list of the file's symbols, narrowed by typing; Enter jumps to the
+    /// highlighted one
+    fn render_symbol_picker(&mut self, ui: &mut egui::Ui, pos: egui::Pos2) {
+        if self.symbol_picker.is_none() {
+            return;
+        }
+        let symbols = self.outline().to_vec();
+        let lines: Vec<usize> = symbols.iter().map(|s| self.content.line_of(s.at) + 1).collect();
+        let Some((query, selected)) = &mut self.symbol_picker else { return };
+        let id = ui.id().with(("editor_symbols", self.id));
+        let (up, down) = ui.input(|i| (i.key_pressed(egui::Key::ArrowUp), i.key_pressed(egui::Key::ArrowDown)));
+        let mut matches = outline::filter(&symbols, query);
+        if down {
+            *selected += 1;
+        }
+        if up {
+            *selected = selected.saturating_sub(1);
+        }
+        *selected = (*selected).min(matches.len().saturating_sub(1));
+        let (mut chosen, mut cancel) = (None, false);
+        let area = egui::Area::new(id.with("area"))
+            .fixed_pos(pos)
+            .order(egui::Order::Foreground)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.set_width(320.0);
+                    let edit = egui::TextEdit::singleline(query).id(id).hint_text("Go to symbol").desired_width(320.0);
+                    let response = ui.add(edit);
+                    if !response.has_focus() && !response.lost_focus() {
+                        response.request_focus();
+                    }
+                    if response.changed() {
+                        matches = outline::filter(&symbols, query);
+                        *selected = 0;
+                    }
+                    // Clicking a row takes focus from the query, so only Enter and Escape
+                    // close the picker from here
+                    if response.lost_focus() {
+                        let (enter, escape) =
+                            ui.input(|i| (i.key_pressed(egui::Key::Enter), i.key_pressed(egui::Key::Escape)));
+                        if enter {
+                            chosen = matches.get(*selected).copied();
+                        }
+                        cancel = enter || escape;
+                    }
+                    if matches.is_empty() {
+                        let text = if symbols.is_empty() { "No symbols in this file" } else { "No matching symbols" };
+                        ui.label(egui::RichText::new(text).size(12.0).color(crate::theme::TEXT_SECONDARY));
+                    }
+                    let first = selected.saturating_sub(SYMBOL_ROWS - 1);
+                    // Nesting is shown while the whole outline is listed
+                    let nested = query.trim().is_empty();
+                    for (row, &i) in matches.iter().enumerate().skip(first).take(SYMBOL_ROWS) {
+                        let symbol = &symbols[i];
+                        let mut job = egui::text::LayoutJob::default();
+                        let mono = FontId::monospace(13.0);
+                        let indent = if nested { "  ".repeat(symbol.depth) } else { String::new() };
+                        let kind = format!("{}{:<7}", indent, symbol.kind.label());
+                        job.append(&kind, 0.0, egui::TextFormat::simple(mono.clone(), crate::theme::TEXT_SECONDARY));
+                        job.append(&symbol.name, 0.0, egui::TextFormat::simple(mono.clone(), crate::theme::TEXT_PRIMARY));
+                        job.append(&format!("  :{}", lines[i]), 0.0, egui::TextFormat::simple(mono, crate::theme::TEXT_SECONDARY));
+                        if ui.selectable_label(row == *selected, job).clicked() {
+                            chosen = Some(i);
+                        }
+                    }
+                    if matches.len() > SYMBOL_ROWS {
+                        let text = format!("{} of {}", *selected + 1, matches.len());
+                        ui.label(egui::RichText::new(text).size(11.0).color(crate::theme::TEXT_SECONDARY));
+                    }
+                });
+            });
+        cancel |= area.response.clicked_elsewhere();
+        if let Some(i) = chosen {
+            self.symbol_picker = None;
+            self.jump_to(symbols[i].at);
+        } else if cancel {
+            self.symbol_picker = None;
+            self.grab_focus = true;
+        }
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A key press as Vim sees it; letters and symbols arrive as text instead. Ctrl+[
+/// is Escape.
+fn vim_key_of(key: egui::Key, modifiers: egui::Modifiers) -> Option<VimKey> {
+    if modifiers.ctrl {
+        return match key {
+            egui::Key::R => Some(VimKey::Ctrl('r')),
+            egui::Key::OpenBracket => Some(VimKey::Esc),
+            _ => None,
+        };
+    }
+    if modifiers.alt || modifiers.mac_cmd {
+        return None;
+    }
+    Some(match key {
+        egui::Key::Escape => VimKey::Esc,
+        egui::Key::Enter => VimKey::Enter,
+        egui::Key::Backspace => VimKey::Backspace,
+        egui::Key::Delete => VimKey::Delete,
+        egui::Key::Tab => VimKey::Tab,
+        egui::Key::ArrowLeft => VimKey::Left,
+        egui::Key::ArrowRight => VimKey::Right,
+        egui::Key::ArrowUp => VimKey::Up,
+        egui::Key::ArrowDown => VimKey::Down,
+        _ => return None,
+    })
+}
 
-        i += 1;
+pub fn severity_color(severity: Severity) -> Color32 {
+    match severity {
+        Severity::Error => crate::theme::ERROR,
+        Severity::Warning => crate::theme::WARNING,
+        Severity::Information | Severity::Hint => crate::theme::ACCENT,
     }
+}
 
-    spans
+/// Wavy underline from `x0` to `x1`
+fn squiggle(painter: &egui::Painter, x0: f32, x1: f32, y: f32, color: Color32) {
+    let step = 2.0;
+    let points: Vec<egui::Pos2> = (0..=((x1 - x0) / step).ceil() as usize)
+        .map(|i| egui::pos2((x0 + i as f32 * step).min(x1), if i % 2 == 0 { y } else { y - 1.5 }))
+        .collect();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
 }
 
-fn render_highlighted_line(
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_highlighted_line(
     painter: &egui::Painter,
     font: &FontId,
     char_width: f32,
@@ -884,7 +3564,8 @@ fn render_highlighted_line(
         .filter(|s| s.start < line_byte_end && s.end > line_byte_start)
         .collect();
 
-    if relevant.is_empty() {
+    let narrow = wrap::width(line) == line.chars().count();
+    if relevant.is_empty() && narrow {
         painter.text(
             egui::pos2(x_start, y),
             egui::Align2::LEFT_TOP,
@@ -905,6 +3586,10 @@ fn render_highlighted_line(
             .map(|s| s.color)
             .unwrap_or(default_color);
 
+        if ch == '\t' {
+            col += wrap::char_cols(ch);
+            continue;
+        }
         let mut buf = [0u8; 4];
         let s = ch.encode_utf8(&mut buf);
         painter.text(
@@ -914,6 +3599,7 @@ fn render_highlighted_line(
             font.clone(),
             color,
         );
-        col += 1;
+        col += wrap::char_cols(ch);
     }
 }
+
@@ -0,0 +1,156 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn label(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+/// How a file's text is stored on disk; the editor always works on UTF-8 with `\n`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileEncoding {
+    pub encoding: &'static Encoding,
+    /// Whether the file starts with a byte order mark
+    pub bom: bool,
+    pub line_ending: LineEnding,
+}
+
+impl Default for FileEncoding {
+    fn default() -> Self {
+        Self { encoding: UTF_8, bom: false, line_ending: LineEnding::Lf }
+    }
+}
+
+impl FileEncoding {
+    pub fn label(&self) -> String {
+        let name = match self.encoding.name() {
+            "UTF-16LE" => "UTF-16 LE",
+            "UTF-16BE" => "UTF-16 BE",
+            name => name,
+        };
+        if self.bom && self.encoding == UTF_8 {
+            format!("{} with BOM", name)
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+/// Encodings offered in the editor's status bar
+pub const CHOICES: &[&Encoding] = &[
+    UTF_8,
+    UTF_16LE,
+    UTF_16BE,
+    encoding_rs::SHIFT_JIS,
+    encoding_rs::EUC_JP,
+    encoding_rs::ISO_2022_JP,
+    encoding_rs::GBK,
+    encoding_rs::BIG5,
+    encoding_rs::EUC_KR,
+    encoding_rs::WINDOWS_1252,
+];
+
+/// Decode a file, working out its encoding from a BOM, else from its contents
+pub fn decode(bytes: &[u8]) -> (String, FileEncoding) {
+    decode_as(bytes, detect(bytes))
+}
+
+/// A file's encoding, from a BOM, else from its contents. `bytes` may be just the
+/// start of the file: a character cut off at the end doesn't count against UTF-8.
+pub fn detect(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => UTF_8,
+        Err(e) if e.error_len().is_none() => UTF_8,
+        Err(_) => {
+            let mut detector = chardetng::EncodingDetector::new();
+            detector.feed(bytes, true);
+            detector.guess(None, true)
+        }
+    }
+}
+
+/// Decode a file as `encoding` (a BOM still wins), with line endings normalised to `\n`
+pub fn decode_as(bytes: &[u8], encoding: &'static Encoding) -> (String, FileEncoding) {
+    let (encoding, bom_len) = Encoding::for_bom(bytes).unwrap_or((encoding, 0));
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+    // The line ending most lines use; lone `\r`s are left alone
+    let crlf = text.matches("\r\n").count();
+    let line_ending = if crlf > 0 && crlf * 2 >= text.matches('\n').count() { LineEnding::CrLf } else { LineEnding::Lf };
+    let text = if crlf > 0 { text.replace("\r\n", "\n") } else { text.into_owned() };
+    (text, FileEncoding { encoding, bom: bom_len > 0, line_ending })
+}
+
+/// Encode editor text for writing back; fails if it has characters the encoding
+/// can't represent
+pub fn encode(text: &str, format: FileEncoding) -> Result<Vec<u8>, String> {
+    let text = match format.line_ending {
+        LineEnding::Lf => std::borrow::Cow::Borrowed(text),
+        LineEnding::CrLf => std::borrow::Cow::Owned(text.replace('\n', "\r\n")),
+    };
+    // encoding_rs only decodes UTF-16, so encode it by hand
+    let utf16 = |to_bytes: fn(u16) -> [u8; 2]| {
+        let mut bytes: Vec<u8> = if format.bom { to_bytes(0xFEFF).to_vec() } else { Vec::new() };
+        bytes.extend(text.encode_utf16().flat_map(to_bytes));
+        bytes
+    };
+    if format.encoding == UTF_16LE {
+        return Ok(utf16(u16::to_le_bytes));
+    }
+    if format.encoding == UTF_16BE {
+        return Ok(utf16(u16::to_be_bytes));
+    }
+    let mut bytes = if format.bom && format.encoding == UTF_8 { vec![0xEF, 0xBB, 0xBF] } else { Vec::new() };
+    let (encoded, _, unmappable) = format.encoding.encode(&text);
+    if unmappable {
+        let c = text.chars().find(|c| {
+            let mut buf = [0; 4];
+            format.encoding.encode(c.encode_utf8(&mut buf)).2
+        });
+        return Err(format!("{} can't be saved as {}", c.map(|c| format!("'{}'", c)).unwrap_or_default(), format.label()));
+    }
+    bytes.extend_from_slice(&encoded);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_encodings_and_line_endings() {
+        let sjis = encoding_rs::SHIFT_JIS.encode("こんにちは、世界\r\n日本語のテキスト\r\n").0.into_owned();
+        let (text, format) = decode(&sjis);
+        assert_eq!(text, "こんにちは、世界\n日本語のテキスト\n");
+        assert_eq!(format.encoding, encoding_rs::SHIFT_JIS);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert_eq!(encode(&text, format).unwrap(), sjis);
+
+        let utf16: Vec<u8> = [0xFF, 0xFE].into_iter().chain("a\nb".encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        let (text, format) = decode(&utf16);
+        assert_eq!((text.as_str(), format.encoding, format.bom), ("a\nb", UTF_16LE, true));
+        assert_eq!(encode(&text, format).unwrap(), utf16);
+
+        let bom = b"\xEF\xBB\xBFplain\n";
+        let (text, format) = decode(bom);
+        assert_eq!((text.as_str(), format.label()), ("plain\n", "UTF-8 with BOM".to_string()));
+        assert_eq!(encode(&text, format).unwrap(), bom);
+    }
+
+    #[test]
+    fn reports_unmappable_characters() {
+        let format = FileEncoding { encoding: encoding_rs::SHIFT_JIS, ..Default::default() };
+        assert_eq!(encode("日本 😀", format).unwrap_err(), "'😀' can't be saved as Shift_JIS");
+    }
+}
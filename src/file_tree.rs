@@ -1,23 +1,49 @@
 use eframe::egui::{self, Rect};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 pub struct FileTree {
     pub root: PathBuf,
-    expanded: std::collections::HashSet<PathBuf>,
+    expanded: HashSet<PathBuf>,
     pending_open: Option<PathBuf>,
+    /// File picked with "Select for Compare", to compare the next one with
+    compare_base: Option<PathBuf>,
+    pending_compare: Option<(PathBuf, PathBuf)>,
+    /// Directory listings, re-read when the watcher reports a change inside
+    listings: HashMap<PathBuf, Vec<PathBuf>>,
 }
 
 impl FileTree {
     pub fn new(root: PathBuf) -> Self {
-        let mut expanded = std::collections::HashSet::new();
+        let mut expanded = HashSet::new();
         expanded.insert(root.clone());
-        Self { root, expanded, pending_open: None }
+        Self { root, expanded, pending_open: None, compare_base: None, pending_compare: None, listings: HashMap::new() }
+    }
+
+    /// Directories whose contents are on screen
+    pub fn expanded_dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.expanded.iter()
+    }
+
+    /// Forget the listings of the directories holding changed paths
+    pub fn invalidate(&mut self, changed: &HashSet<PathBuf>) {
+        for path in changed {
+            if let Some(parent) = path.parent() {
+                self.listings.remove(parent);
+            }
+            self.listings.remove(path);
+        }
     }
 
     pub fn take_pending_open(&mut self) -> Option<PathBuf> {
         self.pending_open.take()
     }
 
+    /// Two files to compare, the first as the old version
+    pub fn take_pending_compare(&mut self) -> Option<(PathBuf, PathBuf)> {
+        self.pending_compare.take()
+    }
+
     pub fn render(&mut self, ui: &mut egui::Ui, rect: Rect) {
         ui.painter()
             .rect_filled(rect, 0.0, crate::theme::BG_SURFACE);
@@ -33,7 +59,14 @@ impl FileTree {
     }
 
     fn render_dir(&mut self, ui: &mut egui::Ui, path: &Path, depth: usize) {
-        let entries = self.read_dir_filtered(path);
+        let entries = match self.listings.get(path) {
+            Some(entries) => entries.clone(),
+            None => {
+                let entries = self.read_dir_filtered(path);
+                self.listings.insert(path.to_path_buf(), entries.clone());
+                entries
+            }
+        };
 
         for entry in entries {
             let is_dir = entry.is_dir();
@@ -63,6 +96,8 @@ impl FileTree {
                         if self.expanded.contains(&entry) {
                             self.expanded.remove(&entry);
                         } else {
+                            // Re-read on expanding, in case the watcher missed something
+                            self.listings.remove(&entry);
                             self.expanded.insert(entry.clone());
                         }
                     } else {
@@ -70,6 +105,22 @@ impl FileTree {
                         self.pending_open = Some(entry.clone());
                     }
                 }
+                if !is_dir {
+                    resp.context_menu(|ui| {
+                        if ui.button("Select for Compare").clicked() {
+                            self.compare_base = Some(entry.clone());
+                            ui.close_menu();
+                        }
+                        let base = self.compare_base.clone().filter(|base| *base != entry);
+                        if let Some(base) = base {
+                            let name = base.file_name().unwrap_or_default().to_string_lossy().to_string();
+                            if ui.button(format!("Compare with '{}'", name)).clicked() {
+                                self.pending_compare = Some((base, entry.clone()));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                }
             });
 
             if is_dir && self.expanded.contains(&entry) {
@@ -87,14 +138,12 @@ impl FileTree {
             .hidden(false)
             .build();
 
-        for result in walker {
-            if let Ok(entry) = result {
-                let p = entry.into_path();
-                if p == path {
-                    continue;
-                }
-                entries.push(p);
+        for entry in walker.flatten() {
+            let p = entry.into_path();
+            if p == path {
+                continue;
             }
+            entries.push(p);
         }
 
         // Sort: dirs first, then alphabetical
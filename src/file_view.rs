@@ -0,0 +1,388 @@
+use crate::encoding::{FileEncoding, LineEnding};
+use eframe::egui::{self, FontId, Rect};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use memmap2::Mmap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Text files larger than this open read-only instead of in the editor
+pub const LARGE_FILE_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Bytes looked at to tell binary files from text
+const SNIFF_LEN: usize = 8000;
+
+/// Bytes indexed between publishing progress to the UI
+const INDEX_CHUNK: usize = 4 * 1024 * 1024;
+
+const BYTES_PER_ROW: usize = 16;
+
+/// How a file should be opened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Text,
+    LargeText,
+    Binary,
+}
+
+/// Binary if the start has a NUL byte (like git does), unless it has a UTF-16 BOM;
+/// large text past `LARGE_FILE_SIZE`
+pub fn classify(path: &Path) -> io::Result<FileKind> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.by_ref().take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+    Ok(if is_binary(&head) {
+        FileKind::Binary
+    } else if len > LARGE_FILE_SIZE {
+        FileKind::LargeText
+    } else {
+        FileKind::Text
+    })
+}
+
+/// Whether a file starting with `head` is binary: it has a NUL byte in its first
+/// `SNIFF_LEN` bytes and no UTF-16 BOM
+pub fn is_binary(head: &[u8]) -> bool {
+    let utf16 = matches!(Encoding::for_bom(head), Some((e, _)) if e != UTF_8);
+    !utf16 && head[..head.len().min(SNIFF_LEN)].contains(&0)
+}
+
+/// Start offsets of the lines of a mapped file, found in the background
+struct LineIndex {
+    starts: Arc<Mutex<Vec<usize>>>,
+    done: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl LineIndex {
+    /// Index the text after the first `skip` bytes (a BOM). UTF-16 is scanned in
+    /// two-byte units, so bytes of other characters aren't taken for newlines.
+    fn start(map: Arc<Mmap>, encoding: &'static Encoding, skip: usize) -> Self {
+        let starts = Arc::new(Mutex::new(vec![skip]));
+        let done = Arc::new(AtomicBool::new(false));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (sink, finished, stop) = (starts.clone(), done.clone(), cancelled.clone());
+        let newline: &[u8] = match encoding {
+            e if e == UTF_16LE => b"\n\0",
+            e if e == UTF_16BE => b"\0\n",
+            _ => b"\n",
+        };
+        std::thread::spawn(move || {
+            // `INDEX_CHUNK` is even, so chunks keep to UTF-16 units
+            for (chunk_index, chunk) in map[skip..].chunks(INDEX_CHUNK).enumerate() {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                let base = skip + chunk_index * INDEX_CHUNK;
+                let found: Vec<usize> = chunk
+                    .chunks(newline.len())
+                    .enumerate()
+                    .filter(|(_, unit)| *unit == newline)
+                    .map(|(i, _)| base + (i + 1) * newline.len())
+                    .filter(|&start| start < map.len())
+                    .collect();
+                sink.lock().unwrap().extend(found);
+            }
+            finished.store(true, Ordering::Release);
+        });
+        Self { starts, done, cancelled }
+    }
+}
+
+impl Drop for LineIndex {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+enum Mode {
+    Hex,
+    Text(LineIndex),
+}
+
+/// Read-only view of a memory-mapped file: a hex dump for binary files, plain
+/// unhighlighted lines for very large text files
+pub struct FileView {
+    pub path: PathBuf,
+    map: Arc<Mmap>,
+    mode: Mode,
+    /// Of the text, going by its BOM or its first bytes
+    encoding: FileEncoding,
+    /// Row at the top of the view
+    first_row: usize,
+    /// Characters scrolled off to the left (text mode)
+    first_col: usize,
+    /// Scrolling not yet amounting to a whole row or column
+    scroll_remainder: egui::Vec2,
+    pub grab_focus: bool,
+}
+
+impl FileView {
+    pub fn open(path: PathBuf, kind: FileKind) -> io::Result<Self> {
+        let file = std::fs::File::open(&path)?;
+        // Safety: the map is only read; if another program truncates the file while
+        // it's open we may read zeros or fault, as with any viewer that maps files
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        let head = &map[..map.len().min(SNIFF_LEN)];
+        let bom_len = Encoding::for_bom(head).map_or(0, |(_, len)| len);
+        let encoding = FileEncoding { encoding: crate::encoding::detect(head), bom: bom_len > 0, line_ending: LineEnding::Lf };
+        let mode = match kind {
+            FileKind::Binary => Mode::Hex,
+            FileKind::Text | FileKind::LargeText => Mode::Text(LineIndex::start(map.clone(), encoding.encoding, bom_len)),
+        };
+        Ok(Self { path, map, mode, encoding, first_row: 0, first_col: 0, scroll_remainder: egui::Vec2::ZERO, grab_focus: false })
+    }
+
+    /// The text of the line in `start..end`, decoded, without its line break
+    fn line(&self, start: usize, end: usize) -> String {
+        let (text, _) = self.encoding.encoding.decode_without_bom_handling(&self.map[start..end]);
+        text.trim_end_matches(['\n', '\r']).to_string()
+    }
+
+    pub fn title(&self) -> String {
+        let name = self.path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        match self.mode {
+            Mode::Hex => format!("{} (hex)", name),
+            Mode::Text(_) => format!("{} (read-only)", name),
+        }
+    }
+
+    /// Rows known so far, and whether that's all of them
+    fn row_count(&self) -> (usize, bool) {
+        match &self.mode {
+            Mode::Hex => (self.map.len().div_ceil(BYTES_PER_ROW).max(1), true),
+            Mode::Text(index) => {
+                let done = index.done.load(Ordering::Acquire);
+                (index.starts.lock().unwrap().len(), done)
+            }
+        }
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        let font = FontId::monospace(13.0);
+        let char_width = 7.8_f32;
+        let line_height = 16.0_f32;
+        let status_height = 20.0;
+        let scrollbar_width = 10.0;
+
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_SURFACE);
+        let (rows, indexed) = self.row_count();
+        if !indexed {
+            ui.ctx().request_repaint_after(std::time::Duration::from_millis(100));
+        }
+
+        let body = Rect::from_min_max(rect.left_top(), egui::pos2(rect.right() - scrollbar_width, rect.bottom() - status_height));
+        let visible = ((body.height() / line_height) as usize).max(1);
+        let max_first = rows.saturating_sub(visible);
+
+        // Scrolling: wheel, keys, and the scrollbar
+        let id = ui.id().with(("file_view", &self.path));
+        let response = ui.interact(body, id, egui::Sense::click());
+        if response.clicked() || std::mem::take(&mut self.grab_focus) {
+            response.request_focus();
+        }
+        if response.hovered() {
+            self.scroll_remainder -= ui.input(|i| i.smooth_scroll_delta);
+            let rows_delta = (self.scroll_remainder.y / line_height).trunc();
+            let cols_delta = (self.scroll_remainder.x / char_width).trunc();
+            self.scroll_remainder -= egui::vec2(cols_delta * char_width, rows_delta * line_height);
+            self.first_row = self.first_row.saturating_add_signed(rows_delta as isize);
+            self.first_col = self.first_col.saturating_add_signed(cols_delta as isize);
+        }
+        if response.has_focus() {
+            ui.input(|i| {
+                let page = visible.saturating_sub(1).max(1);
+                if i.key_pressed(egui::Key::ArrowDown) {
+                    self.first_row += 1;
+                }
+                if i.key_pressed(egui::Key::ArrowUp) {
+                    self.first_row = self.first_row.saturating_sub(1);
+                }
+                if i.key_pressed(egui::Key::PageDown) {
+                    self.first_row += page;
+                }
+                if i.key_pressed(egui::Key::PageUp) {
+                    self.first_row = self.first_row.saturating_sub(page);
+                }
+                if i.key_pressed(egui::Key::Home) {
+                    self.first_row = 0;
+                }
+                if i.key_pressed(egui::Key::End) {
+                    self.first_row = max_first;
+                }
+            });
+        }
+        let track = Rect::from_min_max(egui::pos2(body.right(), body.top()), egui::pos2(rect.right(), body.bottom()));
+        let track_response = ui.interact(track, id.with("scrollbar"), egui::Sense::click_and_drag());
+        if let Some(pos) = track_response.interact_pointer_pos() {
+            let fraction = ((pos.y - track.top()) / track.height()).clamp(0.0, 1.0) as f64;
+            self.first_row = (fraction * max_first as f64).round() as usize;
+        }
+        self.first_row = self.first_row.min(max_first);
+
+        ui.painter().rect_filled(track, 0.0, crate::theme::BG_ELEVATED);
+        if rows > visible {
+            let thumb_height = (track.height() * visible as f32 / rows as f32).max(20.0);
+            let top = track.top() + (track.height() - thumb_height) * (self.first_row as f64 / max_first as f64) as f32;
+            let thumb = Rect::from_min_size(egui::pos2(track.left() + 2.0, top), egui::vec2(scrollbar_width - 4.0, thumb_height));
+            ui.painter().rect_filled(thumb, 3.0, crate::theme::BORDER);
+        }
+
+        let painter = ui.painter_at(body);
+        let rows_shown = self.first_row..(self.first_row + visible + 1).min(rows);
+        match &self.mode {
+            Mode::Hex => {
+                for (i, row) in rows_shown.enumerate() {
+                    let y = body.top() + i as f32 * line_height;
+                    let start = row * BYTES_PER_ROW;
+                    let bytes = &self.map[start..(start + BYTES_PER_ROW).min(self.map.len())];
+                    let pos = egui::pos2(body.left() + 8.0, y);
+                    painter.text(pos, egui::Align2::LEFT_TOP, format!("{:08x}", start), font.clone(), crate::theme::TEXT_SECONDARY);
+                    painter.text(pos + egui::vec2(10.0 * char_width, 0.0), egui::Align2::LEFT_TOP, hex_row(bytes), font.clone(), crate::theme::TEXT_PRIMARY);
+                    let ascii: String = bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+                    let ascii_x = (10 + BYTES_PER_ROW * 3 + 2) as f32 * char_width;
+                    painter.text(pos + egui::vec2(ascii_x, 0.0), egui::Align2::LEFT_TOP, ascii, font.clone(), crate::theme::TEXT_SECONDARY);
+                }
+            }
+            Mode::Text(index) => {
+                let starts = index.starts.lock().unwrap();
+                let gutter = 10.0 * char_width;
+                for (i, row) in rows_shown.enumerate() {
+                    let y = body.top() + i as f32 * line_height;
+                    let start = starts[row];
+                    let end = starts.get(row + 1).copied().unwrap_or(if indexed { self.map.len() } else { start });
+                    // Only decode what can be on screen of very long lines (a multiple of
+                    // four bytes, so UTF-16 units stay whole)
+                    let end = end.min(start + (self.first_col + 1024) * 4);
+                    let line: String = self.line(start, end).chars().skip(self.first_col).collect();
+                    painter.text(egui::pos2(body.left() + 8.0, y), egui::Align2::LEFT_TOP, format!("{:>8}", row + 1), font.clone(), crate::theme::TEXT_SECONDARY);
+                    painter.text(egui::pos2(body.left() + gutter, y), egui::Align2::LEFT_TOP, line, font.clone(), crate::theme::TEXT_PRIMARY);
+                }
+            }
+        }
+
+        let status_rect = Rect::from_min_max(egui::pos2(rect.left(), rect.bottom() - status_height), rect.right_bottom());
+        ui.painter().rect_filled(status_rect, 0.0, crate::theme::BG_ELEVATED);
+        let size = self.map.len() as f64 / (1024.0 * 1024.0);
+        let encoding = if self.encoding.encoding == UTF_8 { String::new() } else { format!("{}, ", self.encoding.label()) };
+        let status = match &self.mode {
+            Mode::Hex => format!("Binary file, read-only — {:.1} MB", size),
+            Mode::Text(_) if indexed => format!("Large file, read-only, no highlighting — {}{:.1} MB, {} lines", encoding, size, rows),
+            Mode::Text(_) => format!("Large file, read-only — {}{:.1} MB, indexing lines… {}", encoding, size, rows),
+        };
+        ui.painter().text(
+            status_rect.left_center() + egui::vec2(8.0, 0.0),
+            egui::Align2::LEFT_CENTER,
+            status,
+            FontId::proportional(12.0),
+            crate::theme::TEXT_SECONDARY,
+        );
+    }
+}
+
+/// Sixteen bytes as hex, in two groups of eight
+fn hex_row(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(BYTES_PER_ROW * 3 + 1);
+    for (i, b) in bytes.iter().enumerate() {
+        if i == BYTES_PER_ROW / 2 {
+            out.push(' ');
+        }
+        out.push_str(&format!("{:02x} ", b));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn temp_file(bytes: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        file
+    }
+
+    /// A temp file starting with `head`, grown to `len` bytes without writing them
+    fn sized(head: &[u8], len: u64) -> NamedTempFile {
+        let file = temp_file(head);
+        file.as_file().set_len(len).unwrap();
+        file
+    }
+
+    fn utf16(bom: &[u8], text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        bom.iter().copied().chain(text.encode_utf16().flat_map(to_bytes)).collect()
+    }
+
+    fn kind(bytes: &[u8]) -> FileKind {
+        classify(temp_file(bytes).path()).unwrap()
+    }
+
+    #[test]
+    fn classifies_by_content() {
+        assert_eq!(kind(b""), FileKind::Text);
+        assert_eq!(kind("日本語\n".as_bytes()), FileKind::Text);
+        assert_eq!(kind(b"ELF\0\x01"), FileKind::Binary);
+        // Only the start is sniffed
+        let mut late_nul = vec![b'a'; SNIFF_LEN];
+        late_nul.push(0);
+        assert_eq!(kind(&late_nul), FileKind::Text);
+        let mut early_nul = vec![b'a'; SNIFF_LEN - 1];
+        early_nul.push(0);
+        assert_eq!(kind(&early_nul), FileKind::Binary);
+
+        // UTF-16 is full of NULs but is text when it has a BOM; a UTF-8 BOM doesn't excuse them
+        assert_eq!(kind(&utf16(&[0xFF, 0xFE], "abc", u16::to_le_bytes)), FileKind::Text);
+        assert_eq!(kind(&utf16(&[0xFE, 0xFF], "abc", u16::to_be_bytes)), FileKind::Text);
+        assert_eq!(kind(&utf16(&[], "abc", u16::to_le_bytes)), FileKind::Binary);
+        assert_eq!(kind(b"\xEF\xBB\xBFa\0b"), FileKind::Binary);
+    }
+
+    #[test]
+    fn classifies_by_size() {
+        let head = vec![b'a'; SNIFF_LEN];
+        let at_limit = sized(&head, LARGE_FILE_SIZE);
+        assert_eq!(classify(at_limit.path()).unwrap(), FileKind::Text);
+        let over = sized(&head, LARGE_FILE_SIZE + 1);
+        assert_eq!(classify(over.path()).unwrap(), FileKind::LargeText);
+        let utf16 = sized(&utf16(&[0xFF, 0xFE], "abc", u16::to_le_bytes), LARGE_FILE_SIZE + 2);
+        assert_eq!(classify(utf16.path()).unwrap(), FileKind::LargeText);
+        // Binary whatever the size
+        let binary = sized(b"\0", LARGE_FILE_SIZE + 1);
+        assert_eq!(classify(binary.path()).unwrap(), FileKind::Binary);
+    }
+
+    /// Open `bytes` as large text and return its lines once indexed
+    fn lines(bytes: &[u8]) -> (Vec<String>, FileEncoding) {
+        let file = temp_file(bytes);
+        let view = FileView::open(file.path().to_path_buf(), FileKind::LargeText).unwrap();
+        let Mode::Text(index) = &view.mode else { unreachable!() };
+        while !index.done.load(Ordering::Acquire) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let starts = index.starts.lock().unwrap().clone();
+        let ends = starts.iter().skip(1).copied().chain([view.map.len()]);
+        let lines = starts.iter().zip(ends).map(|(&start, end)| view.line(start, end)).collect();
+        (lines, view.encoding)
+    }
+
+    #[test]
+    fn decodes_large_text_in_its_encoding() {
+        // U+010A and U+0A00 hold 0x0A bytes that aren't newlines
+        let text = "Ċ日本\r\nਂx\nlast";
+        let (found, encoding) = lines(&utf16(&[0xFF, 0xFE], text, u16::to_le_bytes));
+        assert_eq!(found, ["Ċ日本", "ਂx", "last"]);
+        assert_eq!(encoding.label(), "UTF-16 LE");
+        let (found, _) = lines(&utf16(&[0xFE, 0xFF], text, u16::to_be_bytes));
+        assert_eq!(found, ["Ċ日本", "ਂx", "last"]);
+
+        let sjis = encoding_rs::SHIFT_JIS.encode("こんにちは\n世界\n").0.into_owned();
+        let (found, encoding) = lines(&sjis);
+        assert_eq!((found, encoding.encoding), (vec!["こんにちは".to_string(), "世界".to_string()], encoding_rs::SHIFT_JIS));
+        let (found, encoding) = lines("\u{FEFF}a\nb".as_bytes());
+        assert_eq!((found, encoding.label()), (vec!["a".to_string(), "b".to_string()], "UTF-8 with BOM".to_string()));
+    }
+}
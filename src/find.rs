@@ -0,0 +1,195 @@
+use crate::buffer::TextBuffer;
+use regex::{Regex, RegexBuilder};
+use std::ops::Range;
+
+/// State of an editor's find/replace bar
+#[derive(Default)]
+pub struct FindState {
+    pub open: bool,
+    pub replace_open: bool,
+    pub query: String,
+    pub replacement: String,
+    pub regex: bool,
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+    /// Only search inside this range (the selection when "in selection" was turned on)
+    pub scope: Option<Range<usize>>,
+    pub matches: Vec<Range<usize>>,
+    pub current: usize,
+    /// Why the query isn't a valid regex
+    pub error: Option<String>,
+    /// Move keyboard focus to the find field on the next frame
+    pub focus_query: bool,
+    /// Editor revision the matches were found in; `None` forces a new search
+    pub revision: Option<u64>,
+}
+
+impl FindState {
+    fn pattern(&self) -> Result<Regex, regex::Error> {
+        build_pattern(&self.query, self.regex, self.case_sensitive, self.whole_word)
+    }
+
+    /// Find all non-empty matches of the query in `text`, within the scope if one is set
+    pub fn update(&mut self, text: &TextBuffer, revision: u64) {
+        self.revision = Some(revision);
+        self.matches.clear();
+        self.error = None;
+        if self.query.is_empty() {
+            return;
+        }
+        let re = match self.pattern() {
+            Ok(re) => re,
+            Err(e) => {
+                self.error = Some(e.to_string().lines().last().unwrap_or("invalid regex").to_string());
+                return;
+            }
+        };
+        let scope = self.scope.clone().unwrap_or(0..text.len_bytes());
+        self.matches = text.find_iter(&re, scope).filter(|m| !m.is_empty()).collect();
+        if self.current >= self.matches.len() {
+            self.current = 0;
+        }
+    }
+
+    /// Index of the first match starting at or after `pos`, wrapping to the first
+    pub fn next_from(&self, pos: usize) -> usize {
+        self.matches.iter().position(|m| m.start >= pos).unwrap_or(0)
+    }
+
+    /// What each match is replaced with; in regex mode `$1` / `${name}` expand to
+    /// capture groups
+    pub fn replacements(&self, text: &TextBuffer, matches: &[Range<usize>]) -> Vec<String> {
+        match self.pattern() {
+            Ok(re) if self.regex => matches
+                .iter()
+                .map(|m| {
+                    // Each match is matched again within its own lines
+                    let start = text.line_start(text.line_of(m.start));
+                    let lines = text.slice(start..text.line_start(text.line_of(m.end) + 1));
+                    let range = m.start - start..m.end - start;
+                    expand_replacements(&re, &self.replacement, &lines, &[range]).remove(0)
+                })
+                .collect(),
+            _ => vec![self.replacement.clone(); matches.len()],
+        }
+    }
+}
+
+/// Compile a find query; literal unless `regex`, case-insensitive unless `case_sensitive`
+pub fn build_pattern(query: &str, regex: bool, case_sensitive: bool, whole_word: bool) -> Result<Regex, regex::Error> {
+    let mut pattern = if regex { query.to_string() } else { regex::escape(query) };
+    if whole_word {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!case_sensitive)
+        .multi_line(true)
+        .build()
+}
+
+/// `replacement` with `$1` / `${name}` expanded for each match of `re` in `text`
+pub fn expand_replacements(re: &Regex, replacement: &str, text: &str, matches: &[Range<usize>]) -> Vec<String> {
+    matches
+        .iter()
+        .map(|range| match re.captures_at(text, range.start) {
+            Some(caps) if caps.get(0).is_some_and(|m| m.range() == *range) => {
+                let mut out = String::new();
+                caps.expand(replacement, &mut out);
+                out
+            }
+            _ => replacement.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(query: &str, text: &str, set: impl FnOnce(&mut FindState)) -> FindState {
+        let mut find = FindState { query: query.to_string(), ..Default::default() };
+        set(&mut find);
+        find.update(&TextBuffer::from(text), 1);
+        find
+    }
+
+    #[test]
+    fn replaces_with_capture_groups() {
+        let text = "let a = f(x);\nlet b = g(y);";
+        let find = search(r"(\w)\((\w)\)", text, |f| {
+            f.regex = true;
+            f.replacement = "$2.$1()".to_string();
+        });
+        assert_eq!(find.matches, [8..12, 22..26]);
+        assert_eq!(find.replacements(&TextBuffer::from(text), &find.matches), ["x.f()", "y.g()"]);
+
+        // Named groups, and `$` left alone outside regex mode
+        let find = search(r"(?P<name>\w+) = ", text, |f| {
+            f.regex = true;
+            f.replacement = "${name}: ".to_string();
+        });
+        assert_eq!(find.replacements(&TextBuffer::from(text), &find.matches), ["a: ", "b: "]);
+        let find = search("f(x)", text, |f| f.replacement = "$1".to_string());
+        assert_eq!(find.replacements(&TextBuffer::from(text), &find.matches), ["$1"]);
+    }
+
+    #[test]
+    fn matches_whole_words_and_case() {
+        let text = "cat concat Cat cat_x cat";
+        assert_eq!(search("cat", text, |_| ()).matches.len(), 5);
+        let whole = search("cat", text, |f| f.whole_word = true);
+        assert_eq!(whole.matches, [0..3, 11..14, 21..24]);
+        let exact = search("cat", text, |f| {
+            f.whole_word = true;
+            f.case_sensitive = true;
+        });
+        assert_eq!(exact.matches, [0..3, 21..24]);
+        // Regex alternatives are whole words as a group
+        let alternatives = search("cat|con", text, |f| {
+            f.regex = true;
+            f.whole_word = true;
+        });
+        assert_eq!(alternatives.matches, [0..3, 11..14, 21..24]);
+    }
+
+    #[test]
+    fn searches_only_the_scope() {
+        let text = "ab ab ab ab";
+        let scoped = search("ab", text, |f| f.scope = Some(2..8));
+        assert_eq!(scoped.matches, [3..5, 6..8]);
+        // A match cut by the scope's end isn't one
+        let scoped = search("ab", text, |f| f.scope = Some(0..7));
+        assert_eq!(scoped.matches, [0..2, 3..5]);
+        // A scope left past the end of shrunken text is clamped
+        let scoped = search("ab", "ab", |f| f.scope = Some(1..50));
+        assert!(scoped.matches.is_empty());
+        assert_eq!(scoped.next_from(0), 0);
+    }
+
+    #[test]
+    fn empty_matches_are_skipped_without_looping() {
+        let text = "aa\nb\n\naé";
+        for query in ["^", "$", "a*", r"\b", "x*"] {
+            let found = search(query, text, |f| f.regex = true);
+            assert!(found.matches.iter().all(|m| !m.is_empty()), "{}", query);
+        }
+        assert_eq!(search("a*", text, |f| f.regex = true).matches, [0..2, 6..7]);
+        assert_eq!(search("^.", text, |f| f.regex = true).matches, [0..1, 3..4, 6..7]);
+    }
+
+    #[test]
+    fn reports_invalid_regex() {
+        let bad = search("(unclosed", "text", |f| f.regex = true);
+        assert!(bad.matches.is_empty());
+        let error = bad.error.unwrap();
+        assert!(!error.is_empty() && !error.contains('\n'), "{}", error);
+        // The same query is fine as a literal, and a fix clears the error
+        let literal = search("(unclosed", "(unclosed", |_| ());
+        assert_eq!((literal.matches.len(), literal.error), (1, None));
+        let mut fixed = search("(", "(", |f| f.regex = true);
+        assert!(fixed.error.is_some());
+        fixed.query = r"\(".to_string();
+        fixed.update(&TextBuffer::from("("), 2);
+        assert_eq!((fixed.matches.len(), fixed.error), (1, None));
+    }
+}
@@ -0,0 +1,74 @@
+use crate::buffer::TextBuffer;
+use std::collections::BTreeMap;
+
+/// Foldable regions from the syntax tree's nodes, given as (first row, last row,
+/// column the node ends at). One region per first line, the largest; a last line
+/// holding just the closing bracket stays visible.
+pub fn from_nodes(text: &TextBuffer, nodes: Vec<(usize, usize, usize)>) -> Vec<(usize, usize)> {
+    let mut by_start: BTreeMap<usize, usize> = BTreeMap::new();
+    for (start, end_row, end_col) in nodes {
+        let line = text.line(end_row);
+        let head = line.get(..end_col).unwrap_or(&line).trim_start();
+        let closing = head.starts_with("</")
+            || (!head.is_empty() && head.chars().all(|c| matches!(c, ')' | ']' | '}' | ';' | ',')));
+        // Ending at the start of a line (e.g. Markdown sections) is ending before it
+        let last = if end_col == 0 || closing { end_row.saturating_sub(1) } else { end_row };
+        if last > start {
+            let entry = by_start.entry(start).or_insert(last);
+            *entry = (*entry).max(last);
+        }
+    }
+    by_start.into_iter().collect()
+}
+
+/// Foldable regions from indentation: each line followed by more deeply indented
+/// ones, for languages without a syntax tree
+pub fn indent_ranges(text: &TextBuffer) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    // Lines that may start a region, with their indentation, deepest last
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut last_text = 0;
+    for line in 0..text.line_count() {
+        let content = text.line(line);
+        if content.trim().is_empty() {
+            continue;
+        }
+        let indent = crate::wrap::width(crate::indent::leading(&content));
+        while let Some(&(start, width)) = open.last() {
+            if width < indent {
+                break;
+            }
+            open.pop();
+            if last_text > start {
+                ranges.push((start, last_text));
+            }
+        }
+        open.push((line, indent));
+        last_text = line;
+    }
+    ranges.extend(open.into_iter().map(|(start, _)| (start, last_text)).filter(|(start, last)| last > start));
+    ranges.sort_unstable();
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_by_indentation() {
+        let text = TextBuffer::from("a:\n  b\n\n  c:\n    d\ne\n  f\n");
+        assert_eq!(indent_ranges(&text), vec![(0, 4), (3, 4), (5, 6)]);
+    }
+
+    #[test]
+    fn keeps_closing_brackets_visible() {
+        let text = TextBuffer::from("fn f() {\n    a();\n}\n# Title\ntext\n");
+        // A function ending with `}` on line 2, and a block starting on the same line
+        assert_eq!(from_nodes(&text, vec![(0, 2, 1), (0, 2, 1)]), vec![(0, 1)]);
+        // A section that runs up to the start of line 5
+        assert_eq!(from_nodes(&text, vec![(3, 5, 0)]), vec![(3, 4)]);
+        // Ending after code rather than a bracket
+        assert_eq!(from_nodes(&text, vec![(0, 1, 8)]), vec![(0, 1)]);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::config::FormatterConfig;
+use similar::{DiffOp, TextDiff};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A formatter that hasn't answered after this long is given up on
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What formats a file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Formatter {
+    /// A program reading the text on stdin and writing the result to stdout
+    Command(FormatterConfig),
+    /// The file's language server (`textDocument/formatting`)
+    LanguageServer,
+}
+
+/// The formatter for a file: the configured one for its extension, else a built-in
+/// one, else its language server
+pub fn formatter_for(path: &Path, configured: &BTreeMap<String, FormatterConfig>) -> Formatter {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    let config = configured.get(&ext).cloned().or_else(|| {
+        let (command, args): (&str, &[&str]) = match ext.as_str() {
+            "rs" => ("rustfmt", &["--emit", "stdout"]),
+            "py" | "pyi" => ("black", &["--quiet", "--stdin-filename", "{path}", "-"]),
+            "js" | "mjs" | "cjs" | "jsx" | "ts" | "mts" | "cts" | "tsx" | "json" | "css" | "scss" | "html"
+            | "md" | "yaml" | "yml" => ("prettier", &["--stdin-filepath", "{path}"]),
+            _ => return None,
+        };
+        Some(FormatterConfig { command: command.to_string(), args: args.iter().map(|a| a.to_string()).collect() })
+    });
+    match config {
+        Some(config) if config.command != "lsp" => Formatter::Command(config),
+        _ => Formatter::LanguageServer,
+    }
+}
+
+/// Run a formatter program over `text`; the error is what it printed on failure.
+/// The result has `\n` line endings like the editor's buffer, whatever the
+/// formatter was set up to write. The running process is left in `process`, so it
+/// can be killed.
+fn run(config: &FormatterConfig, path: &Path, text: String, process: &Mutex<Option<Child>>) -> Result<String, String> {
+    let args = config.args.iter().map(|a| a.replace("{path}", &path.to_string_lossy()));
+    let mut command = Command::new(&config.command);
+    command.args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    if let Some(dir) = path.parent() {
+        command.current_dir(dir);
+    }
+    let mut child = command.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => format!("{} not found", config.command),
+        _ => format!("{}: {}", config.command, e),
+    })?;
+    let mut stdin = child.stdin.take().ok_or("no stdin")?;
+    let mut stdout = child.stdout.take().ok_or("no stdout")?;
+    let mut stderr = child.stderr.take().ok_or("no stderr")?;
+    *process.lock().unwrap() = Some(child);
+    // Write and read stderr from other threads so a formatter that answers early
+    // or says a lot can't deadlock us
+    let writer = std::thread::spawn(move || stdin.write_all(text.as_bytes()));
+    let errors = std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = stderr.read_to_end(&mut bytes);
+        bytes
+    });
+    let mut output = Vec::new();
+    stdout.read_to_end(&mut output).map_err(|e| format!("{}: {}", config.command, e))?;
+    // Wait without holding the lock, which killing the process needs
+    let status = loop {
+        match process.lock().unwrap().as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) => break status,
+            Some(Ok(None)) => {}
+            Some(Err(e)) => return Err(format!("{}: {}", config.command, e)),
+            None => return Err(format!("{} was stopped", config.command)),
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let _ = writer.join();
+    let stderr = errors.join().unwrap_or_default();
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        let message = stderr.trim();
+        return Err(if message.is_empty() {
+            format!("{} failed ({})", config.command, status)
+        } else {
+            format!("{}: {}", config.command, message)
+        });
+    }
+    let formatted = String::from_utf8(output).map_err(|_| format!("{} printed invalid UTF-8", config.command))?;
+    Ok(if formatted.contains("\r\n") { formatted.replace("\r\n", "\n") } else { formatted })
+}
+
+/// A formatter program running in the background for one editor; dropping it
+/// kills the program if it's still running
+pub struct FormatJob {
+    pub editor: usize,
+    result: Arc<Mutex<Option<Result<String, String>>>>,
+    process: Arc<Mutex<Option<Child>>>,
+}
+
+impl FormatJob {
+    pub fn start(editor: usize, config: FormatterConfig, path: &Path, text: String) -> Self {
+        let result = Arc::new(Mutex::new(None));
+        let process = Arc::new(Mutex::new(None));
+        let (sink, running) = (result.clone(), process.clone());
+        let path = path.to_path_buf();
+        std::thread::spawn(move || {
+            let formatted = run(&config, &path, text, &running);
+            *sink.lock().unwrap() = Some(formatted);
+        });
+        Self { editor, result, process }
+    }
+
+    /// The formatted text or error, once the formatter has finished
+    pub fn poll(&self) -> Option<Result<String, String>> {
+        self.result.lock().unwrap().take()
+    }
+}
+
+impl Drop for FormatJob {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.process.lock().unwrap().take() {
+            // Reaped by the thread if it already finished; otherwise it's stopped here
+            if let Ok(None) = child.try_wait() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+/// The line-level replacements turning `old` into `new`, in document order, so that
+/// unchanged lines (and cursors on them) are left alone
+pub fn minimal_edits(old: &str, new: &str) -> Vec<(Range<usize>, String)> {
+    let diff = TextDiff::from_lines(old, new);
+    let offsets = |lines: &[&str]| {
+        let mut offsets = vec![0];
+        for line in lines {
+            offsets.push(offsets.last().unwrap() + line.len());
+        }
+        offsets
+    };
+    let (old_at, new_at) = (offsets(diff.old_slices()), offsets(diff.new_slices()));
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+    for op in diff.ops() {
+        if let DiffOp::Equal { .. } = op {
+            continue;
+        }
+        let (old_lines, new_lines) = (op.old_range(), op.new_range());
+        let range = old_at[old_lines.start]..old_at[old_lines.end];
+        let text = &new[new_at[new_lines.start]..new_at[new_lines.end]];
+        // Adjacent delete + insert ops make one replacement
+        match edits.last_mut() {
+            Some((last, last_text)) if last.end == range.start => {
+                last.end = range.end;
+                last_text.push_str(text);
+            }
+            _ => edits.push((range, text.to_string())),
+        }
+    }
+    edits
+}
+
+/// Where offset `pos` in `old` ends up after `edits` (from `minimal_edits`). Inside a
+/// replaced chunk it keeps its line and column, as far as the new lines allow.
+pub fn map_offset(old: &str, pos: usize, edits: &[(Range<usize>, String)]) -> usize {
+    let mut shift = 0isize;
+    for (range, text) in edits {
+        if range.end <= pos {
+            shift += text.len() as isize - range.len() as isize;
+            continue;
+        }
+        if range.start > pos {
+            break;
+        }
+        let before = &old[range.start..pos];
+        let line = before.matches('\n').count();
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1);
+        let line_start = text.split_inclusive('\n').take(line).map(str::len).sum::<usize>();
+        let new_line = text[line_start..].split('\n').next().unwrap_or_default();
+        let mut column = column.min(new_line.len());
+        while !new_line.is_char_boundary(column) {
+            column -= 1;
+        }
+        return range.start.saturating_add_signed(shift) + line_start + column;
+    }
+    pos.saturating_add_signed(shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn apply(old: &str, edits: &[(Range<usize>, String)]) -> String {
+        let mut text = old.to_string();
+        for (range, new) in edits.iter().rev() {
+            text.replace_range(range.clone(), new);
+        }
+        text
+    }
+
+    #[test]
+    fn edits_touch_only_changed_lines() {
+        let old = "fn main(){\n    let x=1;\n    call( x );\n}\n";
+        let new = "fn main() {\n    let x = 1;\n    call(x);\n}\n";
+        let edits = minimal_edits(old, new);
+        assert_eq!(apply(old, &edits), new);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].0, 0..old.find('}').unwrap());
+
+        let old = "a\nb\nc\nd\n";
+        let new = "a\nB\nc\nD\n";
+        let edits = minimal_edits(old, new);
+        assert_eq!(edits, vec![(2..4, "B\n".to_string()), (6..8, "D\n".to_string())]);
+        assert!(minimal_edits(new, new).is_empty());
+    }
+
+    #[test]
+    fn offsets_keep_line_and_column() {
+        let old = "a\nlet x=1;\nlast\n";
+        let new = "a\nlet x = 1;\nlast\n";
+        let edits = minimal_edits(old, new);
+        // After the edit: shifted by the growth
+        let last = old.find("last").unwrap();
+        assert_eq!(map_offset(old, last + 2, &edits), new.find("last").unwrap() + 2);
+        // Before the edit: unchanged
+        assert_eq!(map_offset(old, 1, &edits), 1);
+        // Inside the edit: same line and column
+        assert_eq!(map_offset(old, 2 + 4, &edits), 2 + 4);
+        // Column past the end of the new line is clamped
+        let edits = minimal_edits("abcdef\n", "ab\n");
+        assert_eq!(map_offset("abcdef\n", 5, &edits), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_gets_lf_line_endings() {
+        let script = "printf 'a\\r\\nb\\r\\n'";
+        let config = FormatterConfig { command: "sh".to_string(), args: vec!["-c".to_string(), script.to_string()] };
+        let path = std::env::temp_dir().join("a.txt");
+        assert_eq!(run(&config, &path, "a\nb\n".to_string(), &Mutex::new(None)), Ok("a\nb\n".to_string()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dropping_a_job_kills_its_formatter() {
+        let config = FormatterConfig { command: "sleep".to_string(), args: vec!["30".to_string()] };
+        let job = FormatJob::start(0, config, &std::env::temp_dir().join("a.txt"), String::new());
+        let pid = loop {
+            if let Some(pid) = job.process.lock().unwrap().as_ref().map(Child::id) {
+                break pid;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        let proc = PathBuf::from(format!("/proc/{}", pid));
+        assert!(proc.exists());
+        drop(job);
+        assert!(!proc.exists());
+    }
+
+    #[test]
+    fn picks_formatters() {
+        let mut configured = BTreeMap::new();
+        assert!(matches!(formatter_for(Path::new("a.rs"), &configured), Formatter::Command(c) if c.command == "rustfmt"));
+        assert_eq!(formatter_for(Path::new("a.go"), &configured), Formatter::LanguageServer);
+        configured.insert("rs".to_string(), FormatterConfig { command: "lsp".to_string(), args: Vec::new() });
+        assert_eq!(formatter_for(Path::new("a.rs"), &configured), Formatter::LanguageServer);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::buffer::TextBuffer;
+use similar::{DiffOp, TextDiff};
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// A file's contents in the last commit; `None` if it isn't in a git repository
+/// or isn't committed
+fn head_contents(path: &Path) -> Option<Vec<u8>> {
+    let dir = path.parent()?;
+    let name = path.file_name()?.to_str()?;
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["show", &format!("HEAD:./{}", name)])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// Fetches a file's committed contents in the background
+pub struct HeadJob {
+    result: Arc<Mutex<Option<Option<Vec<u8>>>>>,
+}
+
+impl HeadJob {
+    pub fn start(path: &Path) -> Self {
+        let result = Arc::new(Mutex::new(None));
+        let sink = result.clone();
+        let path = path.to_path_buf();
+        std::thread::spawn(move || {
+            let contents = head_contents(&path);
+            *sink.lock().unwrap() = Some(contents);
+        });
+        Self { result }
+    }
+
+    /// The committed contents (`None` inside if there are none), once fetched
+    pub fn poll(&self) -> Option<Option<Vec<u8>>> {
+        self.result.lock().unwrap().take()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HunkKind {
+    Added,
+    Modified,
+    /// Lines removed just before `Hunk::new.start`
+    Deleted,
+}
+
+/// Lines that differ from the committed version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hunk {
+    /// Lines in the buffer
+    pub new: Range<usize>,
+    /// The committed lines they replace
+    pub old_text: String,
+}
+
+impl Hunk {
+    pub fn kind(&self) -> HunkKind {
+        match (self.new.is_empty(), self.old_text.is_empty()) {
+            (true, _) => HunkKind::Deleted,
+            (false, true) => HunkKind::Added,
+            (false, false) => HunkKind::Modified,
+        }
+    }
+}
+
+/// Diffs a buffer against its committed version in the background
+pub struct HunksJob {
+    /// Revision of the buffer being diffed
+    pub revision: u64,
+    result: Arc<Mutex<Option<Vec<Hunk>>>>,
+}
+
+impl HunksJob {
+    pub fn start(revision: u64, head: Arc<str>, current: TextBuffer) -> Self {
+        let result = Arc::new(Mutex::new(None));
+        let sink = result.clone();
+        std::thread::spawn(move || {
+            let found = hunks(&head, &current.to_string());
+            *sink.lock().unwrap() = Some(found);
+        });
+        Self { revision, result }
+    }
+
+    /// The hunks, once found
+    pub fn poll(&self) -> Option<Vec<Hunk>> {
+        self.result.lock().unwrap().take()
+    }
+}
+
+/// Line changes from `head` to `current`. Buffers hold `\n` line endings only, so a
+/// CRLF `head` is compared as if it had them too.
+pub fn hunks(head: &str, current: &str) -> Vec<Hunk> {
+    let head = if head.contains("\r\n") { Cow::Owned(head.replace("\r\n", "\n")) } else { Cow::Borrowed(head) };
+    let diff = TextDiff::from_lines(head.as_ref(), current);
+    let old_lines = diff.old_slices();
+    let mut hunks: Vec<Hunk> = Vec::new();
+    for op in diff.ops() {
+        if let DiffOp::Equal { .. } = op {
+            continue;
+        }
+        let (old, new) = (op.old_range(), op.new_range());
+        let old_text: String = old_lines[old].concat();
+        // Adjacent delete + insert ops make one modification
+        match hunks.last_mut() {
+            Some(last) if last.new.end == new.start => {
+                last.new.end = new.end;
+                last.old_text.push_str(&old_text);
+            }
+            _ => hunks.push(Hunk { new, old_text }),
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_added_modified_and_deleted_lines() {
+        let head = "a\nb\nc\nd\n";
+        let current = "a\nB\nc\nnew\n";
+        let found = hunks(head, current);
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].new.clone(), found[0].kind(), found[0].old_text.as_str()), (1..2, HunkKind::Modified, "b\n"));
+        assert_eq!((found[1].new.clone(), found[1].kind()), (3..4, HunkKind::Modified));
+
+        let found = hunks("a\nb\n", "a\nx\ny\nb\n");
+        assert_eq!((found[0].new.clone(), found[0].kind()), (1..3, HunkKind::Added));
+
+        let found = hunks("a\nb\nc\n", "a\nc\n");
+        assert_eq!((found[0].new.clone(), found[0].kind(), found[0].old_text.as_str()), (1..1, HunkKind::Deleted, "b\n"));
+    }
+
+    #[test]
+    fn pure_insert_and_delete() {
+        // Insert at the top, and at the end
+        let found = hunks("a\nb\n", "x\na\nb\n");
+        assert_eq!(found, [Hunk { new: 0..1, old_text: String::new() }]);
+        let found = hunks("a\nb\n", "a\nb\nx\ny\n");
+        assert_eq!(found, [Hunk { new: 2..4, old_text: String::new() }]);
+        assert_eq!(found[0].kind(), HunkKind::Added);
+
+        // Delete the first lines, and the last
+        let found = hunks("a\nb\nc\n", "c\n");
+        assert_eq!(found, [Hunk { new: 0..0, old_text: "a\nb\n".to_string() }]);
+        let found = hunks("a\nb\nc\n", "a\n");
+        assert_eq!(found, [Hunk { new: 1..1, old_text: "b\nc\n".to_string() }]);
+        assert_eq!(found[0].kind(), HunkKind::Deleted);
+
+        // Everything, both ways
+        assert_eq!(hunks("", "a\n"), [Hunk { new: 0..1, old_text: String::new() }]);
+        assert_eq!(hunks("a\n", ""), [Hunk { new: 0..0, old_text: "a\n".to_string() }]);
+        assert!(hunks("a\nb\n", "a\nb\n").is_empty());
+    }
+
+    #[test]
+    fn edits_at_end_of_file() {
+        // Last line without a newline, changed
+        let found = hunks("a\nb", "a\nc");
+        assert_eq!(found, [Hunk { new: 1..2, old_text: "b".to_string() }]);
+        // A newline added to the last line changes it
+        let found = hunks("a\nb", "a\nb\n");
+        assert_eq!(found, [Hunk { new: 1..2, old_text: "b".to_string() }]);
+        // Typing on after the end
+        let found = hunks("a\nb\n", "a\nb\nc");
+        assert_eq!(found, [Hunk { new: 2..3, old_text: String::new() }]);
+    }
+
+    #[test]
+    fn crlf_head_matches_lf_buffer() {
+        assert!(hunks("a\r\nb\r\n", "a\nb\n").is_empty());
+        let found = hunks("a\r\nb\r\nc\r\n", "a\nB\nc\n");
+        assert_eq!(found, [Hunk { new: 1..2, old_text: "b\n".to_string() }]);
+        assert_eq!(found[0].kind(), HunkKind::Modified);
+    }
+}
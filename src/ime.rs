@@ -0,0 +1,49 @@
+use eframe::egui::{self, Color32, FontId, Rect};
+
+/// Paint an IME composition string at the cursor with an underline.
+/// Returns the thin cursor rect at the end of the composition, for `IMEOutput`.
+pub fn paint_ime_preedit(
+    painter: &egui::Painter,
+    preedit: &str,
+    cursor_rect: Rect,
+    font: &FontId,
+    bg: Color32,
+) -> Rect {
+    if preedit.is_empty() {
+        return Rect::from_min_size(cursor_rect.min, egui::vec2(1.0, cursor_rect.height()));
+    }
+
+    let galley = painter.layout_no_wrap(preedit.to_string(), font.clone(), crate::theme::TEXT_PRIMARY);
+    let text_rect = Rect::from_min_size(
+        cursor_rect.min,
+        egui::vec2(galley.size().x, cursor_rect.height()),
+    );
+    painter.rect_filled(text_rect, 0.0, bg);
+    painter.galley(text_rect.min, galley, crate::theme::TEXT_PRIMARY);
+    painter.line_segment(
+        [
+            egui::pos2(text_rect.left(), text_rect.bottom() - 1.0),
+            egui::pos2(text_rect.right(), text_rect.bottom() - 1.0),
+        ],
+        egui::Stroke::new(1.0, crate::theme::TEXT_PRIMARY),
+    );
+
+    Rect::from_min_size(
+        egui::pos2(text_rect.right(), text_rect.top()),
+        egui::vec2(1.0, text_rect.height()),
+    )
+}
+
+/// Tell the platform where the text area and cursor are so the candidate window follows it
+pub fn set_ime_output(ui: &egui::Ui, rect: Rect, cursor_rect: Rect) {
+    let to_global = ui
+        .ctx()
+        .layer_transform_to_global(ui.layer_id())
+        .unwrap_or_default();
+    ui.ctx().output_mut(|o| {
+        o.ime = Some(egui::output::IMEOutput {
+            rect: to_global * rect,
+            cursor_rect: to_global * cursor_rect,
+        });
+    });
+}
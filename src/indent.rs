@@ -0,0 +1,209 @@
+use crate::buffer::TextBuffer;
+use crate::syntax::Lang;
+
+/// Bytes searched either side of the cursor for a matching bracket
+const BRACKET_SEARCH: usize = 20_000;
+
+/// Lines looked at when guessing a file's indentation
+const DETECT_LINES: usize = 2000;
+
+/// One level of indentation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndentUnit {
+    Tabs,
+    Spaces(usize),
+}
+
+impl Default for IndentUnit {
+    fn default() -> Self {
+        IndentUnit::Spaces(4)
+    }
+}
+
+impl IndentUnit {
+    pub fn text(self) -> String {
+        match self {
+            IndentUnit::Tabs => "\t".to_string(),
+            IndentUnit::Spaces(n) => " ".repeat(n),
+        }
+    }
+
+    pub fn label(self) -> String {
+        match self {
+            IndentUnit::Tabs => "Tabs".to_string(),
+            IndentUnit::Spaces(n) => format!("Spaces: {}", n),
+        }
+    }
+
+    /// Guess from how a file's lines are indented; `None` if nothing is
+    pub fn detect(text: &str) -> Option<Self> {
+        let (mut tabs, mut spaced) = (0, 0);
+        // How often the indent grows by each number of spaces from one line to the next
+        let mut steps = [0usize; 9];
+        let mut prev = 0;
+        for line in text.lines().take(DETECT_LINES).filter(|l| !l.trim().is_empty()) {
+            if line.starts_with('\t') {
+                tabs += 1;
+                continue;
+            }
+            let spaces = line.len() - line.trim_start_matches(' ').len();
+            if spaces > 0 {
+                spaced += 1;
+            }
+            if spaces > prev && spaces - prev < steps.len() {
+                steps[spaces - prev] += 1;
+            }
+            prev = spaces;
+        }
+        if tabs > spaced {
+            return Some(IndentUnit::Tabs);
+        }
+        // The most common step wins; ties go to the wider one
+        let (width, count) = steps.iter().enumerate().skip(2).max_by_key(|(width, count)| (**count, *width))?;
+        if *count > 0 {
+            Some(IndentUnit::Spaces(width))
+        } else if steps[1] > 0 {
+            Some(IndentUnit::Spaces(1))
+        } else {
+            None
+        }
+    }
+}
+
+/// Leading whitespace of a line
+pub fn leading(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// `indent` one level shallower: back to the previous stop for spaces
+pub fn dedent(indent: &str, unit: IndentUnit) -> &str {
+    if let Some(rest) = indent.strip_suffix('\t') {
+        return rest;
+    }
+    let width = match unit {
+        IndentUnit::Spaces(n) => n.max(1),
+        IndentUnit::Tabs => 4,
+    };
+    let spaces = indent.len() - indent.trim_end_matches(' ').len();
+    let drop = match spaces % width {
+        0 => width.min(spaces),
+        partial => partial,
+    };
+    &indent[..indent.len() - drop]
+}
+
+/// Whether a line ending in `before` (the text before the cursor) opens a block,
+/// so the next line is indented a level deeper
+pub fn opens_block(lang: Option<Lang>, before: &str) -> bool {
+    let before = before.trim_end();
+    match before.chars().last() {
+        Some('{' | '(' | '[') => true,
+        Some(':') => matches!(lang, Some(Lang::Python | Lang::Yaml)),
+        Some('>') => lang == Some(Lang::Html) && !before.ends_with("/>") && !before.ends_with("-->"),
+        _ => false,
+    }
+}
+
+/// Closing counterpart of a bracket or quote that is typed in pairs
+pub fn closing(open: char, lang: Option<Lang>) -> Option<char> {
+    match open {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' | '`' => Some(open),
+        // Lifetimes in Rust
+        '\'' if lang != Some(Lang::Rust) => Some('\''),
+        _ => None,
+    }
+}
+
+/// Offsets of a bracket next to `pos` (just after it, else just before it) and the
+/// bracket matching it, if found nearby
+pub fn matching_bracket(text: &TextBuffer, pos: usize) -> Option<(usize, usize)> {
+    // Round the window out to character boundaries
+    let start = match pos.saturating_sub(BRACKET_SEARCH) {
+        0 => 0,
+        start => text.next_char(start),
+    };
+    let end = match pos + BRACKET_SEARCH {
+        end if end >= text.len_bytes() => text.len_bytes(),
+        end => text.next_char(end),
+    };
+    let window = text.slice(start..end);
+    // Brackets are ASCII, so searching bytes can't match inside a character
+    let bytes = window.as_bytes();
+    let at = pos - start;
+    let candidates = [at, at.wrapping_sub(1)];
+    for i in candidates.into_iter().filter(|&i| i < bytes.len()) {
+        let (open, close, forward) = match bytes[i] {
+            b'(' => (b'(', b')', true),
+            b'[' => (b'[', b']', true),
+            b'{' => (b'{', b'}', true),
+            b')' => (b'(', b')', false),
+            b']' => (b'[', b']', false),
+            b'}' => (b'{', b'}', false),
+            _ => continue,
+        };
+        let mut depth = 0usize;
+        let found = if forward {
+            bytes[i..].iter().position(|&b| {
+                if b == open {
+                    depth += 1;
+                } else if b == close {
+                    depth -= 1;
+                }
+                depth == 0
+            }).map(|j| i + j)
+        } else {
+            bytes[..=i].iter().rposition(|&b| {
+                if b == close {
+                    depth += 1;
+                } else if b == open {
+                    depth -= 1;
+                }
+                depth == 0
+            })
+        };
+        return found.map(|j| (start + i, start + j));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_indentation() {
+        assert_eq!(IndentUnit::detect("fn f() {\n  let a = 1;\n  if a {\n    b();\n  }\n}\n"), Some(IndentUnit::Spaces(2)));
+        assert_eq!(IndentUnit::detect("a:\n    b: 1\n    c:\n        d: 2\n"), Some(IndentUnit::Spaces(4)));
+        assert_eq!(IndentUnit::detect("int f() {\n\treturn 1;\n}\n"), Some(IndentUnit::Tabs));
+        assert_eq!(IndentUnit::detect("no\nindent\n"), None);
+    }
+
+    #[test]
+    fn dedents_to_the_previous_stop() {
+        assert_eq!(dedent("        ", IndentUnit::Spaces(4)), "    ");
+        assert_eq!(dedent("      ", IndentUnit::Spaces(4)), "    ");
+        assert_eq!(dedent("\t\t", IndentUnit::Tabs), "\t");
+        assert_eq!(dedent("", IndentUnit::Spaces(2)), "");
+    }
+
+    #[test]
+    fn opens_blocks_by_language() {
+        assert!(opens_block(Some(Lang::Rust), "fn main() { "));
+        assert!(opens_block(Some(Lang::Python), "def f():"));
+        assert!(!opens_block(Some(Lang::Rust), "let a = b::"));
+        assert!(!opens_block(None, "a = 1"));
+    }
+
+    #[test]
+    fn finds_matching_brackets() {
+        let text = TextBuffer::from("f(a[1], {b})");
+        assert_eq!(matching_bracket(&text, 1), Some((1, 11)));
+        assert_eq!(matching_bracket(&text, 12), Some((11, 1)));
+        assert_eq!(matching_bracket(&text, 6), Some((5, 3)));
+        assert_eq!(matching_bracket(&text, 8), Some((8, 10)));
+        assert_eq!(matching_bracket(&text, 7), None);
+    }
+}
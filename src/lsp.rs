@@ -0,0 +1,981 @@
+use crate::buffer::TextBuffer;
+use crate::config::LspServerConfig;
+use crate::indent::IndentUnit;
+use crate::outline::{Symbol, SymbolKind};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// A position as LSP counts it: 0-based line and UTF-16 code unit within the line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Position {
+    pub fn of(text: &TextBuffer, byte: usize) -> Self {
+        let byte = byte.min(text.len_bytes());
+        let line = text.line_of(byte);
+        let character = text.byte_to_utf16(byte) - text.byte_to_utf16(text.line_start(line));
+        Self { line, character }
+    }
+
+    /// Byte offset in `text`, clamped to the end of the line
+    pub fn to_byte(self, text: &TextBuffer) -> usize {
+        if self.line >= text.line_count() {
+            return text.len_bytes();
+        }
+        let line_end = text.line_end(self.line);
+        let utf16 = text.byte_to_utf16(text.line_start(self.line)) + self.character;
+        text.utf16_to_byte(utf16.min(text.byte_to_utf16(line_end)))
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "line": self.line, "character": self.character })
+    }
+
+    fn from_json(v: &Value) -> Option<Self> {
+        Some(Self {
+            line: v.get("line")?.as_u64()? as usize,
+            character: v.get("character")?.as_u64()? as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LspRange {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl LspRange {
+    pub fn of(text: &TextBuffer, range: std::ops::Range<usize>) -> Self {
+        Self { start: Position::of(text, range.start), end: Position::of(text, range.end) }
+    }
+
+    pub fn to_bytes(self, text: &TextBuffer) -> std::ops::Range<usize> {
+        let start = self.start.to_byte(text);
+        start..self.end.to_byte(text).max(start)
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "start": self.start.to_json(), "end": self.end.to_json() })
+    }
+
+    fn from_json(v: &Value) -> Option<Self> {
+        Some(Self { start: Position::from_json(v.get("start")?)?, end: Position::from_json(v.get("end")?)? })
+    }
+}
+
+/// One incremental edit for `textDocument/didChange`, in the coordinates of the
+/// document as it was just before this edit
+#[derive(Clone, Debug)]
+pub struct ContentChange {
+    pub range: LspRange,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub range: LspRange,
+    pub severity: Severity,
+    pub message: String,
+    pub source: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompletionItem {
+    pub label: String,
+    pub detail: Option<String>,
+    /// Text to insert in place of the word being completed
+    pub insert_text: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub path: PathBuf,
+    pub range: LspRange,
+}
+
+#[derive(Clone, Debug)]
+pub struct TextEdit {
+    pub range: LspRange,
+    pub new_text: String,
+}
+
+/// An entry of a document's outline
+#[derive(Clone, Debug)]
+pub struct LspSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Everything the symbol covers
+    pub range: LspRange,
+    /// Its name
+    pub selection: LspRange,
+}
+
+impl LspSymbol {
+    pub fn to_symbol(&self, text: &TextBuffer) -> Symbol {
+        let range = self.range.to_bytes(text);
+        let at = self.selection.start.to_byte(text);
+        Symbol { name: self.name.clone(), kind: self.kind, range, at, depth: 0 }
+    }
+}
+
+/// What an editor wants from its language server, at a byte offset in its buffer
+#[derive(Clone, Debug)]
+pub enum LspRequest {
+    Completion(usize),
+    Hover(usize),
+    Definition(usize),
+    References(usize),
+    Rename(usize, String),
+    /// The whole document, indented with the buffer's unit
+    Formatting(IndentUnit),
+    DocumentSymbols,
+}
+
+/// Something a language server sent back. `owner` is the editor that asked and `at`
+/// the byte offset it asked about.
+#[derive(Clone, Debug)]
+pub enum LspEvent {
+    Diagnostics { path: PathBuf, diagnostics: Vec<Diagnostic> },
+    Completion { owner: usize, at: usize, items: Vec<CompletionItem> },
+    Hover { owner: usize, at: usize, text: String },
+    Definition { locations: Vec<Location> },
+    References { locations: Vec<Location> },
+    Rename { edits: Vec<(PathBuf, Vec<TextEdit>)> },
+    Formatting { owner: usize, result: Result<Vec<TextEdit>, String> },
+    Symbols { owner: usize, symbols: Vec<LspSymbol> },
+}
+
+/// LSP `languageId` for a file, by extension
+pub fn language_id(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "javascriptreact",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "typescriptreact",
+        _ => return None,
+    })
+}
+
+/// Built-in server for a language, used unless the config names another
+fn default_server(language_id: &str) -> Option<LspServerConfig> {
+    let (command, args): (&str, &[&str]) = match language_id {
+        "rust" => ("rust-analyzer", &[]),
+        "python" => ("pyright-langserver", &["--stdio"]),
+        "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => {
+            ("typescript-language-server", &["--stdio"])
+        }
+        _ => return None,
+    };
+    Some(LspServerConfig { command: command.to_string(), args: args.iter().map(|a| a.to_string()).collect() })
+}
+
+/// Files marking a project root, nearest first, per language
+fn root_markers(language_id: &str) -> &'static [&'static str] {
+    match language_id {
+        "rust" => &["Cargo.toml"],
+        "python" => &["pyproject.toml", "setup.py", "setup.cfg", "requirements.txt"],
+        _ => &["tsconfig.json", "jsconfig.json", "package.json"],
+    }
+}
+
+/// Workspace root for a file: the nearest directory with a project file, else the
+/// repository root, else the file's directory
+pub fn workspace_root(path: &Path, language_id: &str) -> PathBuf {
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let find = |names: &[&str]| dir.ancestors().find(|d| names.iter().any(|n| d.join(n).exists())).map(Path::to_path_buf);
+    find(root_markers(language_id)).or_else(|| find(&[".git"])).unwrap_or_else(|| dir.to_path_buf())
+}
+
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for &b in path.to_string_lossy().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' && i + 2 < encoded.len() {
+            let hex = std::str::from_utf8(&encoded[i + 1..i + 3]).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+/// Write a JSON-RPC message with its `Content-Length` header
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Read one JSON-RPC message; `None` at end of stream
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(io::Error::other)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RequestKind {
+    Initialize,
+    Shutdown,
+    Completion,
+    Hover,
+    Definition,
+    References,
+    Rename,
+    Formatting,
+    DocumentSymbols,
+}
+
+/// Who asked for an outstanding request, so the reply can be routed back
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    kind: RequestKind,
+    owner: usize,
+    at: usize,
+}
+
+/// A running language server, talking JSON-RPC over its stdin/stdout
+pub struct LspClient {
+    pub root: PathBuf,
+    config: LspServerConfig,
+    /// Taken by `drop` to wait for it off the UI thread
+    process: Option<Child>,
+    /// Messages for the writer thread, so a server that stops reading can't block the UI
+    outgoing: mpsc::Sender<Value>,
+    /// Messages read by the reader thread, drained on the UI thread
+    incoming: Arc<Mutex<Vec<Value>>>,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+    /// Messages held back until the server has answered `initialize`
+    queued: Option<Vec<Value>>,
+    capabilities: Value,
+    /// Documents we sent `didOpen` for, with their current version
+    open_docs: HashMap<PathBuf, i32>,
+}
+
+impl LspClient {
+    pub fn start(config: &LspServerConfig, root: &Path) -> io::Result<Self> {
+        let mut process = Command::new(&config.command)
+            .args(&config.args)
+            .current_dir(root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut stdin = process.stdin.take().ok_or_else(|| io::Error::other("no stdin"))?;
+        let stdout = process.stdout.take().ok_or_else(|| io::Error::other("no stdout"))?;
+
+        let incoming = Arc::new(Mutex::new(Vec::new()));
+        let sink = incoming.clone();
+        let name = config.command.clone();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            loop {
+                match read_message(&mut reader) {
+                    Ok(Some(message)) => sink.lock().unwrap().push(message),
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("{}: {}", name, e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Ends once the client is dropped, closing the server's stdin
+        let (outgoing, queue) = mpsc::channel::<Value>();
+        let name = config.command.clone();
+        std::thread::spawn(move || {
+            for message in queue {
+                if let Err(e) = write_message(&mut stdin, &message) {
+                    eprintln!("{}: {}", name, e);
+                    break;
+                }
+            }
+        });
+
+        let mut client = Self {
+            root: root.to_path_buf(),
+            config: config.clone(),
+            process: Some(process),
+            outgoing,
+            incoming,
+            next_id: 0,
+            pending: HashMap::new(),
+            queued: Some(Vec::new()),
+            capabilities: Value::Null,
+            open_docs: HashMap::new(),
+        };
+        let params = json!({
+            "processId": std::process::id(),
+            "rootUri": path_to_uri(root),
+            "workspaceFolders": [{
+                "uri": path_to_uri(root),
+                "name": root.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            }],
+            "capabilities": {
+                "textDocument": {
+                    "synchronization": { "didSave": true },
+                    "completion": { "completionItem": { "snippetSupport": false } },
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "publishDiagnostics": {},
+                    "definition": {},
+                    "references": {},
+                    "rename": {},
+                    "documentSymbol": { "hierarchicalDocumentSymbolSupport": true },
+                },
+            },
+        });
+        let id = client.next_request_id(Pending { kind: RequestKind::Initialize, owner: 0, at: 0 });
+        client.write(json!({ "jsonrpc": "2.0", "id": id, "method": "initialize", "params": params }));
+        Ok(client)
+    }
+
+    fn next_request_id(&mut self, pending: Pending) -> u64 {
+        self.next_id += 1;
+        self.pending.insert(self.next_id, pending);
+        self.next_id
+    }
+
+    fn write(&self, message: Value) {
+        // Fails only once the writer thread gave up, which it already reported
+        let _ = self.outgoing.send(message);
+    }
+
+    /// Send now, or once initialization is done
+    fn send(&mut self, message: Value) {
+        match &mut self.queued {
+            Some(queue) => queue.push(message),
+            None => self.write(message),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn request(&mut self, method: &str, params: Value, pending: Pending) {
+        let id = self.next_request_id(pending);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+    }
+
+    pub fn is_open(&self, path: &Path) -> bool {
+        self.open_docs.contains_key(path)
+    }
+
+    /// Characters after which the server wants to be asked for completions
+    pub fn trigger_characters(&self) -> Vec<String> {
+        self.capabilities
+            .pointer("/completionProvider/triggerCharacters")
+            .and_then(Value::as_array)
+            .map(|chars| chars.iter().filter_map(|c| c.as_str().map(str::to_string)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the server can list a document's symbols; false until it has started
+    pub fn has_document_symbols(&self) -> bool {
+        self.capabilities.get("documentSymbolProvider").is_some_and(|p| !matches!(p, Value::Null | Value::Bool(false)))
+    }
+
+    /// Whether the server only accepts whole documents in `didChange`
+    fn full_sync(&self) -> bool {
+        let kind = self.capabilities.get("textDocumentSync");
+        let kind = kind.and_then(|k| k.get("change")).or(kind).and_then(Value::as_u64);
+        kind == Some(1)
+    }
+
+    pub fn did_open(&mut self, path: &Path, text: &TextBuffer) {
+        let Some(language) = language_id(path) else { return };
+        self.open_docs.insert(path.to_path_buf(), 0);
+        self.notify(
+            "textDocument/didOpen",
+            json!({ "textDocument": {
+                "uri": path_to_uri(path),
+                "languageId": language,
+                "version": 0,
+                "text": text.to_string(),
+            }}),
+        );
+    }
+
+    /// Send edits made since the last change; `text` is the buffer after all of them
+    pub fn did_change(&mut self, path: &Path, changes: &[ContentChange], text: &TextBuffer) {
+        let Some(version) = self.open_docs.get_mut(path) else { return };
+        *version += 1;
+        let version = *version;
+        let content_changes: Vec<Value> = if self.full_sync() {
+            vec![json!({ "text": text.to_string() })]
+        } else {
+            changes.iter().map(|c| json!({ "range": c.range.to_json(), "text": c.text })).collect()
+        };
+        self.notify(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": path_to_uri(path), "version": version },
+                "contentChanges": content_changes,
+            }),
+        );
+    }
+
+    pub fn did_save(&mut self, path: &Path) {
+        if self.is_open(path) {
+            self.notify("textDocument/didSave", json!({ "textDocument": { "uri": path_to_uri(path) } }));
+        }
+    }
+
+    pub fn did_close(&mut self, path: &Path) {
+        if self.open_docs.remove(path).is_some() {
+            self.notify("textDocument/didClose", json!({ "textDocument": { "uri": path_to_uri(path) } }));
+        }
+    }
+
+    /// Send an editor's request; `text` is its buffer, to turn offsets into positions
+    pub fn send_request(&mut self, owner: usize, path: &Path, request: &LspRequest, text: &TextBuffer) {
+        let at = match request {
+            LspRequest::Completion(at)
+            | LspRequest::Hover(at)
+            | LspRequest::Definition(at)
+            | LspRequest::References(at)
+            | LspRequest::Rename(at, _) => *at,
+            LspRequest::Formatting(_) | LspRequest::DocumentSymbols => 0,
+        };
+        let mut params = json!({
+            "textDocument": { "uri": path_to_uri(path) },
+            "position": Position::of(text, at).to_json(),
+        });
+        let (method, kind) = match request {
+            LspRequest::Completion(_) => ("textDocument/completion", RequestKind::Completion),
+            LspRequest::Hover(_) => ("textDocument/hover", RequestKind::Hover),
+            LspRequest::Definition(_) => ("textDocument/definition", RequestKind::Definition),
+            LspRequest::References(_) => {
+                params["context"] = json!({ "includeDeclaration": true });
+                ("textDocument/references", RequestKind::References)
+            }
+            LspRequest::Rename(_, new_name) => {
+                params["newName"] = json!(new_name);
+                ("textDocument/rename", RequestKind::Rename)
+            }
+            LspRequest::Formatting(indent) => {
+                params = json!({ "textDocument": { "uri": path_to_uri(path) }, "options": formatting_options(*indent) });
+                ("textDocument/formatting", RequestKind::Formatting)
+            }
+            LspRequest::DocumentSymbols => {
+                params = json!({ "textDocument": { "uri": path_to_uri(path) } });
+                ("textDocument/documentSymbol", RequestKind::DocumentSymbols)
+            }
+        };
+        self.request(method, params, Pending { kind, owner, at });
+    }
+
+    /// Handle everything the server sent since the last call
+    pub fn poll(&mut self) -> Vec<LspEvent> {
+        let messages = std::mem::take(&mut *self.incoming.lock().unwrap());
+        let mut events = Vec::new();
+        for message in messages {
+            let method = message.get("method").and_then(Value::as_str);
+            match (method, message.get("id")) {
+                // A request from the server; we support none, but must answer
+                (Some(method), Some(id)) => {
+                    let result = match method {
+                        "workspace/configuration" => {
+                            let items = message.pointer("/params/items").and_then(Value::as_array);
+                            Value::Array(vec![Value::Null; items.map_or(0, Vec::len)])
+                        }
+                        _ => Value::Null,
+                    };
+                    self.write(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+                }
+                (Some("textDocument/publishDiagnostics"), None) => {
+                    if let Some(event) = parse_diagnostics(&message["params"]) {
+                        events.push(event);
+                    }
+                }
+                (Some(_), None) => {}
+                (None, Some(id)) => {
+                    let Some(pending) = id.as_u64().and_then(|id| self.pending.remove(&id)) else { continue };
+                    if let Some(error) = message.get("error") {
+                        let error = format!("{}: {}", self.config.command, error["message"].as_str().unwrap_or("request failed"));
+                        // Formatting errors are shown in the editor that asked
+                        if let RequestKind::Formatting = pending.kind {
+                            events.push(LspEvent::Formatting { owner: pending.owner, result: Err(error) });
+                        } else {
+                            eprintln!("{}", error);
+                        }
+                        continue;
+                    }
+                    events.extend(self.handle_response(pending, &message["result"]));
+                }
+                (None, None) => {}
+            }
+        }
+        events
+    }
+
+    fn handle_response(&mut self, pending: Pending, result: &Value) -> Option<LspEvent> {
+        let Pending { kind, owner, at } = pending;
+        match kind {
+            RequestKind::Initialize => {
+                self.capabilities = result.get("capabilities").cloned().unwrap_or(Value::Null);
+                self.write(json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+                for message in self.queued.take().unwrap_or_default() {
+                    self.write(message);
+                }
+                None
+            }
+            RequestKind::Shutdown => None,
+            RequestKind::Completion => Some(LspEvent::Completion { owner, at, items: parse_completions(result) }),
+            RequestKind::Hover => {
+                let text = hover_text(result.get("contents")?);
+                (!text.trim().is_empty()).then_some(LspEvent::Hover { owner, at, text })
+            }
+            RequestKind::Definition => Some(LspEvent::Definition { locations: parse_locations(result) }),
+            RequestKind::References => Some(LspEvent::References { locations: parse_locations(result) }),
+            RequestKind::Rename => Some(LspEvent::Rename { edits: parse_workspace_edit(result) }),
+            RequestKind::Formatting => Some(LspEvent::Formatting { owner, result: Ok(parse_edits(result)) }),
+            RequestKind::DocumentSymbols => Some(LspEvent::Symbols { owner, symbols: parse_symbols(result) }),
+        }
+    }
+}
+
+impl Drop for LspClient {
+    fn drop(&mut self) {
+        let id = self.next_request_id(Pending { kind: RequestKind::Shutdown, owner: 0, at: 0 });
+        self.write(json!({ "jsonrpc": "2.0", "id": id, "method": "shutdown" }));
+        self.write(json!({ "jsonrpc": "2.0", "method": "exit" }));
+        let Some(mut process) = self.process.take() else { return };
+        // Give it a moment to exit by itself, without holding up the UI
+        std::thread::spawn(move || {
+            for _ in 0..10 {
+                if let Ok(Some(_)) = process.try_wait() {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            let _ = process.kill();
+            let _ = process.wait();
+        });
+    }
+}
+
+/// `FormattingOptions` asking for the buffer's own indentation
+fn formatting_options(indent: IndentUnit) -> Value {
+    match indent {
+        IndentUnit::Tabs => json!({ "tabSize": 4, "insertSpaces": false }),
+        IndentUnit::Spaces(n) => json!({ "tabSize": n, "insertSpaces": true }),
+    }
+}
+
+fn parse_diagnostics(params: &Value) -> Option<LspEvent> {
+    let path = uri_to_path(params.get("uri")?.as_str()?)?;
+    let diagnostics = params
+        .get("diagnostics")?
+        .as_array()?
+        .iter()
+        .filter_map(|d| {
+            Some(Diagnostic {
+                range: LspRange::from_json(d.get("range")?)?,
+                severity: match d.get("severity").and_then(Value::as_u64) {
+                    Some(2) => Severity::Warning,
+                    Some(3) => Severity::Information,
+                    Some(4) => Severity::Hint,
+                    _ => Severity::Error,
+                },
+                message: d.get("message")?.as_str()?.to_string(),
+                source: d.get("source").and_then(Value::as_str).map(str::to_string),
+            })
+        })
+        .collect();
+    Some(LspEvent::Diagnostics { path, diagnostics })
+}
+
+fn parse_completions(result: &Value) -> Vec<CompletionItem> {
+    // Either a plain list or a `CompletionList` with `items`
+    let items = result.get("items").unwrap_or(result).as_array();
+    items
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            let label = item.get("label")?.as_str()?.to_string();
+            let insert_text = item
+                .pointer("/textEdit/newText")
+                .or_else(|| item.get("insertText"))
+                .and_then(Value::as_str)
+                .unwrap_or(&label)
+                .to_string();
+            Some(CompletionItem {
+                detail: item.get("detail").and_then(Value::as_str).map(str::to_string),
+                label,
+                insert_text,
+            })
+        })
+        .collect()
+}
+
+/// Plain text of hover contents, which may be a string, a `MarkupContent` or a list of
+/// `MarkedString`s
+fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(s) => s.clone(),
+        Value::Array(parts) => parts.iter().map(hover_text).collect::<Vec<_>>().join("\n\n"),
+        Value::Object(o) => o.get("value").and_then(Value::as_str).unwrap_or_default().to_string(),
+        _ => String::new(),
+    }
+}
+
+/// A `Location`, a list of them, or a list of `LocationLink`s
+fn parse_locations(result: &Value) -> Vec<Location> {
+    let parse = |v: &Value| {
+        let uri = v.get("uri").or_else(|| v.get("targetUri"))?.as_str()?;
+        let range = v.get("targetSelectionRange").or_else(|| v.get("range"))?;
+        Some(Location { path: uri_to_path(uri)?, range: LspRange::from_json(range)? })
+    };
+    match result {
+        Value::Array(items) => items.iter().filter_map(parse).collect(),
+        Value::Object(_) => parse(result).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_edits(edits: &Value) -> Vec<TextEdit> {
+    edits
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| {
+            Some(TextEdit {
+                range: LspRange::from_json(e.get("range")?)?,
+                new_text: e.get("newText")?.as_str()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// `DocumentSymbol`s, flattened with children after their parent, or the flat
+/// `SymbolInformation`s older servers send
+fn parse_symbols(result: &Value) -> Vec<LspSymbol> {
+    fn walk(items: &Value, symbols: &mut Vec<LspSymbol>) {
+        for item in items.as_array().into_iter().flatten() {
+            let name = item.get("name").and_then(Value::as_str);
+            let kind = SymbolKind::from_lsp(item.get("kind").and_then(Value::as_u64).unwrap_or(0));
+            let range = item.get("range").or_else(|| item.pointer("/location/range")).and_then(LspRange::from_json);
+            let selection = item.get("selectionRange").and_then(LspRange::from_json).or(range);
+            if let (Some(name), Some(range), Some(selection)) = (name, range, selection) {
+                symbols.push(LspSymbol { name: name.to_string(), kind, range, selection });
+            }
+            walk(&item["children"], symbols);
+        }
+    }
+    let mut symbols = Vec::new();
+    walk(result, &mut symbols);
+    symbols
+}
+
+/// Edits per file from a `WorkspaceEdit`, in either its `changes` or `documentChanges` form
+fn parse_workspace_edit(result: &Value) -> Vec<(PathBuf, Vec<TextEdit>)> {
+    let mut files = Vec::new();
+    if let Some(changes) = result.get("changes").and_then(Value::as_object) {
+        for (uri, edits) in changes {
+            if let Some(path) = uri_to_path(uri) {
+                files.push((path, parse_edits(edits)));
+            }
+        }
+    }
+    for change in result.get("documentChanges").and_then(Value::as_array).into_iter().flatten() {
+        let uri = change.pointer("/textDocument/uri").and_then(Value::as_str);
+        if let Some(path) = uri.and_then(uri_to_path) {
+            files.push((path, parse_edits(&change["edits"])));
+        }
+    }
+    files
+}
+
+/// Apply LSP edits to a buffer as byte-range replacements in document order
+pub fn resolve_edits(text: &TextBuffer, edits: &[TextEdit]) -> Vec<(std::ops::Range<usize>, String)> {
+    let mut resolved: Vec<_> = edits.iter().map(|e| (e.range.to_bytes(text), e.new_text.clone())).collect();
+    resolved.sort_by_key(|(range, _)| range.start);
+    resolved
+}
+
+/// Language servers for all open files, started on demand: one per server command and
+/// workspace root
+#[derive(Default)]
+pub struct LspManager {
+    /// Servers by language id, overriding the built-in ones
+    servers: BTreeMap<String, LspServerConfig>,
+    clients: Vec<LspClient>,
+    /// Which client serves each file, once worked out (`None` if none can)
+    routes: HashMap<PathBuf, Option<usize>>,
+    /// Servers that failed to start, not retried
+    failed: HashSet<(String, PathBuf)>,
+}
+
+impl LspManager {
+    pub fn new(servers: BTreeMap<String, LspServerConfig>) -> Self {
+        Self { servers, ..Default::default() }
+    }
+
+    /// The client for a file, starting its server if needed
+    pub fn client_for(&mut self, path: &Path) -> Option<&mut LspClient> {
+        if let Some(route) = self.routes.get(path) {
+            return route.map(|i| &mut self.clients[i]);
+        }
+        let route = self.route(path);
+        self.routes.insert(path.to_path_buf(), route);
+        route.map(|i| &mut self.clients[i])
+    }
+
+    fn route(&mut self, path: &Path) -> Option<usize> {
+        let language = language_id(path)?;
+        let config = self.servers.get(language).cloned().or_else(|| default_server(language))?;
+        let root = workspace_root(path, language);
+        if let Some(i) = self.clients.iter().position(|c| c.config == config && c.root == root) {
+            return Some(i);
+        }
+        if self.failed.contains(&(config.command.clone(), root.clone())) {
+            return None;
+        }
+        match LspClient::start(&config, &root) {
+            Ok(client) => {
+                self.clients.push(client);
+                Some(self.clients.len() - 1)
+            }
+            Err(e) => {
+                eprintln!("Failed to start language server {}: {}", config.command, e);
+                self.failed.insert((config.command, root));
+                None
+            }
+        }
+    }
+
+    /// Close documents that are no longer open in any editor
+    pub fn retain_open(&mut self, open: &HashSet<PathBuf>) {
+        for client in &mut self.clients {
+            let closed: Vec<PathBuf> = client.open_docs.keys().filter(|p| !open.contains(*p)).cloned().collect();
+            for path in closed {
+                client.did_close(&path);
+            }
+        }
+    }
+
+    pub fn poll(&mut self) -> Vec<LspEvent> {
+        self.clients.iter_mut().flat_map(LspClient::poll).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn message_framing_round_trips() {
+        let mut out = Vec::new();
+        write_message(&mut out, &json!({ "id": 1, "method": "ü" })).unwrap();
+        write_message(&mut out, &json!({ "id": 2 })).unwrap();
+        let mut reader = io::Cursor::new(out);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "id": 1, "method": "ü" })));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "id": 2 })));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn positions_count_utf16_units() {
+        let text = TextBuffer::from("a😀b\nxé\n");
+        // 😀 is 4 bytes in UTF-8 but 2 units in UTF-16
+        assert_eq!(Position::of(&text, 5), Position { line: 0, character: 3 });
+        assert_eq!(Position { line: 0, character: 3 }.to_byte(&text), 5);
+        assert_eq!(Position::of(&text, 10), Position { line: 1, character: 2 });
+        // Past the end of a line clamps to it
+        assert_eq!(Position { line: 1, character: 99 }.to_byte(&text), 10);
+        assert_eq!(Position { line: 9, character: 0 }.to_byte(&text), text.len_bytes());
+    }
+
+    #[test]
+    fn parses_both_symbol_shapes() {
+        let range = |line| json!({ "start": { "line": line, "character": 0 }, "end": { "line": line + 1, "character": 0 } });
+        let nested = json!([{
+            "name": "Editor", "kind": 23, "range": range(0), "selectionRange": range(0),
+            "children": [{ "name": "render", "kind": 6, "range": range(1), "selectionRange": range(1) }],
+        }]);
+        let flat = json!([{ "name": "main", "kind": 12, "location": { "uri": "file:///a.rs", "range": range(2) } }]);
+        let names = |symbols: Vec<LspSymbol>| symbols.into_iter().map(|s| (s.name, s.kind)).collect::<Vec<_>>();
+        assert_eq!(
+            names(parse_symbols(&nested)),
+            [("Editor".to_string(), SymbolKind::Type), ("render".to_string(), SymbolKind::Method)]
+        );
+        let symbols = parse_symbols(&flat);
+        assert_eq!(symbols[0].selection, symbols[0].range);
+        assert_eq!(names(symbols), [("main".to_string(), SymbolKind::Function)]);
+    }
+
+    #[test]
+    fn uris_round_trip() {
+        let path = Path::new("/tmp/a dir/ファイル#1.rs");
+        let uri = path_to_uri(path);
+        assert_eq!(uri.split('/').nth(4), Some("a%20dir"));
+        assert_eq!(uri_to_path(&uri).as_deref(), Some(path));
+    }
+
+    /// The stub server built from `examples/lsp_stub.rs`
+    fn stub_server() -> LspServerConfig {
+        let exe = std::env::current_exe().unwrap();
+        let dir = exe.parent().and_then(Path::parent).unwrap();
+        let stub = dir.join("examples").join(format!("lsp_stub{}", std::env::consts::EXE_SUFFIX));
+        assert!(stub.exists(), "build the stub first: cargo build --example lsp_stub");
+        LspServerConfig { command: stub.to_string_lossy().to_string(), args: Vec::new() }
+    }
+
+    fn wait_for(client: &mut LspClient, mut f: impl FnMut(&LspEvent) -> bool) -> LspEvent {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(event) = client.poll().into_iter().find(|e| f(e)) {
+                return event;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no matching event from the stub server");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_dont_wait_for_the_server_to_read() {
+        let config = LspServerConfig { command: "sleep".to_string(), args: vec!["10".to_string()] };
+        let client = LspClient::start(&config, &std::env::temp_dir()).unwrap();
+        // Far more than a pipe holds, with nobody reading it
+        let started = Instant::now();
+        client.write(json!({ "text": "x".repeat(4 << 20) }));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn talks_to_stub_server() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("lsp_stub_test.rs");
+        let mut client = LspClient::start(&stub_server(), &dir).unwrap();
+
+        // The stub reports every `TODO` as a warning, so diagnostics show whether it
+        // reconstructed the document from our incremental changes correctly
+        let mut text = TextBuffer::from("fn main() {}\n// TODO\n");
+        client.did_open(&path, &text);
+        let LspEvent::Diagnostics { diagnostics, .. } = wait_for(&mut client, |e| matches!(e, LspEvent::Diagnostics { .. }))
+        else {
+            unreachable!()
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start, Position { line: 1, character: 3 });
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+
+        let mut changes = Vec::new();
+        for (range, insert) in [(0..0, "// TODO 😀\n"), (27..27, "x TODO")] {
+            changes.push(ContentChange { range: LspRange::of(&text, range.clone()), text: insert.to_string() });
+            text.replace(range, insert);
+        }
+        client.did_change(&path, &changes, &text);
+        let LspEvent::Diagnostics { diagnostics, .. } =
+            wait_for(&mut client, |e| matches!(e, LspEvent::Diagnostics { diagnostics, .. } if diagnostics.len() == 3))
+        else {
+            unreachable!()
+        };
+        let starts: Vec<Position> = diagnostics.iter().map(|d| d.range.start).collect();
+        assert_eq!(
+            starts,
+            [Position { line: 0, character: 3 }, Position { line: 2, character: 3 }, Position { line: 2, character: 9 }]
+        );
+
+        client.send_request(7, &path, &LspRequest::Completion(3), &text);
+        let LspEvent::Completion { owner, at, items } = wait_for(&mut client, |e| matches!(e, LspEvent::Completion { .. }))
+        else {
+            unreachable!()
+        };
+        assert_eq!((owner, at), (7, 3));
+        assert_eq!(items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>(), ["alpha", "beta"]);
+        assert_eq!(client.trigger_characters(), ["."]);
+
+        client.send_request(7, &path, &LspRequest::Hover(16), &text);
+        let LspEvent::Hover { text: hover, .. } = wait_for(&mut client, |e| matches!(e, LspEvent::Hover { .. })) else {
+            unreachable!()
+        };
+        assert_eq!(hover, "hover 1:3");
+
+        client.send_request(7, &path, &LspRequest::Definition(16), &text);
+        let LspEvent::Definition { locations, .. } = wait_for(&mut client, |e| matches!(e, LspEvent::Definition { .. }))
+        else {
+            unreachable!()
+        };
+        assert_eq!(locations[0].path, path);
+        assert_eq!(locations[0].range.start, Position { line: 0, character: 0 });
+
+        client.send_request(7, &path, &LspRequest::Rename(16, "renamed".into()), &text);
+        let LspEvent::Rename { edits, .. } = wait_for(&mut client, |e| matches!(e, LspEvent::Rename { .. })) else {
+            unreachable!()
+        };
+        assert_eq!(edits.len(), 1);
+        let resolved = resolve_edits(&text, &edits[0].1);
+        assert_eq!(resolved, vec![(0..2, "renamed".to_string())]);
+
+        // Formatting asks for the buffer's own indentation
+        for (indent, options) in
+            [(IndentUnit::Tabs, "tabSize 4 insertSpaces false"), (IndentUnit::Spaces(2), "tabSize 2 insertSpaces true")]
+        {
+            client.send_request(7, &path, &LspRequest::Formatting(indent), &text);
+            let LspEvent::Formatting { result, .. } =
+                wait_for(&mut client, |e| matches!(e, LspEvent::Formatting { .. }))
+            else {
+                unreachable!()
+            };
+            assert_eq!(result.unwrap()[0].new_text, format!("{}\n", options));
+        }
+    }
+}
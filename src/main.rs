@@ -1,10 +1,32 @@
 mod agent_view;
 mod app;
+mod buffer;
+mod color_scheme;
+mod config;
+mod diff_view;
 mod editor;
+mod encoding;
+mod find;
+mod fold;
+mod format;
+mod git;
 mod file_tree;
+mod file_view;
+mod ime;
+mod indent;
+mod lsp;
+mod outline;
 mod pane;
+mod problems;
+mod project_search;
+mod syntax;
+mod term_responder;
 mod terminal;
 mod theme;
+mod undo;
+mod vim;
+mod watcher;
+mod wrap;
 
 fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions {
@@ -0,0 +1,162 @@
+use std::ops::Range;
+
+/// What a symbol is, coarsely, for the tag shown beside its name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Module,
+    Type,
+    Function,
+    Method,
+    Constant,
+    Variable,
+    Field,
+    Heading,
+    Other,
+}
+
+impl SymbolKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            SymbolKind::Module => "mod",
+            SymbolKind::Type => "type",
+            SymbolKind::Function => "fn",
+            SymbolKind::Method => "method",
+            SymbolKind::Constant => "const",
+            SymbolKind::Variable => "var",
+            SymbolKind::Field => "field",
+            SymbolKind::Heading => "#",
+            SymbolKind::Other => "",
+        }
+    }
+
+    /// From an LSP `SymbolKind` number
+    pub fn from_lsp(kind: u64) -> Self {
+        match kind {
+            1..=4 => SymbolKind::Module,
+            5 | 10 | 11 | 23 | 26 => SymbolKind::Type,
+            6 | 9 => SymbolKind::Method,
+            12 => SymbolKind::Function,
+            14 | 22 => SymbolKind::Constant,
+            13 | 15..=19 | 21 => SymbolKind::Variable,
+            7 | 8 | 20 => SymbolKind::Field,
+            _ => SymbolKind::Other,
+        }
+    }
+}
+
+/// A named item in a file: a function, type, heading and so on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Everything the symbol covers, e.g. a function with its body
+    pub range: Range<usize>,
+    /// Where its name is, to jump to
+    pub at: usize,
+    /// How many other symbols contain it; set by `nest`
+    pub depth: usize,
+}
+
+/// Put symbols in document order, outer before inner, and set their depths
+pub fn nest(symbols: &mut [Symbol]) {
+    symbols.sort_by(|a, b| a.range.start.cmp(&b.range.start).then(b.range.end.cmp(&a.range.end)));
+    // Ends of the symbols containing the current one, innermost last
+    let mut open: Vec<usize> = Vec::new();
+    for symbol in symbols {
+        while open.last().is_some_and(|&end| end < symbol.range.end) {
+            open.pop();
+        }
+        symbol.depth = open.len();
+        open.push(symbol.range.end);
+    }
+}
+
+/// The symbols containing `pos`, outermost first; `symbols` must be nested
+pub fn enclosing(symbols: &[Symbol], pos: usize) -> Vec<&Symbol> {
+    let mut chain: Vec<&Symbol> = Vec::new();
+    for symbol in symbols.iter().take_while(|s| s.range.start <= pos) {
+        if pos <= symbol.range.end {
+            chain.truncate(symbol.depth);
+            if chain.len() == symbol.depth {
+                chain.push(symbol);
+            }
+        }
+    }
+    chain
+}
+
+/// Indices of the symbols whose names hold the query's characters in order,
+/// ignoring case
+pub fn filter(symbols: &[Symbol], query: &str) -> Vec<usize> {
+    let query = query.to_lowercase();
+    symbols
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| {
+            let mut name = s.name.chars().flat_map(char::to_lowercase);
+            query.chars().filter(|c| !c.is_whitespace()).all(|q| name.any(|c| c == q))
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// A 1-based `line` or `line:column` (also `line,column`), as 0-based values
+pub fn parse_line_col(text: &str) -> Option<(usize, Option<usize>)> {
+    let mut parts = text.trim().splitn(2, [':', ',']);
+    let line: usize = parts.next()?.trim().parse().ok()?;
+    let column = match parts.next().map(str::trim) {
+        None | Some("") => None,
+        Some(col) => Some(col.parse::<usize>().ok()?.saturating_sub(1)),
+    };
+    Some((line.saturating_sub(1), column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, range: Range<usize>) -> Symbol {
+        Symbol { name: name.to_string(), kind: SymbolKind::Function, at: range.start, range, depth: 0 }
+    }
+
+    #[test]
+    fn nests_by_containment() {
+        let mut symbols = vec![
+            symbol("b", 40..60),
+            symbol("inner", 10..20),
+            symbol("outer", 0..30),
+            symbol("deep", 12..15),
+            symbol("c", 60..70),
+        ];
+        nest(&mut symbols);
+        let order: Vec<(&str, usize)> = symbols.iter().map(|s| (s.name.as_str(), s.depth)).collect();
+        assert_eq!(order, [("outer", 0), ("inner", 1), ("deep", 2), ("b", 0), ("c", 0)]);
+
+        let names = |pos| enclosing(&symbols, pos).iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(13), ["outer", "inner", "deep"]);
+        assert_eq!(names(25), ["outer"]);
+        assert_eq!(names(35), Vec::<String>::new());
+        // Touching siblings: the later one wins
+        assert_eq!(names(60), ["c"]);
+    }
+
+    #[test]
+    fn filters_by_subsequence() {
+        let symbols = vec![symbol("render_status_bar", 0..1), symbol("Renderer", 1..2), symbol("save", 2..3)];
+        assert_eq!(filter(&symbols, ""), [0, 1, 2]);
+        assert_eq!(filter(&symbols, "rsb"), [0]);
+        assert_eq!(filter(&symbols, "REN"), [0, 1]);
+        assert_eq!(filter(&symbols, "x"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn parses_line_and_column() {
+        assert_eq!(parse_line_col("42"), Some((41, None)));
+        assert_eq!(parse_line_col(" 42:7 "), Some((41, Some(6))));
+        assert_eq!(parse_line_col("42,7"), Some((41, Some(6))));
+        assert_eq!(parse_line_col("42:"), Some((41, None)));
+        assert_eq!(parse_line_col("0"), Some((0, None)));
+        assert_eq!(parse_line_col("x"), None);
+        assert_eq!(parse_line_col("4:x"), None);
+    }
+}
@@ -5,7 +5,11 @@ use eframe::egui;
 pub enum TabContent {
     Terminal(usize), // terminal instance id
     FileTree,
+    Search,          // project-wide search for the open folder
+    Problems,        // diagnostics from language servers
     Editor(usize),   // editor instance id
+    FileView(usize), // read-only hex or large-file view id
+    Diff(usize),     // diff view id
     ClaudeCode(usize), // Claude Code terminal instance id
     Codex(usize),      // Codex terminal instance id
 }
@@ -15,17 +19,28 @@ impl TabContent {
         match self {
             TabContent::Terminal(id) => format!("Terminal {}", id),
             TabContent::FileTree => "Files".to_string(),
+            TabContent::Search => "Search".to_string(),
+            TabContent::Problems => "Problems".to_string(),
             TabContent::Editor(id) => format!("Editor {}", id),
+            TabContent::FileView(id) => format!("File {}", id),
+            TabContent::Diff(id) => format!("Diff {}", id),
             TabContent::ClaudeCode(_) => "Claude Code".to_string(),
             TabContent::Codex(_) => "Codex".to_string(),
         }
     }
 
-    pub fn title_with_editors(&self, editors: &std::collections::HashMap<usize, crate::editor::Editor>) -> String {
+    pub fn title_with_editors(
+        &self,
+        editors: &std::collections::HashMap<usize, crate::editor::Editor>,
+        file_views: &std::collections::HashMap<usize, crate::file_view::FileView>,
+        diff_views: &std::collections::HashMap<usize, crate::diff_view::DiffView>,
+    ) -> String {
         match self {
             TabContent::Editor(id) => {
                 editors.get(id).map(|e| e.title()).unwrap_or_else(|| format!("Editor {}", id))
             }
+            TabContent::FileView(id) => file_views.get(id).map(|v| v.title()).unwrap_or_else(|| self.title()),
+            TabContent::Diff(id) => diff_views.get(id).map(|v| v.title()).unwrap_or_else(|| self.title()),
             _ => self.title(),
         }
     }
@@ -168,66 +183,14 @@ pub fn render_pane_tree(
     }
 }
 
-/// Draw tab bar for a leaf pane, returns the remaining rect for content
-pub fn draw_tab_bar(ui: &mut egui::Ui, rect: egui::Rect, leaf: &mut LeafPane) -> egui::Rect {
-    let tab_height = 28.0;
-    let tab_rect = egui::Rect::from_min_size(rect.left_top(), egui::vec2(rect.width(), tab_height));
-
-    // Background
-    ui.painter()
-        .rect_filled(tab_rect, 0.0, crate::theme::TAB_INACTIVE);
-
-    // Use rect position as unique pane identifier
-    let pane_id = (rect.left() as i32, rect.top() as i32);
-
-    let mut x = tab_rect.left() + 4.0;
-    for (i, tab) in leaf.tabs.iter().enumerate() {
-        let title = tab.title();
-        let text_width = title.len() as f32 * 7.5 + 16.0;
-        let this_tab = egui::Rect::from_min_size(egui::pos2(x, tab_rect.top()), egui::vec2(text_width, tab_height));
-
-        let bg = if i == leaf.active_tab {
-            crate::theme::TAB_ACTIVE
-        } else {
-            crate::theme::TAB_INACTIVE
-        };
-        ui.painter().rect_filled(this_tab, 2.0, bg);
-
-        let id = ui.id().with("tab").with(pane_id).with(i);
-        let resp = ui.interact(this_tab, id, egui::Sense::click());
-        if resp.clicked() {
-            leaf.active_tab = i;
-        }
-
-        let color = if i == leaf.active_tab {
-            crate::theme::TEXT_PRIMARY
-        } else {
-            crate::theme::TEXT_SECONDARY
-        };
-        ui.painter().text(
-            this_tab.center(),
-            egui::Align2::CENTER_CENTER,
-            &title,
-            egui::FontId::proportional(13.0),
-            color,
-        );
-
-        x += text_width + 2.0;
-    }
-
-    // Content area below tabs
-    egui::Rect::from_min_max(
-        egui::pos2(rect.left(), rect.top() + tab_height),
-        rect.right_bottom(),
-    )
-}
-
 /// Draw tab bar with editor-aware titles
 pub fn draw_tab_bar_with_editors(
     ui: &mut egui::Ui,
     rect: egui::Rect,
     leaf: &mut LeafPane,
     editors: &std::collections::HashMap<usize, crate::editor::Editor>,
+    file_views: &std::collections::HashMap<usize, crate::file_view::FileView>,
+    diff_views: &std::collections::HashMap<usize, crate::diff_view::DiffView>,
 ) -> egui::Rect {
     let tab_height = 28.0;
     let tab_rect = egui::Rect::from_min_size(rect.left_top(), egui::vec2(rect.width(), tab_height));
@@ -239,7 +202,7 @@ pub fn draw_tab_bar_with_editors(
 
     let mut x = tab_rect.left() + 4.0;
     for (i, tab) in leaf.tabs.iter().enumerate() {
-        let title = tab.title_with_editors(editors);
+        let title = tab.title_with_editors(editors, file_views, diff_views);
         let text_width = title.len() as f32 * 7.5 + 16.0;
         let this_tab = egui::Rect::from_min_size(egui::pos2(x, tab_rect.top()), egui::vec2(text_width, tab_height));
 
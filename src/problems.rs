@@ -0,0 +1,402 @@
+use crate::editor::severity_color;
+use crate::lsp::{Diagnostic, Location, LspRange, Position, Severity};
+use eframe::egui::{self, Rect};
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// `source` of diagnostics found in build output
+const BUILD_SOURCE: &str = "build";
+
+/// Diagnostics for every file that has any, as reported by language servers or
+/// found in build output
+pub struct Problems {
+    root: PathBuf,
+    /// From language servers, by file
+    lsp: BTreeMap<PathBuf, Vec<Diagnostic>>,
+    /// From each terminal's build output, by terminal
+    build: BTreeMap<usize, Vec<(PathBuf, Diagnostic)>>,
+    /// The command line each terminal's build diagnostics came from
+    build_commands: BTreeMap<usize, String>,
+    /// Both of the above together, by file
+    files: BTreeMap<PathBuf, Vec<Diagnostic>>,
+    show_errors: bool,
+    show_warnings: bool,
+    /// Information and hints
+    show_info: bool,
+    collapsed: HashSet<PathBuf>,
+    pending_open: Option<Location>,
+}
+
+impl Problems {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            lsp: BTreeMap::new(),
+            build: BTreeMap::new(),
+            build_commands: BTreeMap::new(),
+            files: BTreeMap::new(),
+            show_errors: true,
+            show_warnings: true,
+            show_info: true,
+            collapsed: HashSet::new(),
+            pending_open: None,
+        }
+    }
+
+    /// Paths are shown relative to this folder
+    pub fn set_root(&mut self, root: PathBuf) {
+        self.root = root;
+    }
+
+    /// Replace a language server's diagnostics for a file
+    pub fn set(&mut self, path: PathBuf, diagnostics: Vec<Diagnostic>) {
+        if diagnostics.is_empty() {
+            self.lsp.remove(&path);
+        } else {
+            self.lsp.insert(path.clone(), diagnostics);
+        }
+        self.merge(&path);
+    }
+
+    /// Replace what was found in a terminal's build output; returns the files whose
+    /// diagnostics changed
+    pub fn set_build(&mut self, terminal: usize, diagnostics: Vec<(PathBuf, Diagnostic)>) -> Vec<PathBuf> {
+        let old = self.build.remove(&terminal).unwrap_or_default();
+        let mut changed: Vec<PathBuf> = old.into_iter().chain(diagnostics.iter().cloned()).map(|(p, _)| p).collect();
+        changed.sort();
+        changed.dedup();
+        if !diagnostics.is_empty() {
+            self.build.insert(terminal, diagnostics);
+        }
+        for path in &changed {
+            self.merge(path);
+        }
+        changed
+    }
+
+    /// Look for errors in the output of a command run in a terminal, resolving relative
+    /// paths against the root. The terminal's last errors stay until another command
+    /// finds some, or the command that found them runs again, so running `ls` after
+    /// a build doesn't lose them.
+    pub fn scan_build(&mut self, terminal: usize, command: &str, output: &str) -> Vec<PathBuf> {
+        let diagnostics = parse_build_output(output, &self.root);
+        if diagnostics.is_empty() {
+            if self.build_commands.get(&terminal).is_none_or(|c| c != command) {
+                return Vec::new();
+            }
+            self.build_commands.remove(&terminal);
+        } else {
+            self.build_commands.insert(terminal, command.to_string());
+        }
+        self.set_build(terminal, diagnostics)
+    }
+
+    /// Drop the build output of terminals that have closed
+    pub fn retain_build(&mut self, open: impl Fn(usize) -> bool) -> Vec<PathBuf> {
+        self.build_commands.retain(|&t, _| open(t));
+        let closed: Vec<usize> = self.build.keys().copied().filter(|&t| !open(t)).collect();
+        closed.into_iter().flat_map(|t| self.set_build(t, Vec::new())).collect()
+    }
+
+    /// Recombine the diagnostics of one file from both sources
+    fn merge(&mut self, path: &Path) {
+        let mut diagnostics: Vec<Diagnostic> = self.lsp.get(path).cloned().unwrap_or_default();
+        for build in self.build.values() {
+            diagnostics.extend(build.iter().filter(|(p, _)| p == path).map(|(_, d)| d.clone()));
+        }
+        if diagnostics.is_empty() {
+            self.files.remove(path);
+        } else {
+            diagnostics.sort_by_key(|d| (d.range.start.line, d.range.start.character, d.severity));
+            self.files.insert(path.to_path_buf(), diagnostics);
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> &[Diagnostic] {
+        self.files.get(path).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// A row the user clicked
+    pub fn take_pending_open(&mut self) -> Option<Location> {
+        self.pending_open.take()
+    }
+
+    /// Errors, warnings and the rest across all files
+    fn counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for d in self.files.values().flatten() {
+            match d.severity {
+                Severity::Error => counts.0 += 1,
+                Severity::Warning => counts.1 += 1,
+                Severity::Information | Severity::Hint => counts.2 += 1,
+            }
+        }
+        counts
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_SURFACE);
+        let mut child_ui = ui.new_child(egui::UiBuilder::new().max_rect(rect.shrink(6.0)));
+        let ui = &mut child_ui;
+
+        let (errors, warnings, info) = self.counts();
+        ui.horizontal(|ui| {
+            for (value, label, hint) in [
+                (&mut self.show_errors, format!("✖ {}", errors), "Show errors"),
+                (&mut self.show_warnings, format!("⚠ {}", warnings), "Show warnings"),
+                (&mut self.show_info, format!("ℹ {}", info), "Show information and hints"),
+            ] {
+                if ui.selectable_label(*value, label).on_hover_text(hint).clicked() {
+                    *value = !*value;
+                }
+            }
+        });
+        ui.separator();
+
+        let root = self.root.clone();
+        let filters = (self.show_errors, self.show_warnings, self.show_info);
+        let shown = |severity| match severity {
+            Severity::Error => filters.0,
+            Severity::Warning => filters.1,
+            Severity::Information | Severity::Hint => filters.2,
+        };
+        let mut toggle = None;
+        egui::ScrollArea::vertical()
+            .id_salt("problems_list")
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                for (path, diagnostics) in &self.files {
+                    let shown: Vec<&Diagnostic> = diagnostics.iter().filter(|d| shown(d.severity)).collect();
+                    if shown.is_empty() {
+                        continue;
+                    }
+                    let name = path.strip_prefix(&root).unwrap_or(path).display().to_string();
+                    let collapsed = self.collapsed.contains(path);
+                    let arrow = if collapsed { "▶" } else { "▼" };
+                    let header = format!("{} {}  ({})", arrow, name, shown.len());
+                    if ui.selectable_label(false, egui::RichText::new(header).strong()).clicked() {
+                        toggle = Some(path.clone());
+                    }
+                    if collapsed {
+                        continue;
+                    }
+                    for d in shown {
+                        let icon = match d.severity {
+                            Severity::Error => "✖",
+                            Severity::Warning => "⚠",
+                            Severity::Information | Severity::Hint => "ℹ",
+                        };
+                        let start = d.range.start;
+                        let mut job = egui::text::LayoutJob::default();
+                        let font = egui::FontId::proportional(13.0);
+                        let format = |color| egui::TextFormat::simple(font.clone(), color);
+                        job.append(icon, 8.0, format(severity_color(d.severity)));
+                        // Only the first line of multi-line messages fits a row
+                        let message = d.message.lines().next().unwrap_or_default();
+                        job.append(message, 6.0, format(crate::theme::TEXT_PRIMARY));
+                        let source = d.source.as_deref().map(|s| format!("{} ", s)).unwrap_or_default();
+                        let position = format!("{}[{}:{}]", source, start.line + 1, start.character + 1);
+                        job.append(&position, 8.0, format(crate::theme::TEXT_SECONDARY));
+
+                        let response = ui.add(egui::Label::new(job).sense(egui::Sense::click()));
+                        if response.hovered() {
+                            ui.ctx().set_cursor_icon(egui::CursorIcon::PointingHand);
+                        }
+                        let response = if d.message.contains('\n') {
+                            response.on_hover_text(&d.message)
+                        } else {
+                            response
+                        };
+                        if response.clicked() {
+                            self.pending_open = Some(Location { path: path.clone(), range: d.range });
+                        }
+                    }
+                    ui.add_space(6.0);
+                }
+                if errors + warnings + info == 0 {
+                    ui.label(egui::RichText::new("No problems").color(crate::theme::TEXT_SECONDARY));
+                }
+            });
+        if let Some(path) = toggle {
+            if !self.collapsed.remove(&path) {
+                self.collapsed.insert(path);
+            }
+        }
+    }
+}
+
+/// Diagnostics in compiler and build tool output:
+/// - `path:line[:col]: error|warning|note: message` (GCC, Clang, Go, ESLint's unix format…)
+/// - rustc's `error[E0308]: message` followed by `--> path:line:col`
+/// - TypeScript's `path(line,col): error TS2322: message`
+///
+/// Colour escapes are ignored and relative paths resolved against `dir`.
+pub fn parse_build_output(output: &str, dir: &Path) -> Vec<(PathBuf, Diagnostic)> {
+    static PATTERNS: OnceLock<[Regex; 4]> = OnceLock::new();
+    let [escape, location, rust_header, rust_location] = PATTERNS.get_or_init(|| {
+        [
+            Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(\x07|\x1b\\)|\x1b[()][0-9A-Za-z]").unwrap(),
+            Regex::new(
+                r"^(?P<path>[^\s:(][^:(]*?)(?::(?P<line>\d+)(?::(?P<col>\d+))?|\((?P<tline>\d+),(?P<tcol>\d+)\)):?\s+(?P<severity>fatal error|error|warning|note|info)(?:\s+[A-Z]+\d+)?\s*:\s*(?P<message>.+)$",
+            )
+            .unwrap(),
+            Regex::new(r"^(?P<severity>error|warning)(?:\[(?P<code>[A-Za-z0-9]+)\])?: (?P<message>.+)$").unwrap(),
+            Regex::new(r"^\s*--> (?P<path>.+?):(?P<line>\d+):(?P<col>\d+)$").unwrap(),
+        ]
+    });
+    let severity_of = |s: &str| match s {
+        "fatal error" | "error" => Severity::Error,
+        "warning" => Severity::Warning,
+        _ => Severity::Information,
+    };
+    let number = |m: Option<regex::Match>| m.and_then(|m| m.as_str().parse::<usize>().ok());
+    let diagnostic = |path: &str, line: usize, col: usize, severity, message: &str| {
+        let path = Path::new(path.trim());
+        let path = if path.is_absolute() { path.to_path_buf() } else { dir.join(path) };
+        let at = Position { line: line.saturating_sub(1), character: col.saturating_sub(1) };
+        let diagnostic = Diagnostic {
+            range: LspRange { start: at, end: at },
+            severity,
+            message: message.trim().to_string(),
+            source: Some(BUILD_SOURCE.to_string()),
+        };
+        (path, diagnostic)
+    };
+
+    let output = escape.replace_all(output, "");
+    let mut found: Vec<(PathBuf, Diagnostic)> = Vec::new();
+    // A rustc header waiting for the location on a following line
+    let mut pending: Option<(Severity, String)> = None;
+    for line in output.lines() {
+        // Progress bars redraw the line with a carriage return; only the last text shows
+        let line = line.trim_end_matches('\r').rsplit('\r').next().unwrap_or_default();
+        if let Some(c) = rust_header.captures(line) {
+            let message = match c.name("code") {
+                Some(code) => format!("{} [{}]", &c["message"], code.as_str()),
+                None => c["message"].to_string(),
+            };
+            pending = Some((severity_of(&c["severity"]), message));
+        } else if let Some(c) = rust_location.captures(line) {
+            if let (Some((severity, message)), Some(line), Some(col)) =
+                (pending.take(), number(c.name("line")), number(c.name("col")))
+            {
+                found.push(diagnostic(&c["path"], line, col, severity, &message));
+            }
+        } else if let Some(c) = location.captures(line) {
+            let Some(line) = number(c.name("line")).or(number(c.name("tline"))) else { continue };
+            let col = number(c.name("col")).or(number(c.name("tcol"))).unwrap_or(1);
+            found.push(diagnostic(&c["path"], line, col, severity_of(&c["severity"]), &c["message"]));
+        }
+    }
+    // Cargo and some compilers repeat themselves
+    let mut seen = HashSet::new();
+    found.retain(|(path, d)| seen.insert((path.clone(), d.range.start.line, d.range.start.character, d.message.clone())));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(line: usize, severity: Severity) -> Diagnostic {
+        let at = Position { line, character: 0 };
+        Diagnostic { range: LspRange { start: at, end: at }, severity, message: String::new(), source: None }
+    }
+
+    #[test]
+    fn parses_rustc_output() {
+        let output = "    Building [=====>   ] 3/8\r\x1b[0m\x1b[1m\x1b[38;5;9merror[E0308]\x1b[0m\x1b[1m: mismatched types\x1b[0m
+  --> src/main.rs:4:18
+   |
+4  |     let x: u8 = \"a\";
+   |                 ^^^ expected `u8`
+warning: unused variable: `y`
+ --> /abs/lib.rs:10:9
+error: aborting due to 1 previous error
+warning: `app` (bin \"app\") generated 1 warning
+";
+        let found = parse_build_output(output, Path::new("/work"));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, Path::new("/work/src/main.rs"));
+        assert_eq!(found[0].1.message, "mismatched types [E0308]");
+        assert_eq!(found[0].1.severity, Severity::Error);
+        assert_eq!(found[0].1.range.start, Position { line: 3, character: 17 });
+        assert_eq!(found[1].0, Path::new("/abs/lib.rs"));
+        assert_eq!(found[1].1.severity, Severity::Warning);
+        assert_eq!(found[1].1.source.as_deref(), Some("build"));
+    }
+
+    #[test]
+    fn parses_gcc_and_typescript_output() {
+        let output = "main.c:3:5: error: expected ';' before 'return'
+main.c:3:5: error: expected ';' before 'return'
+include/util.h:12: warning: unused parameter
+lib/a.go:7:2: note: declared here
+src/app.ts(14,3): error TS2322: Type 'string' is not assignable to type 'number'.
+Build failed: 2 errors
+ls: cannot access 'x': No such file or directory
+";
+        let found = parse_build_output(output, Path::new("/work"));
+        let summary: Vec<(String, usize, usize, Severity)> = found
+            .iter()
+            .map(|(p, d)| (p.display().to_string(), d.range.start.line, d.range.start.character, d.severity))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/work/main.c".to_string(), 2, 4, Severity::Error),
+                ("/work/include/util.h".to_string(), 11, 0, Severity::Warning),
+                ("/work/lib/a.go".to_string(), 6, 1, Severity::Information),
+                ("/work/src/app.ts".to_string(), 13, 2, Severity::Error),
+            ]
+        );
+        assert_eq!(found[3].1.message, "Type 'string' is not assignable to type 'number'.");
+    }
+
+    #[test]
+    fn merges_sources_and_counts() {
+        let mut problems = Problems::new(PathBuf::from("/work"));
+        let a = PathBuf::from("/work/a.rs");
+        let b = PathBuf::from("/work/b.rs");
+        problems.set(a.clone(), vec![diagnostic(5, Severity::Warning), diagnostic(1, Severity::Error)]);
+        problems.set(b.clone(), vec![diagnostic(0, Severity::Hint)]);
+        assert_eq!(problems.counts(), (1, 1, 1));
+        // Sorted by position
+        assert_eq!(problems.get(&a)[0].range.start.line, 1);
+
+        let changed = problems.set_build(7, vec![(a.clone(), diagnostic(3, Severity::Error))]);
+        assert_eq!(changed, [PathBuf::from("/work/a.rs")]);
+        assert_eq!(problems.counts(), (2, 1, 1));
+        assert_eq!(problems.get(&a).iter().map(|d| d.range.start.line).collect::<Vec<_>>(), [1, 3, 5]);
+
+        // Replacing one source leaves the other
+        problems.set(a.clone(), Vec::new());
+        assert_eq!(problems.get(&a).len(), 1);
+        problems.set(b.clone(), Vec::new());
+        assert!(problems.get(&b).is_empty());
+        assert_eq!(problems.retain_build(|t| t != 7), [PathBuf::from("/work/a.rs")]);
+        assert!(problems.get(&a).is_empty());
+        assert_eq!(problems.counts(), (0, 0, 0));
+    }
+
+    #[test]
+    fn build_errors_last_until_the_build_runs_again() {
+        let mut problems = Problems::new(PathBuf::from("/work"));
+        let errors = "error: bad\n --> src/a.rs:1:1\n";
+        assert_eq!(problems.scan_build(1, "cargo build", errors), [PathBuf::from("/work/src/a.rs")]);
+        // Other commands without errors leave them, in this terminal or another
+        assert!(problems.scan_build(1, "ls", "Cargo.toml src\n").is_empty());
+        assert!(problems.scan_build(2, "cargo build", "").is_empty());
+        assert_eq!(problems.counts(), (1, 0, 0));
+        // Other errors replace them
+        let errors = "src/b.c:2:3: warning: odd\n";
+        let changed = problems.scan_build(1, "make", errors);
+        assert_eq!(changed, [PathBuf::from("/work/src/a.rs"), PathBuf::from("/work/src/b.c")]);
+        assert_eq!(problems.counts(), (0, 1, 0));
+        // Running the same build again clears them, even before it finds any
+        assert_eq!(problems.scan_build(1, "make", "cc -c src/b.c\n"), [PathBuf::from("/work/src/b.c")]);
+        assert_eq!(problems.counts(), (0, 0, 0));
+    }
+}
@@ -0,0 +1,720 @@
+use crate::encoding::FileEncoding;
+use crate::find::{build_pattern, expand_replacements};
+use eframe::egui::{self, FontId, Rect};
+use ignore::overrides::{Override, OverrideBuilder};
+use regex::Regex;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Lines shown above and below each hit
+const CONTEXT_LINES: usize = 1;
+
+/// Stop collecting once this many hits were found
+const MAX_HITS: usize = 10_000;
+
+/// Files larger than this are skipped
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// One match, with the line it starts on
+#[derive(Clone, Debug)]
+pub struct Hit {
+    /// Byte range in the file
+    pub range: Range<usize>,
+    /// 0-based line of the match start
+    pub line: usize,
+    pub line_text: String,
+    /// Part of `line_text` that matched (cut at the line end for multi-line matches)
+    pub highlight: Range<usize>,
+    /// `(line, text)` of the surrounding lines
+    pub before: Vec<(usize, String)>,
+    pub after: Vec<(usize, String)>,
+}
+
+/// All hits in one file, in order
+#[derive(Clone, Debug)]
+pub struct FileHits {
+    pub path: PathBuf,
+    pub hits: Vec<Hit>,
+    collapsed: bool,
+}
+
+/// A search running on background threads; results are drained on the UI thread
+struct SearchJob {
+    found: Arc<Mutex<Vec<FileHits>>>,
+    done: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Drop for SearchJob {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Replacing in one file: the contents the hits were found in, and the result
+pub struct FileReplacement {
+    pub path: PathBuf,
+    /// Decoded with `\n` line endings, like an editor's buffer
+    pub original: String,
+    /// How the file is stored, to write it back the same way
+    encoding: FileEncoding,
+    pub edits: Vec<(Range<usize>, String)>,
+    /// Changed lines as `(old lines, new lines)` blocks, for the preview
+    diff: Vec<(Vec<String>, Vec<String>)>,
+}
+
+/// Search and replace across the folder open in the file tree
+pub struct ProjectSearch {
+    root: PathBuf,
+    query: String,
+    replacement: String,
+    regex: bool,
+    case_sensitive: bool,
+    whole_word: bool,
+    /// Comma-separated globs; when any are given only matching files are searched
+    include: String,
+    /// Comma-separated globs of files to skip
+    exclude: String,
+    results: Vec<FileHits>,
+    job: Option<SearchJob>,
+    error: Option<String>,
+    /// Pattern the current results were found with
+    pattern: Option<Regex>,
+    preview: Option<Vec<FileReplacement>>,
+    pending_open: Option<(PathBuf, Range<usize>)>,
+    pending_apply: Option<Vec<FileReplacement>>,
+    /// `(path, why)` of files Replace All left alone
+    skipped: Vec<(PathBuf, String)>,
+    pub grab_focus: bool,
+}
+
+impl ProjectSearch {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            query: String::new(),
+            replacement: String::new(),
+            regex: false,
+            case_sensitive: false,
+            whole_word: false,
+            include: String::new(),
+            exclude: String::new(),
+            results: Vec::new(),
+            job: None,
+            error: None,
+            pattern: None,
+            preview: None,
+            pending_open: None,
+            pending_apply: None,
+            skipped: Vec::new(),
+            grab_focus: false,
+        }
+    }
+
+    /// Search a different folder, dropping the old results
+    pub fn set_root(&mut self, root: PathBuf) {
+        *self = Self { grab_focus: self.grab_focus, ..Self::new(root) };
+    }
+
+    /// A hit the user clicked: the file and the byte range to select
+    pub fn take_pending_open(&mut self) -> Option<(PathBuf, Range<usize>)> {
+        self.pending_open.take()
+    }
+
+    /// Replacements the user confirmed in the preview
+    pub fn take_pending_apply(&mut self) -> Option<Vec<FileReplacement>> {
+        self.pending_apply.take()
+    }
+
+    /// A file Replace All had to leave alone, shown under the results
+    pub fn report_skipped(&mut self, path: PathBuf, why: String) {
+        self.skipped.push((path, why));
+    }
+
+    fn hit_count(&self) -> usize {
+        self.results.iter().map(|f| f.hits.len()).sum()
+    }
+
+    /// Whether the search stopped at `MAX_HITS`, so the results may be missing files
+    fn limit_reached(&self) -> bool {
+        self.hit_count() >= MAX_HITS
+    }
+
+    /// Replace All works on complete results only: replacing in some of the
+    /// matches would leave the rest behind unseen
+    fn can_replace(&self) -> bool {
+        self.job.is_none() && !self.results.is_empty() && self.preview.is_none() && !self.limit_reached()
+    }
+
+    /// Start a new search, cancelling the one in progress
+    fn start(&mut self) {
+        self.job = None;
+        self.results.clear();
+        self.preview = None;
+        self.skipped.clear();
+        self.error = None;
+        self.pattern = None;
+        if self.query.is_empty() {
+            return;
+        }
+        let re = match build_pattern(&self.query, self.regex, self.case_sensitive, self.whole_word) {
+            Ok(re) => re,
+            Err(e) => {
+                self.error = Some(e.to_string().lines().last().unwrap_or("invalid regex").to_string());
+                return;
+            }
+        };
+        let filter = match file_filter(&self.root, &self.include, &self.exclude) {
+            Ok(filter) => filter,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return;
+            }
+        };
+        self.pattern = Some(re.clone());
+
+        let job = SearchJob {
+            found: Arc::new(Mutex::new(Vec::new())),
+            done: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let (found, done, cancelled) = (job.found.clone(), job.done.clone(), job.cancelled.clone());
+        let root = self.root.clone();
+        std::thread::spawn(move || {
+            search_tree(&root, &re, filter, &cancelled, &found);
+            done.store(true, Ordering::Release);
+        });
+        self.job = Some(job);
+    }
+
+    /// Move results found since the last frame into the list, keeping files sorted
+    fn poll(&mut self) {
+        let Some(job) = &self.job else { return };
+        // Read `done` first so nothing pushed before it was set is left behind
+        let finished = job.done.load(Ordering::Acquire);
+        let new: Vec<FileHits> = std::mem::take(&mut *job.found.lock().unwrap());
+        if !new.is_empty() {
+            self.results.extend(new);
+            self.results.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        if finished {
+            self.job = None;
+        }
+    }
+
+    /// Work out every replacement in the results, for the preview
+    fn build_preview(&mut self) {
+        let Some(re) = &self.pattern else { return };
+        let mut files = Vec::new();
+        self.skipped.clear();
+        for file in &self.results {
+            let Some((original, encoding)) = read_text(&file.path) else {
+                self.skipped.push((file.path.clone(), "can no longer be read".to_string()));
+                continue;
+            };
+            let ranges: Vec<Range<usize>> = file.hits.iter().map(|h| h.range.clone()).collect();
+            // The file may have changed since it was searched
+            let current: Vec<Range<usize>> = re.find_iter(&original).filter(|m| !m.is_empty()).map(|m| m.range()).collect();
+            if current != ranges {
+                self.skipped.push((file.path.clone(), "changed since the search".to_string()));
+                continue;
+            }
+            let replacements = if self.regex {
+                expand_replacements(re, &self.replacement, &original, &ranges)
+            } else {
+                vec![self.replacement.clone(); ranges.len()]
+            };
+            let edits: Vec<(Range<usize>, String)> = ranges.into_iter().zip(replacements).collect();
+            let diff = diff_blocks(&original, &edits);
+            files.push(FileReplacement { path: file.path.clone(), original, encoding, edits, diff });
+        }
+        self.preview = Some(files);
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        self.poll();
+        if self.job.is_some() {
+            ui.ctx().request_repaint_after(std::time::Duration::from_millis(50));
+        }
+
+        ui.painter().rect_filled(rect, 0.0, crate::theme::BG_SURFACE);
+        let mut child_ui = ui.new_child(egui::UiBuilder::new().max_rect(rect.shrink(6.0)));
+        let ui = &mut child_ui;
+
+        let mut search = false;
+        let query = ui.add(
+            egui::TextEdit::singleline(&mut self.query)
+                .id(egui::Id::new("project_search_query"))
+                .hint_text("Search in folder")
+                .desired_width(f32::INFINITY),
+        );
+        if std::mem::take(&mut self.grab_focus) {
+            query.request_focus();
+        }
+        search |= query.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        ui.add(
+            egui::TextEdit::singleline(&mut self.replacement)
+                .id(egui::Id::new("project_search_replacement"))
+                .hint_text(if self.regex { "Replace ($1 for groups)" } else { "Replace" })
+                .desired_width(f32::INFINITY),
+        );
+        ui.horizontal(|ui| {
+            let width = (ui.available_width() - ui.spacing().item_spacing.x) / 2.0;
+            for (globs, id, hint) in [
+                (&mut self.include, "project_search_include", "Files to include (e.g. src/**, *.rs)"),
+                (&mut self.exclude, "project_search_exclude", "Files to exclude"),
+            ] {
+                let field = ui.add(egui::TextEdit::singleline(globs).id(egui::Id::new(id)).hint_text(hint).desired_width(width));
+                search |= field.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            }
+        });
+        ui.horizontal(|ui| {
+            for (value, label, hint) in [
+                (&mut self.case_sensitive, "Aa", "Match case"),
+                (&mut self.whole_word, "ab", "Match whole word"),
+                (&mut self.regex, ".*", "Use regular expression"),
+            ] {
+                if ui.selectable_label(*value, label).on_hover_text(hint).clicked() {
+                    *value = !*value;
+                    search = true;
+                }
+            }
+            let replace_all = ui
+                .add_enabled(self.can_replace(), egui::Button::new("Replace All…"))
+                .on_disabled_hover_text("Not while results are cut off at the limit; narrow the search first");
+            if replace_all.clicked() {
+                self.build_preview();
+            }
+        });
+        if search {
+            self.start();
+        }
+
+        let status = if let Some(error) = &self.error {
+            egui::RichText::new(error).color(crate::theme::ERROR)
+        } else {
+            let hits = self.hit_count();
+            let mut text = format!("{} results in {} files", hits, self.results.len());
+            let mut color = crate::theme::TEXT_SECONDARY;
+            if self.job.is_some() {
+                text.push_str(" — searching…");
+            } else if self.limit_reached() {
+                text.push_str(" — stopped at the limit, some files weren't searched");
+                color = crate::theme::WARNING;
+            }
+            egui::RichText::new(text).color(color)
+        };
+        if !self.query.is_empty() || self.error.is_some() {
+            ui.label(status.size(12.0));
+        }
+        if !self.skipped.is_empty() {
+            let why: Vec<String> = self
+                .skipped
+                .iter()
+                .map(|(path, why)| format!("{}: {}", path.strip_prefix(&self.root).unwrap_or(path).display(), why))
+                .collect();
+            let text = match why.as_slice() {
+                [one] => format!("Skipped {}", one),
+                _ => format!("Skipped {} files — hover to see why", why.len()),
+            };
+            ui.label(egui::RichText::new(text).color(crate::theme::WARNING).size(12.0)).on_hover_text(why.join("\n"));
+        }
+        ui.separator();
+
+        if self.preview.is_some() {
+            self.render_preview(ui);
+        } else {
+            self.render_results(ui);
+        }
+    }
+
+    fn render_results(&mut self, ui: &mut egui::Ui) {
+        let mono = FontId::monospace(12.0);
+        let root = self.root.clone();
+        egui::ScrollArea::vertical()
+            .id_salt("project_search_results")
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                for file in &mut self.results {
+                    let name = file.path.strip_prefix(&root).unwrap_or(&file.path).display().to_string();
+                    let arrow = if file.collapsed { "▶" } else { "▼" };
+                    let header = format!("{} {}  ({})", arrow, name, file.hits.len());
+                    if ui.selectable_label(false, egui::RichText::new(header).strong()).clicked() {
+                        file.collapsed = !file.collapsed;
+                    }
+                    if file.collapsed {
+                        continue;
+                    }
+                    // Context lines shared by neighbouring hits are shown once
+                    let mut shown_through = None;
+                    for hit in &file.hits {
+                        for (line, text) in &hit.before {
+                            if shown_through.is_none_or(|s| *line > s) {
+                                ui.label(egui::RichText::new(format!("{:>5}  {}", line + 1, text))
+                                    .font(mono.clone())
+                                    .color(crate::theme::TEXT_SECONDARY));
+                            }
+                        }
+                        if shown_through.is_none_or(|s| hit.line > s) {
+                            let job = hit_layout(hit, &mono);
+                            let response = ui.add(egui::Label::new(job).sense(egui::Sense::click()));
+                            if response.hovered() {
+                                ui.ctx().set_cursor_icon(egui::CursorIcon::PointingHand);
+                            }
+                            if response.clicked() {
+                                self.pending_open = Some((file.path.clone(), hit.range.clone()));
+                            }
+                        }
+                        shown_through = Some(shown_through.map_or(hit.line, |s: usize| s.max(hit.line)));
+                        for (line, text) in &hit.after {
+                            if *line > shown_through.unwrap_or(0) {
+                                ui.label(egui::RichText::new(format!("{:>5}  {}", line + 1, text))
+                                    .font(mono.clone())
+                                    .color(crate::theme::TEXT_SECONDARY));
+                                shown_through = Some(*line);
+                            }
+                        }
+                    }
+                    ui.add_space(6.0);
+                }
+            });
+    }
+
+    fn render_preview(&mut self, ui: &mut egui::Ui) {
+        let Some(files) = &self.preview else { return };
+        let edit_count: usize = files.iter().map(|f| f.edits.len()).sum();
+        let (mut apply, mut cancel) = (false, false);
+        ui.horizontal(|ui| {
+            ui.label(format!("Replace {} matches in {} files?", edit_count, files.len()));
+            apply = ui.button("Apply").clicked();
+            cancel = ui.button("Cancel").clicked();
+        });
+        let mono = FontId::monospace(12.0);
+        let removed = egui::Color32::from_rgb(255, 235, 233);
+        let added = egui::Color32::from_rgb(230, 255, 237);
+        egui::ScrollArea::vertical()
+            .id_salt("project_search_preview")
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                for file in files {
+                    let name = file.path.strip_prefix(&self.root).unwrap_or(&file.path).display().to_string();
+                    ui.label(egui::RichText::new(name).strong());
+                    for (old, new) in &file.diff {
+                        for line in old {
+                            ui.label(egui::RichText::new(format!("- {}", line)).font(mono.clone()).background_color(removed));
+                        }
+                        for line in new {
+                            ui.label(egui::RichText::new(format!("+ {}", line)).font(mono.clone()).background_color(added));
+                        }
+                        ui.add_space(2.0);
+                    }
+                    ui.add_space(6.0);
+                }
+            });
+
+        if apply {
+            self.pending_apply = self.preview.take();
+            self.results.clear();
+        } else if cancel {
+            self.preview = None;
+        }
+    }
+}
+
+impl FileReplacement {
+    /// The file's contents with every replacement made
+    pub fn apply_to(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for (range, replacement) in &self.edits {
+            out.push_str(&text[last..range.start]);
+            out.push_str(replacement);
+            last = range.end;
+        }
+        out.push_str(&text[last..]);
+        out
+    }
+
+    /// Make the replacements in the file on disk, in its own encoding and line
+    /// endings, unless it changed since the search
+    pub fn write(&self) -> Result<(), String> {
+        let bytes = std::fs::read(&self.path).map_err(|e| e.to_string())?;
+        let (text, _) = crate::encoding::decode_as(&bytes, self.encoding.encoding);
+        if text != self.original {
+            return Err("changed since the search".to_string());
+        }
+        let bytes = crate::encoding::encode(&self.apply_to(&text), self.encoding)?;
+        std::fs::write(&self.path, bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// The walk's filter for comma-separated include and exclude globs, which match
+/// like .gitignore lines relative to `root`
+fn file_filter(root: &Path, include: &str, exclude: &str) -> Result<Override, ignore::Error> {
+    let globs = |list: &str| list.split(',').map(str::trim).filter(|g| !g.is_empty()).map(str::to_string).collect::<Vec<_>>();
+    let mut builder = OverrideBuilder::new(root);
+    for glob in globs(include) {
+        builder.add(&glob)?;
+    }
+    for glob in globs(exclude) {
+        builder.add(&format!("!{}", glob))?;
+    }
+    builder.build()
+}
+
+/// Search the files under `root`, adding each one's hits to `found` as it's done.
+/// Stops early when cancelled or once `MAX_HITS` are found.
+fn search_tree(root: &Path, re: &Regex, filter: Override, cancelled: &AtomicBool, found: &Mutex<Vec<FileHits>>) {
+    let total = AtomicUsize::new(0);
+    // Same filtering as the file tree: .gitignore respected, dotfiles shown
+    let walker = ignore::WalkBuilder::new(root).hidden(false).overrides(filter).build_parallel();
+    walker.run(|| {
+        let total = &total;
+        Box::new(move |entry| {
+            if cancelled.load(Ordering::Relaxed) || total.load(Ordering::Relaxed) >= MAX_HITS {
+                return ignore::WalkState::Quit;
+            }
+            let Ok(entry) = entry else {
+                return ignore::WalkState::Continue;
+            };
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                return ignore::WalkState::Continue;
+            }
+            if let Some((text, _)) = read_text(entry.path()) {
+                let hits = search_text(re, &text);
+                if !hits.is_empty() {
+                    total.fetch_add(hits.len(), Ordering::Relaxed);
+                    let file = FileHits { path: entry.into_path(), hits, collapsed: false };
+                    found.lock().unwrap().push(file);
+                }
+            }
+            ignore::WalkState::Continue
+        })
+    });
+}
+
+/// Contents of a text file decoded the way the editor would, so offsets into it
+/// match an open buffer; `None` for binary or very large files
+fn read_text(path: &Path) -> Option<(String, FileEncoding)> {
+    if std::fs::metadata(path).ok()?.len() > MAX_FILE_SIZE {
+        return None;
+    }
+    let bytes = std::fs::read(path).ok()?;
+    if crate::file_view::is_binary(&bytes) {
+        return None;
+    }
+    Some(crate::encoding::decode(&bytes))
+}
+
+/// Byte offset where each line starts
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect()
+}
+
+fn line_text<'a>(text: &'a str, starts: &[usize], line: usize) -> &'a str {
+    let end = starts.get(line + 1).map_or(text.len(), |&s| s - 1);
+    text[starts[line]..end].trim_end_matches('\r')
+}
+
+/// Every non-empty match of `re` in `text`, with context lines
+fn search_text(re: &Regex, text: &str) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    let mut starts = None;
+    for m in re.find_iter(text).filter(|m| !m.is_empty()) {
+        let starts: &Vec<usize> = starts.get_or_insert_with(|| line_starts(text));
+        let line = starts.partition_point(|&s| s <= m.start()) - 1;
+        let line_str = line_text(text, starts, line);
+        let col = m.start() - starts[line];
+        let context = |lines: Range<usize>| lines.map(|l| (l, line_text(text, starts, l).to_string())).collect();
+        hits.push(Hit {
+            range: m.range(),
+            line,
+            line_text: line_str.to_string(),
+            highlight: col.min(line_str.len())..(col + m.len()).min(line_str.len()),
+            before: context(line.saturating_sub(CONTEXT_LINES)..line),
+            after: context(line + 1..(line + 1 + CONTEXT_LINES).min(starts.len())),
+        });
+    }
+    hits
+}
+
+/// `old lines → new lines` for each run of lines touched by the edits
+fn diff_blocks(text: &str, edits: &[(Range<usize>, String)]) -> Vec<(Vec<String>, Vec<String>)> {
+    let starts = line_starts(text);
+    let line_of = |byte: usize| starts.partition_point(|&s| s <= byte) - 1;
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < edits.len() {
+        // Group edits whose lines overlap
+        let first = line_of(edits[i].0.start);
+        let mut last = line_of(edits[i].0.end);
+        let mut j = i + 1;
+        while j < edits.len() && line_of(edits[j].0.start) <= last {
+            last = last.max(line_of(edits[j].0.end));
+            j += 1;
+        }
+        let block_start = starts[first];
+        let block_end = starts.get(last + 1).map_or(text.len(), |&s| s - 1);
+        let mut new = String::new();
+        let mut pos = block_start;
+        for (range, replacement) in &edits[i..j] {
+            new.push_str(&text[pos..range.start]);
+            new.push_str(replacement);
+            pos = range.end;
+        }
+        new.push_str(&text[pos..block_end]);
+        let old = text[block_start..block_end].lines().map(str::to_string).collect();
+        blocks.push((old, new.lines().map(str::to_string).collect()));
+        i = j;
+    }
+    blocks
+}
+
+/// A hit's line with its line number, the match highlighted
+fn hit_layout(hit: &Hit, font: &FontId) -> egui::text::LayoutJob {
+    let mut job = egui::text::LayoutJob::default();
+    let plain = egui::TextFormat::simple(font.clone(), crate::theme::TEXT_PRIMARY);
+    let number = egui::TextFormat::simple(font.clone(), crate::theme::TEXT_SECONDARY);
+    let matched = egui::TextFormat {
+        background: egui::Color32::from_rgba_unmultiplied(255, 200, 0, 110),
+        ..plain.clone()
+    };
+    job.append(&format!("{:>5}  ", hit.line + 1), 0.0, number);
+    job.append(&hit.line_text[..hit.highlight.start], 0.0, plain.clone());
+    job.append(&hit.line_text[hit.highlight.clone()], 0.0, matched);
+    job.append(&hit.line_text[hit.highlight.end..], 0.0, plain);
+    job
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temp folder holding `files`
+    fn folder(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let folder = tempfile::tempdir().unwrap();
+        // .gitignore only applies inside a repository
+        std::fs::create_dir(folder.path().join(".git")).unwrap();
+        for (path, text) in files {
+            let path = folder.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        folder
+    }
+
+    /// Run `search` to the end; the files with hits, relative to the root
+    fn run(search: &mut ProjectSearch) -> Vec<String> {
+        search.start();
+        while search.job.is_some() {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            search.poll();
+        }
+        let root = search.root.clone();
+        search.results.iter().map(|f| f.path.strip_prefix(&root).unwrap().display().to_string()).collect()
+    }
+
+    #[test]
+    fn filters_files_by_glob_and_gitignore() {
+        let folder = folder(&[
+                (".gitignore", "target/\n*.log\n"),
+                ("src/main.rs", "needle"),
+                ("src/notes.txt", "needle"),
+                ("docs/guide.rs", "needle"),
+                ("target/out.rs", "needle"),
+                ("debug.log", "needle"),
+            ],
+        );
+        let mut search = ProjectSearch::new(folder.path().to_path_buf());
+        search.query = "needle".to_string();
+        assert_eq!(run(&mut search), ["docs/guide.rs", "src/main.rs", "src/notes.txt"]);
+
+        search.include = "*.rs".to_string();
+        assert_eq!(run(&mut search), ["docs/guide.rs", "src/main.rs"]);
+        search.include = " src/** , *.md ".to_string();
+        assert_eq!(run(&mut search), ["src/main.rs", "src/notes.txt"]);
+        search.include = "*.rs".to_string();
+        search.exclude = "docs/**".to_string();
+        assert_eq!(run(&mut search), ["src/main.rs"]);
+        search.include.clear();
+        search.exclude = "*.txt, docs".to_string();
+        assert_eq!(run(&mut search), ["src/main.rs"]);
+
+        search.exclude = "src/[".to_string();
+        assert!(run(&mut search).is_empty());
+        assert!(search.error.is_some());
+    }
+
+    #[test]
+    fn matches_literally_unless_regex() {
+        let text = "a.c abc\nA.C";
+        let ranges = |re: &Regex| search_text(re, text).iter().map(|h| (h.range.start, h.range.end)).collect::<Vec<_>>();
+        assert_eq!(ranges(&build_pattern("a.c", false, true, false).unwrap()), [(0, 3)]);
+        assert_eq!(ranges(&build_pattern("a.c", false, false, false).unwrap()), [(0, 3), (8, 11)]);
+        assert_eq!(ranges(&build_pattern("a.c", true, true, false).unwrap()), [(0, 3), (4, 7)]);
+        // Anchors match at every line
+        assert_eq!(ranges(&build_pattern(r"^\w", true, true, false).unwrap()), [(0, 1), (8, 9)]);
+
+        let hits = search_text(&build_pattern("abc", false, true, false).unwrap(), text);
+        assert_eq!((hits[0].line, hits[0].highlight.clone()), (0, 4..7));
+        assert_eq!(hits[0].after, [(1, "A.C".to_string())]);
+    }
+
+    #[test]
+    fn replace_all_is_off_at_the_limit() {
+        let many = "x\n".repeat(MAX_HITS);
+        let folder = folder(&[("a.txt", &many), ("b.txt", "x\n")]);
+        let mut search = ProjectSearch::new(folder.path().to_path_buf());
+        search.query = "x".to_string();
+        run(&mut search);
+        assert!(search.limit_reached());
+        assert!(!search.can_replace());
+
+        search.query = "y".to_string();
+        std::fs::write(folder.path().join("b.txt"), "y\n").unwrap();
+        assert_eq!(run(&mut search), ["b.txt"]);
+        assert!(search.can_replace());
+    }
+
+    #[test]
+    fn offsets_and_writes_follow_the_editor_text() {
+        let folder = folder(&[("a.txt", "one\r\ntwo\r\n")]);
+        let mut search = ProjectSearch::new(folder.path().to_path_buf());
+        search.query = "two".to_string();
+        search.replacement = "2".to_string();
+        run(&mut search);
+        // Offsets are into the text with `\n` line endings, as an editor holds it
+        assert_eq!(search.results[0].hits[0].range, 4..7);
+
+        search.build_preview();
+        let files = search.preview.take().unwrap();
+        assert_eq!(files[0].original, "one\ntwo\n");
+        files[0].write().unwrap();
+        assert_eq!(std::fs::read(folder.path().join("a.txt")).unwrap(), b"one\r\n2\r\n");
+        assert_eq!(files[0].write(), Err("changed since the search".to_string()));
+    }
+
+    #[test]
+    fn searches_every_encoding_and_reports_skipped_files() {
+        let folder = folder(&[("utf8.txt", "日本語\n"), ("stale.txt", "日本語\n")]);
+        // Long enough for the encoding to be guessed
+        let sjis = encoding_rs::SHIFT_JIS.encode("これは日本語のテキストファイルです。文字コードはシフトJISです。\n");
+        std::fs::write(folder.path().join("sjis.txt"), sjis.0).unwrap();
+        let utf16: Vec<u8> = [0xFF, 0xFE].into_iter().chain("x 日本語\n".encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        std::fs::write(folder.path().join("utf16.txt"), utf16).unwrap();
+        std::fs::write(folder.path().join("binary.bin"), "\0\x01日本語").unwrap();
+
+        let mut search = ProjectSearch::new(folder.path().to_path_buf());
+        search.query = "日本語".to_string();
+        let mut found = run(&mut search);
+        found.sort();
+        assert_eq!(found, ["sjis.txt", "stale.txt", "utf16.txt", "utf8.txt"]);
+
+        std::fs::write(folder.path().join("stale.txt"), "changed\n").unwrap();
+        search.build_preview();
+        assert_eq!(search.preview.as_ref().unwrap().len(), 3);
+        assert_eq!(search.skipped, [(folder.path().join("stale.txt"), "changed since the search".to_string())]);
+    }
+}
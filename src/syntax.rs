@@ -0,0 +1,813 @@
+use crate::buffer::TextBuffer;
+use crate::outline::{self, Symbol, SymbolKind};
+use eframe::egui::Color32;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, TextProvider, Tree};
+
+/// A run of text in one colour; spans never overlap
+#[derive(Clone)]
+pub struct HighlightSpan {
+    pub start: usize, // byte offset in content
+    pub end: usize,
+    pub color: Color32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lang {
+    Rust,
+    Python,
+    JavaScript,
+    TypeScript,
+    Tsx,
+    Html,
+    Css,
+    Json,
+    Yaml,
+    Markdown,
+    MarkdownInline,
+    /// No tree-sitter grammar; highlighted lexically
+    Sql,
+}
+
+const LANG_COUNT: usize = 12;
+
+/// Injected layers nest at most this deep (e.g. Markdown → code fence → macro body)
+const MAX_INJECTION_DEPTH: usize = 3;
+
+/// SQL inside Rust string literals, recognised by a leading SQL keyword
+const RUST_SQL_INJECTION: &str = r#"
+((string_content) @injection.content
+ (#match? @injection.content "^\\s*(?i:select|insert|update|delete|with|create|alter|drop)\\b")
+ (#set! injection.language "sql"))
+"#;
+
+impl Lang {
+    pub fn from_path(path: &Path) -> Option<Lang> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "pyi" => Some(Lang::Python),
+            "mjs" | "cjs" => Some(Lang::JavaScript),
+            "mts" | "cts" => Some(Lang::TypeScript),
+            "htm" => Some(Lang::Html),
+            _ => Lang::from_name(&ext),
+        }
+    }
+
+    /// Resolve a language name as written in code fences and injection queries
+    pub fn from_name(name: &str) -> Option<Lang> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "rust" | "rs" => Some(Lang::Rust),
+            "python" | "py" | "python3" => Some(Lang::Python),
+            "javascript" | "js" | "jsx" => Some(Lang::JavaScript),
+            "typescript" | "ts" => Some(Lang::TypeScript),
+            "tsx" => Some(Lang::Tsx),
+            "html" => Some(Lang::Html),
+            "css" => Some(Lang::Css),
+            "json" | "jsonc" => Some(Lang::Json),
+            "yaml" | "yml" => Some(Lang::Yaml),
+            "markdown" | "md" => Some(Lang::Markdown),
+            "markdown_inline" => Some(Lang::MarkdownInline),
+            "sql" => Some(Lang::Sql),
+            _ => None,
+        }
+    }
+}
+
+/// A language's parser and compiled queries
+struct Grammar {
+    language: Language,
+    highlights: Query,
+    /// Colour for each capture in `highlights`
+    colors: Vec<Option<Color32>>,
+    injections: Option<Query>,
+}
+
+fn grammar(lang: Lang) -> Option<&'static Grammar> {
+    static GRAMMARS: [OnceLock<Option<Grammar>>; LANG_COUNT] = [const { OnceLock::new() }; LANG_COUNT];
+    GRAMMARS[lang as usize].get_or_init(|| load_grammar(lang)).as_ref()
+}
+
+fn load_grammar(lang: Lang) -> Option<Grammar> {
+    let js_highlights = tree_sitter_javascript::HIGHLIGHT_QUERY;
+    let (language, highlights, injections): (Language, String, String) = match lang {
+        Lang::Rust => (
+            tree_sitter_rust::LANGUAGE.into(),
+            tree_sitter_rust::HIGHLIGHTS_QUERY.into(),
+            format!("{}\n{}", tree_sitter_rust::INJECTIONS_QUERY, RUST_SQL_INJECTION),
+        ),
+        Lang::Python => (
+            tree_sitter_python::LANGUAGE.into(),
+            tree_sitter_python::HIGHLIGHTS_QUERY.into(),
+            String::new(),
+        ),
+        Lang::JavaScript => (
+            tree_sitter_javascript::LANGUAGE.into(),
+            format!("{}\n{}", tree_sitter_javascript::JSX_HIGHLIGHT_QUERY, js_highlights),
+            tree_sitter_javascript::INJECTIONS_QUERY.into(),
+        ),
+        Lang::TypeScript => (
+            tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+            format!("{}\n{}", tree_sitter_typescript::HIGHLIGHTS_QUERY, js_highlights),
+            tree_sitter_javascript::INJECTIONS_QUERY.into(),
+        ),
+        Lang::Tsx => (
+            tree_sitter_typescript::LANGUAGE_TSX.into(),
+            format!(
+                "{}\n{}\n{}",
+                tree_sitter_javascript::JSX_HIGHLIGHT_QUERY,
+                tree_sitter_typescript::HIGHLIGHTS_QUERY,
+                js_highlights
+            ),
+            tree_sitter_javascript::INJECTIONS_QUERY.into(),
+        ),
+        Lang::Html => (
+            tree_sitter_html::LANGUAGE.into(),
+            tree_sitter_html::HIGHLIGHTS_QUERY.into(),
+            tree_sitter_html::INJECTIONS_QUERY.into(),
+        ),
+        Lang::Css => (
+            tree_sitter_css::LANGUAGE.into(),
+            tree_sitter_css::HIGHLIGHTS_QUERY.into(),
+            String::new(),
+        ),
+        Lang::Json => (
+            tree_sitter_json::LANGUAGE.into(),
+            tree_sitter_json::HIGHLIGHTS_QUERY.into(),
+            String::new(),
+        ),
+        Lang::Yaml => (
+            tree_sitter_yaml::LANGUAGE.into(),
+            tree_sitter_yaml::HIGHLIGHTS_QUERY.into(),
+            String::new(),
+        ),
+        Lang::Markdown => (
+            tree_sitter_md::LANGUAGE.into(),
+            tree_sitter_md::HIGHLIGHT_QUERY_BLOCK.into(),
+            tree_sitter_md::INJECTION_QUERY_BLOCK.into(),
+        ),
+        Lang::MarkdownInline => (
+            tree_sitter_md::INLINE_LANGUAGE.into(),
+            tree_sitter_md::HIGHLIGHT_QUERY_INLINE.into(),
+            tree_sitter_md::INJECTION_QUERY_INLINE.into(),
+        ),
+        // No SQL grammar crate is available yet; `paint_sql` highlights it lexically
+        Lang::Sql => return None,
+    };
+
+    let highlights = match Query::new(&language, &highlights) {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("Failed to compile {:?} highlight query: {}", lang, e);
+            return None;
+        }
+    };
+    let injections = if injections.is_empty() {
+        None
+    } else {
+        match Query::new(&language, &injections) {
+            Ok(q) => Some(q),
+            Err(e) => {
+                eprintln!("Failed to compile {:?} injection query: {}", lang, e);
+                None
+            }
+        }
+    };
+    let colors = highlights.capture_names().iter().map(|n| capture_color(n)).collect();
+    Some(Grammar { language, highlights, colors, injections })
+}
+
+/// Map a capture name to a colour, falling back to its shorter prefixes
+/// (`function.method.builtin` → `function.method` → `function`)
+fn capture_color(name: &str) -> Option<Color32> {
+    let keyword = Color32::from_rgb(198, 120, 221);  // purple
+    let type_ = Color32::from_rgb(229, 192, 123);    // yellow
+    let constant = Color32::from_rgb(209, 154, 102); // orange
+    let string = Color32::from_rgb(152, 195, 121);   // green
+    let comment = Color32::from_rgb(150, 150, 150);  // gray
+    let function = Color32::from_rgb(97, 175, 239);  // blue
+    let property = Color32::from_rgb(224, 108, 117); // red
+    let escape = Color32::from_rgb(86, 182, 194);    // cyan
+
+    let mut name = name;
+    loop {
+        let color = match name {
+            "comment" => Some(comment),
+            "string" | "text.literal" => Some(string),
+            "string.escape" | "escape" | "string.regexp" => Some(escape),
+            "string.special.key" | "property" | "attribute" | "tag" | "label"
+            | "variable.builtin" => Some(property),
+            "keyword" | "import" | "charset" | "media" | "keyframes" | "supports"
+            | "text.title" => Some(keyword),
+            "type" | "constructor" | "namespace" => Some(type_),
+            "constant" | "number" | "boolean" | "float" => Some(constant),
+            "function" | "text.uri" | "text.reference" => Some(function),
+            _ => None,
+        };
+        if color.is_some() {
+            return color;
+        }
+        match name.rfind('.') {
+            Some(dot) => name = &name[..dot],
+            None => return None,
+        }
+    }
+}
+
+/// Syntax tree for one buffer, kept up to date incrementally as it is edited
+pub struct Syntax {
+    lang: Lang,
+    parser: Parser,
+    tree: Option<Tree>,
+    /// The tree has been edited (or dropped) and must be reparsed before use
+    stale: bool,
+    injections: Injections,
+    /// For SQL files, the lexing state at the start of each line, as far as known
+    sql_lines: Vec<SqlState>,
+}
+
+/// Trees of the languages injected into a buffer, kept between paints and edited
+/// along with the main tree so they too are reparsed incrementally
+struct Injections {
+    parser: Parser,
+    /// By nesting depth, language and where the injected text starts; `true` once
+    /// the tree was edited and needs reparsing
+    trees: HashMap<(usize, Lang, usize), (Tree, bool)>,
+}
+
+impl Injections {
+    fn edit(&mut self, edit: &InputEdit) {
+        let delta = edit.new_end_byte as isize - edit.old_end_byte as isize;
+        self.trees = std::mem::take(&mut self.trees)
+            .into_iter()
+            .filter_map(|((depth, lang, start), (mut tree, _))| {
+                let start = if start <= edit.start_byte {
+                    start
+                } else if start >= edit.old_end_byte {
+                    start.saturating_add_signed(delta)
+                } else {
+                    // Its start was deleted: it's gone or a different injection now
+                    return None;
+                };
+                tree.edit(edit);
+                Some(((depth, lang, start), (tree, true)))
+            })
+            .collect();
+    }
+
+    /// The tree of `lang` injected over `content`, parsed only if it's new or edited
+    fn tree(
+        &mut self,
+        depth: usize,
+        lang: Lang,
+        g: &Grammar,
+        content: tree_sitter::Range,
+        text: &TextBuffer,
+    ) -> Option<Tree> {
+        let key = (depth, lang, content.start_byte);
+        let old = match self.trees.remove(&key) {
+            Some((tree, false)) => {
+                self.trees.insert(key, (tree.clone(), false));
+                return Some(tree);
+            }
+            Some((tree, true)) => Some(tree),
+            None => None,
+        };
+        self.parser.set_language(&g.language).ok()?;
+        self.parser.set_included_ranges(&[content]).ok()?;
+        let tree = self.parser.parse_with_options(&mut |byte, _| text.chunk_at(byte), old.as_ref(), None)?;
+        self.trees.insert(key, (tree.clone(), false));
+        Some(tree)
+    }
+}
+
+impl Syntax {
+    pub fn for_path(path: &Path) -> Option<Self> {
+        let lang = Lang::from_path(path)?;
+        let mut parser = Parser::new();
+        if let Some(g) = grammar(lang) {
+            if let Err(e) = parser.set_language(&g.language) {
+                eprintln!("Failed to load {:?} grammar: {}", lang, e);
+                return None;
+            }
+        }
+        let injections = Injections { parser: Parser::new(), trees: HashMap::new() };
+        Some(Self { lang, parser, tree: None, stale: true, injections, sql_lines: vec![SqlState::Code] })
+    }
+
+    pub fn lang(&self) -> Lang {
+        self.lang
+    }
+
+    /// Record an edit so the next parse can reuse the unchanged parts of the tree
+    pub fn edit(&mut self, edit: &InputEdit) {
+        if let Some(tree) = &mut self.tree {
+            tree.edit(edit);
+        }
+        self.injections.edit(edit);
+        self.sql_lines.truncate(edit.start_position.row + 1);
+        self.stale = true;
+    }
+
+    /// Bring the tree up to date with `text` after edits
+    fn parse(&mut self, text: &TextBuffer) {
+        if self.stale {
+            self.tree = self.parser.parse_with_options(&mut |byte, _| text.chunk_at(byte), self.tree.as_ref(), None);
+            self.stale = false;
+        }
+    }
+
+    /// Named nodes spanning several lines, as (first row, last row, end column), for
+    /// folding; `None` for languages without a grammar
+    pub fn multiline_nodes(&mut self, text: &TextBuffer) -> Option<Vec<(usize, usize, usize)>> {
+        grammar(self.lang)?;
+        self.parse(text);
+        let tree = self.tree.as_ref()?;
+        let mut nodes = Vec::new();
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+            let (start, end) = (node.start_position(), node.end_position());
+            let multiline = end.row > start.row;
+            if multiline && node.is_named() && cursor.depth() > 0 {
+                nodes.push((start.row, end.row, end.column));
+            }
+            // Nodes on one line can't hold nodes spanning several
+            if multiline && cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    return Some(nodes);
+                }
+            }
+        }
+    }
+
+    /// Functions, types, headings and the like, nested; `None` for languages without
+    /// a grammar
+    pub fn outline(&mut self, text: &TextBuffer) -> Option<Vec<Symbol>> {
+        grammar(self.lang)?;
+        self.parse(text);
+        let tree = self.tree.as_ref()?;
+        let mut symbols = Vec::new();
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+            symbols.extend(outline_symbol(self.lang, node, text));
+            // Code declares nothing inside a single line worth listing; data keys may
+            let search = node.end_position().row > node.start_position().row
+                || matches!(self.lang, Lang::Json | Lang::Yaml);
+            if search && cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    outline::nest(&mut symbols);
+                    return Some(symbols);
+                }
+            }
+        }
+    }
+
+    /// SQL lexing state at the start of `line`, lexing the lines above that aren't
+    /// known yet
+    fn sql_state(&mut self, text: &TextBuffer, line: usize) -> SqlState {
+        while self.sql_lines.len() <= line {
+            let above = self.sql_lines.len() - 1;
+            let end = text.line_start(above + 1);
+            let state = paint_sql(&text.slice(text.line_start(above)..end), 0, &mut [], 0, self.sql_lines[above]);
+            self.sql_lines.push(state);
+        }
+        self.sql_lines[line]
+    }
+
+    /// Highlight spans for `range` of `text`, including injected languages
+    pub fn highlights(&mut self, text: &TextBuffer, range: Range<usize>) -> Vec<HighlightSpan> {
+        let range = range.start.min(text.len_bytes())..range.end.min(text.len_bytes());
+        let mut colors = vec![None; range.len()];
+        match grammar(self.lang) {
+            Some(g) => {
+                self.parse(text);
+                if let Some(tree) = &self.tree {
+                    let layer = Layer { g, root: tree.root_node(), depth: 0 };
+                    paint_layer(layer, text, range.clone(), &mut self.injections, &mut colors, range.start);
+                }
+            }
+            None => {
+                // Lex from the start of the first line, whose state is known, so a
+                // comment or string opened above is coloured right
+                let line = text.line_of(range.start);
+                let state = self.sql_state(text, line);
+                let start = text.line_start(line);
+                paint_sql(&text.slice(start..range.end), start, &mut colors, range.start, state);
+            }
+        }
+
+        let mut spans: Vec<HighlightSpan> = Vec::new();
+        for (i, color) in colors.into_iter().enumerate() {
+            let Some(color) = color else { continue };
+            let pos = range.start + i;
+            match spans.last_mut() {
+                Some(last) if last.end == pos && last.color == color => last.end = pos + 1,
+                _ => spans.push(HighlightSpan { start: pos, end: pos + 1, color }),
+            }
+        }
+        spans
+    }
+}
+
+/// Describe replacing `range` of `text` with `new_text`, for `Syntax::edit`
+pub fn input_edit(text: &TextBuffer, range: Range<usize>, new_text: &str) -> InputEdit {
+    let point_at = |offset: usize| {
+        let row = text.line_of(offset);
+        Point { row, column: offset - text.line_start(row) }
+    };
+    let start_position = point_at(range.start);
+    let new_rows = new_text.matches('\n').count();
+    let new_end_position = match new_text.rfind('\n') {
+        Some(p) => Point { row: start_position.row + new_rows, column: new_text.len() - p - 1 },
+        None => Point { row: start_position.row, column: start_position.column + new_text.len() },
+    };
+    InputEdit {
+        start_byte: range.start,
+        old_end_byte: range.end,
+        new_end_byte: range.start + new_text.len(),
+        start_position,
+        old_end_position: point_at(range.end),
+        new_end_position,
+    }
+}
+
+/// The symbol a node declares, if it's one the outline shows
+fn outline_symbol(lang: Lang, node: Node, text: &TextBuffer) -> Option<Symbol> {
+    let name_of = |n: Node| text.slice(n.byte_range());
+    let field = |name: &str| node.child_by_field_name(name);
+    // A function directly inside a class, impl or trait is a method
+    let in_type = |types: &[&str]| node.parent().and_then(|p| p.parent()).is_some_and(|p| types.contains(&p.kind()));
+    let script = matches!(lang, Lang::JavaScript | Lang::TypeScript | Lang::Tsx);
+    let (kind, name, at) = match (lang, node.kind()) {
+        (Lang::Rust, "function_item" | "function_signature_item") => {
+            let kind = if in_type(&["impl_item", "trait_item"]) { SymbolKind::Method } else { SymbolKind::Function };
+            (kind, field("name")?, None)
+        }
+        (Lang::Rust, "struct_item" | "enum_item" | "union_item" | "trait_item" | "type_item") => {
+            (SymbolKind::Type, field("name")?, None)
+        }
+        (Lang::Rust, "impl_item") => {
+            let ty = field("type")?;
+            let name = match field("trait") {
+                Some(tr) => format!("impl {} for {}", name_of(tr), name_of(ty)),
+                None => format!("impl {}", name_of(ty)),
+            };
+            return Some(symbol(SymbolKind::Type, name, node, ty.start_byte()));
+        }
+        (Lang::Rust, "mod_item") => (SymbolKind::Module, field("name")?, None),
+        (Lang::Rust, "const_item" | "static_item") => (SymbolKind::Constant, field("name")?, None),
+        (Lang::Rust, "macro_definition") => (SymbolKind::Function, field("name")?, None),
+        (Lang::Python, "function_definition") => {
+            let kind = if in_type(&["class_definition"]) { SymbolKind::Method } else { SymbolKind::Function };
+            (kind, field("name")?, None)
+        }
+        (Lang::Python, "class_definition") => (SymbolKind::Type, field("name")?, None),
+        (_, "function_declaration" | "generator_function_declaration") if script => {
+            (SymbolKind::Function, field("name")?, None)
+        }
+        (
+            _,
+            "class_declaration"
+            | "abstract_class_declaration"
+            | "interface_declaration"
+            | "type_alias_declaration"
+            | "enum_declaration",
+        ) if script => (SymbolKind::Type, field("name")?, None),
+        (_, "method_definition" | "method_signature") if script => (SymbolKind::Method, field("name")?, None),
+        (_, "variable_declarator") if script => {
+            let value = field("value")?;
+            if !matches!(value.kind(), "arrow_function" | "function_expression" | "class") {
+                return None;
+            }
+            (SymbolKind::Function, field("name")?, None)
+        }
+        // Sections run from a heading to the next one at the same level or above
+        (Lang::Markdown, "section") => {
+            let heading = node.named_child(0).filter(|h| h.kind().ends_with("_heading"))?;
+            let content = heading.child_by_field_name("heading_content")?;
+            (SymbolKind::Heading, content, Some(heading.start_byte()))
+        }
+        (Lang::Json, "pair") => (SymbolKind::Field, field("key")?, None),
+        (Lang::Yaml, "block_mapping_pair") => (SymbolKind::Field, field("key")?, None),
+        (Lang::Css, "rule_set") => (SymbolKind::Other, node.named_child(0)?, None),
+        _ => return None,
+    };
+    let at = at.unwrap_or(name.start_byte());
+    let name = name_of(name);
+    let name = name.lines().next().unwrap_or_default().trim().trim_matches('"');
+    (!name.is_empty()).then(|| symbol(kind, name.to_string(), node, at))
+}
+
+fn symbol(kind: SymbolKind, name: String, node: Node, at: usize) -> Symbol {
+    Symbol { name, kind, range: node.byte_range(), at, depth: 0 }
+}
+
+/// Lets queries read node text straight from the rope
+struct RopeText<'a>(&'a TextBuffer);
+
+impl<'a> TextProvider<&'a [u8]> for RopeText<'a> {
+    type I = std::iter::Map<ropey::iter::Chunks<'a>, fn(&'a str) -> &'a [u8]>;
+
+    fn text(&mut self, node: Node) -> Self::I {
+        self.0.chunks(node.byte_range()).map(str::as_bytes)
+    }
+}
+
+/// Set `colors` (which starts at byte `base`) over `start..end`, clipped to its extent
+fn paint(colors: &mut [Option<Color32>], base: usize, start: usize, end: usize, color: Option<Color32>) {
+    let start = start.max(base) - base;
+    let end = end.min(base + colors.len()).saturating_sub(base);
+    if start < end {
+        colors[start..end].fill(color);
+    }
+}
+
+/// A parsed language in a buffer: the main one, or one injected `depth` levels deep
+struct Layer<'a> {
+    g: &'a Grammar,
+    root: Node<'a>,
+    depth: usize,
+}
+
+/// Paint one parsed layer over `range`, then the languages injected into it
+fn paint_layer(
+    Layer { g, root, depth }: Layer,
+    text: &TextBuffer,
+    range: Range<usize>,
+    injections: &mut Injections,
+    colors: &mut [Option<Color32>],
+    base: usize,
+) {
+    let mut cursor = QueryCursor::new();
+    cursor.set_byte_range(range.clone());
+
+    // Inner nodes paint over outer ones; for the same node the earliest pattern wins
+    let mut captures = Vec::new();
+    let mut it = cursor.captures(&g.highlights, root, RopeText(text));
+    while let Some((m, idx)) = it.next() {
+        let c = m.captures[*idx];
+        captures.push((c.node.start_byte(), c.node.end_byte(), m.pattern_index, g.colors[c.index as usize]));
+    }
+    captures.sort_by_key(|&(start, end, pattern, _)| (start, Reverse(end), Reverse(pattern)));
+    for (start, end, _, color) in captures {
+        paint(colors, base, start, end, color);
+    }
+
+    let Some(query) = &g.injections else { return };
+    if depth >= MAX_INJECTION_DEPTH {
+        return;
+    }
+    let content_idx = query.capture_index_for_name("injection.content");
+    let language_idx = query.capture_index_for_name("injection.language");
+    let mut found = Vec::new();
+    let mut it = cursor.matches(query, root, RopeText(text));
+    while let Some(m) = it.next() {
+        let mut lang = query
+            .property_settings(m.pattern_index)
+            .iter()
+            .find(|p| &*p.key == "injection.language")
+            .and_then(|p| p.value.as_deref())
+            .and_then(Lang::from_name);
+        let mut content = None;
+        for c in m.captures {
+            if Some(c.index) == content_idx {
+                content = Some(c.node.range());
+            } else if Some(c.index) == language_idx {
+                lang = Lang::from_name(&text.slice(c.node.byte_range()));
+            }
+        }
+        if let (Some(lang), Some(content)) = (lang, content) {
+            found.push((lang, content));
+        }
+    }
+
+    for (lang, content) in found {
+        let visible = range.start.max(content.start_byte)..range.end.min(content.end_byte);
+        if visible.is_empty() {
+            continue;
+        }
+        let Some(injected) = grammar(lang) else {
+            if lang == Lang::Sql {
+                let sql = text.slice(content.start_byte..content.end_byte);
+                paint_sql(&sql, content.start_byte, colors, base, SqlState::Code);
+            }
+            continue;
+        };
+        if let Some(tree) = injections.tree(depth + 1, lang, injected, content, text) {
+            let layer = Layer { g: injected, root: tree.root_node(), depth: depth + 1 };
+            paint_layer(layer, text, visible, injections, colors, base);
+        }
+    }
+}
+
+const SQL_KEYWORDS: &[&str] = &[
+    "add", "all", "alter", "and", "as", "asc", "begin", "between", "by", "case", "commit",
+    "constraint", "create", "cross", "default", "delete", "desc", "distinct", "drop", "else",
+    "end", "exists", "foreign", "from", "full", "group", "having", "if", "in", "index", "inner",
+    "insert", "into", "is", "join", "key", "left", "like", "limit", "not", "null", "offset", "on",
+    "or", "order", "outer", "primary", "references", "returning", "right", "rollback", "select",
+    "set", "table", "then", "transaction", "union", "unique", "update", "values", "view", "when",
+    "where", "with",
+];
+
+/// Where SQL lexing is at the end of some text: inside a comment or string that
+/// carries on, or not
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SqlState {
+    Code,
+    BlockComment,
+    String,
+}
+
+/// Lexical SQL highlighting (there is no tree-sitter SQL grammar in the build):
+/// keywords, strings, numbers and comments in `sql`, which starts at byte `offset`
+/// in `state`. Returns the state at its end.
+fn paint_sql(sql: &str, offset: usize, colors: &mut [Option<Color32>], base: usize, state: SqlState) -> SqlState {
+    let keyword = capture_color("keyword");
+    let string = capture_color("string");
+    let number = capture_color("number");
+    let comment = capture_color("comment");
+
+    let mut mark = |start: usize, end: usize, color| paint(colors, base, offset + start, offset + end, color);
+    let bytes = sql.as_bytes();
+    let len = bytes.len();
+    // The end of a block comment or string from `i`, and whether it closes
+    let comment_end = |i: usize| sql[i..].find("*/").map_or((len, false), |p| (i + p + 2, true));
+    let string_end = |i: usize| sql[i..].find('\'').map_or((len, false), |p| (i + p + 1, true));
+    let mut i = 0;
+    // Finish what the text before left open
+    match state {
+        SqlState::Code => {}
+        SqlState::BlockComment => {
+            let (end, closed) = comment_end(0);
+            mark(0, end, comment);
+            if !closed {
+                return state;
+            }
+            i = end;
+        }
+        SqlState::String => {
+            let (end, closed) = string_end(0);
+            mark(0, end, string);
+            if !closed {
+                return state;
+            }
+            i = end;
+        }
+    }
+    while i < len {
+        let b = bytes[i];
+        let start = i;
+        if b == b'-' && bytes.get(i + 1) == Some(&b'-') {
+            while i < len && bytes[i] != b'\n' {
+                i += 1;
+            }
+            mark(start, i, comment);
+        } else if b == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            let (end, closed) = comment_end(i + 2);
+            mark(start, end, comment);
+            if !closed {
+                return SqlState::BlockComment;
+            }
+            i = end;
+        } else if b == b'\'' {
+            let (end, closed) = string_end(i + 1);
+            mark(start, end, string);
+            if !closed {
+                return SqlState::String;
+            }
+            i = end;
+        } else if b.is_ascii_digit() && (i == 0 || !bytes[i - 1].is_ascii_alphanumeric()) {
+            while i < len && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                i += 1;
+            }
+            mark(start, i, number);
+        } else if b.is_ascii_alphabetic() || b == b'_' {
+            while i < len && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let word = sql[start..i].to_ascii_lowercase();
+            if SQL_KEYWORDS.contains(&word.as_str()) {
+                mark(start, i, keyword);
+            }
+        } else {
+            i += 1;
+        }
+    }
+    SqlState::Code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Colour of the first byte of `needle` in `source`, highlighted as a file with
+    /// extension `ext`
+    fn color_at(ext: &str, source: &str, needle: &str) -> Option<Color32> {
+        let text = TextBuffer::from(source);
+        let mut syntax = Syntax::for_path(Path::new(&format!("test.{}", ext))).unwrap();
+        let at = source.find(needle).unwrap();
+        let spans = syntax.highlights(&text, 0..text.len_bytes());
+        spans.iter().find(|s| s.start <= at && at < s.end).map(|s| s.color)
+    }
+
+    #[test]
+    fn comment_markers_in_strings_are_strings() {
+        let source = "let url = \"http://example.com\"; // real\n";
+        assert_eq!(color_at("rs", source, "//example"), capture_color("string"));
+        assert_eq!(color_at("rs", source, "// real"), capture_color("comment"));
+        assert_eq!(color_at("py", "s = '# not a comment'\n", "# not"), capture_color("string"));
+    }
+
+    #[test]
+    fn block_comments_nest_and_span_lines() {
+        let source = "/* outer /* inner */ still\ncomment */ fn f() {}\n";
+        assert_eq!(color_at("rs", source, "still"), capture_color("comment"));
+        assert_eq!(color_at("rs", source, "comment */"), capture_color("comment"));
+        assert_eq!(color_at("rs", source, "fn"), capture_color("keyword"));
+        assert_eq!(color_at("js", "/* a\n b */ let x;\n", "b */"), capture_color("comment"));
+    }
+
+    #[test]
+    fn injected_languages_are_highlighted() {
+        // A Rust code fence in Markdown
+        let source = "# Title\n\n```rust\nfn main() {}\n```\n";
+        assert_eq!(color_at("md", source, "fn main"), capture_color("keyword"));
+        // SQL in a Rust string, highlighted lexically
+        let source = "let q = \"SELECT name FROM users -- all\";\n";
+        assert_eq!(color_at("rs", source, "SELECT"), capture_color("keyword"));
+        assert_eq!(color_at("rs", source, "-- all"), capture_color("comment"));
+        // Other strings are left alone
+        assert_eq!(color_at("rs", "let s = \"selection\";\n", "selection"), capture_color("string"));
+    }
+
+    #[test]
+    fn injected_trees_are_kept_and_edited() {
+        let source = "# Title\n\n```rust\nfn main() {}\n```\n";
+        let mut text = TextBuffer::from(source);
+        let mut syntax = Syntax::for_path(Path::new("test.md")).unwrap();
+        let fence = source.find("fn").unwrap();
+        let stale = |syntax: &Syntax| syntax.injections.trees.get(&(1, Lang::Rust, fence)).map(|(_, stale)| *stale);
+        syntax.highlights(&text, 0..text.len_bytes());
+        assert_eq!(stale(&syntax), Some(false));
+
+        // An edit inside the fence keeps its tree, to be reparsed from
+        let at = fence + "fn main() {".len();
+        syntax.edit(&input_edit(&text, at..at, " let x = 1; "));
+        text.replace(at..at, " let x = 1; ");
+        assert_eq!(stale(&syntax), Some(true));
+        let spans = syntax.highlights(&text, 0..text.len_bytes());
+        assert_eq!(stale(&syntax), Some(false));
+        let let_at = text.to_string().find("let").unwrap();
+        assert!(spans.iter().any(|s| s.start == let_at && s.color == capture_color("keyword").unwrap()));
+
+        // An edit before it moves it
+        syntax.edit(&input_edit(&text, 0..0, "\n"));
+        text.replace(0..0, "\n");
+        assert!(syntax.injections.trees.contains_key(&(1, Lang::Rust, fence + 1)));
+    }
+
+    #[test]
+    fn sql_files_are_highlighted_lexically() {
+        let source = "select 'it''s' from t where id = 42; -- done\n/* block */\n";
+        assert_eq!(color_at("sql", source, "select"), capture_color("keyword"));
+        assert_eq!(color_at("sql", source, "'it"), capture_color("string"));
+        assert_eq!(color_at("sql", source, "42"), capture_color("number"));
+        assert_eq!(color_at("sql", source, "-- done"), capture_color("comment"));
+        assert_eq!(color_at("sql", source, "block"), capture_color("comment"));
+        assert_eq!(color_at("sql", source, "from"), capture_color("keyword"));
+    }
+
+    #[test]
+    fn sql_comments_opened_above_the_view_are_comments() {
+        let source = "select 1;\n/* start\nstill select\nend */ select 'a\nb' from t;\n";
+        let mut text = TextBuffer::from(source);
+        let mut syntax = Syntax::for_path(Path::new("test.sql")).unwrap();
+        let color = |syntax: &mut Syntax, text: &TextBuffer, needle: &str| {
+            let at = text.to_string().find(needle).unwrap();
+            let spans = syntax.highlights(text, at..text.len_bytes());
+            spans.iter().find(|s| s.start <= at && at < s.end).map(|s| s.color)
+        };
+        assert_eq!(color(&mut syntax, &text, "still"), capture_color("comment"));
+        assert_eq!(color(&mut syntax, &text, "b' from"), capture_color("string"));
+        assert_eq!(color(&mut syntax, &text, "from"), capture_color("keyword"));
+
+        // Edits above the view are taken into account
+        let at = source.find("/*").unwrap();
+        syntax.edit(&input_edit(&text, at..at + 2, "--"));
+        text.replace(at..at + 2, "--");
+        assert_eq!(color(&mut syntax, &text, "select\nend"), capture_color("keyword"));
+    }
+}
@@ -0,0 +1,492 @@
+use crate::color_scheme::{format_x_color, parse_x_color, ColorScheme};
+use base64::Engine;
+use std::sync::{Arc, Mutex};
+
+/// Handles the control sequences vt100 ignores: terminal queries (DA, DSR/CPR,
+/// XTVERSION, XTGETTCAP, window size), colour queries and palette changes
+/// (OSC 4/10/11/12 and their resets) and OSC 52 clipboard access. PTY output goes through `process`, which
+/// also feeds the vt100 parser and collects the bytes to write back to the PTY.
+pub struct TermResponder {
+    parser: vte::Parser,
+    performer: Performer,
+}
+
+struct Performer {
+    colors: Arc<Mutex<ColorScheme>>,
+    /// Scheme from config, restored by the OSC 1xx reset sequences
+    base: ColorScheme,
+    replies: Vec<u8>,
+    /// Query that must be answered from the screen state at the point it was received
+    screen_query: Option<ScreenQuery>,
+    /// Hex-encoded capability names of an XTGETTCAP request in progress
+    tcap: Option<Vec<u8>>,
+    clipboard: Arc<Mutex<Vec<ClipboardRequest>>>,
+}
+
+/// Clipboard access requested through OSC 52, carried out on the UI thread
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClipboardRequest {
+    Copy(String),
+    /// The reply echoes the selection parameter and the query's terminator
+    Paste { selection: String, bell_terminated: bool },
+}
+
+/// Build the OSC 52 reply carrying clipboard contents
+pub fn clipboard_reply(selection: &str, text: &str, bell_terminated: bool) -> Vec<u8> {
+    let data = base64::engine::general_purpose::STANDARD.encode(text);
+    let mut reply = format!("\x1b]52;{};{}", selection, data).into_bytes();
+    if bell_terminated {
+        reply.push(0x07);
+    } else {
+        reply.extend_from_slice(b"\x1b\\");
+    }
+    reply
+}
+
+#[derive(Clone, Copy)]
+enum ScreenQuery {
+    /// DSR 6 (`CSI 6 n`) or DECXCPR (`CSI ? 6 n`)
+    CursorPosition { private: bool },
+    /// `CSI 18 t`
+    TextAreaSize,
+}
+
+const XTVERSION: &str = concat!("aio-terminal(", env!("CARGO_PKG_VERSION"), ")");
+
+/// Capabilities reported through XTGETTCAP
+const TERMCAPS: &[(&str, &str)] = &[
+    ("TN", "xterm-256color"),
+    ("name", "xterm-256color"),
+    ("Co", "256"),
+    ("colors", "256"),
+    ("RGB", "8/8/8"),
+];
+
+impl TermResponder {
+    pub fn new(colors: Arc<Mutex<ColorScheme>>, clipboard: Arc<Mutex<Vec<ClipboardRequest>>>) -> Self {
+        let base = colors.lock().map(|c| c.clone()).unwrap_or_default();
+        Self {
+            parser: vte::Parser::new(),
+            performer: Performer {
+                colors,
+                base,
+                replies: Vec::new(),
+                screen_query: None,
+                tcap: None,
+                clipboard,
+            },
+        }
+    }
+
+    /// Feed PTY output to `screen` and answer any queries in it, returning the
+    /// reply to write back (empty if none). Output before a cursor position query
+    /// is applied to the screen first, so the report matches what the program expects.
+    pub fn process(&mut self, bytes: &[u8], screen: &mut vt100::Parser) -> Vec<u8> {
+        let mut flushed = 0;
+        for (i, &b) in bytes.iter().enumerate() {
+            self.parser.advance(&mut self.performer, b);
+            if let Some(query) = self.performer.screen_query.take() {
+                screen.process(&bytes[flushed..=i]);
+                flushed = i + 1;
+                self.performer.answer_screen_query(query, screen.screen());
+            }
+        }
+        screen.process(&bytes[flushed..]);
+        std::mem::take(&mut self.performer.replies)
+    }
+}
+
+/// Which dynamic colour an OSC 10-19 number refers to
+#[derive(Clone, Copy)]
+enum Dynamic {
+    Foreground,
+    Background,
+    Cursor,
+}
+
+impl Dynamic {
+    fn from_osc(n: u16) -> Option<Self> {
+        match n {
+            10 => Some(Self::Foreground),
+            11 => Some(Self::Background),
+            12 => Some(Self::Cursor),
+            _ => None,
+        }
+    }
+
+    fn slot(self, scheme: &mut ColorScheme) -> &mut eframe::egui::Color32 {
+        match self {
+            Self::Foreground => &mut scheme.foreground,
+            Self::Background => &mut scheme.background,
+            Self::Cursor => &mut scheme.cursor,
+        }
+    }
+}
+
+impl Performer {
+    fn csi(&mut self, body: &str) {
+        self.replies.extend_from_slice(b"\x1b[");
+        self.replies.extend_from_slice(body.as_bytes());
+    }
+
+    fn dcs(&mut self, body: &str) {
+        self.replies.extend_from_slice(b"\x1bP");
+        self.replies.extend_from_slice(body.as_bytes());
+        self.replies.extend_from_slice(b"\x1b\\");
+    }
+
+    fn answer_screen_query(&mut self, query: ScreenQuery, screen: &vt100::Screen) {
+        match query {
+            ScreenQuery::CursorPosition { private } => {
+                let (row, col) = screen.cursor_position();
+                let marker = if private { "?" } else { "" };
+                self.csi(&format!("{}{};{}R", marker, row + 1, col + 1));
+            }
+            ScreenQuery::TextAreaSize => {
+                let (rows, cols) = screen.size();
+                self.csi(&format!("8;{};{}t", rows, cols));
+            }
+        }
+    }
+
+    /// XTGETTCAP: one reply per requested name, `1+r name=value` or `0+r` if unknown
+    fn answer_tcap(&mut self, request: &[u8]) {
+        for hex_name in request.split(|&b| b == b';').filter(|n| !n.is_empty()) {
+            let value = hex_decode(hex_name)
+                .and_then(|name| TERMCAPS.iter().find(|(cap, _)| cap.as_bytes() == name.as_slice()))
+                .map(|(_, value)| *value);
+            match value {
+                Some(value) => {
+                    let name = String::from_utf8_lossy(hex_name).to_string();
+                    self.dcs(&format!("1+r{}={}", name, hex_encode(value.as_bytes())));
+                }
+                None => self.dcs("0+r"),
+            }
+        }
+    }
+
+    fn reply(&mut self, body: &str, bell_terminated: bool) {
+        self.replies.extend_from_slice(b"\x1b]");
+        self.replies.extend_from_slice(body.as_bytes());
+        if bell_terminated {
+            self.replies.push(0x07);
+        } else {
+            self.replies.extend_from_slice(b"\x1b\\");
+        }
+    }
+
+    /// OSC 4 ; index ; spec [; index ; spec ...]
+    fn palette(&mut self, args: &[&[u8]], bell_terminated: bool) {
+        for pair in args.chunks(2) {
+            let [index, spec] = pair else { break };
+            let Some(index) = parse_num(index).and_then(|i| u8::try_from(i).ok()) else {
+                continue;
+            };
+            if *spec == b"?" {
+                let color = self.colors.lock().ok().map(|c| c.palette[index as usize]);
+                if let Some(color) = color {
+                    self.reply(&format!("4;{};{}", index, format_x_color(color)), bell_terminated);
+                }
+            } else if let Some(color) = std::str::from_utf8(spec).ok().and_then(parse_x_color) {
+                if let Ok(mut c) = self.colors.lock() {
+                    c.palette[index as usize] = color;
+                }
+            }
+        }
+    }
+
+    /// OSC 10-19: each further argument applies to the next colour number
+    fn dynamic(&mut self, first: u16, args: &[&[u8]], bell_terminated: bool) {
+        for (n, spec) in (first..20).zip(args) {
+            let Some(which) = Dynamic::from_osc(n) else { continue };
+            if *spec == b"?" {
+                let color = self.colors.lock().ok().map(|mut c| *which.slot(&mut c));
+                if let Some(color) = color {
+                    self.reply(&format!("{};{}", n, format_x_color(color)), bell_terminated);
+                }
+            } else if let Some(color) = std::str::from_utf8(spec).ok().and_then(parse_x_color) {
+                if let Ok(mut c) = self.colors.lock() {
+                    *which.slot(&mut c) = color;
+                }
+            }
+        }
+    }
+
+    /// OSC 52 ; selection ; base64 data, or `?` to read
+    fn clipboard(&mut self, args: &[&[u8]], bell_terminated: bool) {
+        let [selection, data, ..] = args else { return };
+        let request = if *data == b"?" {
+            ClipboardRequest::Paste {
+                selection: String::from_utf8_lossy(selection).to_string(),
+                bell_terminated,
+            }
+        } else {
+            match base64::engine::general_purpose::STANDARD.decode(data) {
+                Ok(bytes) => ClipboardRequest::Copy(String::from_utf8_lossy(&bytes).to_string()),
+                Err(_) => return,
+            }
+        };
+        if let Ok(mut queue) = self.clipboard.lock() {
+            queue.push(request);
+        }
+    }
+
+    fn reset_palette(&mut self, args: &[&[u8]]) {
+        let Ok(mut c) = self.colors.lock() else { return };
+        if args.iter().all(|a| a.is_empty()) {
+            c.palette = self.base.palette;
+            return;
+        }
+        for index in args.iter().filter_map(|a| parse_num(a)) {
+            if let Ok(index) = u8::try_from(index) {
+                c.palette[index as usize] = self.base.palette[index as usize];
+            }
+        }
+    }
+
+    fn reset_dynamic(&mut self, which: Dynamic) {
+        let base = *which.slot(&mut self.base);
+        if let Ok(mut c) = self.colors.lock() {
+            *which.slot(&mut c) = base;
+        }
+    }
+}
+
+impl vte::Perform for Performer {
+    fn csi_dispatch(&mut self, params: &vte::Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore {
+            return;
+        }
+        let first = params.iter().next().map(|p| p[0]).unwrap_or(0);
+        match (intermediates, action) {
+            // Primary DA: VT220 with ANSI colour
+            ([], 'c') if first == 0 => self.csi("?62;22c"),
+            // Secondary DA: terminal type, firmware version, ROM cartridge
+            ([b'>'], 'c') if first == 0 => self.csi(">1;10;0c"),
+            // Tertiary DA: unit ID
+            ([b'='], 'c') if first == 0 => self.dcs("!|00000000"),
+            ([], 'n') if first == 5 => self.csi("0n"),
+            ([], 'n') if first == 6 => {
+                self.screen_query = Some(ScreenQuery::CursorPosition { private: false });
+            }
+            ([b'?'], 'n') if first == 6 => {
+                self.screen_query = Some(ScreenQuery::CursorPosition { private: true });
+            }
+            ([], 't') if first == 18 => self.screen_query = Some(ScreenQuery::TextAreaSize),
+            // XTVERSION
+            ([b'>'], 'q') if first == 0 => self.dcs(&format!(">|{}", XTVERSION)),
+            _ => {}
+        }
+    }
+
+    fn hook(&mut self, _params: &vte::Params, intermediates: &[u8], ignore: bool, action: char) {
+        if !ignore && intermediates == b"+" && action == 'q' {
+            self.tcap = Some(Vec::new());
+        }
+    }
+
+    fn put(&mut self, byte: u8) {
+        if let Some(buf) = &mut self.tcap {
+            buf.push(byte);
+        }
+    }
+
+    fn unhook(&mut self) {
+        if let Some(request) = self.tcap.take() {
+            self.answer_tcap(&request);
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        let Some(code) = params.first().and_then(|p| parse_num(p)) else {
+            return;
+        };
+        let args = &params[1..];
+        match code {
+            4 => self.palette(args, bell_terminated),
+            10..=19 => self.dynamic(code, args, bell_terminated),
+            52 => self.clipboard(args, bell_terminated),
+            104 => self.reset_palette(args),
+            110..=119 => {
+                if let Some(which) = Dynamic::from_osc(code - 100) {
+                    self.reset_dynamic(which);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_num(bytes: &[u8]) -> Option<u16> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (TermResponder, vt100::Parser) {
+        let colors = Arc::new(Mutex::new(ColorScheme::default()));
+        let clipboard = Arc::new(Mutex::new(Vec::new()));
+        (TermResponder::new(colors, clipboard), vt100::Parser::new(24, 80, 0))
+    }
+
+    fn reply_to(input: &[u8]) -> Vec<u8> {
+        let (mut responder, mut screen) = setup();
+        responder.process(input, &mut screen)
+    }
+
+    #[test]
+    fn plain_output_has_no_reply() {
+        let (mut responder, mut screen) = setup();
+        assert!(responder.process(b"hello \x1b[1mworld\x1b[0m\r\n", &mut screen).is_empty());
+        assert_eq!(screen.screen().contents(), "hello world");
+    }
+
+    #[test]
+    fn primary_device_attributes() {
+        assert_eq!(reply_to(b"\x1b[c"), b"\x1b[?62;22c");
+        assert_eq!(reply_to(b"\x1b[0c"), b"\x1b[?62;22c");
+    }
+
+    #[test]
+    fn secondary_and_tertiary_device_attributes() {
+        assert_eq!(reply_to(b"\x1b[>c"), b"\x1b[>1;10;0c");
+        assert_eq!(reply_to(b"\x1b[=c"), b"\x1bP!|00000000\x1b\\");
+    }
+
+    #[test]
+    fn device_status_report() {
+        assert_eq!(reply_to(b"\x1b[5n"), b"\x1b[0n");
+    }
+
+    #[test]
+    fn cursor_position_report() {
+        assert_eq!(reply_to(b"\x1b[6n"), b"\x1b[1;1R");
+        assert_eq!(reply_to(b"\x1b[5;10H\x1b[6n"), b"\x1b[5;10R");
+        assert_eq!(reply_to(b"\x1b[3;7H\x1b[?6n"), b"\x1b[?3;7R");
+    }
+
+    #[test]
+    fn cursor_position_reflects_output_before_query_only() {
+        assert_eq!(reply_to(b"abc\x1b[6nde"), b"\x1b[1;4R");
+    }
+
+    #[test]
+    fn cursor_position_query_split_across_reads() {
+        let (mut responder, mut screen) = setup();
+        assert!(responder.process(b"xy\x1b[", &mut screen).is_empty());
+        assert_eq!(responder.process(b"6n", &mut screen), b"\x1b[1;3R");
+    }
+
+    #[test]
+    fn text_area_size() {
+        assert_eq!(reply_to(b"\x1b[18t"), b"\x1b[8;24;80t");
+    }
+
+    #[test]
+    fn xtversion() {
+        let expected = format!("\x1bP>|{}\x1b\\", XTVERSION);
+        assert_eq!(reply_to(b"\x1b[>q"), expected.as_bytes());
+        assert_eq!(reply_to(b"\x1b[>0q"), expected.as_bytes());
+    }
+
+    #[test]
+    fn xtgettcap_known_and_unknown() {
+        // "TN" = 544e, "Co" = 436f, "xx" = 7878
+        let reply = reply_to(b"\x1bP+q544e;436f;7878\x1b\\");
+        let expected = format!(
+            "\x1bP1+r544e={}\x1b\\\x1bP1+r436f={}\x1b\\\x1bP0+r\x1b\\",
+            hex_encode(b"xterm-256color"),
+            hex_encode(b"256"),
+        );
+        assert_eq!(String::from_utf8(reply).unwrap(), expected);
+    }
+
+    #[test]
+    fn osc_background_query_matches_terminator() {
+        assert_eq!(reply_to(b"\x1b]11;?\x1b\\"), b"\x1b]11;rgb:ffff/ffff/ffff\x1b\\");
+        assert_eq!(reply_to(b"\x1b]11;?\x07"), b"\x1b]11;rgb:ffff/ffff/ffff\x07");
+    }
+
+    #[test]
+    fn osc_foreground_query() {
+        assert_eq!(reply_to(b"\x1b]10;?\x07"), b"\x1b]10;rgb:2424/2424/2424\x07");
+        // The terminal has no selection to colour, so OSC 17 goes unanswered
+        assert!(reply_to(b"\x1b]17;?\x07").is_empty());
+    }
+
+    #[test]
+    fn osc_palette_set_query_and_reset() {
+        let (mut responder, mut screen) = setup();
+        assert!(responder.process(b"\x1b]4;1;rgb:12/34/56\x07", &mut screen).is_empty());
+        assert_eq!(
+            responder.process(b"\x1b]4;1;?\x07", &mut screen),
+            b"\x1b]4;1;rgb:1212/3434/5656\x07"
+        );
+        responder.process(b"\x1b]104;1\x07", &mut screen);
+        assert_eq!(
+            responder.process(b"\x1b]4;1;?\x07", &mut screen),
+            b"\x1b]4;1;rgb:cdcd/3131/3131\x07"
+        );
+    }
+
+    #[test]
+    fn osc_dynamic_colour_set_and_reset() {
+        let (mut responder, mut screen) = setup();
+        responder.process(b"\x1b]11;#102030\x07", &mut screen);
+        assert_eq!(
+            responder.process(b"\x1b]11;?\x07", &mut screen),
+            b"\x1b]11;rgb:1010/2020/3030\x07"
+        );
+        responder.process(b"\x1b]111\x07", &mut screen);
+        assert_eq!(
+            responder.process(b"\x1b]11;?\x07", &mut screen),
+            b"\x1b]11;rgb:ffff/ffff/ffff\x07"
+        );
+    }
+
+    #[test]
+    fn several_queries_in_one_read() {
+        assert_eq!(reply_to(b"\x1b[c\x1b[5n"), b"\x1b[?62;22c\x1b[0n");
+    }
+
+    #[test]
+    fn osc52_copy_and_paste_requests() {
+        let colors = Arc::new(Mutex::new(ColorScheme::default()));
+        let clipboard = Arc::new(Mutex::new(Vec::new()));
+        let mut responder = TermResponder::new(colors, clipboard.clone());
+        let mut screen = vt100::Parser::new(24, 80, 0);
+
+        // "こんにちは" yanked from a remote vim
+        assert!(responder.process(b"\x1b]52;c;44GT44KT44Gr44Gh44Gv\x07", &mut screen).is_empty());
+        assert!(responder.process(b"\x1b]52;c;?\x1b\\", &mut screen).is_empty());
+        assert!(responder.process(b"\x1b]52;c;!!notbase64\x07", &mut screen).is_empty());
+        assert_eq!(
+            *clipboard.lock().unwrap(),
+            vec![
+                ClipboardRequest::Copy("こんにちは".to_string()),
+                ClipboardRequest::Paste { selection: "c".to_string(), bell_terminated: false },
+            ]
+        );
+    }
+
+    #[test]
+    fn osc52_reply_encoding() {
+        assert_eq!(clipboard_reply("c", "hi", true), b"\x1b]52;c;aGk=\x07");
+        assert_eq!(clipboard_reply("p", "", false), b"\x1b]52;p;\x1b\\");
+    }
+}
@@ -1,3 +1,6 @@
+use crate::color_scheme::ColorScheme;
+use crate::config::{ClipboardRead, TerminalProfile};
+use crate::term_responder::{clipboard_reply, ClipboardRequest, TermResponder};
 use eframe::egui::{self, Color32, FontId, Rect};
 use portable_pty::{CommandBuilder, NativePtySystem, PtySize, PtySystem};
 use std::io::{Read, Write};
@@ -11,61 +14,77 @@ pub struct Terminal {
     cols: u16,
     id: usize,
     pub grab_focus: bool,
+    /// In-progress IME composition (preedit) text, shown at the cursor
+    ime_preedit: String,
+    /// Name of the profile this terminal was started from
+    pub profile_name: String,
+    /// Current colours; applications may change them with OSC 4/10/11/12
+    colors: Arc<Mutex<ColorScheme>>,
+    font_size: f32,
+    font_family: egui::FontFamily,
+    /// OSC 52 requests from the PTY reader thread
+    clipboard: Arc<Mutex<Vec<ClipboardRequest>>>,
+    pub clipboard_read: ClipboardRead,
+    /// OSC 52 read waiting for the user to allow or deny it: (selection, bell_terminated)
+    pending_clipboard_read: Option<(String, bool)>,
+    /// Output since the last command was entered, for finding build errors
+    output: Arc<Mutex<OutputLog>>,
+    /// `OutputLog::version` last handed out by `take_new_output`
+    output_seen: u64,
 }
 
-static NEXT_TERM_ID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-
-impl Terminal {
-    pub fn new(rows: u16, cols: u16) -> Result<Self, Box<dyn std::error::Error>> {
-        let pty_system = NativePtySystem::default();
-        let pair = pty_system.openpty(PtySize {
-            rows,
-            cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        })?;
-
-        let mut cmd = CommandBuilder::new_default_prog();
-        cmd.env("TERM", "xterm-256color");
-        cmd.env("COLORTERM", "truecolor");
+/// Raw PTY output of the command last entered at the shell
+#[derive(Default)]
+struct OutputLog {
+    bytes: Vec<u8>,
+    /// Bumped on every change
+    version: u64,
+    /// The command line, as it read on screen when Enter was pressed
+    command: String,
+    /// Cursor position when the command started being typed, i.e. the prompt's end
+    prompt_end: Option<(u16, u16)>,
+}
 
-        let child = pair.slave.spawn_command(cmd)?;
-        drop(pair.slave);
+/// Only the end of a long command's output is kept
+const OUTPUT_LOG_LIMIT: usize = 256 * 1024;
 
-        let reader = pair.master.try_clone_reader()?;
-        let writer = pair.master.take_writer()?;
+static NEXT_TERM_ID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 
-        let parser = Arc::new(Mutex::new(vt100::Parser::new(rows, cols, 1000)));
-        let parser_clone = parser.clone();
+const DEFAULT_FONT_SIZE: f32 = 14.0;
 
-        // Background thread to read PTY output
-        std::thread::spawn(move || {
-            let mut reader = reader;
-            let mut buf = [0u8; 8192];
-            loop {
-                match reader.read(&mut buf) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        if let Ok(mut p) = parser_clone.lock() {
-                            p.process(&buf[..n]);
-                        }
-                    }
-                    Err(_) => break,
-                }
+impl Terminal {
+    pub fn new(
+        rows: u16,
+        cols: u16,
+        profile: &TerminalProfile,
+        colors: ColorScheme,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cmd = match &profile.program {
+            Some(program) => {
+                let mut cmd = CommandBuilder::new(program);
+                cmd.args(&profile.args);
+                cmd
             }
-        });
-
-        let id = NEXT_TERM_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            None => CommandBuilder::new_default_prog(),
+        };
+        if let Some(cwd) = profile.cwd_path() {
+            cmd.cwd(cwd);
+        }
+        cmd.env("TERM", "xterm-256color");
+        cmd.env("COLORTERM", "truecolor");
+        for (k, v) in &profile.env {
+            cmd.env(k, v);
+        }
 
-        Ok(Self {
-            parser,
-            writer: Arc::new(Mutex::new(writer)),
-            _child: child,
-            rows,
-            cols,
-            id,
-            grab_focus: false,
-        })
+        let mut term = Self::spawn(rows, cols, cmd, colors)?;
+        term.profile_name = profile.name.clone();
+        if let Some(size) = profile.font_size {
+            term.font_size = size.clamp(6.0, 48.0);
+        }
+        if profile.font.is_some() {
+            term.font_family = egui::FontFamily::Name(profile.font_family_name().into());
+        }
+        Ok(term)
     }
 
     pub fn with_command(
@@ -75,14 +94,6 @@ impl Terminal {
         args: &[&str],
         extra_env: &[(&str, &str)],
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let pty_system = NativePtySystem::default();
-        let pair = pty_system.openpty(PtySize {
-            rows,
-            cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        })?;
-
         let mut cmd = CommandBuilder::new(program);
         for arg in args {
             cmd.arg(arg);
@@ -100,15 +111,44 @@ impl Terminal {
             cmd.env(k, v);
         }
 
+        let mut term = Self::spawn(rows, cols, cmd, ColorScheme::default())?;
+        term.profile_name = program.to_string();
+        Ok(term)
+    }
+
+    fn spawn(
+        rows: u16,
+        cols: u16,
+        cmd: CommandBuilder,
+        colors: ColorScheme,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let pty_system = NativePtySystem::default();
+        let pair = pty_system.openpty(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+
         let child = pair.slave.spawn_command(cmd)?;
         drop(pair.slave);
 
         let reader = pair.master.try_clone_reader()?;
         let writer = pair.master.take_writer()?;
 
+        let writer: Arc<Mutex<Box<dyn Write + Send>>> = Arc::new(Mutex::new(writer));
+        let writer_clone = writer.clone();
+
         let parser = Arc::new(Mutex::new(vt100::Parser::new(rows, cols, 1000)));
         let parser_clone = parser.clone();
 
+        let colors = Arc::new(Mutex::new(colors));
+        let clipboard = Arc::new(Mutex::new(Vec::new()));
+        let mut responder = TermResponder::new(colors.clone(), clipboard.clone());
+        let output = Arc::new(Mutex::new(OutputLog::default()));
+        let output_clone = output.clone();
+
+        // Background thread to read PTY output
         std::thread::spawn(move || {
             let mut reader = reader;
             let mut buf = [0u8; 8192];
@@ -116,8 +156,23 @@ impl Terminal {
                 match reader.read(&mut buf) {
                     Ok(0) => break,
                     Ok(n) => {
-                        if let Ok(mut p) = parser_clone.lock() {
-                            p.process(&buf[..n]);
+                        if let Ok(mut log) = output_clone.lock() {
+                            log.bytes.extend_from_slice(&buf[..n]);
+                            if log.bytes.len() > OUTPUT_LOG_LIMIT {
+                                let excess = log.bytes.len() - OUTPUT_LOG_LIMIT;
+                                log.bytes.drain(..excess);
+                            }
+                            log.version += 1;
+                        }
+                        let reply = match parser_clone.lock() {
+                            Ok(mut p) => responder.process(&buf[..n], &mut p),
+                            Err(_) => Vec::new(),
+                        };
+                        if !reply.is_empty() {
+                            if let Ok(mut w) = writer_clone.lock() {
+                                let _ = w.write_all(&reply);
+                                let _ = w.flush();
+                            }
                         }
                     }
                     Err(_) => break,
@@ -129,12 +184,22 @@ impl Terminal {
 
         Ok(Self {
             parser,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
             _child: child,
             rows,
             cols,
             id,
             grab_focus: false,
+            ime_preedit: String::new(),
+            profile_name: String::new(),
+            colors,
+            font_size: DEFAULT_FONT_SIZE,
+            font_family: egui::FontFamily::Monospace,
+            clipboard,
+            clipboard_read: ClipboardRead::default(),
+            pending_clipboard_read: None,
+            output,
+            output_seen: 0,
         })
     }
 
@@ -176,20 +241,112 @@ impl Terminal {
     }
 
     pub fn write_input(&self, data: &[u8]) {
+        // Enter at the shell starts a new command, whose output replaces the last
+        // one's. Keys for full-screen programs aren't commands.
+        if let (Ok(mut log), Ok(parser)) = (self.output.lock(), self.parser.lock()) {
+            let screen = parser.screen();
+            if !screen.alternate_screen() {
+                let cursor = screen.cursor_position();
+                let start = *log.prompt_end.get_or_insert(cursor);
+                if data.contains(&b'\r') {
+                    let from = if start.0 == cursor.0 { start.1 } else { 0 };
+                    log.command = screen.contents_between(cursor.0, from, cursor.0, self.cols).trim().to_string();
+                    log.prompt_end = None;
+                    log.bytes.clear();
+                    log.version += 1;
+                }
+            }
+        }
         if let Ok(mut w) = self.writer.lock() {
             let _ = w.write_all(data);
             let _ = w.flush();
         }
     }
 
+    /// The current command line and its output, if the output changed since last asked
+    pub fn take_new_output(&mut self) -> Option<(String, String)> {
+        let log = self.output.lock().ok()?;
+        if log.version == self.output_seen {
+            return None;
+        }
+        self.output_seen = log.version;
+        Some((log.command.clone(), String::from_utf8_lossy(&log.bytes).into_owned()))
+    }
+
+    /// Carry out OSC 52 requests: copies go straight to the system clipboard,
+    /// reads follow the `clipboard_read` policy
+    fn handle_clipboard_requests(&mut self, ctx: &egui::Context) {
+        let requests = match self.clipboard.lock() {
+            Ok(mut queue) => std::mem::take(&mut *queue),
+            Err(_) => return,
+        };
+        for request in requests {
+            match request {
+                ClipboardRequest::Copy(text) => ctx.copy_text(text),
+                ClipboardRequest::Paste { selection, bell_terminated } => match self.clipboard_read {
+                    ClipboardRead::Allow => self.send_clipboard(&selection, bell_terminated),
+                    ClipboardRead::Ask => self.pending_clipboard_read = Some((selection, bell_terminated)),
+                    ClipboardRead::Deny => {}
+                },
+            }
+        }
+    }
+
+    fn send_clipboard(&self, selection: &str, bell_terminated: bool) {
+        let text = arboard::Clipboard::new()
+            .and_then(|mut c| c.get_text())
+            .unwrap_or_default();
+        self.write_input(&clipboard_reply(selection, &text, bell_terminated));
+    }
+
+    /// Bar at the top of the terminal asking whether a program may read the clipboard
+    fn render_clipboard_prompt(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        let Some((selection, bell_terminated)) = self.pending_clipboard_read.clone() else {
+            return;
+        };
+        let bar = Rect::from_min_size(rect.left_top(), egui::vec2(rect.width(), 30.0));
+        ui.painter().rect_filled(bar, 0.0, crate::theme::BG_ELEVATED);
+        ui.painter().line_segment(
+            [bar.left_bottom(), bar.right_bottom()],
+            egui::Stroke::new(1.0, crate::theme::BORDER),
+        );
+
+        let mut child_ui = ui.new_child(
+            egui::UiBuilder::new()
+                .max_rect(bar.shrink2(egui::vec2(8.0, 4.0)))
+                .layout(egui::Layout::left_to_right(egui::Align::Center)),
+        );
+        child_ui.label(
+            egui::RichText::new("A program in this terminal wants to read the clipboard")
+                .color(crate::theme::TEXT_PRIMARY)
+                .size(12.0),
+        );
+        if child_ui.button("Allow").clicked() {
+            self.send_clipboard(&selection, bell_terminated);
+            self.pending_clipboard_read = None;
+        }
+        if child_ui.button("Deny").clicked() {
+            self.pending_clipboard_read = None;
+        }
+    }
+
     pub fn render(&mut self, ui: &mut egui::Ui, rect: Rect) {
+        self.handle_clipboard_requests(ui.ctx());
+
+        let colors = self.colors.lock().map(|c| c.clone()).unwrap_or_default();
+
         // Background
-        ui.painter()
-            .rect_filled(rect, 0.0, crate::theme::TERMINAL_BG);
+        ui.painter().rect_filled(rect, 0.0, colors.background);
 
-        let font = FontId::monospace(14.0);
-        let char_width = 8.4_f32;
-        let line_height = 17.0_f32;
+        // Fall back to the built-in monospace font if the profile font failed to load
+        let family = if ui.fonts(|f| f.families().contains(&self.font_family)) {
+            self.font_family.clone()
+        } else {
+            egui::FontFamily::Monospace
+        };
+        let font = FontId::new(self.font_size, family);
+        let char_width = ui.fonts(|f| f.glyph_width(&font, 'M'));
+        let line_height = (self.font_size * 17.0 / 14.0).round();
 
         // Calculate visible size and resize if needed
         let visible_cols = ((rect.width() - 4.0) / char_width).floor().max(1.0) as u16;
@@ -206,6 +363,10 @@ impl Terminal {
 
         let has_focus = ui.memory(|mem| mem.has_focus(unique_id));
 
+        if response.lost_focus() {
+            self.ime_preedit.clear();
+        }
+
         if has_focus {
             ui.input(|i| {
                 for event in &i.events {
@@ -213,6 +374,22 @@ impl Terminal {
                         egui::Event::Text(text) => {
                             self.write_input(text.as_bytes());
                         }
+                        egui::Event::Ime(ime) => match ime {
+                            egui::ImeEvent::Enabled | egui::ImeEvent::Disabled => {
+                                self.ime_preedit.clear();
+                            }
+                            egui::ImeEvent::Preedit(text) => {
+                                self.ime_preedit = text.clone();
+                            }
+                            egui::ImeEvent::Commit(text) => {
+                                self.ime_preedit.clear();
+                                if !text.is_empty() {
+                                    self.write_input(text.as_bytes());
+                                }
+                            }
+                        },
+                        // While composing, Enter/Backspace/arrows belong to the IME
+                        egui::Event::Key { .. } if !self.ime_preedit.is_empty() => {}
                         egui::Event::Key {
                             key,
                             pressed: true,
@@ -238,16 +415,29 @@ impl Terminal {
             for row in 0..visible_rows {
                 for col in 0..visible_cols {
                     if let Some(cell) = screen.cell(row, col) {
-                        let ch = cell.contents();
-                        if ch.is_empty() || ch == " " {
-                            continue;
-                        }
-
-                        let fg = vt100_color_to_egui(cell.fgcolor(), true);
                         let pos = egui::pos2(
                             rect.left() + 2.0 + col as f32 * char_width,
                             rect.top() + 2.0 + row as f32 * line_height,
                         );
+
+                        let mut fg = vt100_color_to_egui(cell.fgcolor(), colors.foreground, &colors);
+                        let mut bg = vt100_color_to_egui(cell.bgcolor(), colors.background, &colors);
+                        if cell.inverse() {
+                            std::mem::swap(&mut fg, &mut bg);
+                        }
+                        if bg != colors.background {
+                            let width = if cell.is_wide() { 2.0 } else { 1.0 };
+                            ui.painter().rect_filled(
+                                Rect::from_min_size(pos, egui::vec2(char_width * width, line_height)),
+                                0.0,
+                                bg,
+                            );
+                        }
+
+                        let ch = cell.contents();
+                        if ch.is_empty() || ch == " " {
+                            continue;
+                        }
                         ui.painter().text(
                             pos,
                             egui::Align2::LEFT_TOP,
@@ -269,14 +459,36 @@ impl Terminal {
                     ),
                     egui::vec2(char_width, line_height),
                 );
-                ui.painter().rect_filled(
+                ui.painter().rect_filled(cursor_rect, 0.0, colors.cursor);
+                // An opaque block cursor hides the glyph, so redraw it in the background colour
+                if colors.cursor.is_opaque() {
+                    if let Some(cell) = screen.cell(cursor_row, cursor_col) {
+                        let ch = cell.contents();
+                        if !ch.is_empty() {
+                            ui.painter().text(
+                                cursor_rect.min,
+                                egui::Align2::LEFT_TOP,
+                                &ch,
+                                font.clone(),
+                                colors.background,
+                            );
+                        }
+                    }
+                }
+
+                let ime_cursor_rect = crate::ime::paint_ime_preedit(
+                    ui.painter(),
+                    &self.ime_preedit,
                     cursor_rect,
-                    0.0,
-                    Color32::from_rgba_premultiplied(200, 200, 200, 128),
+                    &font,
+                    colors.background,
                 );
+                crate::ime::set_ime_output(ui, rect, ime_cursor_rect);
             }
         }
 
+        self.render_clipboard_prompt(ui, rect);
+
         // Focus indicator border
         if has_focus {
             ui.painter().rect_stroke(
@@ -292,46 +504,14 @@ impl Terminal {
     }
 }
 
-fn vt100_color_to_egui(color: vt100::Color, is_fg: bool) -> Color32 {
+fn vt100_color_to_egui(color: vt100::Color, default: Color32, colors: &ColorScheme) -> Color32 {
     match color {
-        vt100::Color::Default => {
-            if is_fg {
-                Color32::from_rgb(36, 36, 36)
-            } else {
-                crate::theme::TERMINAL_BG
-            }
-        }
-        vt100::Color::Idx(i) => ansi_256_to_color32(i),
+        vt100::Color::Default => default,
+        vt100::Color::Idx(i) => colors.palette[i as usize],
         vt100::Color::Rgb(r, g, b) => Color32::from_rgb(r, g, b),
     }
 }
 
-fn ansi_256_to_color32(idx: u8) -> Color32 {
-    const BASIC: [(u8, u8, u8); 16] = [
-        (0, 0, 0),       (205, 49, 49),    (13, 188, 121),   (229, 229, 16),
-        (36, 114, 200),   (188, 63, 188),   (17, 168, 205),   (229, 229, 229),
-        (102, 102, 102),  (241, 76, 76),    (35, 209, 139),   (245, 245, 67),
-        (59, 142, 234),   (214, 112, 214),  (41, 184, 219),   (255, 255, 255),
-    ];
-
-    if idx < 16 {
-        let (r, g, b) = BASIC[idx as usize];
-        return Color32::from_rgb(r, g, b);
-    }
-
-    if idx < 232 {
-        let idx = idx - 16;
-        let r = (idx / 36) % 6;
-        let g = (idx / 6) % 6;
-        let b = idx % 6;
-        let to_val = |v: u8| if v == 0 { 0 } else { 55 + 40 * v };
-        return Color32::from_rgb(to_val(r), to_val(g), to_val(b));
-    }
-
-    let v = 8 + 10 * (idx - 232);
-    Color32::from_rgb(v, v, v)
-}
-
 fn key_to_escape(key: egui::Key, modifiers: &egui::Modifiers) -> Vec<u8> {
     if modifiers.ctrl {
         match key {
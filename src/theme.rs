@@ -10,6 +10,9 @@ pub const BORDER: Color32 = Color32::from_rgb(218, 218, 218);
 pub const TEXT_PRIMARY: Color32 = Color32::from_rgb(36, 36, 36);
 pub const TEXT_SECONDARY: Color32 = Color32::from_rgb(120, 120, 120);
 pub const ACCENT: Color32 = Color32::from_rgb(0, 122, 255);
+pub const ERROR: Color32 = Color32::from_rgb(215, 58, 73);
+pub const WARNING: Color32 = Color32::from_rgb(191, 135, 0);
+pub const SUCCESS: Color32 = Color32::from_rgb(34, 134, 58);
 pub const TAB_ACTIVE: Color32 = Color32::from_rgb(255, 255, 255);
 pub const TAB_INACTIVE: Color32 = Color32::from_rgb(238, 238, 238);
 pub const TERMINAL_BG: Color32 = Color32::from_rgb(255, 255, 255);
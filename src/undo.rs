@@ -0,0 +1,388 @@
+use crate::buffer::TextBuffer;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Edits further apart than this start a new undo group
+const GROUP_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Oldest groups are dropped beyond this many
+const MAX_GROUPS: usize = 1000;
+
+/// Replacing `deleted` at byte `start` with `inserted`; invertible by swapping the two
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EditOp {
+    pub start: usize,
+    pub deleted: String,
+    pub inserted: String,
+}
+
+/// A cursor and its selection anchor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    pub cursor: usize,
+    pub anchor: Option<usize>,
+}
+
+impl Selection {
+    pub fn caret(cursor: usize) -> Self {
+        Self { cursor, anchor: None }
+    }
+
+    /// Selected byte range (empty for a bare cursor)
+    pub fn range(&self) -> Range<usize> {
+        let anchor = self.anchor.unwrap_or(self.cursor);
+        anchor.min(self.cursor)..anchor.max(self.cursor)
+    }
+}
+
+/// How an edit was made, which decides whether it merges with the previous one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditKind {
+    /// Typing a single character at each cursor (possibly over a selection)
+    Insert,
+    /// Backspace / Delete of a single character at each cursor
+    Delete,
+    /// Anything else (paste, cut, newline, replacing a selection) stands alone
+    Other,
+}
+
+/// Edits undone and redone together. Ops are in the order they were applied, each
+/// in the coordinates of the buffer at that moment.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UndoGroup {
+    pub ops: Vec<EditOp>,
+    /// Cursors before the first edit, primary first; restored by undo
+    pub before: Vec<Selection>,
+    /// Cursors after the last edit; restored by redo
+    pub after: Vec<Selection>,
+}
+
+#[derive(Default)]
+pub struct UndoHistory {
+    undo: Vec<UndoGroup>,
+    redo: Vec<UndoGroup>,
+    /// Kind and time of the last recorded edit, while its group is still open
+    open_group: Option<(EditKind, Instant)>,
+    /// `undo.len()` when the file was last saved, if that state is still reachable
+    saved_depth: Option<usize>,
+    /// While set, every edit joins the group at this depth, so a whole Vim change
+    /// undoes at once
+    held: Option<usize>,
+}
+
+impl UndoHistory {
+    pub fn new() -> Self {
+        Self { saved_depth: Some(0), ..Default::default() }
+    }
+
+    /// Record one editing step (one op per cursor) that was just applied, merging it
+    /// into the open group when it continues the same run of typing or deleting
+    pub fn record(&mut self, ops: Vec<EditOp>, kind: EditKind, before: Vec<Selection>, after: Vec<Selection>) {
+        let now = Instant::now();
+        let held = self.held.is_some_and(|depth| self.undo.len() > depth);
+        let merge = held || match (self.open_group, self.undo.last()) {
+            (Some((last_kind, at)), Some(group))
+                if last_kind == kind && kind != EditKind::Other && now - at < GROUP_TIMEOUT =>
+            {
+                // The cursors must be where the last step left them
+                let continues = group.after == before;
+                // A space after a word starts a new group
+                let word_break = kind == EditKind::Insert
+                    && ops.iter().any(|op| op.inserted.starts_with(char::is_whitespace))
+                    && group.ops.last().is_some_and(|op| !op.inserted.ends_with(char::is_whitespace));
+                continues && !word_break
+            }
+            _ => false,
+        };
+
+        // A saved state that was only reachable by redo is gone once redo is cleared
+        if self.saved_depth.is_some_and(|d| d > self.undo.len()) {
+            self.saved_depth = None;
+        }
+        self.redo.clear();
+
+        if merge {
+            if let Some(group) = self.undo.last_mut() {
+                group.ops.extend(ops);
+                group.after = after;
+            }
+        } else {
+            self.undo.push(UndoGroup { ops, before, after });
+            if self.undo.len() > MAX_GROUPS {
+                self.undo.remove(0);
+                self.saved_depth = self.saved_depth.and_then(|d| d.checked_sub(1));
+                self.held = self.held.map(|d| d.saturating_sub(1));
+            }
+        }
+        self.open_group = Some((kind, now));
+    }
+
+    /// Join the edits from now until `end_group` into one group
+    pub fn begin_group(&mut self) {
+        self.held = Some(self.undo.len());
+    }
+
+    pub fn end_group(&mut self) {
+        self.held = None;
+        self.open_group = None;
+    }
+
+    /// Take the group to undo; the caller applies its ops inverted, last first
+    pub fn undo(&mut self) -> Option<UndoGroup> {
+        self.end_group();
+        let group = self.undo.pop()?;
+        self.redo.push(group.clone());
+        Some(group)
+    }
+
+    /// Take the group to redo; the caller reapplies its ops in order
+    pub fn redo(&mut self) -> Option<UndoGroup> {
+        self.end_group();
+        let group = self.redo.pop()?;
+        self.undo.push(group.clone());
+        Some(group)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.open_group = None;
+        self.saved_depth = Some(self.undo.len());
+    }
+
+    /// Whether the buffer is back to its last saved contents
+    pub fn at_saved(&self) -> bool {
+        self.saved_depth == Some(self.undo.len())
+    }
+}
+
+/// Undo history as stored on disk, tied to the file contents it ends at
+#[derive(Serialize, Deserialize)]
+struct SavedHistory {
+    content_hash: u64,
+    undo: Vec<UndoGroup>,
+    redo: Vec<UndoGroup>,
+}
+
+impl UndoHistory {
+    /// Load the history stored for `file`, if it was stored for the contents now open
+    pub fn load(file: &Path, content: &TextBuffer) -> Option<Self> {
+        let path = history_path(file)?;
+        let text = std::fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&text) {
+            Ok(saved) => Self::restore(saved, content),
+            Err(e) => {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// A stored history, unless the contents it ends at aren't `content`
+    fn restore(saved: SavedHistory, content: &TextBuffer) -> Option<Self> {
+        if saved.content_hash != content_hash(content) {
+            // The file changed outside the editor; the ops no longer line up
+            return None;
+        }
+        Some(Self {
+            saved_depth: Some(saved.undo.len()),
+            undo: saved.undo,
+            redo: saved.redo,
+            open_group: None,
+            held: None,
+        })
+    }
+
+    /// Store the history for `file`; call right after saving so the two match
+    pub fn store(&self, file: &Path, content: &TextBuffer) -> std::io::Result<()> {
+        let Some(path) = history_path(file) else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let saved = SavedHistory {
+            content_hash: content_hash(content),
+            undo: self.undo.clone(),
+            redo: self.redo.clone(),
+        };
+        let json = serde_json::to_string(&saved).map_err(std::io::Error::other)?;
+        std::fs::write(path, json)
+    }
+}
+
+/// `~/.aio-terminal/undo/<hash of the file's path>.json`, kept out of the project
+/// so it never shows up in version control
+fn history_path(file: &Path) -> Option<PathBuf> {
+    let file = file.canonicalize().ok()?;
+    let name = format!("{:016x}.json", fnv1a(FNV_OFFSET, file.to_string_lossy().as_bytes()));
+    dirs::home_dir().map(|h| h.join(".aio-terminal").join("undo").join(name))
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across builds
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn content_hash(content: &TextBuffer) -> u64 {
+    content
+        .chunks(0..content.len_bytes())
+        .fold(FNV_OFFSET, |hash, chunk| fnv1a(hash, chunk.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(start: usize, deleted: &str, inserted: &str) -> Vec<EditOp> {
+        vec![EditOp { start, deleted: deleted.to_string(), inserted: inserted.to_string() }]
+    }
+
+    fn at(cursor: usize) -> Vec<Selection> {
+        vec![Selection::caret(cursor)]
+    }
+
+    /// Type `text` one character at a time from `start`
+    fn type_text(history: &mut UndoHistory, start: usize, text: &str) {
+        for (i, c) in text.char_indices() {
+            history.record(op(start + i, "", &c.to_string()), EditKind::Insert, at(start + i), at(start + i + 1));
+        }
+    }
+
+    fn group_count(history: &mut UndoHistory) -> usize {
+        let mut count = 0;
+        while history.undo().is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn merges_runs_of_the_same_kind() {
+        let mut history = UndoHistory::new();
+        type_text(&mut history, 0, "abc");
+        assert_eq!(history.undo.len(), 1);
+        assert_eq!(history.undo[0].ops.len(), 3);
+        // Deleting after typing, and anything `Other`, start new groups
+        history.record(op(2, "c", ""), EditKind::Delete, at(3), at(2));
+        history.record(op(1, "b", ""), EditKind::Delete, at(2), at(1));
+        history.record(op(1, "", "xy"), EditKind::Other, at(1), at(3));
+        history.record(op(3, "", "zw"), EditKind::Other, at(3), at(5));
+        assert_eq!(history.undo.len(), 4);
+        // Typing somewhere the cursor wasn't left doesn't continue the group
+        type_text(&mut history, 5, "1");
+        type_text(&mut history, 0, "2");
+        assert_eq!(group_count(&mut history), 6);
+    }
+
+    #[test]
+    fn a_pause_starts_a_new_group() {
+        let mut history = UndoHistory::new();
+        type_text(&mut history, 0, "ab");
+        let (kind, _) = history.open_group.unwrap();
+        history.open_group = Instant::now().checked_sub(GROUP_TIMEOUT * 2).map(|then| (kind, then));
+        type_text(&mut history, 2, "cd");
+        assert_eq!(history.undo.len(), 2);
+        assert_eq!(history.undo().unwrap().ops.len(), 2);
+    }
+
+    #[test]
+    fn whitespace_after_a_word_breaks_the_group() {
+        let mut history = UndoHistory::new();
+        type_text(&mut history, 0, "one two");
+        // "one", then " two": the space goes with the word it starts
+        assert_eq!(history.undo.len(), 2);
+        let last: String = history.undo[1].ops.iter().map(|op| op.inserted.as_str()).collect();
+        assert_eq!(last, " two");
+        // Runs of spaces stay together
+        let mut history = UndoHistory::new();
+        type_text(&mut history, 0, "a   ");
+        assert_eq!(history.undo.len(), 2);
+    }
+
+    #[test]
+    fn held_groups_take_every_edit() {
+        let mut history = UndoHistory::new();
+        type_text(&mut history, 0, "x");
+        history.begin_group();
+        history.record(op(1, "", "\n"), EditKind::Other, at(1), at(2));
+        type_text(&mut history, 2, "a b");
+        history.record(op(0, "x", ""), EditKind::Delete, at(1), at(0));
+        history.end_group();
+        type_text(&mut history, 0, "y");
+        assert_eq!(history.undo.len(), 3);
+        assert_eq!(history.undo[1].ops.len(), 5);
+        assert_eq!(history.undo[1].before, at(1));
+        assert_eq!(history.undo[1].after, at(0));
+        // An empty hold records nothing
+        history.begin_group();
+        history.end_group();
+        assert_eq!(history.undo.len(), 3);
+    }
+
+    #[test]
+    fn saved_state_is_forgotten_when_redo_is_dropped() {
+        let mut history = UndoHistory::new();
+        history.record(op(0, "", "a"), EditKind::Other, at(0), at(1));
+        history.mark_saved();
+        assert!(history.at_saved());
+        history.undo();
+        assert!(!history.at_saved());
+        history.redo();
+        assert!(history.at_saved());
+        // Undo past the save, then edit: the saved state can't come back
+        history.undo();
+        history.record(op(0, "", "b"), EditKind::Other, at(0), at(1));
+        assert_eq!(history.saved_depth, None);
+        history.undo();
+        assert!(!history.at_saved());
+    }
+
+    #[test]
+    fn eviction_shifts_saved_depth_and_hold() {
+        let mut history = UndoHistory::new();
+        for i in 0..MAX_GROUPS {
+            history.record(op(i, "", "x"), EditKind::Other, at(i), at(i + 1));
+        }
+        history.mark_saved();
+        history.begin_group();
+        assert_eq!((history.saved_depth, history.held), (Some(MAX_GROUPS), Some(MAX_GROUPS)));
+        history.record(op(MAX_GROUPS, "", "y"), EditKind::Other, at(MAX_GROUPS), at(MAX_GROUPS + 1));
+        assert_eq!(history.undo.len(), MAX_GROUPS);
+        assert_eq!((history.saved_depth, history.held), (Some(MAX_GROUPS - 1), Some(MAX_GROUPS - 1)));
+        // The hold still joins the next edit to the group it started
+        history.record(op(MAX_GROUPS + 1, "", "z"), EditKind::Other, at(MAX_GROUPS + 1), at(MAX_GROUPS + 2));
+        assert_eq!(history.undo.len(), MAX_GROUPS);
+        assert_eq!(history.undo().unwrap().ops.len(), 2);
+        assert!(history.at_saved());
+
+        // A save whose state was evicted is gone for good
+        let mut history = UndoHistory::new();
+        for i in 0..=MAX_GROUPS {
+            history.record(op(i, "", "x"), EditKind::Other, at(i), at(i + 1));
+        }
+        assert_eq!(history.saved_depth, None);
+    }
+
+    #[test]
+    fn restores_only_onto_matching_contents() {
+        let text = TextBuffer::from("ab");
+        let other = TextBuffer::from("ac");
+        let stored = || SavedHistory {
+            content_hash: content_hash(&text),
+            undo: vec![UndoGroup { ops: op(1, "", "b"), before: at(1), after: at(2) }],
+            redo: Vec::new(),
+        };
+        assert!(UndoHistory::restore(stored(), &other).is_none());
+        let mut history = UndoHistory::restore(stored(), &text).unwrap();
+        assert!(history.at_saved());
+        assert_eq!(history.undo().unwrap().ops[0].inserted, "b");
+        assert!(!history.at_saved());
+    }
+}
@@ -0,0 +1,1251 @@
+use crate::buffer::TextBuffer;
+use regex::Regex;
+use std::collections::HashMap;
+use std::iter::{Copied, Peekable};
+use std::ops::Range;
+
+/// Bytes either side of the cursor that word motions look through
+const WINDOW: usize = 20_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+    VisualLine,
+}
+
+impl Mode {
+    pub fn label(self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+            Mode::VisualLine => "VISUAL LINE",
+        }
+    }
+}
+
+/// A key press as the modal layer sees it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VimKey {
+    Char(char),
+    Ctrl(char),
+    Esc,
+    Enter,
+    Backspace,
+    Delete,
+    Tab,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    /// `w` / `W`; `true` for WORDs, which only break at whitespace
+    WordStart(bool),
+    /// `b` / `B`
+    WordBack(bool),
+    /// `e` / `E`
+    WordEnd(bool),
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /// `gg`; a count picks the line
+    FirstLine,
+    /// `G`; a count picks the line
+    LastLine,
+    /// `f`, `F`, `t` and `T`
+    Find { c: char, forward: bool, till: bool },
+    MatchingBracket,
+    ParagraphForward,
+    ParagraphBack,
+}
+
+impl Motion {
+    /// Whether an operator takes whole lines
+    pub fn linewise(self) -> bool {
+        matches!(self, Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine)
+    }
+
+    /// Whether an operator includes the character the motion lands on
+    pub fn inclusive(self) -> bool {
+        matches!(self, Motion::WordEnd(_) | Motion::Find { .. } | Motion::MatchingBracket)
+    }
+}
+
+/// `iw`, `a(`, `i"`, `ap`, ...
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Object {
+    Word(bool),
+    Pair(char, char),
+    Quote(char),
+    Paragraph,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+    Lowercase,
+    Uppercase,
+    SwapCase,
+}
+
+/// What an operator works on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Motion(Motion),
+    /// A text object; `true` for the "a" form
+    Object(Object, bool),
+    /// The operator doubled (`dd`, `>>`): whole lines
+    Lines,
+    /// The visual selection
+    Selection,
+}
+
+/// Where `i`, `a`, `I`, `A`, `o` and `O` start typing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertAt {
+    Cursor,
+    After,
+    LineStart,
+    LineEnd,
+    LineBelow,
+    LineAbove,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Move(Motion),
+    Operate(Operator, Target),
+    Insert(InsertAt),
+    Put { before: bool },
+    Replace(char),
+    Join,
+    ToggleCase,
+    Undo,
+    Redo,
+    Repeat,
+    /// `v` / `V`: enter that visual mode, or leave it if already there
+    Visual(Mode),
+    /// `o` in visual mode: move to the other end of the selection
+    SwapEnds,
+    /// A text object typed in visual mode, which selects it
+    Select(Object, bool),
+    /// `:`, `/` or `?`
+    CommandLine(char),
+    SearchNext { reverse: bool },
+    /// `*` / `#`: search for the word under the cursor
+    SearchWord { forward: bool },
+    Escape,
+}
+
+/// A complete command, with its count and register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Command {
+    pub action: Action,
+    pub count: Option<usize>,
+    pub register: Option<char>,
+}
+
+impl Command {
+    pub fn count(&self) -> usize {
+        self.count.unwrap_or(1)
+    }
+
+    /// Whether `.` repeats it; changes made in visual mode aren't
+    fn is_change(&self, visual: bool) -> bool {
+        !visual
+            && match self.action {
+                Action::Operate(op, _) => op != Operator::Yank,
+                Action::Insert(_) | Action::Put { .. } | Action::Replace(_) | Action::Join | Action::ToggleCase => true,
+                _ => false,
+            }
+    }
+}
+
+/// Text yanked or deleted into a register
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    /// Put as whole lines rather than inside one
+    pub linewise: bool,
+}
+
+/// State of an editor's Vim mode
+pub struct Vim {
+    pub mode: Mode,
+    /// Keys of the command being typed
+    pending: Vec<VimKey>,
+    registers: HashMap<char, Register>,
+    /// Text of the command line while it's open, starting with `:`, `/` or `?`
+    pub command_line: Option<String>,
+    /// Where the visual selection started
+    pub visual_anchor: usize,
+    /// Lines of the last visual selection, for `:'<,'>`
+    pub visual_lines: Option<(usize, usize)>,
+    /// Column `j` and `k` aim for, kept across shorter lines
+    pub want_col: Option<usize>,
+    /// Last `f`, `F`, `t` or `T`, for `;` and `,`
+    last_find: Option<Motion>,
+    /// Last search pattern, and whether it searched forward
+    pub last_search: Option<(String, bool)>,
+    /// Keys of the last change without its count, and the count, replayed by `.`
+    last_change: (Vec<VimKey>, Option<usize>),
+    /// Same for the change being made, until it's finished
+    recording: Option<(Vec<VimKey>, Option<usize>)>,
+    /// Set while `.` replays keys, so they aren't recorded again
+    pub replaying: bool,
+    /// Error or information shown in the status bar until the next key
+    pub message: Option<String>,
+}
+
+impl Default for Vim {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vim {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Normal,
+            pending: Vec::new(),
+            registers: HashMap::new(),
+            command_line: None,
+            visual_anchor: 0,
+            visual_lines: None,
+            want_col: None,
+            last_find: None,
+            last_search: None,
+            last_change: (Vec::new(), None),
+            recording: None,
+            replaying: false,
+            message: None,
+        }
+    }
+
+    /// Keys typed so far of an unfinished command
+    pub fn pending(&self) -> String {
+        self.pending
+            .iter()
+            .map(|k| match k {
+                VimKey::Char(c) => c.to_string(),
+                VimKey::Ctrl(c) => format!("^{}", c.to_ascii_uppercase()),
+                _ => String::new(),
+            })
+            .collect()
+    }
+
+    /// Take a key in normal or visual mode; returns the command once it's complete
+    pub fn key(&mut self, key: VimKey) -> Option<Command> {
+        self.pending.push(key);
+        let visual = matches!(self.mode, Mode::Visual | Mode::VisualLine);
+        match parse(&self.pending, visual, self.last_find) {
+            Parse::More => None,
+            Parse::Invalid => {
+                self.pending.clear();
+                None
+            }
+            Parse::Done(command) => {
+                let keys = std::mem::take(&mut self.pending);
+                // `;` and `,` repeat the find without replacing it (unless it's `f;`)
+                let repeated = match keys.as_slice() {
+                    [.., VimKey::Char('f' | 'F' | 't' | 'T'), _] => false,
+                    [.., last] => matches!(last, VimKey::Char(';' | ',')),
+                    [] => false,
+                };
+                if let Action::Move(m @ Motion::Find { .. }) | Action::Operate(_, Target::Motion(m @ Motion::Find { .. })) = command.action {
+                    if !repeated {
+                        self.last_find = Some(m);
+                    }
+                }
+                if command.is_change(visual) && !self.replaying {
+                    self.recording = Some((without_counts(&keys), command.count));
+                }
+                Some(command)
+            }
+        }
+    }
+
+    /// Note a key typed in insert mode as part of the change being made
+    pub fn record(&mut self, key: VimKey) {
+        if let Some((keys, _)) = &mut self.recording {
+            keys.push(key);
+        }
+    }
+
+    /// The change being made is finished; it's what `.` repeats from now on
+    pub fn end_change(&mut self) {
+        if let Some(keys) = self.recording.take() {
+            self.last_change = keys;
+        }
+    }
+
+    /// Keys to replay for `.`; a count given to `.` replaces the change's own
+    pub fn last_change(&self, count: Option<usize>) -> Vec<VimKey> {
+        let (keys, recorded) = &self.last_change;
+        let mut keys = keys.clone();
+        if let Some(count) = count.or(*recorded) {
+            let at = if keys.first() == Some(&VimKey::Char('"')) { 2.min(keys.len()) } else { 0 };
+            keys.splice(at..at, count.to_string().chars().map(VimKey::Char));
+        }
+        keys
+    }
+
+    /// Put text in a register: a named one (appending for capitals) or the clipboard,
+    /// and the unnamed one, which `p` uses by default. Yanks also go to `"0`.
+    pub fn store(&mut self, name: Option<char>, register: Register, yank: bool) {
+        let register = match name {
+            Some('_') => return,
+            Some(c) if c.is_ascii_uppercase() => {
+                let entry = self.registers.entry(c.to_ascii_lowercase()).or_default();
+                entry.text.push_str(&register.text);
+                entry.linewise |= register.linewise;
+                entry.clone()
+            }
+            Some('+' | '*') => {
+                if let Err(e) = arboard::Clipboard::new().and_then(|mut c| c.set_text(register.text.clone())) {
+                    eprintln!("Failed to copy to the clipboard: {}", e);
+                }
+                register
+            }
+            Some(c) if c.is_ascii_lowercase() => {
+                self.registers.insert(c, register.clone());
+                register
+            }
+            _ => register,
+        };
+        if yank {
+            self.registers.insert('0', register.clone());
+        }
+        self.registers.insert('"', register);
+    }
+
+    /// Contents of a register, the unnamed one by default
+    pub fn register(&self, name: Option<char>) -> Option<Register> {
+        match name.unwrap_or('"') {
+            '+' | '*' => {
+                let text = arboard::Clipboard::new().and_then(|mut c| c.get_text()).ok()?;
+                Some(Register { linewise: text.ends_with('\n'), text })
+            }
+            c => self.registers.get(&c.to_ascii_lowercase()).cloned(),
+        }
+    }
+}
+
+enum Parse<T> {
+    Done(T),
+    /// The keys so far could start a command
+    More,
+    Invalid,
+}
+
+type Keys<'a> = Peekable<Copied<std::slice::Iter<'a, VimKey>>>;
+
+/// Read a command from the keys typed so far
+fn parse(keys: &[VimKey], visual: bool, last_find: Option<Motion>) -> Parse<Command> {
+    let mut keys: Keys = keys.iter().copied().peekable();
+    let mut register = None;
+    if keys.peek() == Some(&VimKey::Char('"')) {
+        keys.next();
+        match keys.next() {
+            None => return Parse::More,
+            Some(VimKey::Char(c)) if c.is_ascii_alphanumeric() || matches!(c, '"' | '+' | '*' | '_') => register = Some(c),
+            Some(_) => return Parse::Invalid,
+        }
+    }
+    let count = parse_count(&mut keys);
+    let Some(key) = keys.next() else { return Parse::More };
+    let done = |action, count| Parse::Done(Command { action, count, register });
+
+    let c = match key {
+        VimKey::Char(c) => c,
+        VimKey::Esc => return done(Action::Escape, count),
+        VimKey::Ctrl('r') => return done(Action::Redo, count),
+        key => {
+            return match parse_motion(key, &mut keys, last_find) {
+                Parse::Done(motion) => done(Action::Move(motion), count),
+                Parse::More => Parse::More,
+                Parse::Invalid => Parse::Invalid,
+            }
+        }
+    };
+
+    if visual {
+        let op = match c {
+            'd' | 'x' => Some(Operator::Delete),
+            'c' | 's' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            '>' => Some(Operator::Indent),
+            '<' => Some(Operator::Outdent),
+            'u' => Some(Operator::Lowercase),
+            'U' => Some(Operator::Uppercase),
+            '~' => Some(Operator::SwapCase),
+            _ => None,
+        };
+        if let Some(op) = op {
+            return done(Action::Operate(op, Target::Selection), count);
+        }
+        match c {
+            'o' => return done(Action::SwapEnds, count),
+            'i' | 'a' => {
+                return match keys.next() {
+                    None => Parse::More,
+                    Some(key) => match parse_object(key) {
+                        Some(object) => done(Action::Select(object, c == 'a'), count),
+                        None => Parse::Invalid,
+                    },
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Operators, with the key that doubles them to work on lines
+    let op = match c {
+        'd' => Some((Operator::Delete, 'd')),
+        'c' => Some((Operator::Change, 'c')),
+        'y' => Some((Operator::Yank, 'y')),
+        '>' => Some((Operator::Indent, '>')),
+        '<' => Some((Operator::Outdent, '<')),
+        'g' => match keys.next() {
+            None => return Parse::More,
+            Some(VimKey::Char('g')) => return done(Action::Move(Motion::FirstLine), count),
+            Some(VimKey::Char('u')) => Some((Operator::Lowercase, 'u')),
+            Some(VimKey::Char('U')) => Some((Operator::Uppercase, 'U')),
+            Some(VimKey::Char('~')) => Some((Operator::SwapCase, '~')),
+            Some(_) => return Parse::Invalid,
+        },
+        _ => None,
+    };
+    if let Some((op, double)) = op {
+        let motion_count = parse_count(&mut keys);
+        let count = match (count, motion_count) {
+            (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+            (a, b) => a.or(b),
+        };
+        let target = match keys.next() {
+            None => return Parse::More,
+            Some(VimKey::Char(d)) if d == double => Target::Lines,
+            Some(VimKey::Char(kind @ ('i' | 'a'))) => match keys.next() {
+                None => return Parse::More,
+                Some(key) => match parse_object(key) {
+                    Some(object) => Target::Object(object, kind == 'a'),
+                    None => return Parse::Invalid,
+                },
+            },
+            Some(key) => match parse_motion(key, &mut keys, last_find) {
+                Parse::Done(motion) => Target::Motion(motion),
+                Parse::More => return Parse::More,
+                Parse::Invalid => return Parse::Invalid,
+            },
+        };
+        return done(Action::Operate(op, target), count);
+    }
+
+    let action = match c {
+        'x' => Action::Operate(Operator::Delete, Target::Motion(Motion::Right)),
+        'X' => Action::Operate(Operator::Delete, Target::Motion(Motion::Left)),
+        's' => Action::Operate(Operator::Change, Target::Motion(Motion::Right)),
+        'S' => Action::Operate(Operator::Change, Target::Lines),
+        'C' => Action::Operate(Operator::Change, Target::Motion(Motion::LineEnd)),
+        'D' => Action::Operate(Operator::Delete, Target::Motion(Motion::LineEnd)),
+        'Y' => Action::Operate(Operator::Yank, Target::Lines),
+        'p' => Action::Put { before: false },
+        'P' => Action::Put { before: true },
+        'r' => match keys.next() {
+            None => return Parse::More,
+            Some(VimKey::Char(r)) => Action::Replace(r),
+            Some(_) => return Parse::Invalid,
+        },
+        'J' => Action::Join,
+        '~' => Action::ToggleCase,
+        'u' => Action::Undo,
+        '.' => Action::Repeat,
+        'i' => Action::Insert(InsertAt::Cursor),
+        'a' => Action::Insert(InsertAt::After),
+        'I' => Action::Insert(InsertAt::LineStart),
+        'A' => Action::Insert(InsertAt::LineEnd),
+        'o' => Action::Insert(InsertAt::LineBelow),
+        'O' => Action::Insert(InsertAt::LineAbove),
+        'v' => Action::Visual(Mode::Visual),
+        'V' => Action::Visual(Mode::VisualLine),
+        ':' | '/' | '?' => Action::CommandLine(c),
+        'n' => Action::SearchNext { reverse: false },
+        'N' => Action::SearchNext { reverse: true },
+        '*' => Action::SearchWord { forward: true },
+        '#' => Action::SearchWord { forward: false },
+        _ => match parse_motion(key, &mut keys, last_find) {
+            Parse::Done(motion) => Action::Move(motion),
+            Parse::More => return Parse::More,
+            Parse::Invalid => return Parse::Invalid,
+        },
+    };
+    done(action, count)
+}
+
+/// A count: digits not starting with 0, which is a motion
+/// A command's keys with its counts (before it and after an operator) taken out
+fn without_counts(keys: &[VimKey]) -> Vec<VimKey> {
+    let mut keys: Keys = keys.iter().copied().peekable();
+    let mut out = Vec::new();
+    if keys.peek() == Some(&VimKey::Char('"')) {
+        out.extend(keys.next());
+        out.extend(keys.next());
+    }
+    parse_count(&mut keys);
+    match keys.next() {
+        Some(key @ VimKey::Char('d' | 'c' | 'y' | '>' | '<')) => {
+            out.push(key);
+            parse_count(&mut keys);
+        }
+        Some(key @ VimKey::Char('g')) => {
+            out.push(key);
+            if let Some(key @ VimKey::Char('u' | 'U' | '~')) = keys.peek().copied() {
+                out.push(key);
+                keys.next();
+                parse_count(&mut keys);
+            }
+        }
+        key => out.extend(key),
+    }
+    out.extend(keys);
+    out
+}
+
+fn parse_count(keys: &mut Keys) -> Option<usize> {
+    let mut count: Option<usize> = None;
+    while let Some(&VimKey::Char(c)) = keys.peek() {
+        match c.to_digit(10) {
+            Some(d) if count.is_some() || d > 0 => {
+                count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(d as usize));
+                keys.next();
+            }
+            _ => break,
+        }
+    }
+    count
+}
+
+fn parse_motion(key: VimKey, keys: &mut Keys, last_find: Option<Motion>) -> Parse<Motion> {
+    let motion = match key {
+        VimKey::Left | VimKey::Backspace | VimKey::Char('h') => Motion::Left,
+        VimKey::Right | VimKey::Char('l' | ' ') => Motion::Right,
+        VimKey::Up | VimKey::Char('k') => Motion::Up,
+        VimKey::Down | VimKey::Enter | VimKey::Char('j') => Motion::Down,
+        VimKey::Char('w') => Motion::WordStart(false),
+        VimKey::Char('W') => Motion::WordStart(true),
+        VimKey::Char('b') => Motion::WordBack(false),
+        VimKey::Char('B') => Motion::WordBack(true),
+        VimKey::Char('e') => Motion::WordEnd(false),
+        VimKey::Char('E') => Motion::WordEnd(true),
+        VimKey::Char('0') => Motion::LineStart,
+        VimKey::Char('^') => Motion::FirstNonBlank,
+        VimKey::Char('$') => Motion::LineEnd,
+        VimKey::Char('G') => Motion::LastLine,
+        VimKey::Char('%') => Motion::MatchingBracket,
+        VimKey::Char('}') => Motion::ParagraphForward,
+        VimKey::Char('{') => Motion::ParagraphBack,
+        VimKey::Char('g') => match keys.next() {
+            None => return Parse::More,
+            Some(VimKey::Char('g')) => Motion::FirstLine,
+            Some(_) => return Parse::Invalid,
+        },
+        VimKey::Char(f @ ('f' | 'F' | 't' | 'T')) => match keys.next() {
+            None => return Parse::More,
+            Some(VimKey::Char(c)) => Motion::Find { c, forward: f.is_lowercase(), till: matches!(f, 't' | 'T') },
+            Some(_) => return Parse::Invalid,
+        },
+        VimKey::Char(repeat @ (';' | ',')) => match last_find {
+            Some(Motion::Find { c, forward, till }) => Motion::Find { c, forward: forward == (repeat == ';'), till },
+            _ => return Parse::Invalid,
+        },
+        _ => return Parse::Invalid,
+    };
+    Parse::Done(motion)
+}
+
+fn parse_object(key: VimKey) -> Option<Object> {
+    let VimKey::Char(c) = key else { return None };
+    Some(match c {
+        'w' => Object::Word(false),
+        'W' => Object::Word(true),
+        '(' | ')' | 'b' => Object::Pair('(', ')'),
+        '{' | '}' | 'B' => Object::Pair('{', '}'),
+        '[' | ']' => Object::Pair('[', ']'),
+        '<' | '>' => Object::Pair('<', '>'),
+        '"' | '\'' | '`' => Object::Quote(c),
+        'p' => Object::Paragraph,
+        _ => return None,
+    })
+}
+
+/// 0 for whitespace, 1 for punctuation, 2 for word characters; WORDs count
+/// punctuation as word characters
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        2
+    } else {
+        1
+    }
+}
+
+/// Characters near `pos`, with their offsets
+fn chars_around(text: &TextBuffer, pos: usize) -> Vec<(usize, char)> {
+    let start = match pos.saturating_sub(WINDOW) {
+        0 => 0,
+        start => text.next_char(start),
+    };
+    let end = match pos + WINDOW {
+        end if end >= text.len_bytes() => text.len_bytes(),
+        end => text.next_char(end),
+    };
+    text.slice(start..end).char_indices().map(|(i, c)| (start + i, c)).collect()
+}
+
+fn word_start(text: &TextBuffer, pos: usize, big: bool) -> usize {
+    let chars = chars_around(text, pos);
+    let Some(mut i) = chars.iter().position(|&(at, _)| at >= pos) else { return text.len_bytes() };
+    let first = class(chars[i].1, big);
+    if first != 0 {
+        while i < chars.len() && class(chars[i].1, big) == first {
+            i += 1;
+        }
+    }
+    while i < chars.len() && class(chars[i].1, big) == 0 {
+        // An empty line counts as a word
+        if i > 0 && chars[i].1 == '\n' && chars[i - 1].1 == '\n' && chars[i - 1].0 >= pos {
+            break;
+        }
+        i += 1;
+    }
+    chars.get(i).map_or(text.len_bytes(), |&(at, _)| at)
+}
+
+fn word_back(text: &TextBuffer, pos: usize, big: bool) -> usize {
+    let chars = chars_around(text, pos);
+    let mut i = chars.iter().position(|&(at, _)| at >= pos).unwrap_or(chars.len());
+    if i == 0 {
+        return pos;
+    }
+    i -= 1;
+    while i > 0 && class(chars[i].1, big) == 0 {
+        if chars[i].1 == '\n' && chars[i - 1].1 == '\n' {
+            return chars[i].0;
+        }
+        i -= 1;
+    }
+    let c = class(chars[i].1, big);
+    while i > 0 && class(chars[i - 1].1, big) == c {
+        i -= 1;
+    }
+    chars[i].0
+}
+
+/// Last character of the word after `pos`, or of the one at `pos` when `here`
+pub fn word_end(text: &TextBuffer, pos: usize, big: bool, here: bool) -> usize {
+    let chars = chars_around(text, pos);
+    let Some(mut i) = chars.iter().position(|&(at, _)| at >= pos) else { return pos };
+    if !here {
+        i += 1;
+    }
+    while i < chars.len() && class(chars[i].1, big) == 0 {
+        i += 1;
+    }
+    if i >= chars.len() {
+        return chars.last().map_or(pos, |&(at, _)| at);
+    }
+    let c = class(chars[i].1, big);
+    while i + 1 < chars.len() && class(chars[i + 1].1, big) == c {
+        i += 1;
+    }
+    chars[i].0
+}
+
+pub fn first_non_blank(text: &TextBuffer, line: usize) -> usize {
+    text.line_start(line) + crate::indent::leading(&text.line(line)).len()
+}
+
+fn is_blank(text: &TextBuffer, line: usize) -> bool {
+    text.line(line).trim().is_empty()
+}
+
+/// The `count`th `c` after `pos` on its line (before it, backwards), or next to it
+/// for `till`
+fn find_in_line(text: &TextBuffer, pos: usize, c: char, forward: bool, till: bool, count: usize) -> Option<usize> {
+    let line = text.line_of(pos);
+    if forward {
+        let rest = text.slice(pos..text.line_end(line));
+        let hit = rest.char_indices().skip(1).filter(|&(_, ch)| ch == c).nth(count - 1)?.0 + pos;
+        Some(if till { text.prev_char(hit) } else { hit })
+    } else {
+        let start = text.line_start(line);
+        let before = text.slice(start..pos);
+        let hit = before.char_indices().rev().filter(|&(_, ch)| ch == c).nth(count - 1)?.0 + start;
+        Some(if till { text.next_char(hit) } else { hit })
+    }
+}
+
+/// Where `motion` moves the cursor from `pos`, or `None` if it can't (e.g. `f` finds
+/// nothing). `col` is the column `j` and `k` aim for.
+pub fn motion_target(text: &TextBuffer, pos: usize, motion: Motion, count: Option<usize>, col: usize) -> Option<usize> {
+    let n = count.unwrap_or(1).max(1);
+    let line = text.line_of(pos);
+    let last = text.line_count() - 1;
+    let repeat = |f: &dyn Fn(usize) -> usize| (0..n).fold(pos, |p, _| f(p));
+    Some(match motion {
+        Motion::Left => {
+            let start = text.line_start(line);
+            repeat(&|p| if p > start { text.prev_char(p) } else { p })
+        }
+        Motion::Right => {
+            let end = text.line_end(line);
+            repeat(&|p| if p < end { text.next_char(p) } else { p })
+        }
+        Motion::Up | Motion::Down => {
+            let target = match motion {
+                Motion::Up if line > 0 => line.saturating_sub(n),
+                Motion::Down if line < last => (line + n).min(last),
+                _ => return None,
+            };
+            text.line_start(target) + crate::wrap::byte_at_col(&text.line(target), col)
+        }
+        Motion::WordStart(big) => repeat(&|p| word_start(text, p, big)),
+        Motion::WordBack(big) => repeat(&|p| word_back(text, p, big)),
+        Motion::WordEnd(big) => repeat(&|p| word_end(text, p, big, false)),
+        Motion::LineStart => text.line_start(line),
+        Motion::FirstNonBlank => first_non_blank(text, line),
+        Motion::LineEnd => text.line_end((line + n - 1).min(last)),
+        Motion::FirstLine => first_non_blank(text, count.map_or(0, |c| c.saturating_sub(1)).min(last)),
+        Motion::LastLine => first_non_blank(text, count.map_or(last, |c| c.saturating_sub(1)).min(last)),
+        Motion::Find { c, forward, till } => find_in_line(text, pos, c, forward, till, n)?,
+        Motion::MatchingBracket => {
+            // The first bracket from the cursor on
+            let rest = text.slice(pos..text.line_end(line));
+            let at = pos + rest.find(['(', ')', '[', ']', '{', '}'])?;
+            crate::indent::matching_bracket(text, at)?.1
+        }
+        Motion::ParagraphForward => {
+            let mut l = line;
+            for _ in 0..n {
+                while l <= last && is_blank(text, l) {
+                    l += 1;
+                }
+                while l <= last && !is_blank(text, l) {
+                    l += 1;
+                }
+            }
+            if l > last {
+                text.len_bytes()
+            } else {
+                text.line_start(l)
+            }
+        }
+        Motion::ParagraphBack => {
+            let mut l = line;
+            for _ in 0..n {
+                while l > 0 && is_blank(text, l) {
+                    l -= 1;
+                }
+                while l > 0 && !is_blank(text, l) {
+                    l -= 1;
+                }
+            }
+            text.line_start(l)
+        }
+    })
+}
+
+/// Bytes a text object covers at `pos`, and whether it's whole lines; line ranges
+/// run from the first line's start to the last line's end
+pub fn object_range(text: &TextBuffer, pos: usize, object: Object, around: bool) -> Option<(Range<usize>, bool)> {
+    match object {
+        Object::Word(big) => {
+            let line = text.line_of(pos);
+            let start = text.line_start(line);
+            let content = text.line(line);
+            let chars: Vec<(usize, char)> = content.char_indices().collect();
+            let i = chars.iter().position(|&(at, _)| at >= pos - start)?;
+            let kind = class(chars[i].1, big);
+            let offset = |k: usize| chars.get(k).map_or(content.len(), |&(at, _)| at);
+            let run_end = |mut k: usize| {
+                let kind = chars.get(k).map(|&(_, c)| class(c, big));
+                while k < chars.len() && Some(class(chars[k].1, big)) == kind {
+                    k += 1;
+                }
+                k
+            };
+            let (mut a, b) = (i, run_end(i));
+            while a > 0 && class(chars[a - 1].1, big) == kind {
+                a -= 1;
+            }
+            let (mut from, mut to) = (offset(a), offset(b));
+            if around {
+                if kind == 0 {
+                    // The whitespace and the word after it
+                    to = offset(run_end(b));
+                } else if b < chars.len() && class(chars[b].1, big) == 0 {
+                    to = offset(run_end(b));
+                } else {
+                    // No whitespace after the word: take what's before it
+                    while a > 0 && class(chars[a - 1].1, big) == 0 {
+                        a -= 1;
+                    }
+                    from = offset(a);
+                }
+            }
+            Some((start + from..start + to, false))
+        }
+        Object::Pair(open, close) => {
+            let chars = chars_around(text, pos);
+            let at = chars.iter().position(|&(at, _)| at >= pos).unwrap_or(chars.len()).min(chars.len().checked_sub(1)?);
+            // The nearest bracket before the cursor (or under it) that isn't closed in between
+            let mut depth = 0usize;
+            let mut open_at = None;
+            for i in (0..=at).rev() {
+                let c = chars[i].1;
+                if c == close && i != at {
+                    depth += 1;
+                } else if c == open {
+                    if depth == 0 {
+                        open_at = Some(i);
+                        break;
+                    }
+                    depth -= 1;
+                }
+            }
+            let open_at = open_at?;
+            let mut depth = 0usize;
+            let close_at = (open_at + 1..chars.len()).find(|&i| {
+                let c = chars[i].1;
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    if depth == 0 {
+                        return true;
+                    }
+                    depth -= 1;
+                }
+                false
+            })?;
+            let (open_pos, close_pos) = (chars[open_at].0, chars[close_at].0);
+            if around {
+                return Some((open_pos..close_pos + close.len_utf8(), false));
+            }
+            let (mut from, mut to) = (open_pos + open.len_utf8(), close_pos);
+            // A block's inner lines, leaving the brackets' own lines alone
+            let (open_line, close_line) = (text.line_of(open_pos), text.line_of(close_pos));
+            if close_line > open_line {
+                if text.slice(from..text.line_end(open_line)).trim().is_empty() {
+                    from = text.line_start(open_line + 1);
+                }
+                if text.slice(text.line_start(close_line)..close_pos).trim().is_empty() {
+                    to = text.line_start(close_line).max(from);
+                }
+            }
+            Some((from..to, false))
+        }
+        Object::Quote(quote) => {
+            let line = text.line_of(pos);
+            let start = text.line_start(line);
+            let content = text.line(line);
+            let bytes = content.as_bytes();
+            let quotes: Vec<usize> = content
+                .char_indices()
+                .filter(|&(i, c)| c == quote && (i == 0 || bytes[i - 1] != b'\\'))
+                .map(|(i, _)| i)
+                .collect();
+            // The pair around the cursor, or else the next one
+            let pair = quotes.chunks_exact(2).find(|pair| pair[1] >= pos - start)?;
+            let (a, b) = (pair[0], pair[1]);
+            if around {
+                let trailing = content[b + 1..].len() - content[b + 1..].trim_start().len();
+                Some((start + a..start + b + 1 + trailing, false))
+            } else {
+                Some((start + a + 1..start + b, false))
+            }
+        }
+        Object::Paragraph => {
+            let line = text.line_of(pos);
+            let last = text.line_count() - 1;
+            let kind = is_blank(text, line);
+            let (mut a, mut b) = (line, line);
+            while a > 0 && is_blank(text, a - 1) == kind {
+                a -= 1;
+            }
+            while b < last && is_blank(text, b + 1) == kind {
+                b += 1;
+            }
+            // "ap" takes the blank lines after a paragraph, or the paragraph after blank lines
+            if around {
+                while b < last && is_blank(text, b + 1) != kind {
+                    b += 1;
+                }
+            }
+            Some((text.line_start(a)..text.line_end(b), true))
+        }
+    }
+}
+
+/// Bytes to delete to remove lines `first..=last`: with the newline after them, or
+/// the one before them when they end the text
+pub fn line_range(text: &TextBuffer, first: usize, last: usize) -> Range<usize> {
+    if last + 1 < text.line_count() {
+        text.line_start(first)..text.line_start(last + 1)
+    } else if first > 0 {
+        text.line_end(first - 1)..text.len_bytes()
+    } else {
+        0..text.len_bytes()
+    }
+}
+
+/// Text with its case changed by `gu`, `gU` or `g~`
+pub fn change_case(text: &str, op: Operator) -> String {
+    match op {
+        Operator::Lowercase => text.to_lowercase(),
+        Operator::Uppercase => text.to_uppercase(),
+        _ => text
+            .chars()
+            .flat_map(|c| {
+                let swapped: Vec<char> = if c.is_uppercase() { c.to_lowercase().collect() } else { c.to_uppercase().collect() };
+                swapped
+            })
+            .collect(),
+    }
+}
+
+/// Start of the next match after `from` (or the previous one before it), wrapping
+/// around the ends of the text
+pub fn search(text: &TextBuffer, pattern: &Regex, from: usize, forward: bool) -> Option<usize> {
+    let len = text.len_bytes();
+    if forward {
+        let after = text.next_char(from);
+        let mut matches = text.find_iter(pattern, after..len).chain(text.find_iter(pattern, 0..len));
+        matches.next().map(|m| m.start)
+    } else {
+        // The last match before `from`, else the last of all
+        let (mut before, mut last) = (None, None);
+        for m in text.find_iter(pattern, 0..len) {
+            if m.start < from {
+                before = Some(m.start);
+            }
+            last = Some(m.start);
+        }
+        before.or(last)
+    }
+}
+
+/// A Vim search pattern as a regex: `\(`, `\|`, `\<` and friends are special and
+/// their bare forms literal, the other way round from regexes
+pub fn vim_pattern(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(special @ ('(' | ')' | '|' | '+' | '?' | '{' | '}')) => out.push(special),
+                Some('=') => out.push('?'),
+                Some('<' | '>') => out.push_str(r"\b"),
+                Some('/') => out.push('/'),
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push_str(r"\\"),
+            },
+            '(' | ')' | '|' | '+' | '?' | '{' | '}' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A `:s` replacement for `Regex::replace`: `&` and `\1` become `${0}` and `${1}`,
+/// `\r` and `\n` newlines
+fn vim_replacement(replacement: &str) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => out.push_str("${0}"),
+            '$' => out.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => out.push_str(&format!("${{{}}}", d)),
+                Some('r' | 'n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A line an ex command refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// 1-based, as typed
+    Line(usize),
+    Current,
+    Last,
+}
+
+/// Lines an ex command applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExRange {
+    All,
+    /// `'<,'>`: the last visual selection
+    Selection,
+    Lines(Address, Address),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ex {
+    Write,
+    Quit { force: bool },
+    WriteQuit,
+    Goto(Address),
+    Substitute { range: Option<ExRange>, pattern: String, replacement: String, all: bool, ignore_case: bool },
+}
+
+/// Read an ex command (the text after `:`)
+pub fn parse_ex(input: &str) -> Result<Ex, String> {
+    let input = input.trim();
+    let (range, rest) = parse_range(input);
+    match rest {
+        "w" | "write" => Ok(Ex::Write),
+        "q" | "quit" => Ok(Ex::Quit { force: false }),
+        "q!" | "quit!" => Ok(Ex::Quit { force: true }),
+        "wq" | "x" | "wq!" | "x!" => Ok(Ex::WriteQuit),
+        "" => match range {
+            Some(ExRange::Lines(_, line)) => Ok(Ex::Goto(line)),
+            _ => Err(format!("Not an editor command: {}", input)),
+        },
+        _ => {
+            let Some(args) = rest.strip_prefix("substitute").or_else(|| rest.strip_prefix('s')) else {
+                return Err(format!("Not an editor command: {}", input));
+            };
+            let mut chars = args.chars();
+            let delimiter = chars.next().filter(|c| !c.is_alphanumeric() && !c.is_whitespace() && *c != '\\');
+            let Some(delimiter) = delimiter else {
+                return Err(format!("Not an editor command: {}", input));
+            };
+            // Split at unescaped delimiters; an escaped one stands for itself
+            let mut parts = vec![String::new()];
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some(d) if d == delimiter => parts.last_mut().unwrap().push(d),
+                        Some(other) => {
+                            let part = parts.last_mut().unwrap();
+                            part.push('\\');
+                            part.push(other);
+                        }
+                        None => parts.last_mut().unwrap().push('\\'),
+                    },
+                    c if c == delimiter && parts.len() < 3 => parts.push(String::new()),
+                    c => parts.last_mut().unwrap().push(c),
+                }
+            }
+            let pattern = parts[0].clone();
+            if pattern.is_empty() {
+                return Err("No previous substitute pattern".to_string());
+            }
+            let replacement = parts.get(1).cloned().unwrap_or_default();
+            let flags = parts.get(2).cloned().unwrap_or_default();
+            if let Some(flag) = flags.chars().find(|c| !matches!(c, 'g' | 'i' | 'I')) {
+                return Err(format!("Unknown flag: {}", flag));
+            }
+            Ok(Ex::Substitute {
+                range,
+                pattern: vim_pattern(&pattern),
+                replacement: vim_replacement(&replacement),
+                all: flags.contains('g'),
+                ignore_case: flags.contains('i'),
+            })
+        }
+    }
+}
+
+fn parse_range(input: &str) -> (Option<ExRange>, &str) {
+    if let Some(rest) = input.strip_prefix('%') {
+        return (Some(ExRange::All), rest);
+    }
+    if let Some(rest) = input.strip_prefix("'<,'>") {
+        return (Some(ExRange::Selection), rest);
+    }
+    let (Some(first), rest) = parse_address(input) else { return (None, input) };
+    if let Some(after) = rest.strip_prefix(',') {
+        if let (Some(second), rest) = parse_address(after) {
+            return (Some(ExRange::Lines(first, second)), rest);
+        }
+    }
+    (Some(ExRange::Lines(first, first)), rest)
+}
+
+fn parse_address(input: &str) -> (Option<Address>, &str) {
+    if let Some(rest) = input.strip_prefix('.') {
+        return (Some(Address::Current), rest);
+    }
+    if let Some(rest) = input.strip_prefix('$') {
+        return (Some(Address::Last), rest);
+    }
+    let digits = input.len() - input.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match input[..digits].parse() {
+        Ok(n) => (Some(Address::Line(n)), &input[digits..]),
+        Err(_) => (None, input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(text: &str) -> Vec<VimKey> {
+        text.chars().map(VimKey::Char).collect()
+    }
+
+    fn command(text: &str) -> Option<Command> {
+        let mut vim = Vim::new();
+        keys(text).into_iter().filter_map(|k| vim.key(k)).last()
+    }
+
+    #[test]
+    fn parses_counts_operators_and_objects() {
+        let c = command("2d3w").unwrap();
+        assert_eq!((c.action, c.count), (Action::Operate(Operator::Delete, Target::Motion(Motion::WordStart(false))), Some(6)));
+        let c = command("\"ayy").unwrap();
+        assert_eq!((c.action, c.register), (Action::Operate(Operator::Yank, Target::Lines), Some('a')));
+        assert_eq!(command("ci(").unwrap().action, Action::Operate(Operator::Change, Target::Object(Object::Pair('(', ')'), false)));
+        assert_eq!(command("gUiw").unwrap().action, Action::Operate(Operator::Uppercase, Target::Object(Object::Word(false), false)));
+        assert_eq!(command("dt,").unwrap().action, Action::Operate(Operator::Delete, Target::Motion(Motion::Find { c: ',', forward: true, till: true })));
+        assert_eq!(command("0").unwrap().action, Action::Move(Motion::LineStart));
+        let c = command("10G").unwrap();
+        assert_eq!((c.action, c.count), (Action::Move(Motion::LastLine), Some(10)));
+        assert!(command("d").is_none());
+        assert!(command("dq").is_none());
+    }
+
+    #[test]
+    fn records_changes_for_repeat() {
+        let mut vim = Vim::new();
+        for k in keys("cw") {
+            vim.key(k);
+        }
+        vim.record(VimKey::Char('x'));
+        vim.record(VimKey::Esc);
+        vim.end_change();
+        // Moving doesn't replace the change
+        vim.key(VimKey::Char('w'));
+        vim.end_change();
+        assert_eq!(vim.last_change(None), vec![VimKey::Char('c'), VimKey::Char('w'), VimKey::Char('x'), VimKey::Esc]);
+
+        // A count given to `.` replaces the one the change was made with
+        for k in keys("\"a2d3w") {
+            vim.key(k);
+        }
+        vim.end_change();
+        assert_eq!(vim.last_change(None), keys("\"a6dw"));
+        assert_eq!(vim.last_change(Some(2)), keys("\"a2dw"));
+        for k in keys("gU2iw") {
+            vim.key(k);
+        }
+        vim.end_change();
+        assert_eq!(vim.last_change(None), keys("2gUiw"));
+        for k in keys("x") {
+            vim.key(k);
+        }
+        vim.end_change();
+        assert_eq!(vim.last_change(Some(10)), keys("10x"));
+    }
+
+    #[test]
+    fn moves_by_words() {
+        let text = TextBuffer::from("foo.bar baz\n\nqux");
+        assert_eq!(motion_target(&text, 0, Motion::WordStart(false), None, 0), Some(3));
+        assert_eq!(motion_target(&text, 0, Motion::WordStart(true), None, 0), Some(8));
+        // The empty line is a word of its own
+        assert_eq!(motion_target(&text, 8, Motion::WordStart(false), None, 0), Some(12));
+        assert_eq!(motion_target(&text, 13, Motion::WordBack(false), None, 0), Some(12));
+        assert_eq!(motion_target(&text, 8, Motion::WordBack(false), Some(2), 0), Some(3));
+        assert_eq!(motion_target(&text, 0, Motion::WordEnd(false), None, 0), Some(2));
+        assert_eq!(motion_target(&text, 2, Motion::WordEnd(false), None, 0), Some(3));
+    }
+
+    #[test]
+    fn moves_within_and_between_lines() {
+        let text = TextBuffer::from("  let x = (a, b);\nshort\n");
+        assert_eq!(motion_target(&text, 0, Motion::FirstNonBlank, None, 0), Some(2));
+        assert_eq!(motion_target(&text, 0, Motion::Find { c: ',', forward: true, till: false }, None, 0), Some(12));
+        assert_eq!(motion_target(&text, 0, Motion::Find { c: ',', forward: true, till: true }, None, 0), Some(11));
+        assert_eq!(motion_target(&text, 0, Motion::Find { c: 'z', forward: true, till: false }, None, 0), None);
+        assert_eq!(motion_target(&text, 5, Motion::MatchingBracket, None, 0), Some(15));
+        // Down keeps the column where the line is long enough
+        assert_eq!(motion_target(&text, 9, Motion::Down, None, 3), Some(21));
+        assert_eq!(motion_target(&text, 9, Motion::Down, None, 9), Some(23));
+        assert_eq!(motion_target(&text, 0, Motion::LastLine, None, 0), Some(24));
+    }
+
+    #[test]
+    fn finds_text_objects() {
+        let text = TextBuffer::from("call(a, \"b c\") next");
+        assert_eq!(object_range(&text, 6, Object::Pair('(', ')'), false), Some((5..13, false)));
+        assert_eq!(object_range(&text, 6, Object::Pair('(', ')'), true), Some((4..14, false)));
+        assert_eq!(object_range(&text, 10, Object::Quote('"'), false), Some((9..12, false)));
+        assert_eq!(object_range(&text, 1, Object::Word(false), false), Some((0..4, false)));
+        assert_eq!(object_range(&text, 16, Object::Word(false), true), Some((14..19, false)));
+
+        // Inside a block, the lines between the brackets
+        let text = TextBuffer::from("fn f() {\n    a();\n}\n");
+        assert_eq!(object_range(&text, 12, Object::Pair('{', '}'), false), Some((9..18, false)));
+
+        let text = TextBuffer::from("a\nb\n\nc\n");
+        assert_eq!(object_range(&text, 0, Object::Paragraph, false), Some((0..3, true)));
+        assert_eq!(object_range(&text, 0, Object::Paragraph, true), Some((0..4, true)));
+    }
+
+    #[test]
+    fn deletes_whole_lines() {
+        let text = TextBuffer::from("a\nb\nc");
+        assert_eq!(line_range(&text, 0, 0), 0..2);
+        assert_eq!(line_range(&text, 2, 2), 3..5);
+        assert_eq!(line_range(&text, 0, 2), 0..5);
+    }
+
+    #[test]
+    fn parses_ex_commands() {
+        assert_eq!(parse_ex("wq"), Ok(Ex::WriteQuit));
+        assert_eq!(parse_ex("q!"), Ok(Ex::Quit { force: true }));
+        assert_eq!(parse_ex("42"), Ok(Ex::Goto(Address::Line(42))));
+        assert_eq!(
+            parse_ex(r"%s/\(foo\)+/[\1&]/g"),
+            Ok(Ex::Substitute {
+                range: Some(ExRange::All),
+                pattern: r"(foo)\+".to_string(),
+                replacement: "[${1}${0}]".to_string(),
+                all: true,
+                ignore_case: false,
+            })
+        );
+        let Ok(Ex::Substitute { range, pattern, .. }) = parse_ex(r"'<,'>s#a\#b#c#") else { panic!() };
+        assert_eq!((range, pattern.as_str()), (Some(ExRange::Selection), "a#b"));
+        assert!(parse_ex("frobnicate").is_err());
+    }
+}
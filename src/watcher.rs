@@ -0,0 +1,124 @@
+use eframe::egui;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Watches directories (not recursively) and collects the paths changed in them, so
+/// open files and the file tree notice edits made by other programs
+pub struct FileWatcher {
+    watcher: Option<RecommendedWatcher>,
+    watched: HashSet<PathBuf>,
+    /// Paths changed since the last `take_changed`, filled by the watcher thread
+    changed: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl FileWatcher {
+    pub fn new(ctx: &egui::Context) -> Self {
+        let changed = Arc::new(Mutex::new(HashSet::new()));
+        let sink = changed.clone();
+        let ctx = ctx.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                let mut changed = sink.lock().unwrap();
+                for path in event.paths {
+                    changed.insert(canonical(&path));
+                    changed.insert(path);
+                }
+                ctx.request_repaint();
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("File watcher: {}", e),
+        });
+        let watcher = watcher.map_err(|e| eprintln!("Failed to start file watcher: {}", e)).ok();
+        Self { watcher, watched: HashSet::new(), changed }
+    }
+
+    /// Watch exactly these directories
+    pub fn watch_dirs(&mut self, dirs: HashSet<PathBuf>) {
+        let Some(watcher) = &mut self.watcher else { return };
+        for dir in self.watched.difference(&dirs) {
+            let _ = watcher.unwatch(dir);
+        }
+        for dir in dirs.difference(&self.watched) {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                eprintln!("Failed to watch {}: {}", dir.display(), e);
+            }
+        }
+        self.watched = dirs;
+    }
+
+    /// Paths created, changed or removed since the last call, both as notify reported
+    /// them and `canonical`
+    pub fn take_changed(&mut self) -> HashSet<PathBuf> {
+        std::mem::take(&mut *self.changed.lock().unwrap())
+    }
+}
+
+/// `path` with symlinks and `..` resolved, for comparing paths reported by notify
+/// with those of open files: on macOS, for one, events under `/var` arrive as
+/// `/private/var`. A file that no longer exists keeps its name in its resolved folder.
+pub fn canonical(path: &Path) -> PathBuf {
+    if let Ok(real) = path.canonicalize() {
+        return real;
+    }
+    match (path.parent().and_then(|dir| dir.canonicalize().ok()), path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// A temp folder holding an empty `real` folder
+    fn folder() -> tempfile::TempDir {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir(folder.path().join("real")).unwrap();
+        folder
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_symlinks_and_missing_files() {
+        let folder = folder();
+        let link = folder.path().join("link");
+        std::os::unix::fs::symlink(folder.path().join("real"), &link).unwrap();
+        std::fs::write(folder.path().join("real/a.txt"), "a").unwrap();
+
+        let real = folder.path().join("real").canonicalize().unwrap();
+        assert_eq!(canonical(&link.join("a.txt")), real.join("a.txt"));
+        assert_eq!(canonical(&link.join("../real/a.txt")), real.join("a.txt"));
+        // Removed files resolve through their folder
+        assert_eq!(canonical(&link.join("gone.txt")), real.join("gone.txt"));
+        assert_eq!(canonical(Path::new("/no/such/dir/x")), Path::new("/no/such/dir/x"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_changes_under_either_path() {
+        let folder = folder();
+        let link = folder.path().join("link");
+        std::os::unix::fs::symlink(folder.path().join("real"), &link).unwrap();
+        let file = link.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+
+        // An editor opened the file through the link; the folder is watched through it too
+        let mut watcher = FileWatcher::new(&egui::Context::default());
+        if watcher.watcher.is_none() {
+            return;
+        }
+        watcher.watch_dirs(HashSet::from([link.clone()]));
+        std::fs::write(folder.path().join("real/a.txt"), "changed").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut changed = HashSet::new();
+        while Instant::now() < deadline && !changed.contains(&canonical(&file)) {
+            std::thread::sleep(Duration::from_millis(20));
+            changed.extend(watcher.take_changed());
+        }
+        assert!(changed.contains(&canonical(&file)), "{:?}", changed);
+    }
+}
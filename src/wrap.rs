@@ -0,0 +1,159 @@
+use crate::buffer::TextBuffer;
+use std::ops::Range;
+use unicode_width::UnicodeWidthChar;
+
+const TAB_COLS: usize = 4;
+
+/// Columns a character takes on screen: two for wide (CJK) characters, four for a tab
+pub fn char_cols(c: char) -> usize {
+    match c {
+        '\t' => TAB_COLS,
+        c => c.width().unwrap_or(1),
+    }
+}
+
+/// Columns a piece of text takes on screen
+pub fn width(text: &str) -> usize {
+    text.chars().map(char_cols).sum()
+}
+
+/// Byte offset in `text` of the character boundary nearest to column `col`
+pub fn byte_at_col(text: &str, col: usize) -> usize {
+    let mut used = 0;
+    for (i, c) in text.char_indices() {
+        let w = char_cols(c);
+        if col <= used + w / 2 {
+            return i;
+        }
+        used += w;
+    }
+    text.len()
+}
+
+/// Byte offsets where a line's rows after the first start, when rows are `cols`
+/// wide. Lines break after spaces, or between wide characters, where possible and
+/// anywhere in a word too long for a row; spaces may hang past the edge.
+pub fn breaks(line: &str, cols: usize) -> Vec<usize> {
+    let mut out = Vec::new();
+    let (mut row_start, mut used, mut last_break) = (0, 0, None);
+    for (i, c) in line.char_indices() {
+        let w = char_cols(c);
+        if used + w > cols && i > row_start && !c.is_whitespace() {
+            let at = match last_break {
+                Some(b) if b > row_start && w == 1 => b,
+                _ => i,
+            };
+            out.push(at);
+            row_start = at;
+            used = width(&line[at..i]);
+            last_break = None;
+        }
+        used += w;
+        if c.is_whitespace() || w > 1 {
+            last_break = Some(i + c.len_utf8());
+        }
+    }
+    out
+}
+
+/// How a buffer's lines map to visual rows: broken up by soft wrap, and hidden
+/// inside folds
+pub struct Layout {
+    /// Row width when soft wrap is on
+    pub cols: Option<usize>,
+    /// Editor revision the layout was made for
+    pub revision: u64,
+    /// Hidden lines, as sorted ranges that don't overlap
+    pub hidden: Vec<Range<usize>>,
+    breaks: Vec<Vec<usize>>,
+    /// First row of each line (hidden lines share the next line's), then the total
+    first_rows: Vec<usize>,
+}
+
+impl Layout {
+    pub fn new(content: &TextBuffer, cols: Option<usize>, hidden: Vec<Range<usize>>, revision: u64) -> Self {
+        let lines = content.line_count();
+        let mut breaks = Vec::with_capacity(lines);
+        let mut first_rows = Vec::with_capacity(lines + 1);
+        let mut rows = 0;
+        let mut next_hidden = hidden.iter().peekable();
+        for line in 0..lines {
+            while next_hidden.next_if(|h| h.end <= line).is_some() {}
+            first_rows.push(rows);
+            if next_hidden.peek().is_some_and(|h| h.contains(&line)) {
+                breaks.push(Vec::new());
+                continue;
+            }
+            let line_breaks = cols.map(|cols| self::breaks(&content.line(line), cols)).unwrap_or_default();
+            rows += line_breaks.len() + 1;
+            breaks.push(line_breaks);
+        }
+        first_rows.push(rows);
+        Self { cols, revision, hidden, breaks, first_rows }
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.first_rows.last().copied().unwrap_or(0)
+    }
+
+    /// Line shown on a row, and where the row starts within the line
+    pub fn row(&self, row: usize) -> (usize, usize) {
+        let row = row.min(self.row_count().saturating_sub(1));
+        let line = self.first_rows.partition_point(|&first| first <= row).saturating_sub(1);
+        let index = row - self.first_rows[line];
+        (line, if index == 0 { 0 } else { self.breaks[line][index - 1] })
+    }
+
+    /// Where the row after `row` starts within its line, if it's on the same line
+    pub fn row_end(&self, row: usize) -> Option<usize> {
+        let (line, _) = self.row(row);
+        self.breaks[line].get(row - self.first_rows[line]).copied()
+    }
+
+    /// Row showing an offset within a line; a break belongs to the row it starts
+    pub fn row_of(&self, line: usize, offset: usize) -> usize {
+        self.first_rows[line] + self.breaks[line].partition_point(|&b| b <= offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaks_after_spaces_and_inside_long_words() {
+        assert_eq!(breaks("the quick brown fox", 10), vec![10]);
+        assert_eq!(breaks("abcdefghijkl", 5), vec![5, 10]);
+        // Spaces hang past the edge rather than starting a row
+        assert_eq!(breaks("abcde   fg", 5), vec![8]);
+        assert!(breaks("short", 10).is_empty());
+    }
+
+    #[test]
+    fn wide_characters_take_two_columns() {
+        assert_eq!(width("日本語abc"), 9);
+        // Three wide characters fill six columns; the fourth starts a row
+        assert_eq!(breaks("日本語です", 6), vec![9]);
+        assert_eq!(byte_at_col("日本", 2), 3);
+        assert_eq!(byte_at_col("日本", 1), 0);
+        assert_eq!(byte_at_col("日本", 9), 6);
+    }
+
+    #[test]
+    fn maps_rows_to_lines() {
+        let content = TextBuffer::from("aaaa bbbb\ncc\n");
+        let layout = Layout::new(&content, Some(5), Vec::new(), 0);
+        assert_eq!(layout.row_count(), 4);
+        assert_eq!(layout.row(1), (0, 5));
+        assert_eq!(layout.row_end(0), Some(5));
+        assert_eq!(layout.row_end(1), None);
+        assert_eq!(layout.row(2), (1, 0));
+        assert_eq!(layout.row_of(0, 5), 1);
+        assert_eq!(layout.row_of(1, 2), 2);
+
+        // Folding away line 1 leaves the wrapped first line and the empty last one
+        let layout = Layout::new(&content, Some(5), vec![Range { start: 1, end: 2 }], 0);
+        assert_eq!(layout.row_count(), 3);
+        assert_eq!(layout.row(2), (2, 0));
+    }
+}